@@ -1,32 +1,45 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use clap::Args;
+use clap::{Args, Subcommand, ValueEnum};
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 use tokio::process::Command;
 use tokio::signal;
-use tracing::{debug, warn};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 //use tracing::instrument;
 //use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use atty::Stream;
 use chrono::prelude::*;
 use colored_json::to_colored_json_auto;
+use futures_util::future::join_all;
 
+use crate::kap_audit::toml_redacted;
 use crate::kap_daemon::KCoreConfig;
-use crate::kap_daemon::{KBossConfig, KNetworkConfig, KPorConfig};
-use crate::setup_logging;
+use crate::kap_daemon::{ConfigInvalid, KBossConfig, KNetworkConfig, KPorConfig, KdaemonConfig};
+use crate::kap_notify::{notify, notify_init, Notification, NotifyKind, NotifySeverity};
+use crate::kap_rule::{toml_commented, toml_commented_out, RuleConfig};
+#[cfg(feature = "wallet")]
+use crate::misc::wallet_keystore_new;
+use crate::misc::write_atomic;
 #[cfg(feature = "aws-iot")]
 use crate::{
     aws_iot::{mqtt_provision_task, AwsIotKeyCertificate},
     rule_config_load,
 };
+use crate::{setup_logging, setup_logging_format, LogFormat};
 
 //type DbConnection = redis::aio::Connection;
 
 #[derive(Args, Debug, Clone)]
 #[clap(about = "FIKA manager activate with factory data")]
 pub struct ActivateOpt {
+    #[clap(subcommand)]
+    command: Option<ActivateCommand>,
     #[clap(
         short = 'p',
         long = "activate-rule",
@@ -35,7 +48,18 @@ pub struct ActivateOpt {
     active: String,
     #[clap(short = 'l', long = "log-level", default_value = "info")]
     log_level: String,
-    #[clap(short, long, action)]
+    #[clap(
+        long = "log-format",
+        value_enum,
+        help = "default rule/core/log_format or text"
+    )]
+    log_format: Option<LogFormat>,
+    #[clap(
+        short,
+        long,
+        action,
+        help = "re-apply sections unchanged since last time"
+    )]
     force: bool,
     #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
     config: String,
@@ -45,11 +69,365 @@ pub struct ActivateOpt {
         default_value = "/etc/fika_manager/rule.toml"
     )]
     rule: String,
+    #[clap(
+        long = "backup-dir",
+        default_value = "/userdata/fika_backup",
+        help = "snapshot config/certs here before activation"
+    )]
+    backup_dir: String,
+    #[clap(long = "no-backup", action)]
+    no_backup: bool,
+    #[clap(
+        long = "dry-run",
+        action,
+        help = "print the planned actions, run nothing"
+    )]
+    dry_run: bool,
+    #[clap(long = "restore", help = "snapshot name or path to roll back")]
+    restore: Option<String>,
+    #[clap(
+        long = "only",
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with = "skip"
+    )]
+    only: Option<Vec<ActivateSection>>,
+    #[clap(long = "skip", value_enum, value_delimiter = ',')]
+    skip: Option<Vec<ActivateSection>>,
+    #[clap(
+        long = "signature",
+        help = "factory data signature [default: <activate-rule>.sig]"
+    )]
+    signature: Option<String>,
+    #[clap(
+        long = "wallet-dir",
+        default_value = "/userdata/wallet",
+        help = "keystore of the wallet generated when core.wallet_address is missing"
+    )]
+    wallet_dir: String,
+    #[clap(
+        long = "wallet-password-file",
+        default_value = "/etc/fika_manager/wallet.pass",
+        help = "passphrase file, secret:[FILE] or keyring:FILE[#ENTRY]"
+    )]
+    wallet_password_file: String,
+    #[clap(
+        long = "log-dir",
+        default_value = "/var/log/fika_manager/activate",
+        help = "hook outputs, bundled as tar.gz here on failure"
+    )]
+    log_dir: String,
+    #[clap(
+        long = "state-file",
+        help = "keep keys in this json file instead of redis [fallback when redis is down]"
+    )]
+    state_file: Option<String>,
+}
+
+impl ActivateOpt {
+    /* command line defaults for callers without a command line */
+    fn with_paths(active: &str, config: &str, rule: &str) -> Self {
+        Self {
+            command: None,
+            active: active.to_string(),
+            log_level: "info".to_string(),
+            log_format: None,
+            force: false,
+            config: config.to_string(),
+            rule: rule.to_string(),
+            backup_dir: "/userdata/fika_backup".to_string(),
+            no_backup: false,
+            dry_run: false,
+            restore: None,
+            only: None,
+            skip: None,
+            signature: None,
+            wallet_dir: "/userdata/wallet".to_string(),
+            wallet_password_file: "/etc/fika_manager/wallet.pass".to_string(),
+            log_dir: "/var/log/fika_manager/activate".to_string(),
+            state_file: None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ActivateCommand {
+    #[clap(about = "check what a previous activation left on the device")]
+    Verify,
+    #[clap(about = "re-run sections requested by cloud, report back via shadow")]
+    Remote {
+        #[clap(help = r#"{"id": $id, "sections": ["boss"], "force": false} or subscribe args"#)]
+        request: String,
+    },
+}
+
+#[derive(ValueEnum, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivateSection {
+    Core,
+    Network,
+    Por,
+    Boss,
+    Provision,
+}
+
+impl ActivateSection {
+    const ALL: [ActivateSection; 5] = [
+        ActivateSection::Core,
+        ActivateSection::Network,
+        ActivateSection::Por,
+        ActivateSection::Boss,
+        ActivateSection::Provision,
+    ];
+
+    /* wallet/mac/serial from core are needed by boss and provision */
+    fn depends(&self) -> &'static [ActivateSection] {
+        match self {
+            ActivateSection::Boss | ActivateSection::Provision => &[ActivateSection::Core],
+            _ => &[],
+        }
+    }
+}
+
+fn sections_select(opt: &ActivateOpt) -> Vec<ActivateSection> {
+    ActivateSection::ALL
+        .iter()
+        .filter(|s| opt.only.as_ref().map(|o| o.contains(s)).unwrap_or(true))
+        .filter(|s| !opt.skip.as_ref().map(|o| o.contains(s)).unwrap_or(false))
+        .copied()
+        .collect()
+}
+
+async fn db_connect() -> Result<redis::aio::Connection> {
+    redis::Client::open("redis://127.0.0.1:6379")
+        .map_err(|e| anyhow!("db/redis open fail - {e}"))?
+        .get_async_connection()
+        .await
+        .map_err(|e| anyhow!("db/redis async connect fail - {e}"))
+}
+
+/*
+ * one event per step on ACTIVATE_PROGRESS_CHANNEL (latest also set under the
+ * same key) for the web UI/installer; redis being down only loses events
+ */
+pub(crate) const ACTIVATE_PROGRESS_CHANNEL: &str = "kap/activate/progress";
+
+#[derive(Serialize, Debug)]
+struct ActivateProgressEvent<'a> {
+    section: ActivateSection,
+    step: &'a str,
+    state: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    index: usize,
+    total: usize,
+    timestamp: DateTime<Utc>,
+}
+
+struct ActivateProgress {
+    db_conn: Option<redis::aio::Connection>,
+    index: usize,
+    total: usize,
+}
+
+impl ActivateProgress {
+    async fn new(sections: &[ActivateSection]) -> Self {
+        let db_conn = match db_connect().await {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!("activate progress not published - {e}");
+                None
+            }
+        };
+        let total = sections
+            .iter()
+            .map(|s| {
+                if *s == ActivateSection::Provision {
+                    1
+                } else {
+                    3
+                }
+            })
+            .sum();
+
+        Self {
+            db_conn,
+            index: 0,
+            total,
+        }
+    }
+
+    /* a step start moves the bar, an error re-sends the same index as fail */
+    async fn step(&mut self, section: ActivateSection, step: &str, error: Option<&anyhow::Error>) {
+        if error.is_none() {
+            self.index += 1;
+        }
+        let state = if error.is_some() { "fail" } else { "start" };
+        self.emit(section, step, state, error.map(|e| e.to_string()))
+            .await;
+    }
+
+    async fn finish(&mut self, section: ActivateSection) {
+        self.index = self.total;
+        self.emit(section, "activate", "done", None).await;
+    }
+
+    async fn emit(
+        &mut self,
+        section: ActivateSection,
+        step: &str,
+        state: &str,
+        error: Option<String>,
+    ) {
+        let event = ActivateProgressEvent {
+            section,
+            step,
+            state,
+            error,
+            index: self.index,
+            total: self.total,
+            timestamp: Utc::now(),
+        };
+        debug!("activate progress {:?}", event);
+
+        let db_conn = if let Some(ref mut c) = self.db_conn {
+            c
+        } else {
+            return;
+        };
+        let payload = match serde_json::to_string(&event) {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        if let Err(e) = db_conn
+            .set::<_, _, ()>(ACTIVATE_PROGRESS_CHANNEL, &payload)
+            .await
+        {
+            warn!("db/redis set {ACTIVATE_PROGRESS_CHANNEL} fail - {e}");
+        }
+        if let Err(e) = db_conn
+            .publish::<_, _, ()>(ACTIVATE_PROGRESS_CHANNEL, &payload)
+            .await
+        {
+            warn!("db/redis publish {ACTIVATE_PROGRESS_CHANNEL} fail - {e}");
+        }
+    }
+}
+
+/*
+ * key/{key}.done store, redis normally; a json object file for manufacturing
+ * images without the runtime stack, picked by --state-file or when redis
+ * does not answer
+ */
+const ACTIVATE_STATE_FILE: &str = "/userdata/fika_activate_state.json";
+
+fn section_marker(section: ActivateSection) -> String {
+    format!("kap/activate/{:?}.hash", section).to_lowercase()
+}
+
+enum ActivateStore {
+    Redis,
+    /* sections may run concurrently, serialize the read-modify-write */
+    File(PathBuf, Mutex<()>),
+}
+
+impl ActivateStore {
+    async fn open(opt: &ActivateOpt) -> Self {
+        if let Some(ref path) = opt.state_file {
+            return Self::File(PathBuf::from(path), Mutex::new(()));
+        }
+
+        match db_connect().await {
+            Ok(_) => Self::Redis,
+            Err(e) => {
+                warn!("{e}, keys kept in {}", ACTIVATE_STATE_FILE);
+                Self::File(PathBuf::from(ACTIVATE_STATE_FILE), Mutex::new(()))
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Redis => "redis",
+            Self::File(..) => "file",
+        }
+    }
+
+    async fn file_load(path: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+        match fs::read_to_string(path).await {
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| anyhow!("state {} invalid - {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(serde_json::Map::new()),
+            Err(e) => Err(anyhow!("state {} open fail - {e}", path.display())),
+        }
+    }
+
+    async fn file_save(
+        path: &Path,
+        state: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<()> {
+        write_atomic(path, serde_json::to_string_pretty(state)?)
+            .await
+            .map_err(|e| anyhow!("state {e}"))
+    }
+
+    async fn set(&self, key: &str, val: &str) -> Result<()> {
+        match self {
+            Self::Redis => db_connect()
+                .await?
+                .set::<_, _, ()>(key, val)
+                .await
+                .map_err(|e| anyhow!("db/redis set {key}/{val} fail - {e}")),
+            Self::File(path, lock) => {
+                let _lock = lock.lock().await;
+                let mut state = Self::file_load(path).await?;
+                state.insert(key.to_string(), serde_json::Value::String(val.to_string()));
+                Self::file_save(path, &state).await
+            }
+        }
+    }
+
+    async fn incr(&self, key: &str) -> Result<()> {
+        match self {
+            Self::Redis => Ok(db_connect().await?.incr::<_, _, ()>(key, 1).await?),
+            Self::File(path, lock) => {
+                let _lock = lock.lock().await;
+                let mut state = Self::file_load(path).await?;
+                let n = state.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+                state.insert(key.to_string(), serde_json::Value::from(n + 1));
+                Self::file_save(path, &state).await
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        match self {
+            Self::Redis => Ok(db_connect().await?.get(key).await?),
+            Self::File(path, _) => Ok(Self::file_load(path)
+                .await?
+                .get(key)
+                .and_then(|v| v.as_str().map(|v| v.to_string()))),
+        }
+    }
+
+    /* the content marker activate_section keeps once a section succeeded */
+    async fn applied(&self, section: ActivateSection) -> Result<bool> {
+        Ok(self.get(&section_marker(section)).await?.is_some())
+    }
+}
+
+/* per run state shared by the sections */
+struct ActivateContext {
+    progress: Mutex<ActivateProgress>,
+    store: ActivateStore,
+    run_dir: PathBuf,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 #[allow(dead_code)]
 struct KapFactory {
+    /* run sections whose depends are met concurrently */
+    parallel: Option<bool>,
     core: KapCoreConfig,
     network: KapNetworkConfig,
     por: KapPorConfig,
@@ -58,97 +436,87 @@ struct KapFactory {
 
 #[async_trait]
 trait FactoryAction {
-    async fn post(&self) -> Result<()> {
-        if let Some(post) = self.get_post() {
-            let mut child = if let Some(key) = self.get_key() {
-                if let Some(cfg) = self.get_cfg() {
-                    let args = serde_json::to_string(&cfg)?;
-                    Command::new(&post)
-                        .arg(&args)
-                        .arg(key)
-                        .spawn()
-                        .map_err(|e| anyhow!("{post}/{args}/{key} run fail - {e}"))?
-                } else {
-                    Command::new(&post)
-                        .arg(key)
-                        .spawn()
-                        .map_err(|e| anyhow!("{post}/{key} run fail - {e}"))?
-                }
-            } else {
-                if let Some(cfg) = self.get_cfg() {
-                    let args = serde_json::to_string(&cfg)?;
-                    Command::new(&post)
-                        .arg(&args)
-                        .spawn()
-                        .map_err(|e| anyhow!("{post}/{args} run fail - {e}"))?
-                } else {
-                    Command::new(&post)
-                        .spawn()
-                        .map_err(|e| anyhow!("{post} run fail - {e}"))?
+    async fn hook(
+        &self,
+        section: ActivateSection,
+        step: &str,
+        path: &str,
+        run_dir: &Path,
+    ) -> Result<()> {
+        let mut cmd = Command::new(path);
+        if let Some(cfg) = self.get_cfg() {
+            cmd.arg(cfg);
+        }
+        if let Some(key) = self.get_key() {
+            cmd.arg(key);
+        }
+        /* own process group, so a timeout takes the hook's children down too */
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+        /* kept for the failure bundle, stdout is the activation result */
+        let log = std::fs::File::create(
+            run_dir.join(format!("{:?}_{step}.log", section).to_lowercase()),
+        )?;
+        let mut child = cmd
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("{:?}/{step} {path} run fail - {e}", section))?;
+
+        let status = if let Some(timeout) = self.get_timeout() {
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    if let Some(pid) = child.id() {
+                        unsafe {
+                            libc::kill(-(pid as i32), libc::SIGKILL);
+                        }
+                    }
+                    _ = child.kill().await;
+                    return Err(anyhow!(
+                        "{:?}/{step} {path} timeout after {}s, killed",
+                        section,
+                        timeout.as_secs()
+                    ));
                 }
-            };
+            }
+        } else {
+            child.wait().await?
+        };
+        debug!("command {} run completed - {}", path, status);
+        if !status.success() {
+            return Err(anyhow!("{:?}/{step} {path} fail - {}", section, status));
+        }
 
-            let status = child.wait().await?;
-            debug!("command {} run completed - {}", post, status);
+        Ok(())
+    }
+    async fn post(&self, section: ActivateSection, run_dir: &Path) -> Result<()> {
+        if let Some(post) = self.get_post() {
+            self.hook(section, "post", post, run_dir).await?;
         }
 
         Ok(())
     }
-    async fn pre(&self) -> Result<()> {
+    async fn pre(&self, section: ActivateSection, run_dir: &Path) -> Result<()> {
         if let Some(pre) = self.get_pre() {
-            let mut child = if let Some(key) = self.get_key() {
-                if let Some(cfg) = self.get_cfg() {
-                    let args = serde_json::to_string(&cfg)?;
-                    Command::new(&pre)
-                        .arg(&args)
-                        .arg(key)
-                        .spawn()
-                        .map_err(|e| anyhow!("{pre}/{args}/{key} run fail - {e}"))?
-                } else {
-                    Command::new(&pre)
-                        .arg(key)
-                        .spawn()
-                        .map_err(|e| anyhow!("{pre}/{key} run fail - {e}"))?
-                }
-            } else {
-                if let Some(cfg) = self.get_cfg() {
-                    let args = serde_json::to_string(&cfg)?;
-                    Command::new(&pre)
-                        .arg(&args)
-                        .spawn()
-                        .map_err(|e| anyhow!("{pre}/{args} run fail - {e}"))?
-                } else {
-                    Command::new(&pre)
-                        .spawn()
-                        .map_err(|e| anyhow!("{pre} run fail - {e}"))?
-                }
-            };
-
-            let status = child.wait().await?;
-            debug!("command {} run completed - {}", pre, status);
+            self.hook(section, "pre", pre, run_dir).await?;
         }
 
         Ok(())
     }
 
-    async fn key_apply(&self) -> Result<()> {
-        let mut db_conn = redis::Client::open("redis://127.0.0.1:6379")
-            .map_err(|e| anyhow!("db/redis open fail - {e}"))?
-            .get_async_connection()
-            .await
-            .map_err(|e| anyhow!("db/redis async connect fail - {e}"))?;
-
+    async fn key_apply(&self, store: &ActivateStore) -> Result<()> {
         if let Some(key) = self.get_key() {
             if let Some(args) = self.get_cfg() {
                 //serde_json::to_string(&self.cfg)?;
                 debug!("args as {}", args);
-                db_conn
-                    .set(&key, &args)
-                    .await
-                    .map_err(|e| anyhow!("db/redis set {key}/{args} fail - {e}"))?;
-
-                let key = format!("{}.done", key);
-                db_conn.incr(&key, 1).await?
+                store.set(key, &args).await?;
+                store.incr(&format!("{}.done", key)).await?;
             }
         }
 
@@ -158,11 +526,130 @@ trait FactoryAction {
     fn get_post(&self) -> Option<&String>;
     fn get_pre(&self) -> Option<&String>;
     fn get_cfg(&self) -> Option<String>;
+    fn get_timeout(&self) -> Option<Duration>;
+    fn get_depends(&self) -> Option<&Vec<ActivateSection>>;
+
+    async fn run(
+        &self,
+        section: ActivateSection,
+        ctx: &ActivateContext,
+        _force: bool,
+    ) -> Result<()> {
+        let (progress, store) = (&ctx.progress, &ctx.store);
+        progress.lock().await.step(section, "pre", None).await;
+        if let Err(e) = self.pre(section, &ctx.run_dir).await {
+            progress.lock().await.step(section, "pre", Some(&e)).await;
+            return Err(e);
+        }
+        progress.lock().await.step(section, "key_apply", None).await;
+        if let Err(e) = self.key_apply(store).await {
+            /* not fatal as before, only reported */
+            progress
+                .lock()
+                .await
+                .step(section, "key_apply", Some(&e))
+                .await;
+        }
+        progress.lock().await.step(section, "post", None).await;
+        if let Err(e) = self.post(section, &ctx.run_dir).await {
+            progress.lock().await.step(section, "post", Some(&e)).await;
+            return Err(e);
+        }
 
-    async fn run(&self, _force: bool) -> Result<()> {
-        _ = self.pre().await?;
-        _ = self.key_apply().await;
-        _ = self.post().await?;
+        Ok(())
+    }
+}
+
+impl KapFactory {
+    fn action(&self, section: ActivateSection) -> Option<&(dyn FactoryAction + Send + Sync)> {
+        match section {
+            ActivateSection::Core => Some(&self.core),
+            ActivateSection::Network => Some(&self.network),
+            ActivateSection::Por => Some(&self.por),
+            ActivateSection::Boss => Some(&self.boss),
+            ActivateSection::Provision => None,
+        }
+    }
+
+    /* content marker, a section is re-applied only when it changed or on --force */
+    fn section_hash(&self, section: ActivateSection) -> Option<String> {
+        let content = match section {
+            ActivateSection::Core => serde_json::to_string(&self.core),
+            ActivateSection::Network => serde_json::to_string(&self.network),
+            ActivateSection::Por => serde_json::to_string(&self.por),
+            ActivateSection::Boss => serde_json::to_string(&self.boss),
+            ActivateSection::Provision => return None,
+        }
+        .ok()?;
+        Some(format!("{:x}", Sha256::digest(content.as_bytes())))
+    }
+
+    /* built-in depends plus the ones declared in activate.toml */
+    fn depends(&self, section: ActivateSection) -> Vec<ActivateSection> {
+        let mut depends = section.depends().to_vec();
+        if let Some(declared) = self.action(section).and_then(|a| a.get_depends()) {
+            depends.extend(declared.iter().filter(|d| **d != section));
+        }
+        depends
+    }
+
+    /* run order, each wave ready once the previous ones are applied; the second
+     * half is what a depends cycle left out */
+    fn waves(
+        &self,
+        sections: &[ActivateSection],
+    ) -> (Vec<Vec<ActivateSection>>, Vec<ActivateSection>) {
+        let parallel = self.parallel.unwrap_or(false);
+        let mut waves: Vec<Vec<ActivateSection>> = vec![];
+        let mut pending = sections.to_vec();
+
+        while !pending.is_empty() {
+            let mut ready = pending
+                .iter()
+                .filter(|s| self.depends(**s).iter().all(|d| !pending.contains(d)))
+                .copied()
+                .collect::<Vec<ActivateSection>>();
+            if ready.is_empty() {
+                break;
+            }
+            if !parallel {
+                ready.truncate(1);
+            }
+            pending.retain(|s| !ready.contains(s));
+            waves.push(ready);
+        }
+
+        (waves, pending)
+    }
+
+    async fn depends_verify(
+        &self,
+        sections: &[ActivateSection],
+        store: &ActivateStore,
+    ) -> Result<()> {
+        for section in sections {
+            for dep in &self.depends(*section) {
+                if sections.contains(dep) {
+                    continue;
+                }
+                let applied = match self.action(*dep) {
+                    Some(_) => store.applied(*dep).await?,
+                    None => true,
+                };
+                if !applied {
+                    return Err(anyhow!(
+                        "section {:?} depends on {:?}, neither selected nor applied before",
+                        section,
+                        dep
+                    ));
+                }
+            }
+        }
+        /* refused before anything runs, not half applied */
+        let (_, cycle) = self.waves(sections);
+        if !cycle.is_empty() {
+            return Err(anyhow!("depends cycle among {:?}", cycle));
+        }
 
         Ok(())
     }
@@ -175,6 +662,8 @@ struct KapCoreConfig {
     key: Option<String>,
     post: Option<String>,
     pre: Option<String>,
+    timeout: Option<Duration>,
+    depends: Option<Vec<ActivateSection>>,
 }
 
 #[async_trait]
@@ -194,6 +683,14 @@ impl FactoryAction for KapCoreConfig {
     fn get_cfg(&self) -> Option<String> {
         None
     }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn get_depends(&self) -> Option<&Vec<ActivateSection>> {
+        self.depends.as_ref()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -203,6 +700,8 @@ struct KapNetworkConfig {
     key: Option<String>,
     post: Option<String>,
     pre: Option<String>,
+    timeout: Option<Duration>,
+    depends: Option<Vec<ActivateSection>>,
 }
 
 #[async_trait]
@@ -222,6 +721,14 @@ impl FactoryAction for KapNetworkConfig {
     fn get_cfg(&self) -> Option<String> {
         None
     }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn get_depends(&self) -> Option<&Vec<ActivateSection>> {
+        self.depends.as_ref()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -231,6 +738,8 @@ struct KapPorConfig {
     key: Option<String>,
     post: Option<String>,
     pre: Option<String>,
+    timeout: Option<Duration>,
+    depends: Option<Vec<ActivateSection>>,
 }
 
 #[async_trait]
@@ -250,6 +759,14 @@ impl FactoryAction for KapPorConfig {
     fn get_cfg(&self) -> Option<String> {
         None
     }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn get_depends(&self) -> Option<&Vec<ActivateSection>> {
+        self.depends.as_ref()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -259,6 +776,8 @@ struct KapBossConfig {
     key: Option<String>,
     post: Option<String>,
     pre: Option<String>,
+    timeout: Option<Duration>,
+    depends: Option<Vec<ActivateSection>>,
 }
 
 #[async_trait]
@@ -278,6 +797,14 @@ impl FactoryAction for KapBossConfig {
     fn get_cfg(&self) -> Option<String> {
         None
     }
+
+    fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn get_depends(&self) -> Option<&Vec<ActivateSection>> {
+        self.depends.as_ref()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -288,6 +815,42 @@ struct ActivateCertificate {
     issue_time: DateTime<Utc>,
 }
 
+pub fn activate_template() -> Result<String> {
+    let mut out = String::from("# FIKA manager activate.toml, factory data per section\n");
+    out.push_str("# run sections whose depends are met concurrently\n# parallel = false\n");
+
+    let sections: Vec<(&str, toml::Value)> = vec![
+        ("core", toml::Value::try_from(KCoreConfig::default())?),
+        ("network", toml::Value::try_from(KNetworkConfig::default())?),
+        ("por", toml::Value::try_from(KPorConfig::default())?),
+        ("boss", toml::Value::try_from(KBossConfig::default())?),
+    ];
+
+    for (name, cfg) in sections {
+        out.push_str(&format!(
+            r#"
+[{name}]
+# cfg is stored under key, `{{key}}.done` counts the applies
+# key = "kap/factory/{name}"
+# hooks receive the cfg json and key as arguments
+# pre = "/etc/fika_manager/factory_{name}_pre.sh"
+# post = "/etc/fika_manager/factory_{name}_post.sh"
+# each hook is killed once running longer than timeout
+# timeout = {{ secs = 120, nanos = 0 }}
+# sections applied before this one, besides the built-in ones
+# depends = ["core"]
+"#
+        ));
+        out.push_str(&toml_commented_out(&toml_commented(
+            &format!("{}.cfg", name),
+            &cfg,
+            &[],
+        )?));
+    }
+
+    Ok(out)
+}
+
 #[cfg(not(feature = "aws-iot"))]
 async fn iot_fleet_provision(
     _rule_path: &str,
@@ -306,14 +869,35 @@ async fn iot_fleet_provision(
 ) -> Result<ActivateCertificate> {
     let (rule, cfg) = rule_config_load(rule_path, Some(config_path)).await?;
 
-    let cert = if rule.aws.dedicated.config_verify().await.is_ok() && force == false {
+    let existing = rule.aws.dedicated.config_verify().await.is_ok();
+    let cert = if existing && force == false {
         debug!("MQTT provision use original one");
         AwsIotKeyCertificate::reload(&rule.aws.dedicated.cert).await?
     } else {
-        mqtt_provision_task(&cfg, &rule.aws).await?
+        let cert = mqtt_provision_task(&cfg, &rule.aws).await?;
+        if let Some(ref provision) = rule.aws.provision {
+            /* the dedicated one is in place, a leftover bootstrap is no failure */
+            if let Err(e) = provision.bootstrap_wipe().await {
+                warn!("bootstrap certificate wipe fail - {e}");
+            }
+        }
+        if existing {
+            notify(Notification::new(
+                NotifyKind::CertRotated,
+                NotifySeverity::Info,
+                "dedicated certificate replaced by a new one",
+                serde_json::json!({ "cert": rule.aws.dedicated.cert, "issue_time": cert.1 }),
+            ))
+            .await;
+        }
+        cert
     };
 
-    let wallet = cfg.core.wallet_address.unwrap();
+    let wallet = cfg
+        .core
+        .wallet_address
+        .map(String::from)
+        .ok_or_else(|| anyhow!("core.wallet_address missing in {}", config_path))?;
     let thingname = rule.aws.thing_name(&cfg.core.mac_address)?;
 
     Ok(ActivateCertificate {
@@ -324,71 +908,1286 @@ async fn iot_fleet_provision(
     })
 }
 
-//#[instrument(name = "activate", skip(opt))]
-async fn main_task(opt: ActivateOpt) -> Result<()> {
-    let cfg = fs::read_to_string(&opt.active)
+const BACKUP_MANIFEST: &str = "manifest.json";
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BackupEntry {
+    origin: String,
+    name: String,
+}
+
+#[cfg(feature = "aws-iot")]
+async fn backup_certificates(rule_path: &str) -> Vec<String> {
+    match RuleConfig::build_from(rule_path).await {
+        Ok(rule) => {
+            let cmp = &rule.aws.dedicated;
+            vec![
+                cmp.cert.clone(),
+                cmp.private.clone(),
+                cmp.cert.replace(".pem", ".info"),
+            ]
+        }
+        Err(e) => {
+            warn!("rule load fail, certificates not in backup - {e}");
+            vec![]
+        }
+    }
+}
+
+#[cfg(not(feature = "aws-iot"))]
+async fn backup_certificates(_rule_path: &str) -> Vec<String> {
+    vec![]
+}
+
+async fn backup_targets(opt: &ActivateOpt) -> Vec<String> {
+    let mut targets = vec![opt.config.clone()];
+    targets.extend(backup_certificates(&opt.rule).await);
+    targets
+}
+
+async fn backup_snapshot(opt: &ActivateOpt) -> Result<PathBuf> {
+    let snapshot = Path::new(&opt.backup_dir).join(Utc::now().format("%Y%m%dT%H%M%S").to_string());
+    fs::create_dir_all(&snapshot)
         .await
-        .map_err(|e| anyhow!("{} open/read fail - {}", &opt.active, e))?;
-    let cfg: KapFactory =
-        toml::from_str(&cfg).map_err(|e| anyhow!("{} invalid toml format - {}", &opt.active, e))?;
-    let force = opt.force;
+        .map_err(|e| anyhow!("backup {} create fail - {e}", snapshot.display()))?;
 
-    debug!("active-rule content as {:#?}", cfg);
+    let mut manifest = vec![];
+    for (i, origin) in backup_targets(opt).await.into_iter().enumerate() {
+        let origin = match fs::canonicalize(&origin).await {
+            Ok(o) => o.to_string_lossy().to_string(),
+            Err(_) => {
+                debug!("backup skip non-exist {}", origin);
+                continue;
+            }
+        };
 
-    _ = cfg.core.run(force).await?;
-    _ = cfg.network.run(force).await?;
-    _ = cfg.por.run(force).await?;
-    _ = cfg.boss.run(force).await?;
+        let file_name = Path::new(&origin)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = format!("{}-{}", i, file_name);
+        fs::copy(&origin, snapshot.join(&name))
+            .await
+            .map_err(|e| anyhow!("backup {} fail - {e}", origin))?;
+        manifest.push(BackupEntry { origin, name });
+    }
 
-    let cert = iot_fleet_provision(&opt.rule, &opt.config, force).await?;
-    let feedback = serde_json::to_string(&cert)?;
+    fs::write(
+        snapshot.join(BACKUP_MANIFEST),
+        serde_json::to_string_pretty(&manifest)?,
+    )
+    .await?;
+    info!(
+        "backup {} entries into {}",
+        manifest.len(),
+        snapshot.display()
+    );
 
-    if atty::is(Stream::Stdout) {
-        println!(
-            "################################# [Activation code] #################################"
-        );
-        let color = to_colored_json_auto(&serde_json::from_str::<serde_json::Value>(&feedback)?)?;
-        println!("{}", color);
-        println!(
-            "#####################################################################################"
-        );
+    Ok(snapshot)
+}
+
+async fn backup_restore(backup_dir: &str, snapshot: &str) -> Result<()> {
+    let snapshot = if Path::new(snapshot).is_dir() {
+        PathBuf::from(snapshot)
     } else {
-        println!("{feedback}");
+        Path::new(backup_dir).join(snapshot)
+    };
+
+    let manifest = fs::read_to_string(snapshot.join(BACKUP_MANIFEST))
+        .await
+        .map_err(|e| anyhow!("snapshot {} manifest open fail - {e}", snapshot.display()))?;
+    let manifest: Vec<BackupEntry> = serde_json::from_str(&manifest)
+        .map_err(|e| anyhow!("snapshot {} manifest invalid - {e}", snapshot.display()))?;
+
+    for entry in manifest {
+        fs::copy(snapshot.join(&entry.name), &entry.origin)
+            .await
+            .map_err(|e| anyhow!("restore {} fail - {e}", entry.origin))?;
+        info!("restore {} from {}", entry.origin, snapshot.display());
     }
 
     Ok(())
 }
 
-//#[tokio::main]
-pub async fn activate(opt: ActivateOpt) -> Result<()> {
-    setup_logging(&opt.log_level)?;
-    debug!("activate-rule path as {}", opt.active);
+#[derive(Debug, PartialEq)]
+enum VerifyStatus {
+    Pass,
+    Fail,
+    /* what a disabled feature or an earlier failure leaves unchecked */
+    Skip,
+}
 
-    let main_jhandle = tokio::spawn(main_task(opt));
-    let future_sig_c = signal::ctrl_c();
+#[derive(Debug)]
+struct VerifyItem {
+    name: String,
+    status: VerifyStatus,
+    detail: String,
+}
 
-    tokio::select! {
-        r = main_jhandle => {
-            let r = r?;
-            debug!("main-task exit due to {:?}", r);
-            r
-        },
-        _ = future_sig_c => {
-            warn!("exit by catch signal-c");
-            Ok(())
-        },
+impl VerifyItem {
+    fn from_result(name: &str, r: Result<String>) -> Self {
+        let (status, detail) = match r {
+            Ok(d) => (VerifyStatus::Pass, d),
+            Err(e) => (VerifyStatus::Fail, e.to_string()),
+        };
+        Self {
+            name: name.to_string(),
+            status,
+            detail,
+        }
+    }
+
+    fn skip(name: &str, detail: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status: VerifyStatus::Skip,
+            detail: detail.to_string(),
+        }
     }
 }
 
-/*#[tokio::test]
-async fn test_toml_duration() {
-    let cp = ConfigTask {
-        topic: String::from("test"),
-        path: PathBuf::from("/tmp/test.sh"),
-        start_at: Some(Duration::from_secs(1)),
-        period: Some(Duration::from_secs(10)),
-    };
+pub(crate) async fn openssl_output(args: &[&str]) -> Result<String> {
+    let output = Command::new("openssl")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("openssl {:?} run fail - {e}", args))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "openssl {:?} fail - {}",
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
 
-    let toml = toml::to_string(&cp);
-    assert_eq!(toml, Ok(String::from("hello")));
-}*/
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/* expiry and certificate/private-key pair via openssl, as on the device image */
+#[cfg(feature = "aws-iot")]
+async fn certificate_verify(cert: &str, private: &str) -> Result<String> {
+    let expiry = openssl_output(&["x509", "-in", cert, "-noout", "-enddate"]).await?;
+    openssl_output(&["x509", "-in", cert, "-noout", "-checkend", "0"])
+        .await
+        .map_err(|_| anyhow!("{} expired ({})", cert, expiry))?;
+
+    let cert_pub = openssl_output(&["x509", "-in", cert, "-noout", "-pubkey"]).await?;
+    let key_pub = openssl_output(&["pkey", "-in", private, "-pubout"]).await?;
+    if cert_pub != key_pub {
+        return Err(anyhow!("{} not match private key {}", cert, private));
+    }
+
+    Ok(expiry)
+}
+
+#[cfg(feature = "aws-iot")]
+async fn verify_certificate(rule_path: &str) -> VerifyItem {
+    let r = async {
+        let rule = RuleConfig::build_from(rule_path).await?;
+        let dedicated = &rule.aws.dedicated;
+        dedicated.config_verify().await?;
+        certificate_verify(&dedicated.cert, &dedicated.private).await
+    };
+    VerifyItem::from_result("certificate", r.await)
+}
+
+#[cfg(not(feature = "aws-iot"))]
+async fn verify_certificate(_rule_path: &str) -> VerifyItem {
+    VerifyItem::skip("certificate", "not support due aws feature disable")
+}
+
+/* the token activation stored */
+fn verify_boss_token(cfg: &KdaemonConfig) -> VerifyItem {
+    let r = match cfg.boss.ap_access_token {
+        Some(ref token) if !token.is_empty() => Ok("ap-access-token stored".to_string()),
+        _ => Err(anyhow!("boss.ap_access_token none, not activated")),
+    };
+    VerifyItem::from_result("boss", r)
+}
+
+/*
+ * one ap-info call with the stored token, no refresh so verify changes no
+ * state; a connect/timeout error is boss-reach, a 401 is boss-auth
+ */
+#[cfg(feature = "boss-api")]
+async fn verify_boss(rule_path: &str, cfg: &KdaemonConfig) -> Vec<VerifyItem> {
+    use crate::web_api::{boss_auth_fail, boss_offline};
+
+    let token = verify_boss_token(cfg);
+    if token.status == VerifyStatus::Fail {
+        return vec![token];
+    }
+    let r = async {
+        let rule = RuleConfig::build_from(rule_path).await?;
+        let mut boss = crate::BossClient::from_config(&rule, cfg)?;
+        boss.auto_refresh = false;
+        boss.sign_from(&rule.boss, &rule.core.config).await?;
+        boss.get_ap_info().await
+    };
+    let r = r.await;
+    let reach = match r {
+        Err(ref e) if boss_offline(e) => Err(anyhow!("boss unreachable - {e}")),
+        _ => Ok("boss answered".to_string()),
+    };
+    let auth = match r {
+        _ if reach.is_err() => VerifyItem::skip("boss-auth", "boss unreachable"),
+        Ok(_) => VerifyItem::from_result("boss-auth", Ok("ap-info accepted".to_string())),
+        Err(e) if boss_auth_fail(&e) => {
+            VerifyItem::from_result("boss-auth", Err(anyhow!("ap-access-token rejected - {e}")))
+        }
+        Err(e) => VerifyItem::from_result("boss-auth", Err(anyhow!("ap-info fail - {e}"))),
+    };
+    vec![token, VerifyItem::from_result("boss-reach", reach), auth]
+}
+
+#[cfg(not(feature = "boss-api"))]
+async fn verify_boss(_rule_path: &str, cfg: &KdaemonConfig) -> Vec<VerifyItem> {
+    vec![
+        verify_boss_token(cfg),
+        VerifyItem::skip("boss-reach", "not support due boss-api feature disable"),
+    ]
+}
+
+async fn verify_task(opt: &ActivateOpt) -> Result<()> {
+    let mut items = vec![];
+
+    match KdaemonConfig::build_from(&opt.config).await {
+        Ok(cfg) => {
+            items.push(VerifyItem::from_result(
+                "wallet",
+                cfg.core.config_verify().await.map(|_| {
+                    cfg.core
+                        .wallet_address
+                        .clone()
+                        .map(String::from)
+                        .unwrap_or_default()
+                }),
+            ));
+            items.push(verify_certificate(&opt.rule).await);
+            items.extend(verify_boss(&opt.rule, &cfg).await);
+        }
+        Err(e) => items.push(VerifyItem::from_result(
+            "config",
+            Err(anyhow!("{} load fail - {e}", opt.config)),
+        )),
+    }
+
+    let store = ActivateStore::open(opt).await;
+    match fs::read_to_string(&opt.active).await {
+        Ok(s) => match toml::from_str::<KapFactory>(&s) {
+            Ok(factory) => {
+                for section in ActivateSection::ALL {
+                    let name = format!("{:?}", section).to_lowercase();
+                    if factory.action(section).is_none() {
+                        continue;
+                    }
+                    items.push(VerifyItem::from_result(
+                        &format!("{}/{}", store.name(), name),
+                        match store.applied(section).await {
+                            Ok(true) => Ok(format!("{} applied", name)),
+                            Ok(false) => Err(anyhow!("{} not applied", name)),
+                            Err(e) => Err(e),
+                        },
+                    ));
+                }
+            }
+            Err(e) => items.push(VerifyItem::from_result(
+                "activate-rule",
+                Err(anyhow!("{} invalid toml format - {e}", opt.active)),
+            )),
+        },
+        Err(e) => items.push(VerifyItem::from_result(
+            "activate-rule",
+            Err(anyhow!("{} open/read fail - {e}", opt.active)),
+        )),
+    }
+
+    for item in &items {
+        println!(
+            "[{}] {:<16} {}",
+            format!("{:?}", item.status).to_uppercase(),
+            item.name,
+            item.detail
+        );
+    }
+
+    let fail = items
+        .iter()
+        .filter(|i| i.status == VerifyStatus::Fail)
+        .count();
+    if fail > 0 {
+        Err(anyhow!("activate verify {} of {} fail", fail, items.len()))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SectionStatus {
+    Applied,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SectionResult {
+    section: ActivateSection,
+    status: SectionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ActivateResult {
+    sections: Vec<SectionResult>,
+    certificate: Option<ActivateCertificate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bundle: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wallet: Option<String>,
+}
+
+async fn activate_section(
+    cfg: &KapFactory,
+    opt: &ActivateOpt,
+    section: ActivateSection,
+    ctx: &ActivateContext,
+) -> (SectionResult, Option<ActivateCertificate>) {
+    let start = std::time::Instant::now();
+    let mut cert = None;
+    let progress = &ctx.progress;
+    let hash = cfg.section_hash(section);
+    let hash_key = section_marker(section);
+
+    if let (false, Some(ref hash)) = (opt.force, &hash) {
+        if let Ok(Some(last)) = ctx.store.get(&hash_key).await {
+            if &last == hash {
+                info!("activate section {:?} unchanged, skip", section);
+                return (
+                    SectionResult {
+                        section,
+                        status: SectionStatus::Skipped,
+                        reason: Some("unchanged since last apply".to_string()),
+                        duration_ms: 0,
+                    },
+                    None,
+                );
+            }
+        }
+    }
+
+    let r = if let Some(action) = cfg.action(section) {
+        let r = action.run(section, ctx, opt.force).await;
+        if let (Ok(_), Some(ref hash)) = (&r, &hash) {
+            if let Err(e) = ctx.store.set(&hash_key, hash).await {
+                warn!("activate section {:?} hash not kept - {e}", section);
+            }
+        }
+        r
+    } else {
+        progress.lock().await.step(section, "provision", None).await;
+        match RuleConfig::build_from(&opt.rule).await {
+            Ok(rule) => notify_init(&rule, None).await,
+            Err(e) => debug!("provision notify without rule - {e}"),
+        }
+        match iot_fleet_provision(&opt.rule, &opt.config, opt.force).await {
+            Ok(c) => {
+                notify(Notification::new(
+                    NotifyKind::Provision,
+                    NotifySeverity::Info,
+                    format!("{} provisioned", c.name),
+                    serde_json::json!({ "thing": c.name, "issue_time": c.issue_time }),
+                ))
+                .await;
+                cert = Some(c);
+                Ok(())
+            }
+            Err(e) => {
+                notify(Notification::new(
+                    NotifyKind::Provision,
+                    NotifySeverity::Critical,
+                    format!("provision fail - {e}"),
+                    serde_json::json!({ "error": e.to_string() }),
+                ))
+                .await;
+                progress
+                    .lock()
+                    .await
+                    .step(section, "provision", Some(&e))
+                    .await;
+                Err(e)
+            }
+        }
+    };
+    let duration_ms = start.elapsed().as_millis() as u64;
+
+    let (status, reason) = match r {
+        Ok(_) => (SectionStatus::Applied, None),
+        Err(e) => {
+            warn!("activate section {:?} fail - {e}", section);
+            (SectionStatus::Failed, Some(e.to_string()))
+        }
+    };
+    (
+        SectionResult {
+            section,
+            status,
+            reason,
+            duration_ms,
+        },
+        cert,
+    )
+}
+
+/*
+ * sections run once their selected depends are applied, one at a time or
+ * (parallel = true) every ready one together; after a failure nothing new
+ * starts and the rest are reported as skipped
+ */
+async fn activate_sections(
+    cfg: &KapFactory,
+    opt: &ActivateOpt,
+    sections: &[ActivateSection],
+    ctx: &ActivateContext,
+) -> ActivateResult {
+    let mut result = ActivateResult {
+        sections: vec![],
+        certificate: None,
+        bundle: None,
+        wallet: None,
+    };
+    let mut failed: Option<ActivateSection> = None;
+    /* depends_verify refused any cycle */
+    let (waves, _) = cfg.waves(sections);
+
+    let mut done: Vec<ActivateSection> = vec![];
+    let mut pending = sections.to_vec();
+    for wave in waves {
+        if failed.is_some() {
+            break;
+        }
+        pending.retain(|s| !wave.contains(s));
+
+        let runs = wave.iter().map(|s| activate_section(cfg, opt, *s, ctx));
+        for (r, cert) in join_all(runs).await {
+            if r.status != SectionStatus::Failed {
+                done.push(r.section);
+            } else if failed.is_none() {
+                failed = Some(r.section);
+            }
+            if cert.is_some() {
+                result.certificate = cert;
+            }
+            result.sections.push(r);
+        }
+    }
+
+    for section in pending {
+        result.sections.push(SectionResult {
+            section,
+            status: SectionStatus::Skipped,
+            reason: Some(match failed {
+                Some(f) => format!("{:?} failed before", f).to_lowercase(),
+                None => "depends cycle".to_string(),
+            }),
+            duration_ms: 0,
+        });
+    }
+    for section in ActivateSection::ALL {
+        if !sections.contains(&section) {
+            result.sections.push(SectionResult {
+                section,
+                status: SectionStatus::Skipped,
+                reason: Some("not selected".to_string()),
+                duration_ms: 0,
+            });
+        }
+    }
+    result
+        .sections
+        .sort_by_key(|r| ActivateSection::ALL.iter().position(|s| *s == r.section));
+
+    if let (None, Some(last)) = (failed, sections.last()) {
+        if done.len() == sections.len() {
+            ctx.progress.lock().await.finish(*last).await;
+        }
+    }
+
+    result
+}
+
+/*
+ * factory public key baked in at build time as `ed25519:<base64>` (raw 32
+ * bytes) or `ecdsa-p256:<base64>` (uncompressed point), signature file is
+ * base64 of the raw ed25519 or DER ecdsa/sha256 signature over activate.toml
+ */
+const FACTORY_PUBKEY: Option<&str> = option_env!("FIKA_FACTORY_PUBKEY");
+
+fn factory_signature_verify(pubkey: &str, data: &[u8], signature: &str) -> Result<()> {
+    let (algorithm, key): (&'static dyn ring::signature::VerificationAlgorithm, &str) =
+        match pubkey.split_once(':') {
+            Some(("ed25519", key)) => (&ring::signature::ED25519, key),
+            Some(("ecdsa-p256", key)) => (&ring::signature::ECDSA_P256_SHA256_ASN1, key),
+            _ => return Err(anyhow!("factory public key format invalid")),
+        };
+    let key = base64::decode(key.trim()).map_err(|e| anyhow!("factory public key - {e}"))?;
+    let signature = base64::decode(signature.trim())
+        .map_err(|e| anyhow!("factory signature invalid base64 - {e}"))?;
+
+    ring::signature::UnparsedPublicKey::new(algorithm, key)
+        .verify(data, &signature)
+        .map_err(|_| anyhow!("factory signature mismatch"))
+}
+
+async fn factory_load(opt: &ActivateOpt) -> Result<KapFactory> {
+    let data = fs::read(&opt.active)
+        .await
+        .map_err(|e| anyhow!("{} open/read fail - {}", &opt.active, e))?;
+
+    if let Some(pubkey) = FACTORY_PUBKEY {
+        let sig_path = opt
+            .signature
+            .clone()
+            .unwrap_or_else(|| format!("{}.sig", opt.active));
+        let signature = fs::read_to_string(&sig_path)
+            .await
+            .map_err(|e| anyhow!("{} signature open/read fail - {}", sig_path, e))?;
+        factory_signature_verify(pubkey, &data, &signature)
+            .map_err(|e| anyhow!("{} reject - {e}", &opt.active))?;
+        info!("{} signature verified", &opt.active);
+    } else if opt.signature.is_some() {
+        return Err(anyhow!(
+            "no factory public key built in, signature unverifiable"
+        ));
+    } else {
+        warn!(
+            "no factory public key built in, {} not verified",
+            &opt.active
+        );
+    }
+
+    let cfg = String::from_utf8(data).map_err(|e| anyhow!("{} not utf8 - {e}", &opt.active))?;
+    toml::from_str(&cfg).map_err(|e| anyhow!("{} invalid toml format - {}", &opt.active, e))
+}
+
+/* a hook must exist and be executable by someone */
+async fn dry_run_path(path: &str, problems: &mut Vec<String>) -> serde_json::Value {
+    use std::os::unix::fs::PermissionsExt;
+
+    let check = match fs::metadata(path).await {
+        Ok(m) if m.is_file() && m.permissions().mode() & 0o111 != 0 => "ok".to_string(),
+        Ok(_) => "not executable".to_string(),
+        Err(e) => e.to_string(),
+    };
+    if check != "ok" {
+        problems.push(format!("{} {}", path, check));
+    }
+    serde_json::json!({ "path": path, "check": check })
+}
+
+#[cfg(feature = "aws-iot")]
+async fn dry_run_file(path: &str, problems: &mut Vec<String>) -> serde_json::Value {
+    let check = match fs::metadata(path).await {
+        Ok(m) if m.is_file() && m.len() > 0 => "ok".to_string(),
+        Ok(_) => "empty or not a file".to_string(),
+        Err(e) => e.to_string(),
+    };
+    if check != "ok" {
+        problems.push(format!("{} {}", path, check));
+    }
+    serde_json::json!({ "path": path, "check": check })
+}
+
+#[cfg(feature = "aws-iot")]
+async fn dry_run_provision(opt: &ActivateOpt, problems: &mut Vec<String>) -> serde_json::Value {
+    let rule = match RuleConfig::build_from(&opt.rule).await {
+        Ok(r) => r,
+        Err(e) => {
+            problems.push(format!("{} {e}", opt.rule));
+            return serde_json::Value::Null;
+        }
+    };
+    let dedicated = &rule.aws.dedicated;
+    if !opt.force && dedicated.config_verify().await.is_ok() {
+        return serde_json::json!({
+            "action": "reuse",
+            "cert": dedicated.cert,
+        });
+    }
+
+    let mut files = vec![];
+    match rule.aws.provision {
+        Some(ref p) => {
+            for path in [&p.ca, &p.cert, &p.private] {
+                files.push(dry_run_file(path, problems).await);
+            }
+        }
+        None => problems.push("rule without aws.provision".to_string()),
+    }
+    serde_json::json!({
+        "action": "fleet-provision",
+        "claim": files,
+        "write": [dedicated.cert, dedicated.private],
+    })
+}
+
+#[cfg(not(feature = "aws-iot"))]
+async fn dry_run_provision(_opt: &ActivateOpt, problems: &mut Vec<String>) -> serde_json::Value {
+    problems.push("provision not support due aws feature disable".to_string());
+    serde_json::Value::Null
+}
+
+async fn dry_run_task(opt: &ActivateOpt) -> Result<()> {
+    let cfg = factory_load(opt).await?;
+    let store = ActivateStore::open(opt).await;
+    let sections = sections_select(opt);
+    let mut problems = vec![];
+    if let Err(e) = cfg.depends_verify(&sections, &store).await {
+        problems.push(e.to_string());
+    }
+
+    let (waves, _) = cfg.waves(&sections);
+
+    let mut plan = vec![];
+    for wave in waves {
+        let mut actions = vec![];
+        for section in wave {
+            let action = match cfg.action(section) {
+                Some(a) => a,
+                None => {
+                    actions.push(serde_json::json!({
+                        "section": section,
+                        "provision": dry_run_provision(opt, &mut problems).await,
+                    }));
+                    continue;
+                }
+            };
+
+            let mut steps = vec![];
+            if let Some(pre) = action.get_pre() {
+                steps.push(serde_json::json!({
+                    "step": "pre",
+                    "hook": dry_run_path(pre, &mut problems).await,
+                }));
+            }
+            if let Some(key) = action.get_key() {
+                steps.push(serde_json::json!({
+                    "step": "key_apply",
+                    "store": store.name(),
+                    "key": key,
+                    "cfg": action.get_cfg(),
+                }));
+            }
+            if let Some(post) = action.get_post() {
+                steps.push(serde_json::json!({
+                    "step": "post",
+                    "hook": dry_run_path(post, &mut problems).await,
+                }));
+            }
+            actions.push(serde_json::json!({
+                "section": section,
+                "depends": cfg.depends(section),
+                "timeout": action.get_timeout().map(|t| t.as_secs()),
+                "steps": steps,
+            }));
+        }
+        plan.push(actions);
+    }
+
+    let doc = serde_json::json!({
+        "activate": opt.active,
+        "backup": if opt.no_backup { None } else { Some(&opt.backup_dir) },
+        "waves": plan,
+        "problems": problems,
+    });
+    println!("{}", to_colored_json_auto(&doc)?);
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("activate dry-run {} problem(s)", problems.len()))
+    }
+}
+
+/* portal kick-off, the per-section result as json */
+#[cfg(feature = "portal")]
+pub(crate) async fn activate_portal(
+    active: &str,
+    config: &str,
+    rule: &str,
+) -> Result<serde_json::Value> {
+    let opt = ActivateOpt::with_paths(active, config, rule);
+    Ok(serde_json::to_value(activate_apply(&opt).await?)?)
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_ensure(opt: &ActivateOpt) -> Result<Option<String>> {
+    let mut cfg = KdaemonConfig::build_from(&opt.config)
+        .await
+        .map_err(|e| anyhow!("{} load fail - {e}", opt.config))?;
+    if cfg.core.wallet_address.is_some() {
+        return Ok(None);
+    }
+
+    let (address, keystore) =
+        wallet_keystore_new(&opt.wallet_dir, &opt.wallet_password_file).await?;
+    cfg.core.wallet_address = Some(address.parse()?);
+    cfg.save(&opt.config).await?;
+    info!("wallet {} generated, keystore {}", address, keystore);
+
+    Ok(Some(address))
+}
+
+#[cfg(not(feature = "wallet"))]
+async fn wallet_ensure(opt: &ActivateOpt) -> Result<Option<String>> {
+    let cfg = KdaemonConfig::build_from(&opt.config)
+        .await
+        .map_err(|e| anyhow!("{} load fail - {e}", opt.config))?;
+    if cfg.core.wallet_address.is_none() {
+        warn!("core.wallet_address missing, not generated due wallet feature disable");
+    }
+
+    Ok(None)
+}
+
+async fn activate_apply(opt: &ActivateOpt) -> Result<ActivateResult> {
+    let cfg = factory_load(opt).await?;
+
+    debug!("active-rule content as {:#?}", cfg);
+
+    let store = ActivateStore::open(opt).await;
+    let sections = sections_select(opt);
+    cfg.depends_verify(&sections, &store).await?;
+    debug!("activate sections {:?}", sections);
+
+    if !opt.no_backup {
+        backup_snapshot(opt).await?;
+    }
+
+    let run_dir = Path::new(&opt.log_dir).join(Utc::now().format("%Y%m%dT%H%M%S").to_string());
+    fs::create_dir_all(&run_dir)
+        .await
+        .map_err(|e| anyhow!("{} create fail - {e}", run_dir.display()))?;
+    let ctx = ActivateContext {
+        progress: Mutex::new(ActivateProgress::new(&sections).await),
+        store,
+        run_dir,
+    };
+
+    /* boss and provision need the wallet from core */
+    let wallet = if sections.contains(&ActivateSection::Core)
+        || sections.contains(&ActivateSection::Provision)
+    {
+        wallet_ensure(opt).await?
+    } else {
+        None
+    };
+
+    let mut result = activate_sections(&cfg, opt, &sections, &ctx).await;
+    result.wallet = wallet;
+    if result
+        .sections
+        .iter()
+        .any(|s| s.status == SectionStatus::Failed)
+    {
+        match bundle_export(opt, &ctx, &result).await {
+            Ok(bundle) => {
+                warn!("activate failed, logs bundled in {}", bundle);
+                result.bundle = Some(bundle);
+            }
+            Err(e) => warn!("activate bundle fail - {e}"),
+        }
+    }
+    _ = fs::remove_dir_all(&ctx.run_dir).await;
+
+    Ok(result)
+}
+
+async fn bundle_journal() -> Result<Vec<u8>> {
+    for (cmd, args) in [
+        ("journalctl", &["-n", "500", "--no-pager"][..]),
+        ("logread", &[][..]),
+    ] {
+        if let Ok(output) = Command::new(cmd).args(args).output().await {
+            if output.status.success() {
+                return Ok(output.stdout);
+            }
+        }
+    }
+
+    Err(anyhow!("neither journalctl nor logread available"))
+}
+
+/* hook logs, journal, config snapshots and the partial result in one tar.gz */
+async fn bundle_export(
+    opt: &ActivateOpt,
+    ctx: &ActivateContext,
+    result: &ActivateResult,
+) -> Result<String> {
+    let run_dir = &ctx.run_dir;
+    fs::write(
+        run_dir.join("result.json"),
+        serde_json::to_string_pretty(result)?,
+    )
+    .await?;
+    match bundle_journal().await {
+        Ok(journal) => fs::write(run_dir.join("journal.log"), journal).await?,
+        Err(e) => warn!("bundle without journal - {e}"),
+    }
+
+    /* the bundle leaves the device, passwords and tokens masked as diagnose does */
+    for origin in [&opt.config, &opt.active, &opt.rule] {
+        if let Some(name) = Path::new(origin).file_name() {
+            match toml_redacted(origin).await {
+                Ok(text) => fs::write(run_dir.join(name), text).await?,
+                Err(e) => debug!("bundle skip {} - {e}", origin),
+            }
+        }
+    }
+    if let ActivateStore::File(ref path, _) = ctx.store {
+        if let Some(name) = path.file_name() {
+            if let Err(e) = fs::copy(path, run_dir.join(name)).await {
+                debug!("bundle skip {} - {e}", path.display());
+            }
+        }
+    }
+
+    let name = run_dir
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let bundle = Path::new(&opt.log_dir).join(format!("activate-{}.tar.gz", name));
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&bundle)
+        .arg("-C")
+        .arg(&opt.log_dir)
+        .arg(&name)
+        .status()
+        .await
+        .map_err(|e| anyhow!("tar run fail - {e}"))?;
+    if !status.success() {
+        return Err(anyhow!("tar {} exit with {}", bundle.display(), status));
+    }
+
+    Ok(bundle.to_string_lossy().to_string())
+}
+
+/*
+ * cloud sets desired state of the `activate` named shadow, a subscribe rule
+ * on aws/kap/shadow/name/activate/state hands it to `activate remote`. The
+ * reported state echoes the id, so the re-delivered desired (shadow version
+ * bumps on every report) is not applied twice.
+ */
+const ACTIVATE_REMOTE_KEY: &str = "kap/activate/remote";
+const ACTIVATE_REMOTE_SHADOW: &str = "kap/aws/shadow/name/activate";
+
+#[derive(Deserialize, Serialize, Debug)]
+struct RemoteRequest {
+    id: String,
+    sections: Vec<ActivateSection>,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct RemoteReport {
+    id: String,
+    result: Option<ActivateResult>,
+    error: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+fn remote_request_parse(request: &str) -> Result<RemoteRequest> {
+    let value: serde_json::Value =
+        serde_json::from_str(request).map_err(|e| anyhow!("remote request invalid - {e}"))?;
+    /* subscribe args wrap the desired state in payload */
+    let value = match value.get("payload") {
+        Some(payload) => payload.clone(),
+        None => value,
+    };
+
+    serde_json::from_value(value).map_err(|e| anyhow!("remote request invalid - {e}"))
+}
+
+async fn remote_task(opt: &ActivateOpt, request: &str) -> Result<()> {
+    let request = remote_request_parse(request)?;
+    let mut db_conn = db_connect().await?;
+
+    let last: Option<String> = db_conn.get(ACTIVATE_REMOTE_KEY).await?;
+    if let Some(last) = last {
+        if serde_json::from_str::<serde_json::Value>(&last)
+            .map(|l| l["id"] == request.id.as_str())
+            .unwrap_or(false)
+        {
+            info!("remote activate {} done before, omit", request.id);
+            return Ok(());
+        }
+    }
+
+    let mut remote = opt.clone();
+    remote.only = Some(request.sections.clone());
+    remote.skip = None;
+    remote.force = request.force;
+    info!(
+        "remote activate {} sections {:?}",
+        request.id, request.sections
+    );
+
+    let r = activate_apply(&remote).await;
+    let report = match r {
+        Ok(ref result) => RemoteReport {
+            id: request.id.clone(),
+            result: Some(result.clone()),
+            error: None,
+            timestamp: Utc::now(),
+        },
+        Err(ref e) => RemoteReport {
+            id: request.id.clone(),
+            result: None,
+            error: Some(e.to_string()),
+            timestamp: Utc::now(),
+        },
+    };
+    let report = serde_json::to_string(&report)?;
+
+    db_conn
+        .set::<_, _, ()>(ACTIVATE_REMOTE_KEY, &report)
+        .await
+        .map_err(|e| anyhow!("db/redis set {ACTIVATE_REMOTE_KEY} fail - {e}"))?;
+    db_conn
+        .publish::<_, _, ()>(ACTIVATE_REMOTE_SHADOW, &report)
+        .await
+        .map_err(|e| anyhow!("db/redis publish {ACTIVATE_REMOTE_SHADOW} fail - {e}"))?;
+    println!("{report}");
+
+    r.map(|_| ())
+}
+
+//#[instrument(name = "activate", skip(opt))]
+async fn main_task(opt: ActivateOpt) -> Result<()> {
+    if let Some(ActivateCommand::Verify) = opt.command {
+        return verify_task(&opt).await;
+    }
+
+    if let Some(ref snapshot) = opt.restore {
+        return backup_restore(&opt.backup_dir, snapshot).await;
+    }
+
+    if let Some(ActivateCommand::Remote { ref request }) = opt.command {
+        return remote_task(&opt, request).await;
+    }
+
+    if opt.dry_run {
+        return dry_run_task(&opt).await;
+    }
+
+    let result = activate_apply(&opt).await?;
+    let feedback = serde_json::to_string(&result)?;
+
+    if atty::is(Stream::Stdout) {
+        println!(
+            "################################# [Activation code] #################################"
+        );
+        let color = to_colored_json_auto(&serde_json::from_str::<serde_json::Value>(&feedback)?)?;
+        println!("{}", color);
+        println!(
+            "#####################################################################################"
+        );
+    } else {
+        println!("{feedback}");
+    }
+
+    let failed = result
+        .sections
+        .iter()
+        .filter(|s| s.status == SectionStatus::Failed)
+        .count();
+    if failed > 0 {
+        Err(anyhow!("activate {} section(s) failed", failed))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "FIKA manager factory reset, inverse of activate")]
+pub struct FactoryResetOpt {
+    #[clap(
+        short = 'p',
+        long = "activate-rule",
+        default_value = "/etc/fika_manager/activate.toml"
+    )]
+    active: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(long = "backup-dir", default_value = "/userdata/fika_backup")]
+    backup_dir: String,
+    #[clap(long = "no-backup", action)]
+    no_backup: bool,
+    #[clap(long = "activate", action, help = "re-run the activation after reset")]
+    activate: bool,
+    #[clap(
+        short = 'y',
+        long = "yes",
+        action,
+        help = "confirm the destructive reset"
+    )]
+    yes: bool,
+}
+
+impl FactoryResetOpt {
+    fn activate_opt(&self) -> ActivateOpt {
+        let mut opt = ActivateOpt::with_paths(&self.active, &self.config, &self.rule);
+        opt.log_level = self.log_level.clone();
+        opt.force = true;
+        opt.backup_dir = self.backup_dir.clone();
+        opt.no_backup = self.no_backup;
+        opt
+    }
+}
+
+/* identity burned at manufacturing survives, everything else back to default */
+async fn factory_reset_config(config: &str) -> Result<KdaemonConfig> {
+    let orig = KdaemonConfig::build_from(config)
+        .await
+        .map_err(|e| anyhow!("{} load fail - {e}", config))?;
+    let mut cfg = KdaemonConfig::default();
+    cfg.core.mac_address = orig.core.mac_address;
+    cfg.core.serial_number = orig.core.serial_number;
+    cfg.core.sku = orig.core.sku;
+
+    /* saved after the wipe, so it has to be one that saves */
+    let violations = cfg.validate();
+    if !violations.is_empty() {
+        return Err(ConfigInvalid {
+            file: config.to_string(),
+            violations,
+        }
+        .into());
+    }
+    Ok(cfg)
+}
+
+async fn factory_reset_db() -> Result<usize> {
+    let mut db_conn = db_connect().await?;
+    let keys: Vec<String> = db_conn.keys("kap/*").await?;
+    if !keys.is_empty() {
+        db_conn
+            .del::<_, ()>(&keys)
+            .await
+            .map_err(|e| anyhow!("db/redis del kap/* fail - {e}"))?;
+    }
+
+    Ok(keys.len())
+}
+
+async fn factory_reset_task(opt: FactoryResetOpt) -> Result<()> {
+    if !opt.yes {
+        return Err(anyhow!("factory reset is destructive, re-run with --yes"));
+    }
+
+    /* an unreadable config stops the reset before anything is gone */
+    let cfg = factory_reset_config(&opt.config).await?;
+    let activate_opt = opt.activate_opt();
+    if !opt.no_backup {
+        backup_snapshot(&activate_opt).await?;
+    }
+
+    for cert in backup_certificates(&opt.rule).await {
+        match fs::remove_file(&cert).await {
+            Ok(_) => info!("{} removed", cert),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("{} remove fail - {e}", cert)),
+        }
+    }
+
+    match factory_reset_db().await {
+        Ok(n) => info!("db/redis {} kap/* keys cleared", n),
+        Err(e) => warn!("db/redis not cleared - {e}"),
+    }
+
+    cfg.save(&opt.config).await?;
+    info!("{} reset to default", opt.config);
+
+    if opt.activate {
+        return main_task(activate_opt).await;
+    }
+
+    Ok(())
+}
+
+pub async fn factory_reset(opt: FactoryResetOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+    factory_reset_task(opt).await
+}
+
+//#[tokio::main]
+pub async fn activate(opt: ActivateOpt) -> Result<()> {
+    /* before activation there may be no rule to take the format from */
+    let format = match opt.log_format {
+        Some(format) => format,
+        None => RuleConfig::build_from(&opt.rule)
+            .await
+            .ok()
+            .and_then(|rule| rule.core.log_format)
+            .unwrap_or_default(),
+    };
+    setup_logging_format(&opt.log_level, format)?;
+    debug!("activate-rule path as {}", opt.active);
+
+    let main_jhandle = tokio::spawn(main_task(opt));
+    let future_sig_c = signal::ctrl_c();
+
+    tokio::select! {
+        r = main_jhandle => {
+            let r = r?;
+            debug!("main-task exit due to {:?}", r);
+            r
+        },
+        _ = future_sig_c => {
+            warn!("exit by catch signal-c");
+            Ok(())
+        },
+    }
+}
+
+/*#[tokio::test]
+async fn test_toml_duration() {
+    let cp = ConfigTask {
+        topic: String::from("test"),
+        path: PathBuf::from("/tmp/test.sh"),
+        start_at: Some(Duration::from_secs(1)),
+        period: Some(Duration::from_secs(10)),
+    };
+
+    let toml = toml::to_string(&cp);
+    assert_eq!(toml, Ok(String::from("hello")));
+}*/
+
+#[test]
+fn test_remote_request_parse() {
+    let r = remote_request_parse(r#"{"id":"r1","sections":["boss"]}"#).unwrap();
+    assert_eq!(r.sections, vec![ActivateSection::Boss]);
+    assert!(!r.force);
+
+    let r = remote_request_parse(
+        r#"{"topic":"aws/kap/shadow/name/activate/state","payload":{"id":"r2","sections":["core","boss"],"force":true},"timestamp":"2022-10-01T00:00:00Z","version":3}"#,
+    )
+    .unwrap();
+    assert_eq!(r.id, "r2");
+    assert!(r.force);
+
+    assert!(remote_request_parse(r#"{"sections":["boss"]}"#).is_err());
+}
+
+#[test]
+fn test_factory_signature_verify() {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let pubkey = format!("ed25519:{}", base64::encode(pair.public_key().as_ref()));
+    let data = b"[core]\nkey = \"kap/factory/core\"\n";
+    let signature = base64::encode(pair.sign(data).as_ref());
+
+    assert!(factory_signature_verify(&pubkey, data, &signature).is_ok());
+    assert!(factory_signature_verify(&pubkey, b"[core]\n", &signature).is_err());
+    assert!(factory_signature_verify("rsa:AAAA", data, &signature).is_err());
+}
+
+#[tokio::test]
+async fn test_activate_only_after_applied() {
+    let dir = std::env::temp_dir().join(format!("fika_activate_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+    std::fs::write(path("activate.toml"), "[core]\n[network]\n[por]\n[boss]\n").unwrap();
+    std::fs::write(
+        path("kdaemon.toml"),
+        r#"
+        [core]
+        wallet_address = "0x8E2BC5E6B05ce8BD2D8C0be0e1D9A5A2d8F8042F"
+        mac_address = "AA:BB:CC:DD:EE:01"
+        serial_number = "FK0001"
+        sku = "K1"
+        [network]
+        wan_type = 0
+        [por]
+        state = true
+        [boss]
+        "#,
+    )
+    .unwrap();
+
+    let mut opt = ActivateOpt::with_paths(
+        &path("activate.toml"),
+        &path("kdaemon.toml"),
+        &path("rule.toml"),
+    );
+    opt.no_backup = true;
+    opt.log_dir = path("log");
+    opt.state_file = Some(path("state.json"));
+
+    opt.only = Some(vec![ActivateSection::Boss]);
+    let e = activate_apply(&opt).await.err().unwrap();
+    assert!(e.to_string().contains("depends on Core"), "{e}");
+
+    let status = |r: &ActivateResult, section| {
+        r.sections
+            .iter()
+            .find(|s| s.section == section)
+            .map(|s| s.status.clone())
+    };
+    opt.only = Some(vec![ActivateSection::Core]);
+    let r = activate_apply(&opt).await.unwrap();
+    assert_eq!(
+        status(&r, ActivateSection::Core),
+        Some(SectionStatus::Applied)
+    );
+
+    /* core done in the run before, boss alone may go now */
+    opt.only = Some(vec![ActivateSection::Boss]);
+    let r = activate_apply(&opt).await.unwrap();
+    assert_eq!(
+        status(&r, ActivateSection::Boss),
+        Some(SectionStatus::Applied)
+    );
+
+    /* a hook exiting non-zero fails its section */
+    let mut core = KapCoreConfig {
+        cfg: None,
+        key: None,
+        post: Some("/bin/false".to_string()),
+        pre: Some("/bin/true".to_string()),
+        timeout: None,
+        depends: None,
+    };
+    assert!(core.pre(ActivateSection::Core, &dir).await.is_ok());
+    let e = core.post(ActivateSection::Core, &dir).await.unwrap_err();
+    assert!(e.to_string().contains("Core/post /bin/false fail"), "{e}");
+    core.post = None;
+    assert!(core.post(ActivateSection::Core, &dir).await.is_ok());
+
+    /* boss depends on core already, a cycle fails before anything runs */
+    std::fs::write(
+        path("activate.toml"),
+        "[core]\ndepends = [\"boss\"]\n[network]\n[por]\n[boss]\n",
+    )
+    .unwrap();
+    opt.only = Some(vec![ActivateSection::Core, ActivateSection::Boss]);
+    let e = activate_apply(&opt).await.err().unwrap();
+    assert!(e.to_string().contains("depends cycle"), "{e}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -3,13 +3,18 @@ use futures_util::future;
 //use process_stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::sync::{mpsc, oneshot, Notify};
 use tokio::task;
 //use std::path::Path;
+use crate::kap_boot::{boot_publish, boot_stage, BootStage};
+use crate::kap_crash::{CrashState, CRASH_STATE_KEY};
 use crate::kap_daemon::KdaemonConfig;
-use crate::DbCommand;
+use crate::kap_watchdog::{watchdog_event, WATCHDOG_TIMEOUT};
+use crate::misc::write_atomic;
+use crate::{DbCommand, ShutdownGuard};
 use aws_iot_device_sdk_rust::{async_event_loop_listener, AWSIoTAsyncClient, AWSIoTSettings};
 use chrono::prelude::*;
 use chrono::serde::ts_seconds;
@@ -29,6 +34,15 @@ pub struct RuleAwsIotProvisionConfig {
     pub private: String,
     pub template: String,
     pub thing_prefix: String,
+    pub wipe: Option<RuleAwsIotBootstrapWipe>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAwsIotBootstrapWipe {
+    Keep,
+    Delete,
+    Shred,
 }
 
 impl Default for RuleAwsIotProvisionConfig {
@@ -39,6 +53,7 @@ impl Default for RuleAwsIotProvisionConfig {
             private: String::from("/etc/fika_manager/bootstrap-inactive.private.key"),
             template: String::from("LongDongPreHookReal"),
             thing_prefix: String::from("LD2"),
+            wipe: Some(RuleAwsIotBootstrapWipe::Keep),
         }
     }
 }
@@ -47,6 +62,48 @@ impl RuleAwsIotProvisionConfig {
     pub fn generate_thing_name(&self, extra: &str) -> Option<String> {
         Some(format!("{}_{}", &self.thing_prefix, extra))
     }
+
+    /* claim credentials are useless once provisioned, only a thief needs them */
+    pub async fn bootstrap_wipe(&self) -> Result<()> {
+        let wipe = self.wipe.unwrap_or(RuleAwsIotBootstrapWipe::Keep);
+        if wipe == RuleAwsIotBootstrapWipe::Keep {
+            return Ok(());
+        }
+
+        for path in [&self.cert, &self.private] {
+            if wipe == RuleAwsIotBootstrapWipe::Shred {
+                file_shred(path).await?;
+            }
+            match fs::remove_file(path).await {
+                Ok(_) => info!("bootstrap {} wiped ({:?})", path, wipe),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(anyhow!("bootstrap {} remove fail - {e}", path)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn file_shred(path: &str) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let len = match fs::metadata(path).await {
+        Ok(m) => m.len() as usize,
+        Err(_) => return Ok(()),
+    };
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(|e| anyhow!("shred {} open fail - {e}", path))?;
+    let noise = std::iter::repeat_with(|| fastrand::u8(..))
+        .take(len)
+        .collect::<Vec<u8>>();
+    file.write_all(&noise).await?;
+    file.sync_all().await?;
+
+    Ok(())
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -126,12 +183,12 @@ impl AwsIotKeyCertificate {
         let now = Utc::now();
         self.issue_time = Some(now);
 
-        fs::write(&cert_path, &self.certificate_pem).await?;
-        fs::write(&private_path, &self.private_key).await?;
+        write_atomic(&cert_path, &self.certificate_pem).await?;
+        write_atomic(&private_path, &self.private_key).await?;
 
         let info_path = cert_path.replace(".pem", ".info");
         let all = serde_json::to_string(self)?;
-        fs::write(&info_path, &all).await?;
+        write_atomic(&info_path, &all).await?;
 
         Ok((self.certificate_id.clone(), now))
     }
@@ -175,19 +232,16 @@ pub async fn mqtt_provision_task(
 
     let cert_path = cmp.cert.clone();
     let private_path = cmp.private.clone();
-    let serial_number = cfg.core.serial_number.clone().to_ascii_lowercase();
-    let mac_address = cfg
-        .core
-        .mac_address
-        .clone()
-        .split(':')
-        .map(|e| e.to_ascii_lowercase())
-        .collect::<String>();
+    let serial_number = cfg.core.serial_number.lowercase();
+    let mac_address = cfg.core.mac_address.compact();
     let sku = cfg.core.sku.clone();
     let endpoint = aws.endpoint.clone().unwrap();
     let model = provision.thing_prefix.clone().to_ascii_uppercase();
 
-    let client_id = format!("pid-{}", &serial_number[(serial_number.len() - 5)..]);
+    let client_id = format!(
+        "pid-{}",
+        &serial_number[serial_number.len().saturating_sub(5)..]
+    );
     let aws = AWSIoTSettings::new(
         client_id,
         provision.ca.clone(),
@@ -400,7 +454,26 @@ async fn mqtt_dedicated_create(
         .or_else(|e| Err(anyhow!("mqtt connect fail - {e}")))
 }
 
-#[instrument(name = "mqtt::dedicated", skip_all)]
+static MQTT_STALLS: AtomicU32 = AtomicU32::new(0);
+
+/* a handler stuck past the watchdog, leave so the retry loop reconnects */
+async fn mqtt_stalled(db_chan: &mpsc::Sender<DbCommand>, side: &str) {
+    let restarts = MQTT_STALLS.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!(
+        "[mqtt/{}] handler stuck over {}s, force leave and reconnect",
+        side,
+        WATCHDOG_TIMEOUT.as_secs()
+    );
+    watchdog_event(
+        db_chan,
+        &format!("mqtt/{}", side),
+        WATCHDOG_TIMEOUT,
+        restarts,
+    )
+    .await;
+}
+
+#[instrument(name = "mqtt::dedicated", skip_all, fields(thing = %thing_name))]
 pub async fn mqtt_dedicated_start(
     mut aws_ipc_rx: mpsc::Receiver<AwsIotCmd>,
     db_chan: mpsc::Sender<DbCommand>,
@@ -414,7 +487,7 @@ pub async fn mqtt_dedicated_start(
         ),
     ),
     pull_topic: Option<Vec<String>>,
-) -> Result<mpsc::Receiver<AwsIotCmd>> {
+) -> Result<Option<mpsc::Receiver<AwsIotCmd>>> {
     let (iot_core_client, eventloop_stuff) = iot;
     /* topic - '#' to monitor all event */
     let topic = format!("$aws/things/{}/shadow/#", thing_name);
@@ -424,6 +497,17 @@ pub async fn mqtt_dedicated_start(
     iot_core_client.subscribe(&topic, QoS::AtMostOnce).await?;
     info!("aws/iot subscribed {} ok", &topic);
 
+    if let Some(state) = crash_state_pending(&db_chan).await {
+        let cmd = AwsIotCmd::ShadowUpdate {
+            topic: "name/crash".to_string(),
+            msg: crash_shadow_state(&state).to_string(),
+        };
+        match mqtt_dedicated_handle_ipc(&iot_core_client, &db_chan, &thing_name, cmd).await {
+            Ok(_) => crash_state_reported(&db_chan, state).await,
+            Err(e) => warn!("crash shadow report fail, next connect again - {e}"),
+        }
+    }
+
     if let Some(pull_topic) = pull_topic {
         let _: Vec<Result<(), rumqttc::ClientError>> =
             future::join_all(pull_topic.iter().map(|t| async {
@@ -436,23 +520,51 @@ pub async fn mqtt_dedicated_start(
     let notify = Arc::new(Notify::new());
     let notify2 = notify.clone();
 
-    let recv_thread: task::JoinHandle<Result<mpsc::Receiver<AwsIotCmd>>> = tokio::spawn(
+    /* None once AwsIotCmd::Exit asked for a clean leave, no reconnect then */
+    let recv_thread: task::JoinHandle<Result<Option<mpsc::Receiver<AwsIotCmd>>>> = tokio::spawn(
         async move {
             let mut receiver = iot_core_client.get_receiver().await;
             loop {
                 tokio::select! {
                     msg = receiver.recv() => {
-                        let r = mqtt_dedicated_handle_iot(&db_chan, &subscribe_ipc_tx, msg).await;
-                        if r.is_err() {
-                            warn!("[mqtt/aws] force leave due to receive-chan error msg");
-                            break;
+                        let r = time::timeout(
+                            WATCHDOG_TIMEOUT,
+                            mqtt_dedicated_handle_iot(&db_chan, &subscribe_ipc_tx, msg),
+                        )
+                        .await;
+                        match r {
+                            Ok(Ok(_)) => {}
+                            Ok(Err(_)) => {
+                                warn!("[mqtt/aws] force leave due to receive-chan error msg");
+                                break;
+                            }
+                            Err(_) => {
+                                mqtt_stalled(&db_chan, "receive").await;
+                                break;
+                            }
                         }
                     },
                     Some(msg) = aws_ipc_rx.recv() => {
-                        let r = mqtt_dedicated_handle_ipc(&iot_core_client, &db_chan, &thing_name, msg).await;
-                        if r.is_err() {
-                            warn!("[mqtt/ipc] should be force leave due to AwsIotCmd::Exit(?!)");
-                            break;
+                        if let AwsIotCmd::Exit = msg {
+                            info!("[mqtt/ipc] exit requested, disconnect");
+                            _ = iot_core_client.get_client().await.disconnect().await;
+                            return Ok(None);
+                        }
+                        let r = time::timeout(
+                            WATCHDOG_TIMEOUT,
+                            mqtt_dedicated_handle_ipc(&iot_core_client, &db_chan, &thing_name, msg),
+                        )
+                        .await;
+                        match r {
+                            Ok(Ok(_)) => {}
+                            Ok(Err(_)) => {
+                                warn!("[mqtt/ipc] should be force leave due to AwsIotCmd::Exit(?!)");
+                                break;
+                            }
+                            Err(_) => {
+                                mqtt_stalled(&db_chan, "ipc").await;
+                                break;
+                            }
                         }
                     },
                     _ = notify2.notified() => {
@@ -462,7 +574,7 @@ pub async fn mqtt_dedicated_start(
                 }
             }
             warn!("[mqtt/aws] out of receive loop");
-            Ok(aws_ipc_rx)
+            Ok(Some(aws_ipc_rx))
         },
     );
     let listen_thread: task::JoinHandle<Result<()>> = tokio::spawn(async move {
@@ -472,9 +584,50 @@ pub async fn mqtt_dedicated_start(
         Ok(())
     });
 
-    let (recv, _listen) = tokio::join!(recv_thread, listen_thread);
+    /* the event loop never ends by itself on a clean leave */
+    let recv = recv_thread.await;
+    listen_thread.abort();
     debug!("dedicated listen/receive thread exited");
-    recv.unwrap()
+    recv?
+}
+
+async fn connection_event(
+    db_chan: &mpsc::Sender<DbCommand>,
+    thing: &str,
+    state: &str,
+    error: Option<String>,
+) {
+    let event = json!({
+        "thing": thing,
+        "state": state,
+        "error": error,
+        "since": Utc::now().timestamp(),
+    });
+    if let Err(e) = crate::set_message(
+        db_chan.clone(),
+        crate::MQTT_STATE_KEY.to_string(),
+        event.to_string(),
+    )
+    .await
+    {
+        warn!("connection state set fail - {e}");
+    }
+    if let Err(e) = crate::event_publish(db_chan, "connection", event).await {
+        warn!("connection event publish fail - {e}");
+    }
+}
+
+/* the dedicated identity is in place, what provisioning leaves behind */
+async fn provision_check(dedicated: &RuleAwsIotDedicatedConfig) -> Result<()> {
+    for path in [&dedicated.cert, &dedicated.private, &dedicated.ca] {
+        let meta = fs::metadata(path)
+            .await
+            .map_err(|e| anyhow!("{} missing - {e}", path))?;
+        if meta.len() == 0 {
+            return Err(anyhow!("{} empty", path));
+        }
+    }
+    Ok(())
 }
 
 //#[instrument(name = "mqtt::dedicated", skip(aws_ipc_rx, db_chan))]
@@ -484,16 +637,25 @@ pub async fn mqtt_dedicated_create_start(
     mut aws_ipc_rx: mpsc::Receiver<AwsIotCmd>,
     db_chan: mpsc::Sender<DbCommand>,
     subscribe_ipc_tx: mpsc::Sender<SubscribeCmd>,
+    mut guard: ShutdownGuard,
 ) -> Result<()> {
     let thing = aws.thing_name(&cfg.core.mac_address)?;
     let pull_topic = &aws.dedicated.pull_topic;
     let mut retry = 1;
 
+    if boot_stage(BootStage::Provision, &provision_check(&aws.dedicated).await) {
+        boot_publish(&db_chan).await;
+    }
+
     loop {
         let thing_name = thing.clone();
         match mqtt_dedicated_create(&aws, &thing_name).await {
             Ok(iot) => {
-                aws_ipc_rx = mqtt_dedicated_start(
+                connection_event(&db_chan, &thing_name, "connected", None).await;
+                if boot_stage(BootStage::Mqtt, &Ok::<(), String>(())) {
+                    boot_publish(&db_chan).await;
+                }
+                let rx = mqtt_dedicated_start(
                     aws_ipc_rx,
                     db_chan.clone(),
                     subscribe_ipc_tx.clone(),
@@ -502,11 +664,26 @@ pub async fn mqtt_dedicated_create_start(
                     pull_topic.clone(),
                 )
                 .await?;
+                connection_event(&db_chan, &thing, "disconnected", None).await;
+                match rx {
+                    Some(rx) => aws_ipc_rx = rx,
+                    None => return Ok(()),
+                }
+            }
+            Err(e) => {
+                warn!("mqtt dedicated create fail - {e}, activate??");
+                connection_event(&db_chan, &thing, "failed", Some(e.to_string())).await;
+                if boot_stage(BootStage::Mqtt, &Err::<(), _>(e)) {
+                    boot_publish(&db_chan).await;
+                }
             }
-            Err(e) => warn!("mqtt dedicated create fail - {e}, activate??"),
         }
 
-        time::sleep(Duration::from_secs(retry * 30)).await;
+        /* held until AwsIotCmd::Exit, only the wait to reconnect gives up on stop */
+        tokio::select! {
+            _ = guard.stopped() => return Ok(()),
+            _ = time::sleep(Duration::from_secs(retry * 30)) => {}
+        }
         warn!("mqtt dedicated restart - {}", retry);
 
         retry = retry + 1;
@@ -724,6 +901,82 @@ async fn shadow_version_compare(
     return Ok(true);
 }
 
+/* an unreported crash from CRASH_STATE_KEY, left so until the shadow has it */
+async fn crash_state_pending(db_chan: &mpsc::Sender<DbCommand>) -> Option<CrashState> {
+    let (resp, rx) = oneshot::channel();
+    db_chan
+        .send(DbCommand::Get {
+            key: CRASH_STATE_KEY.to_string(),
+            resp,
+        })
+        .await
+        .ok()?;
+    let state: CrashState = serde_json::from_str(&rx.await.ok()??).ok()?;
+    if state.reported {
+        return None;
+    }
+
+    info!(
+        "last crash {} at {} to report",
+        state.message, state.timestamp
+    );
+    Some(state)
+}
+
+async fn crash_state_reported(db_chan: &mpsc::Sender<DbCommand>, mut state: CrashState) {
+    state.reported = true;
+    let val = match serde_json::to_string(&state) {
+        Ok(val) => val,
+        Err(e) => {
+            warn!("crash state serialize fail - {e}");
+            return;
+        }
+    };
+    if let Err(e) = crate::set_message(db_chan.clone(), CRASH_STATE_KEY.to_string(), val).await {
+        warn!("crash state mark reported fail - {e}");
+    }
+}
+
+/* shadow `reported` document of a crash, the report file stays on device */
+fn crash_shadow_state(state: &CrashState) -> serde_json::Value {
+    json!({
+        "crash": {
+            "timestamp": state.timestamp.timestamp(),
+            "version": state.version,
+            "message": state.message,
+            "location": state.location,
+            "report": state.report,
+        }
+    })
+}
+
+/* desired state to the subscribe task, mirrored as a `shadow` event */
+async fn subscribe_notify(
+    db_chan: &mpsc::Sender<DbCommand>,
+    subscribe_ipc_tx: &mpsc::Sender<SubscribeCmd>,
+    topic: String,
+    msg: String,
+    version: u16,
+) -> Result<()> {
+    let event = json!({
+        "topic": &topic,
+        "desired": serde_json::from_str::<Value>(&msg).unwrap_or_default(),
+        "version": version,
+    });
+    subscribe_ipc_tx
+        .send(SubscribeCmd::Notify {
+            topic,
+            msg,
+            version: Some(version),
+        })
+        .await?;
+
+    if let Err(e) = crate::event_publish(db_chan, "shadow", event).await {
+        warn!("shadow event publish fail - {e}");
+    }
+    Ok(())
+}
+
 async fn post_iot_publish_msg(
     db_chan: &mpsc::Sender<DbCommand>,
     subscribe_ipc_tx: &mpsc::Sender<SubscribeCmd>,
@@ -744,9 +997,7 @@ async fn post_iot_publish_msg(
                     let p = serde_json::to_string(&shadow.state.desired.unwrap())?;
                     let t = format!("{}/{}", &sub_topic, "state");
 
-                    subscribe_ipc_tx
-                        .send(SubscribeCmd::Notify { topic: t, msg: p })
-                        .await?;
+                    subscribe_notify(db_chan, subscribe_ipc_tx, t, p, shadow.version).await?;
                 }
             }
             Err(e) => {
@@ -754,9 +1005,7 @@ async fn post_iot_publish_msg(
                 warn!("force sync to sub-task");
                 let p = serde_json::to_string(&shadow.state.desired.unwrap())?;
                 let t = format!("{}/{}", &sub_topic, "state");
-                subscribe_ipc_tx
-                    .send(SubscribeCmd::Notify { topic: t, msg: p })
-                    .await?;
+                subscribe_notify(db_chan, subscribe_ipc_tx, t, p, shadow.version).await?;
             }
         }
     }
@@ -788,19 +1037,12 @@ async fn post_iot_publish_msg(
     Ok(())
 }
 
-#[tokio::test]
-async fn test_mac_lowercase() {
-    let mac = "a1:A1:b1:B2:c1:C2".to_string();
-    /*let mac = mac.chars()
-    .filter(|c| c == &':')
-    .map(|c| c.to_ascii_lowercase())
-    .collect::<String>();*/
-    let mac = mac
-        .split(':')
-        .map(|e| e.to_ascii_lowercase())
-        .collect::<String>();
+#[test]
+fn test_mac_lowercase() {
+    use crate::kap_daemon::MacAddress;
 
-    assert_eq!(mac, "a1a1b1b2c1b2");
+    let mac: MacAddress = "a1:A1:b1:B2:c1:C2".parse().unwrap();
+    assert_eq!(mac.compact(), "a1a1b1b2c1c2");
 }
 
 #[derive(Debug)]
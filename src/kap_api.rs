@@ -0,0 +1,480 @@
+use anyhow::{anyhow, Result};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use clap::Args;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::kap_audit::redact;
+use crate::kap_boot::BOOT_STATUS_KEY;
+use crate::kap_crash::CRASH_STATE_KEY;
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_honest::HONEST_STATE_KEY;
+use crate::kap_monitor::MONITOR_STATE_KEY;
+use crate::kap_rule::{RuleApiConfig, RuleConfig};
+use crate::kap_task::task_run;
+use crate::kap_wan::{wan_request, WanProfileState, WanSwitch, WAN_STATUS_KEY, WAN_SWITCH_CHANNEL};
+use crate::kap_watchdog::{db_probe, supervise, Liveness, WATCHDOG_TIMEOUT};
+use crate::{
+    db_serve, setup_logging_rule, shutdown_run, shutdown_signal, DbCommand, LogFormat, LogOverride,
+    Shutdown, DAEMON_START_KEY, EVENT_CHANNEL_PREFIX, LOG_LEVEL_CHANNEL, MQTT_STATE_KEY,
+    SHUTDOWN_GRACE,
+};
+
+/*
+ * local control API for LuCI and installer apps: status, effective config,
+ * whitelisted redis keys, task triggers, the WAN profile switch and the
+ * /events websocket; plain localhost/unix socket, no auth, keep it off the
+ * WAN
+ */
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "FIKA manager local control API")]
+pub struct ApiOpt {
+    #[clap(long = "listen", default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+    #[clap(long = "unix", help = "unix socket path instead of --listen")]
+    unix: Option<PathBuf>,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+    #[clap(
+        long = "log-format",
+        value_enum,
+        help = "default rule/core/log_format or text"
+    )]
+    log_format: Option<LogFormat>,
+}
+
+const API_DB_READ: &[&str] = &[
+    BOOT_STATUS_KEY,
+    HONEST_STATE_KEY,
+    DAEMON_START_KEY,
+    MQTT_STATE_KEY,
+    CRASH_STATE_KEY,
+    MONITOR_STATE_KEY,
+    WAN_STATUS_KEY,
+    "kap/activate/*",
+];
+
+struct ApiState {
+    rule: RuleConfig,
+    api: RuleApiConfig,
+    db: redis::Client,
+    db_chan: mpsc::Sender<DbCommand>,
+}
+
+struct ApiError(StatusCode, anyhow::Error);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1.to_string() }))).into_response()
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.into())
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+fn api_forbidden(what: &str) -> ApiError {
+    ApiError(StatusCode::FORBIDDEN, anyhow!("{} not allowed", what))
+}
+
+/* exact key, or prefix for a pattern ending with '*' */
+fn key_allowed<S: AsRef<str>>(patterns: &[S], key: &str) -> bool {
+    patterns.iter().any(|p| match p.as_ref().strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => p.as_ref() == key,
+    })
+}
+
+impl ApiState {
+    async fn conn(&self) -> Result<redis::aio::Connection> {
+        self.db
+            .get_async_connection()
+            .await
+            .map_err(|e| anyhow!("db/redis async connect fail - {e}"))
+    }
+
+    fn db_readable(&self, key: &str) -> bool {
+        match self.api.db_read {
+            Some(ref keys) => key_allowed(keys, key),
+            None => key_allowed(API_DB_READ, key),
+        }
+    }
+
+    fn db_writable(&self, key: &str) -> bool {
+        key_allowed(self.api.db_write.as_deref().unwrap_or_default(), key)
+    }
+}
+
+async fn api_status(State(st): State<Arc<ApiState>>) -> ApiResult<Json<Value>> {
+    let cfg = KdaemonConfig::build_from(&st.rule.core.config).await?;
+    let uptime = tokio::fs::read_to_string("/proc/uptime")
+        .await
+        .ok()
+        .and_then(|u| u.split_whitespace().next()?.parse::<f64>().ok());
+
+    let (database, daemon_start, honest) = match st.conn().await {
+        Ok(mut conn) => {
+            let start: Option<String> = conn.get(DAEMON_START_KEY).await.unwrap_or_default();
+            let honest: Option<String> = conn.get(HONEST_STATE_KEY).await.unwrap_or_default();
+            (
+                true,
+                start.and_then(|s| s.parse::<i64>().ok()),
+                honest.and_then(|h| serde_json::from_str::<Value>(&h).ok()),
+            )
+        }
+        Err(e) => {
+            debug!("{e}");
+            (false, None, None)
+        }
+    };
+
+    Ok(Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime": uptime,
+        "daemon_start": daemon_start,
+        "database": database,
+        "honest": honest,
+        "mac_address": cfg.core.mac_address,
+        "serial_number": cfg.core.serial_number,
+        "sku": cfg.core.sku,
+        "wallet_address": cfg.core.wallet_address,
+    })))
+}
+
+async fn api_config(State(st): State<Arc<ApiState>>) -> ApiResult<Json<Value>> {
+    let cfg = KdaemonConfig::build_from(&st.rule.core.config).await?;
+    let mut config = json!({
+        "rule": serde_json::to_value(&st.rule)?,
+        "config": serde_json::to_value(&cfg)?,
+    });
+    redact(&mut config);
+    Ok(Json(config))
+}
+
+async fn api_db_get(
+    State(st): State<Arc<ApiState>>,
+    Path(key): Path<String>,
+) -> ApiResult<Json<Value>> {
+    if !st.db_readable(&key) {
+        return Err(api_forbidden(&key));
+    }
+    let value: Option<String> = st.conn().await?.get(&key).await?;
+    Ok(Json(json!({ "key": key, "value": value })))
+}
+
+/* json string taken as is, anything else stored as its json text */
+async fn api_db_set(
+    State(st): State<Arc<ApiState>>,
+    Path(key): Path<String>,
+    Json(value): Json<Value>,
+) -> ApiResult<Json<Value>> {
+    if !st.db_writable(&key) {
+        return Err(api_forbidden(&key));
+    }
+    let value = match value {
+        Value::String(s) => s,
+        v => v.to_string(),
+    };
+    st.conn().await?.set::<_, _, ()>(&key, &value).await?;
+    info!("api db set {}", key);
+    Ok(Json(json!({ "key": key, "saved": true })))
+}
+
+async fn api_task(
+    State(st): State<Arc<ApiState>>,
+    Path(topic): Path<String>,
+) -> ApiResult<Json<Value>> {
+    if let Some(ref tasks) = st.api.tasks {
+        if !key_allowed(tasks, &topic) {
+            return Err(api_forbidden(&topic));
+        }
+    }
+    let task = st
+        .rule
+        .task
+        .iter()
+        .flatten()
+        .find(|t| t.topic == topic)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, anyhow!("task/{} nonexist", topic)))?;
+
+    let output = task_run(task, &st.rule.core, &st.db_chan).await?;
+    if task.db_publish != Some(false) {
+        let _: usize = st.conn().await?.publish(&task.topic, &output).await?;
+    }
+    info!("api task/{} triggered", topic);
+    Ok(Json(json!({ "topic": topic, "output": output })))
+}
+
+/* checked here, applied by whoever listens on LOG_LEVEL_CHANNEL (the daemon) */
+async fn api_log_level(
+    State(st): State<Arc<ApiState>>,
+    Json(ov): Json<LogOverride>,
+) -> ApiResult<Json<Value>> {
+    if !ov.directive.is_empty() {
+        ov.validate()
+            .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e))?;
+    }
+    let receivers: usize = st
+        .conn()
+        .await?
+        .publish(LOG_LEVEL_CHANNEL, serde_json::to_string(&ov)?)
+        .await?;
+    info!(
+        "api log level {:?} to {} receivers",
+        ov.directive, receivers
+    );
+    Ok(Json(json!({
+        "directive": ov.directive,
+        "duration": ov.duration.map(|d| d.as_secs()),
+        "receivers": receivers,
+    })))
+}
+
+/* the profiles and the last report, credentials left out */
+async fn api_wan(State(st): State<Arc<ApiState>>) -> ApiResult<Json<Value>> {
+    let network = KdaemonConfig::build_from(&st.rule.core.config)
+        .await?
+        .network;
+    let profiles: Vec<WanProfileState> = network
+        .wan_profiles()
+        .iter()
+        .map(WanProfileState::from)
+        .collect();
+    let status: Option<String> = st.conn().await?.get(WAN_STATUS_KEY).await?;
+    Ok(Json(json!({
+        "profiles": profiles,
+        "status": status.and_then(|s| serde_json::from_str::<Value>(&s).ok()),
+    })))
+}
+
+/* checked here, applied by whoever listens on WAN_SWITCH_CHANNEL (the daemon) */
+async fn api_wan_switch(
+    State(st): State<Arc<ApiState>>,
+    Json(ask): Json<WanSwitch>,
+) -> ApiResult<Json<Value>> {
+    let network = KdaemonConfig::build_from(&st.rule.core.config)
+        .await?
+        .network;
+    let request = wan_request(&network, &ask.profile, ask.reason)
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e))?;
+    let receivers: usize = st
+        .conn()
+        .await?
+        .publish(WAN_SWITCH_CHANNEL, serde_json::to_string(&request)?)
+        .await?;
+    info!(
+        "api wan switch {} to {} receivers",
+        request.profile, receivers
+    );
+    Ok(Json(json!({
+        "profile": request.profile,
+        "receivers": receivers,
+    })))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    kind: Option<String>,
+}
+
+/* ?kind=task,shadow to narrow, every kap/event/ channel otherwise */
+async fn api_events(
+    State(st): State<Arc<ApiState>>,
+    Query(query): Query<EventsQuery>,
+    ws: WebSocketUpgrade,
+) -> ApiResult<Response> {
+    let mut sub = st.conn().await?.into_pubsub();
+    match query.kind {
+        Some(ref kinds) => {
+            for kind in kinds.split(',').filter(|k| !k.is_empty()) {
+                sub.subscribe(format!("{}{}", EVENT_CHANNEL_PREFIX, kind))
+                    .await?;
+            }
+        }
+        None => sub.psubscribe(format!("{}*", EVENT_CHANNEL_PREFIX)).await?,
+    }
+    Ok(ws.on_upgrade(move |socket| api_events_relay(socket, sub)))
+}
+
+async fn api_events_relay(mut socket: WebSocket, sub: redis::aio::PubSub) {
+    let mut events = sub.into_on_message();
+    debug!("api events client joined");
+
+    loop {
+        tokio::select! {
+            msg = events.next() => {
+                let payload: String = match msg {
+                    Some(msg) => msg.get_payload().unwrap_or_default(),
+                    None => break,
+                };
+                if socket.send(Message::Text(payload)).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+    debug!("api events client left");
+}
+
+async fn api_shutdown() {
+    if let Err(e) = shutdown_signal().await {
+        warn!("api signal setup fail - {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn api_serve_unix(app: Router, path: &PathBuf) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    _ = tokio::fs::remove_file(path).await;
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| anyhow!("api unix socket {:?} bind fail - {e}", path))?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660)).await?;
+
+    let incoming = futures_util::stream::unfold(listener, |l| async move {
+        Some((l.accept().await.map(|(s, _)| s), l))
+    });
+    info!("api listen on {:?}", path);
+    axum::Server::builder(hyper::server::accept::from_stream(incoming))
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(api_shutdown())
+        .await
+        .map_err(|e| anyhow!("api serve fail - {e}"))
+}
+
+pub async fn api_tools(opt: ApiOpt) -> Result<()> {
+    let rule = RuleConfig::build_from(&opt.rule)
+        .await
+        .map_err(|e| anyhow!("rule build from {} fail - {:?}", opt.rule, e))?;
+    setup_logging_rule(
+        &opt.log_level,
+        opt.log_format.or(rule.core.log_format).unwrap_or_default(),
+        &rule.core,
+    )?;
+    let database = rule
+        .core
+        .database
+        .clone()
+        .ok_or_else(|| anyhow!("rule/core/database none invalid"))?;
+    let db =
+        redis::Client::open(database.as_str()).map_err(|e| anyhow!("db/redis open fail - {e}"))?;
+
+    let grace = rule.core.shutdown_grace.unwrap_or(SHUTDOWN_GRACE);
+    let shutdown = Shutdown::new();
+    let db_guard = shutdown.guard_last();
+    let (db_chan, db_rx) = mpsc::channel(32);
+    let db_task = db.clone();
+    let db_rx = Arc::new(Mutex::new(db_rx));
+    let live = Liveness::default();
+    tokio::spawn(db_probe(
+        db_chan.clone(),
+        live.clone(),
+        WATCHDOG_TIMEOUT / 3,
+    ));
+    let watchdog_chan = db_chan.clone();
+    tokio::spawn(async move {
+        let r = supervise(
+            "api/db",
+            live,
+            WATCHDOG_TIMEOUT,
+            Some(watchdog_chan),
+            || db_serve(db_task.clone(), db_rx.clone(), Some(db_guard.clone())),
+        )
+        .await;
+        if let Err(e) = r {
+            warn!("api db serve fail - {e}");
+        }
+    });
+
+    let exit_chan = db_chan.clone();
+    let state = Arc::new(ApiState {
+        api: rule.api.clone().unwrap_or_default(),
+        rule,
+        db,
+        db_chan,
+    });
+    let app = Router::new()
+        .route("/api/status", get(api_status))
+        .route("/api/config", get(api_config))
+        .route("/api/db/*key", get(api_db_get).put(api_db_set))
+        .route("/api/task/*topic", post(api_task))
+        .route("/api/log_level", put(api_log_level))
+        .route("/api/wan", get(api_wan).put(api_wan_switch))
+        .route("/events", get(api_events))
+        .with_state(state);
+
+    let r = match opt.unix {
+        Some(ref path) => api_serve_unix(app, path).await,
+        None => {
+            if !opt.listen.ip().is_loopback() {
+                warn!(
+                    "api on {} is reachable beyond localhost, no auth",
+                    opt.listen
+                );
+            }
+            info!("api listen on {}", opt.listen);
+            axum::Server::bind(&opt.listen)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(api_shutdown())
+                .await
+                .map_err(|e| anyhow!("api serve fail - {e}"))
+        }
+    };
+    /* in-flight task triggers are done, let the db task leave */
+    shutdown_run(
+        shutdown,
+        #[cfg(feature = "aws-iot")]
+        None,
+        Some(&exit_chan),
+        grace,
+    )
+    .await;
+    r
+}
+
+#[test]
+fn test_api_key_allowed() {
+    assert!(key_allowed(API_DB_READ, "kap/honest/state"));
+    assert!(key_allowed(API_DB_READ, "kap/activate/remote"));
+    assert!(!key_allowed(API_DB_READ, "kap/boss/ap_access_token"));
+    assert!(!key_allowed::<&str>(&[], "kap/honest/state"));
+
+    let mut cfg = json!({
+        "boss": { "root_url": "https://boss", "ap_token": "t" },
+        "oauth": [{ "client_secret": "s", "client_id": "id" }],
+        "sign": { "password_file": "/etc/pass" },
+    });
+    redact(&mut cfg);
+    assert_eq!(cfg["boss"]["root_url"], "https://boss");
+    assert_eq!(cfg["boss"]["ap_token"], "***");
+    assert_eq!(cfg["oauth"][0]["client_secret"], "***");
+    assert_eq!(cfg["oauth"][0]["client_id"], "id");
+    assert_eq!(cfg["sign"]["password_file"], "***");
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::kap_rule::{RuleConfigCore, RuleConfigSubscribe, RuleLogFile};
+use crate::kap_task::{output_hash, SubscribeArgs};
+use crate::{DbCommand, LogFile};
+
+/*
+ * every desired state handed to a subscribe script, one json line appended
+ * to rule/core/audit_log when set and the same record XADDed as its `record`
+ * field on the rule/core/audit_stream redis stream through the db actor;
+ * secrets in the payload are masked, payload_hash is over the original so a
+ * change can be matched
+ *
+ * retention drops history: the log rotates at core.audit_log_size with
+ * core.audit_log_keep old files, beyond that the oldest is deleted, and the
+ * stream is only trimmed (MAXLEN ~) when core.audit_stream_maxlen is set
+ */
+
+/* field names masked wherever config or payloads leave the device */
+pub(crate) const REDACT_KEYS: [&str; 5] = ["password", "token", "secret", "private", "key"];
+
+pub(crate) fn redact_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    REDACT_KEYS.iter().any(|r| key.contains(r))
+}
+
+pub(crate) fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if v.is_string() && redact_key(k) {
+                    *v = json!("***");
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/* toml round trip through json so `redact` applies, comments are lost */
+pub(crate) async fn toml_redacted(path: &str) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow!("{} read fail - {e}", path))?;
+    let mut value = serde_json::to_value(toml::from_str::<toml::Value>(&text)?)?;
+    redact(&mut value);
+    Ok(toml::to_string_pretty(&toml::Value::try_from(value)?)?)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditResult {
+    Applied,
+    Failed,
+    Error,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub received: DateTime<Utc>,
+    pub origin: String,
+    pub topic: String,
+    pub script: PathBuf,
+    pub version: Option<u16>,
+    pub payload: Value,
+    pub payload_hash: String,
+    pub result: AuditResult,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/* aws/kap/... is the shadow forwarded by aws_iot, anything else came from local ipc */
+fn audit_origin(topic: &str) -> &'static str {
+    if topic.starts_with("aws/kap/") {
+        "aws-iot"
+    } else {
+        "local"
+    }
+}
+
+impl AuditRecord {
+    pub fn new(
+        sub: &RuleConfigSubscribe,
+        args: &SubscribeArgs,
+        start: DateTime<Utc>,
+        instant: Instant,
+        exit_code: Option<i32>,
+        error: Option<String>,
+    ) -> Self {
+        let mut payload = args.payload.clone();
+        redact(&mut payload);
+        let result = match (&error, exit_code) {
+            (Some(_), _) => AuditResult::Error,
+            (None, Some(0)) => AuditResult::Applied,
+            (None, _) => AuditResult::Failed,
+        };
+
+        Self {
+            timestamp: start,
+            received: args.timestamp,
+            origin: audit_origin(&args.topic).to_string(),
+            topic: args.topic.clone(),
+            script: sub.path.clone(),
+            version: args.version,
+            payload,
+            payload_hash: output_hash(args.payload.to_string().as_bytes()),
+            result,
+            exit_code,
+            error,
+            duration_ms: instant.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+fn audit_file_append(core: &RuleConfigCore, path: &Path, line: &str) -> Result<()> {
+    let mut file = LogFile::open(&RuleLogFile {
+        path: path.to_path_buf(),
+        size: core.audit_log_size,
+        age: None,
+        keep: core.audit_log_keep,
+    })?;
+    file.write_all(format!("{}\n", line).as_bytes())?;
+    Ok(())
+}
+
+/* best effort on both sinks, an audit failure never fails the subscribe */
+pub async fn audit_record(
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+    record: &AuditRecord,
+) {
+    let line = match serde_json::to_string(record) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("audit {} serialize fail - {e}", record.topic);
+            return;
+        }
+    };
+
+    if let Some(ref path) = core.audit_log {
+        if let Err(e) = audit_file_append(core, path, &line) {
+            warn!("audit log {} append fail - {e}", path.display());
+        }
+    }
+    if let Some(ref key) = core.audit_stream {
+        let push = DbCommand::Xadd {
+            key: key.clone(),
+            fields: vec![("record".to_string(), line)],
+            maxlen: core.audit_stream_maxlen,
+        };
+        if let Err(e) = db_chan.send(push).await {
+            warn!("audit stream {} push fail - {e}", key);
+        }
+    }
+    info!(
+        "audit {} version {:?} {:?}",
+        record.topic, record.version, record.result
+    );
+}
+
+#[test]
+fn test_audit_record() {
+    let sub: RuleConfigSubscribe = toml::from_str(
+        r#"
+        topic = "aws/kap/shadow/name/wifi/state"
+        path = "/etc/fika_manager/wifi.sh"
+        "#,
+    )
+    .unwrap();
+    let args = SubscribeArgs::new(
+        &sub.topic,
+        r#"{"ssid":"fika","wifi_password":"hunter2","radios":[{"psk_secret":"s"}]}"#,
+        Some(7),
+    );
+
+    let record = AuditRecord::new(&sub, &args, Utc::now(), Instant::now(), Some(0), None);
+    assert_eq!(record.origin, "aws-iot");
+    assert_eq!(record.result, AuditResult::Applied);
+    assert_eq!(record.payload["ssid"], "fika");
+    assert_eq!(record.payload["wifi_password"], "***");
+    assert_eq!(record.payload["radios"][0]["psk_secret"], "***");
+    assert_eq!(
+        record.payload_hash,
+        output_hash(args.payload.to_string().as_bytes())
+    );
+
+    let record = AuditRecord::new(&sub, &args, Utc::now(), Instant::now(), Some(2), None);
+    assert_eq!(record.result, AuditResult::Failed);
+    let record = AuditRecord::new(
+        &sub,
+        &args,
+        Utc::now(),
+        Instant::now(),
+        None,
+        Some("spawn fail".to_string()),
+    );
+    assert_eq!(record.result, AuditResult::Error);
+    assert_eq!(audit_origin("kap/local/state"), "local");
+}
+
+#[tokio::test]
+async fn test_audit_retention() {
+    let dir = std::env::temp_dir().join(format!("fika_audit_{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+    let core = RuleConfigCore {
+        audit_log: Some(path.clone()),
+        audit_log_size: Some(1),
+        audit_log_keep: Some(2),
+        audit_stream_maxlen: Some(5000),
+        ..Default::default()
+    };
+    let sub: RuleConfigSubscribe = toml::from_str(
+        r#"
+        topic = "kap/local/state"
+        path = "/etc/fika_manager/state.sh"
+        "#,
+    )
+    .unwrap();
+    let args = SubscribeArgs::new(&sub.topic, "{}", None);
+    let record = AuditRecord::new(&sub, &args, Utc::now(), Instant::now(), Some(0), None);
+
+    let (tx, mut rx) = mpsc::channel(8);
+    for _ in 0..4 {
+        audit_record(&core, &tx, &record).await;
+    }
+    assert!(path.exists());
+    assert!(dir.join("audit.log.1").exists());
+    assert!(dir.join("audit.log.2").exists());
+    assert!(!dir.join("audit.log.3").exists());
+
+    match rx.try_recv().unwrap() {
+        DbCommand::Xadd {
+            key,
+            fields,
+            maxlen,
+        } => {
+            assert_eq!(key, "kap/audit");
+            assert_eq!(fields[0].0, "record");
+            assert_eq!(maxlen, Some(5000));
+        }
+        _ => panic!("audit stream not an xadd"),
+    }
+    _ = std::fs::remove_dir_all(&dir);
+}
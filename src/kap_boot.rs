@@ -0,0 +1,217 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_feature::features_init;
+use crate::kap_rule::RuleConfig;
+use crate::{publish_message, rule_config_load, set_message, DbCommand};
+
+/*
+ * bring-up report on BOOT_STATUS_KEY (and rule/core/boot_shadow): each
+ * stage keeps its result, attempts and time since the first report, `stuck`
+ * is the first stage not yet ok. boot_config_load reports the config (rule
+ * and kdaemon.toml, failed or not), daemon_start_mark the database,
+ * aws_iot the certificates (provision) and the first mqtt connect; a stage
+ * once ok is not touched again
+ */
+
+pub const BOOT_STATUS_KEY: &str = "kap/status/boot";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootStage {
+    Config,
+    Database,
+    Provision,
+    Mqtt,
+}
+
+const BOOT_STAGES: [BootStage; 4] = [
+    BootStage::Config,
+    BootStage::Database,
+    BootStage::Provision,
+    BootStage::Mqtt,
+];
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BootStep {
+    pub stage: BootStage,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub at: DateTime<Utc>,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BootReport {
+    pub version: String,
+    pub started: DateTime<Utc>,
+    pub steps: Vec<BootStep>,
+    pub stuck: Option<BootStage>,
+    pub complete: bool,
+}
+
+impl Default for BootReport {
+    fn default() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started: Utc::now(),
+            steps: vec![],
+            stuck: Some(BootStage::Config),
+            complete: false,
+        }
+    }
+}
+
+impl BootReport {
+    /* false when the stage was already ok, nothing to report then */
+    fn record(&mut self, stage: BootStage, error: Option<String>, elapsed_ms: u64) -> bool {
+        let idx = match self.steps.iter().position(|s| s.stage == stage) {
+            Some(idx) if self.steps[idx].ok => return false,
+            Some(idx) => idx,
+            None => {
+                self.steps.push(BootStep {
+                    stage,
+                    ok: false,
+                    error: None,
+                    attempts: 0,
+                    at: Utc::now(),
+                    elapsed_ms,
+                });
+                self.steps.len() - 1
+            }
+        };
+        let step = &mut self.steps[idx];
+        step.ok = error.is_none();
+        step.error = error;
+        step.attempts += 1;
+        step.at = Utc::now();
+        step.elapsed_ms = elapsed_ms;
+
+        self.stuck = BOOT_STAGES
+            .into_iter()
+            .find(|st| !self.steps.iter().any(|s| s.stage == *st && s.ok));
+        self.complete = self.stuck.is_none();
+        true
+    }
+}
+
+struct BootState {
+    report: BootReport,
+    shadow: Option<String>,
+    start: Instant,
+}
+
+static BOOT: Mutex<Option<BootState>> = Mutex::new(None);
+
+fn boot_with<R>(f: impl FnOnce(&mut BootState) -> R) -> R {
+    let mut boot = BOOT.lock().unwrap_or_else(|e| e.into_inner());
+    f(boot.get_or_insert_with(|| BootState {
+        report: Default::default(),
+        shadow: None,
+        start: Instant::now(),
+    }))
+}
+
+/* the daemon's rule and kdaemon.toml load, the config stage ok or not */
+pub async fn boot_config_load(
+    rule_path: &str,
+    cfg_path: Option<&str>,
+) -> Result<(RuleConfig, KdaemonConfig)> {
+    let result = rule_config_load(rule_path, cfg_path).await;
+    if let Ok((ref rule, _)) = result {
+        boot_with(|boot| boot.shadow = rule.core.boot_shadow.clone());
+    }
+    boot_stage(BootStage::Config, &result);
+    /* before any subsystem asks feature_enabled */
+    if let Ok((ref rule, _)) = result {
+        features_init(rule).await;
+    }
+    result
+}
+
+pub fn boot_stage<T, E: Display>(stage: BootStage, result: &Result<T, E>) -> bool {
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let changed = boot_with(|boot| {
+        let elapsed = boot.start.elapsed().as_millis() as u64;
+        boot.report.record(stage, error.clone(), elapsed)
+    });
+    if changed {
+        match error {
+            Some(e) => warn!("boot {:?} fail - {e}", stage),
+            None => info!("boot {:?} ok", stage),
+        }
+    }
+    changed
+}
+
+pub async fn boot_publish(db_chan: &mpsc::Sender<DbCommand>) {
+    let (report, shadow) = boot_with(|boot| (boot.report.clone(), boot.shadow.clone()));
+    let payload = match serde_json::to_string(&report) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("boot report serialize fail - {e}");
+            return;
+        }
+    };
+    if let Err(e) = set_message(
+        db_chan.clone(),
+        BOOT_STATUS_KEY.to_string(),
+        payload.clone(),
+    )
+    .await
+    {
+        warn!("boot report set fail - {e}");
+    }
+    if let Some(shadow) = shadow {
+        if let Err(e) =
+            publish_message(db_chan, format!("kap/aws/shadow/{}", shadow), payload).await
+        {
+            warn!("boot report shadow fail - {e}");
+        }
+    }
+}
+
+#[test]
+fn test_boot_report() {
+    let mut report = BootReport::default();
+    assert_eq!(report.stuck, Some(BootStage::Config));
+
+    assert!(report.record(BootStage::Config, None, 5));
+    assert!(report.record(BootStage::Database, None, 10));
+    assert_eq!(report.stuck, Some(BootStage::Provision));
+
+    assert!(report.record(BootStage::Provision, None, 12));
+    assert!(report.record(BootStage::Mqtt, Some("connection refused".to_string()), 20));
+    assert!(report.record(BootStage::Mqtt, Some("connection refused".to_string()), 50));
+    assert_eq!(report.stuck, Some(BootStage::Mqtt));
+    assert!(!report.complete);
+    let mqtt = report.steps.last().unwrap();
+    assert_eq!((mqtt.attempts, mqtt.elapsed_ms), (2, 50));
+
+    assert!(report.record(BootStage::Mqtt, None, 80));
+    assert!(report.complete && report.stuck.is_none());
+    /* the first connect time stays */
+    assert!(!report.record(BootStage::Mqtt, Some("lost".to_string()), 900));
+    let mqtt = report.steps.last().unwrap();
+    assert!(mqtt.ok);
+    assert_eq!((mqtt.attempts, mqtt.elapsed_ms), (3, 80));
+}
+
+#[tokio::test]
+async fn test_boot_config_load() {
+    assert!(boot_config_load("/nonexistent/rule.toml", None)
+        .await
+        .is_err());
+    let config = boot_with(|boot| boot.report.steps[0].clone());
+    assert_eq!(config.stage, BootStage::Config);
+    assert!(!config.ok && config.error.is_some());
+    assert_eq!(config.attempts, 1);
+}
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use redis::AsyncCommands;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, instrument, warn};
+
+use crate::kap_audit::REDACT_KEYS;
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_rule::{RuleCfgSyncConfig, RuleConfigCore};
+use crate::{publish_message, DbCommand};
+
+/*
+ * on-device config to shadow reported
+ *
+ * kdaemon.toml is watched (through its directory, saves replace it by
+ * rename) and the redis keys matching `keys` are polled every interval,
+ * a uci: config is polled along. Whenever the snapshot differs from the
+ * last report it goes to kap/aws/shadow/{shadow}: the kdaemon fields as
+ * loaded, the keys as json where they parse, every value under a name
+ * containing password/token/secret/private/key or one of `redact` masked,
+ * and null for a key gone since, so the reported document drops it too.
+ */
+
+const CFGSYNC_SHADOW: &str = "name/config";
+const CFGSYNC_KEYS: &str = "kap/cfg/*";
+const CFGSYNC_INTERVAL: Duration = Duration::from_secs(30);
+const CFGSYNC_DEBOUNCE: Duration = Duration::from_secs(1);
+const CFGSYNC_MASK: &str = "***";
+
+fn redact(value: &mut Value, names: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                let k = k.to_lowercase();
+                if !v.is_null() && names.iter().any(|n| k.contains(n.as_str())) {
+                    *v = Value::String(CFGSYNC_MASK.to_string());
+                } else {
+                    redact(v, names);
+                }
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(|v| redact(v, names)),
+        _ => {}
+    }
+}
+
+fn snapshot(config: Result<Value, String>, keys: &[(String, String)], names: &[String]) -> Value {
+    let cfg: Map<String, Value> = keys
+        .iter()
+        .map(|(k, v)| {
+            let v = serde_json::from_str(v).unwrap_or_else(|_| Value::String(v.clone()));
+            (k.clone(), v)
+        })
+        .collect();
+    let mut snapshot = match config {
+        Ok(config) => json!({ "config": config, "cfg": cfg }),
+        Err(e) => json!({ "config": null, "config_error": e, "cfg": cfg }),
+    };
+    redact(&mut snapshot, names);
+    snapshot
+}
+
+/* what goes out: the snapshot, null for each key (and config_error) reported last time and gone */
+fn report(now: &Value, last: Option<&Value>) -> Value {
+    let mut report = now.clone();
+    if last.is_some_and(|l| l.get("config_error").is_some()) && now.get("config_error").is_none() {
+        report["config_error"] = Value::Null;
+    }
+    let gone = last
+        .and_then(|l| l["cfg"].as_object())
+        .map(|l| {
+            l.keys()
+                .filter(|k| now["cfg"].get(k.as_str()).is_none())
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if let Some(cfg) = report["cfg"].as_object_mut() {
+        for k in gone {
+            cfg.insert(k, Value::Null);
+        }
+    }
+    report["timestamp"] = json!(Utc::now());
+    report
+}
+
+async fn keys_read(database: &str, pattern: &str) -> Result<Vec<(String, String)>> {
+    let mut conn = redis::Client::open(database)?
+        .get_async_connection()
+        .await
+        .map_err(|e| anyhow!("db/redis async connect fail - {e}"))?;
+    let mut keys: Vec<String> = vec![];
+    {
+        let mut iter = conn.scan_match::<_, String>(pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+    keys.sort();
+    keys.dedup();
+
+    let mut values = vec![];
+    for key in keys {
+        /* lists/hashes under the pattern are not config values */
+        match redis::cmd("GET")
+            .arg(&key)
+            .query_async::<_, Option<String>>(&mut conn)
+            .await
+        {
+            Ok(Some(v)) => values.push((key, v)),
+            Ok(None) => {}
+            Err(e) => debug!("{} skipped - {e}", key),
+        }
+    }
+    Ok(values)
+}
+
+/* the parent directory, the file itself is replaced on save */
+fn config_watch(path: &Path) -> Result<(RecommendedWatcher, mpsc::Receiver<PathBuf>)> {
+    let file = path.to_path_buf();
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .ok_or_else(|| anyhow!("{:?} without directory", path))?;
+    let (tx, rx) = mpsc::channel(16);
+    let mut watcher = notify::recommended_watcher(move |ev: notify::Result<notify::Event>| {
+        if let Ok(ev) = ev {
+            if !ev.kind.is_access() && ev.paths.iter().any(|p| p == &file) {
+                _ = tx.blocking_send(file.clone());
+            }
+        }
+    })?;
+    watcher.watch(parent, RecursiveMode::NonRecursive)?;
+    Ok((watcher, rx))
+}
+
+#[instrument(name = "cfgsync", skip_all)]
+pub async fn cfgsync_start(
+    cfg: RuleCfgSyncConfig,
+    core: RuleConfigCore,
+    db_chan: mpsc::Sender<DbCommand>,
+) -> Result<()> {
+    if cfg.disable == Some(true) {
+        info!("config sync disabled");
+        return Ok(());
+    }
+
+    let shadow = format!(
+        "kap/aws/shadow/{}",
+        cfg.shadow.as_deref().unwrap_or(CFGSYNC_SHADOW)
+    );
+    let pattern = cfg.keys.clone().unwrap_or_else(|| CFGSYNC_KEYS.to_string());
+    let names: Vec<String> = REDACT_KEYS
+        .iter()
+        .map(|n| n.to_string())
+        .chain(KdaemonConfig::secret_names())
+        .chain(cfg.redact.iter().flatten().map(|n| n.to_lowercase()))
+        .collect();
+
+    /* without a watch (uci, no inotify) the poll covers the file too */
+    let mut watch = match config_watch(Path::new(&core.config)) {
+        Ok(watch) => Some(watch),
+        Err(e) => {
+            warn!("{} not watched, polled - {e}", core.config);
+            None
+        }
+    };
+    let mut poll = time::interval(cfg.interval.unwrap_or(CFGSYNC_INTERVAL));
+    poll.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut last: Option<Value> = None;
+
+    loop {
+        let changed = async {
+            match watch.as_mut() {
+                Some((_, rx)) => rx.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = poll.tick() => {}
+            Some(path) = changed => {
+                /* a save is several events, and the migration writes once more */
+                time::sleep(CFGSYNC_DEBOUNCE).await;
+                if let Some((_, ref mut rx)) = watch {
+                    while rx.try_recv().is_ok() {}
+                }
+                debug!("{:?} changed", path);
+            }
+        }
+
+        let config = KdaemonConfig::build_from(&core.config)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|c| serde_json::to_value(c).map_err(|e| e.to_string()));
+        /* an unreadable db is no reason to null every key in the shadow */
+        let keys = match core.database {
+            Some(ref database) => match keys_read(database, &pattern).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    warn!("{} read fail - {e}", pattern);
+                    continue;
+                }
+            },
+            None => vec![],
+        };
+        let now = snapshot(config, &keys, &names);
+        if last.as_ref() == Some(&now) {
+            continue;
+        }
+
+        let payload = serde_json::to_string(&report(&now, last.as_ref()))?;
+        match publish_message(&db_chan, shadow.clone(), payload).await {
+            Ok(_) => {
+                info!("config reported to {}", shadow);
+                last = Some(now);
+            }
+            Err(e) => warn!("config report fail - {e}"),
+        }
+    }
+}
+
+#[test]
+fn test_cfgsync_snapshot() {
+    let names: Vec<String> = REDACT_KEYS
+        .iter()
+        .map(|n| n.to_string())
+        .chain(KdaemonConfig::secret_names())
+        .chain(["nickname".to_string()])
+        .collect();
+    let config = json!({
+        "core": { "serial_number": "FK0001", "mac_address": "AA:BB:CC:DD:EE:01" },
+        "network": {
+            "wifi_ssid": "fika",
+            "wifi_password": "hunter22",
+            "wan_password": null,
+            "wan": [{ "name": "lte", "pin": "1234" }],
+        },
+        "por": { "state": true, "nickname": "Bob" },
+        "boss": { "access_token": "enc:v1:xx" },
+    });
+    let keys = [
+        ("kap/cfg/led".to_string(), "{\"on\":true}".to_string()),
+        ("kap/cfg/api_key".to_string(), "abc".to_string()),
+        ("kap/cfg/region".to_string(), "tw".to_string()),
+    ];
+    let first = snapshot(Ok(config.clone()), &keys, &names);
+    assert_eq!(first["config"]["core"]["serial_number"], "FK0001");
+    assert_eq!(first["config"]["network"]["wifi_password"], CFGSYNC_MASK);
+    assert!(first["config"]["network"]["wan_password"].is_null());
+    assert_eq!(first["config"]["network"]["wan"][0]["pin"], CFGSYNC_MASK);
+    assert_eq!(first["config"]["por"]["nickname"], CFGSYNC_MASK);
+    assert_eq!(first["config"]["boss"]["access_token"], CFGSYNC_MASK);
+    assert_eq!(first["cfg"]["kap/cfg/led"]["on"], true);
+    assert_eq!(first["cfg"]["kap/cfg/api_key"], CFGSYNC_MASK);
+    assert_eq!(first["cfg"]["kap/cfg/region"], "tw");
+    assert_eq!(snapshot(Ok(config.clone()), &keys, &names), first);
+
+    let second = snapshot(Err("core.sku: missing".to_string()), &keys[..1], &names);
+    assert!(second["config"].is_null());
+    let out = report(&second, Some(&first));
+    assert_eq!(out["config_error"], "core.sku: missing");
+    assert!(out["cfg"]["kap/cfg/region"].is_null());
+    assert!(out["cfg"]
+        .as_object()
+        .unwrap()
+        .contains_key("kap/cfg/api_key"));
+    assert_eq!(out["cfg"]["kap/cfg/led"]["on"], true);
+    assert!(out["timestamp"].is_string());
+    /* fixed again, the error cleared in the shadow */
+    let out = report(&first, Some(&second));
+    assert!(out["config_error"].is_null());
+    assert!(out.as_object().unwrap().contains_key("config_error"));
+    assert!(!report(&first, Some(&first))
+        .as_object()
+        .unwrap()
+        .contains_key("config_error"));
+}
@@ -0,0 +1,400 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::ffi::CString;
+use tokio::fs;
+use tokio::sync::mpsc;
+
+use crate::kap_rule::{RuleConfigCore, RuleTaskBuiltin};
+use crate::{DbCommand, RuleConfigTask};
+
+const FLASH_FS: &[&str] = &["ubifs", "overlay", "jffs2", "squashfs"];
+
+fn parse_meminfo(s: &str) -> Value {
+    let mut mem = Map::new();
+    for line in s.lines() {
+        let (k, v) = if let Some(kv) = line.split_once(':') {
+            kv
+        } else {
+            continue;
+        };
+        let k = match k {
+            "MemTotal" => "total_kb",
+            "MemFree" => "free_kb",
+            "MemAvailable" => "available_kb",
+            "Buffers" => "buffers_kb",
+            "Cached" => "cached_kb",
+            _ => continue,
+        };
+        if let Some(Ok(v)) = v.split_whitespace().next().map(|v| v.parse::<u64>()) {
+            mem.insert(k.to_string(), json!(v));
+        }
+    }
+    Value::Object(mem)
+}
+
+fn parse_netdev(s: &str) -> Value {
+    let mut ifs = Map::new();
+    /* 2 header lines, then `iface: rx(8 fields) tx(8 fields)` */
+    for line in s.lines().skip(2) {
+        let (name, counters) = if let Some(kv) = line.split_once(':') {
+            kv
+        } else {
+            continue;
+        };
+        let c = counters
+            .split_whitespace()
+            .filter_map(|c| c.parse::<u64>().ok())
+            .collect::<Vec<u64>>();
+        if c.len() < 16 {
+            continue;
+        }
+        ifs.insert(
+            name.trim().to_string(),
+            json!({
+                "rx_bytes": c[0],
+                "rx_packets": c[1],
+                "rx_errs": c[2],
+                "rx_drop": c[3],
+                "tx_bytes": c[8],
+                "tx_packets": c[9],
+                "tx_errs": c[10],
+                "tx_drop": c[11],
+            }),
+        );
+    }
+    Value::Object(ifs)
+}
+
+async fn collect_temperature() -> Vec<Value> {
+    let mut zones = vec![];
+    let mut dir = match fs::read_dir("/sys/class/thermal").await {
+        Ok(d) => d,
+        Err(_) => return zones,
+    };
+
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("thermal_zone") {
+            continue;
+        }
+        let path = entry.path();
+        let temp = fs::read_to_string(path.join("temp"))
+            .await
+            .ok()
+            .and_then(|t| t.trim().parse::<i64>().ok());
+        let kind = fs::read_to_string(path.join("type"))
+            .await
+            .map(|t| t.trim().to_string())
+            .unwrap_or_default();
+        if let Some(temp) = temp {
+            zones.push(json!({
+                "zone": name,
+                "type": kind,
+                "celsius": temp as f64 / 1000.0,
+            }));
+        }
+    }
+
+    zones
+}
+
+async fn collect_sysinfo() -> Result<Value> {
+    let loadavg = fs::read_to_string("/proc/loadavg").await?;
+    let loadavg = loadavg
+        .split_whitespace()
+        .take(3)
+        .filter_map(|l| l.parse::<f64>().ok())
+        .collect::<Vec<f64>>();
+    let uptime = fs::read_to_string("/proc/uptime")
+        .await?
+        .split_whitespace()
+        .next()
+        .and_then(|u| u.parse::<f64>().ok())
+        .unwrap_or_default();
+    let meminfo = fs::read_to_string("/proc/meminfo").await?;
+    let cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    Ok(json!({
+        "cpu": { "count": cpus, "loadavg": loadavg },
+        "uptime": uptime as u64,
+        "memory": parse_meminfo(&meminfo),
+        "temperature": collect_temperature().await,
+    }))
+}
+
+async fn collect_netif() -> Result<Value> {
+    let netdev = fs::read_to_string("/proc/net/dev").await?;
+    Ok(parse_netdev(&netdev))
+}
+
+fn statvfs(mount: &str) -> Result<(u64, u64, u64)> {
+    let path = CString::new(mount)?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return Err(anyhow!(
+            "statvfs {} fail - {}",
+            mount,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let frsize = st.f_frsize as u64;
+    Ok((
+        st.f_blocks as u64 * frsize / 1024,
+        st.f_bfree as u64 * frsize / 1024,
+        st.f_bavail as u64 * frsize / 1024,
+    ))
+}
+
+async fn collect_disk() -> Result<Value> {
+    let mounts = fs::read_to_string("/proc/mounts").await?;
+    let mut disks = vec![];
+
+    for line in mounts.lines() {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() < 3 {
+            continue;
+        }
+        let (dev, mount, fstype) = (fields[0], fields[1], fields[2]);
+        if !dev.starts_with("/dev/") && !FLASH_FS.contains(&fstype) {
+            continue;
+        }
+        if let Ok((total, free, avail)) = statvfs(mount) {
+            disks.push(json!({
+                "mount": mount,
+                "fstype": fstype,
+                "total_kb": total,
+                "free_kb": free,
+                "available_kb": avail,
+            }));
+        }
+    }
+
+    Ok(Value::Array(disks))
+}
+
+/* confirmations of a receipt in block at head, 0 while not mined */
+#[cfg(feature = "wallet")]
+fn tx_confirmations(block: Option<u64>, head: u64) -> usize {
+    match block {
+        Some(b) if head >= b => (head - b + 1) as usize,
+        _ => 0,
+    }
+}
+
+/*
+ * tx hashes are pushed to the redis list by whoever sent them, each run
+ * checks their receipts; a reverted one or one `confirmations` deep is
+ * published to topic and dropped from the list, the rest stay pending
+ */
+#[cfg(feature = "wallet")]
+async fn collect_txwatch(
+    task: &RuleConfigTask,
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<Value> {
+    use ethers::prelude::{Http, Middleware, Provider, H256};
+    use redis::AsyncCommands;
+
+    let cfg = task.txwatch.clone().unwrap_or_default();
+    let list = cfg.list.as_deref().unwrap_or("kap/wallet/tx_watch");
+    let topic = cfg.topic.as_deref().unwrap_or("kap/wallet/tx");
+    let depth = cfg.confirmations.unwrap_or(1).max(1);
+    let rpc_url = cfg
+        .rpc_url
+        .as_deref()
+        .ok_or_else(|| anyhow!("txwatch rpc_url/chain.rpc_url missing"))?;
+    let provider = Provider::<Http>::try_from(rpc_url)
+        .map_err(|e| anyhow!("rpc url {} invalid - {e}", rpc_url))?;
+
+    let database = core
+        .database
+        .as_deref()
+        .ok_or_else(|| anyhow!("core.database missing"))?;
+    let mut conn = redis::Client::open(database)?
+        .get_async_connection()
+        .await?;
+    let hashes: Vec<String> = conn.lrange(list, 0, -1).await?;
+    if hashes.is_empty() {
+        return Ok(json!({ "pending": [], "confirmed": 0, "failed": 0 }));
+    }
+
+    let head = provider
+        .get_block_number()
+        .await
+        .map_err(|e| anyhow!("block number query fail - {e}"))?
+        .as_u64();
+    let (mut pending, mut confirmed, mut failed) = (vec![], 0, 0);
+    for hash in hashes {
+        let tx = match hash.trim().parse::<H256>() {
+            Ok(tx) => tx,
+            Err(e) => {
+                tracing::warn!("txwatch {} invalid, dropped - {e}", hash);
+                let _: usize = conn.lrem(list, 0, &hash).await?;
+                continue;
+            }
+        };
+        let receipt = match provider.get_transaction_receipt(tx).await {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                pending.push(json!({ "tx": hash, "confirmations": 0 }));
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("txwatch {} receipt fail - {e}", hash);
+                pending.push(json!({ "tx": hash, "confirmations": null }));
+                continue;
+            }
+        };
+
+        let block = receipt.block_number.map(|b| b.as_u64());
+        let confirmations = tx_confirmations(block, head);
+        let reverted = receipt.status.map(|s| s.as_u64()) == Some(0);
+        if !reverted && confirmations < depth {
+            pending.push(json!({ "tx": hash, "confirmations": confirmations }));
+            continue;
+        }
+
+        let event = json!({
+            "tx": hash,
+            "status": if reverted { "failed" } else { "confirmed" },
+            "block": block,
+            "confirmations": confirmations,
+            "gas_used": receipt.gas_used,
+        });
+        crate::publish_message(db_chan, topic.to_string(), event.to_string()).await?;
+        let _: usize = conn.lrem(list, 0, &hash).await?;
+        if reverted {
+            failed += 1;
+        } else {
+            confirmed += 1;
+        }
+    }
+
+    Ok(json!({ "pending": pending, "confirmed": confirmed, "failed": failed }))
+}
+
+#[cfg(not(feature = "wallet"))]
+async fn collect_txwatch(
+    _task: &RuleConfigTask,
+    _core: &RuleConfigCore,
+    _db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<Value> {
+    Err(anyhow!("txwatch not support due wallet feature disable"))
+}
+
+/* Date has a 1s resolution, its middle is the best guess */
+#[cfg(feature = "boss-api")]
+async fn clock_http_offset(
+    url: &str,
+    client: Option<&crate::web_api::CurlClientConfig>,
+    timeout: std::time::Duration,
+) -> Result<f64> {
+    let client = client
+        .cloned()
+        .unwrap_or_default()
+        .apply(reqwest::Client::builder().timeout(timeout))?
+        .build()?;
+    let t1 = chrono::Utc::now();
+    let resp = client.head(url).send().await?;
+    let t4 = chrono::Utc::now();
+    let date = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|d| d.to_str().ok())
+        .ok_or_else(|| anyhow!("{} no Date header", url))?;
+    let date = chrono::DateTime::parse_from_rfc2822(date)
+        .map_err(|e| anyhow!("{} Date {} invalid - {e}", url, date))?;
+    let local = t1 + (t4 - t1) / 2;
+    Ok((date.timestamp_millis() + 500 - local.timestamp_millis()) as f64 / 1000.0)
+}
+
+#[cfg(not(feature = "boss-api"))]
+async fn clock_http_offset(
+    _url: &str,
+    _client: Option<&crate::web_api::CurlClientConfig>,
+    _timeout: std::time::Duration,
+) -> Result<f64> {
+    Err(anyhow!(
+        "http clock not support due boss-api feature disable"
+    ))
+}
+
+async fn collect_clock(task: &RuleConfigTask, db_chan: &mpsc::Sender<DbCommand>) -> Result<Value> {
+    let cfg = task.clock.clone().unwrap_or_default();
+    let server = cfg.server.as_deref().unwrap_or("pool.ntp.org:123");
+    let timeout = cfg
+        .timeout
+        .unwrap_or_else(|| std::time::Duration::from_secs(3));
+
+    let mut report = match crate::misc::sntp_query(server, timeout).await {
+        Ok(sample) => json!({
+            "source": "ntp",
+            "server": server,
+            "offset": sample.offset,
+            "delay": sample.delay,
+            "stratum": sample.stratum,
+        }),
+        Err(e) => {
+            let url = cfg
+                .http_url
+                .as_deref()
+                .ok_or_else(|| anyhow!("{e}, no http_url to fall back"))?;
+            tracing::warn!("{e}, clock by {} Date instead", url);
+            json!({
+                "source": "http",
+                "server": url,
+                "offset": clock_http_offset(url, cfg.client.as_ref(), timeout).await?,
+            })
+        }
+    };
+    report["timestamp"] = json!(chrono::Utc::now().to_rfc3339());
+
+    let shadow = cfg.shadow.as_deref().unwrap_or("name/clock");
+    crate::publish_message(
+        db_chan,
+        format!("kap/aws/shadow/{}", shadow),
+        report.to_string(),
+    )
+    .await?;
+    Ok(report)
+}
+
+pub async fn builtin_collect(
+    task: &RuleConfigTask,
+    builtin: RuleTaskBuiltin,
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<Value> {
+    match builtin {
+        RuleTaskBuiltin::Sysinfo => collect_sysinfo().await,
+        RuleTaskBuiltin::Netif => collect_netif().await,
+        RuleTaskBuiltin::Disk => collect_disk().await,
+        RuleTaskBuiltin::Txwatch => collect_txwatch(task, core, db_chan).await,
+        RuleTaskBuiltin::Clock => collect_clock(task, db_chan).await,
+    }
+}
+
+#[test]
+fn test_parse_netdev() {
+    let netdev = r#"Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  123456     100    0    0    0     0          0         0   123456     100    0    0    0     0       0          0
+  eth0: 9876543    5000    1    2    0     0          0        10  1234567    4000    3    4    0     0       0          0
+"#;
+    let ifs = parse_netdev(netdev);
+    assert_eq!(ifs["eth0"]["rx_bytes"], 9876543);
+    assert_eq!(ifs["eth0"]["tx_drop"], 4);
+    assert_eq!(ifs["lo"]["tx_packets"], 100);
+}
+
+#[cfg(feature = "wallet")]
+#[test]
+fn test_tx_confirmations() {
+    assert_eq!(tx_confirmations(None, 100), 0);
+    assert_eq!(tx_confirmations(Some(100), 100), 1);
+    assert_eq!(tx_confirmations(Some(98), 100), 3);
+    assert_eq!(tx_confirmations(Some(101), 100), 0);
+}
@@ -0,0 +1,309 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock, TryLockError};
+use std::time::{Duration, Instant};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::kap_rule::RuleConfigCore;
+
+/*
+ * panic hook: a crash-{time}.json report (backtrace, version, uptime, last
+ * log lines, running tasks) into rule/core/crash_dir and a summary on
+ * CRASH_STATE_KEY, which aws_iot reports to the `crash` named shadow once
+ * connected again; with panic = "abort" the hook still runs before abort
+ */
+
+pub const CRASH_STATE_KEY: &str = "kap/crash/last";
+const CRASH_LOG_LINES: usize = 100;
+const CRASH_KEEP: usize = 5;
+const CRASH_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+static LOG_RING: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static ACTIVE_TASKS: Mutex<Vec<(String, DateTime<Utc>)>> = Mutex::new(Vec::new());
+static PROCESS_START: OnceLock<Instant> = OnceLock::new();
+
+/* the hook may run while the panicking thread holds one of these */
+fn crash_lock<T>(lock: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match lock.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/* last CRASH_LOG_LINES formatted events, kept for the crash report */
+#[derive(Clone, Copy)]
+pub(crate) struct LogRing;
+
+pub(crate) struct LogRingLine(Vec<u8>);
+
+impl Write for LogRingLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogRingLine {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.0).trim_end().to_string();
+        if line.is_empty() {
+            return;
+        }
+        if let Some(mut ring) = crash_lock(&LOG_RING) {
+            if ring.len() >= CRASH_LOG_LINES {
+                ring.pop_front();
+            }
+            ring.push_back(line);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogRing {
+    type Writer = LogRingLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogRingLine(vec![])
+    }
+}
+
+/* held by a running task/subscribe, listed in the crash report */
+pub struct TaskActive(String);
+
+impl TaskActive {
+    pub fn enter(topic: &str) -> Self {
+        if let Some(mut tasks) = crash_lock(&ACTIVE_TASKS) {
+            tasks.push((topic.to_string(), Utc::now()));
+        }
+        Self(topic.to_string())
+    }
+}
+
+impl Drop for TaskActive {
+    fn drop(&mut self) {
+        if let Some(mut tasks) = crash_lock(&ACTIVE_TASKS) {
+            if let Some(idx) = tasks.iter().position(|(t, _)| t == &self.0) {
+                tasks.remove(idx);
+            }
+        }
+    }
+}
+
+pub(crate) fn active_task_count() -> usize {
+    crash_lock(&ACTIVE_TASKS)
+        .map(|t| t.len())
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CrashTask {
+    pub topic: String,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CrashReport {
+    pub timestamp: DateTime<Utc>,
+    pub version: String,
+    pub thread: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub uptime: u64,
+    pub system_uptime: Option<u64>,
+    pub active_tasks: Vec<CrashTask>,
+    pub log: Vec<String>,
+    pub backtrace: String,
+}
+
+/* CRASH_STATE_KEY value, `reported` once the shadow has it */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CrashState {
+    pub timestamp: DateTime<Utc>,
+    pub version: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub report: Option<PathBuf>,
+    pub reported: bool,
+}
+
+fn panic_message(info: &std::panic::PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+impl CrashReport {
+    fn capture(info: &std::panic::PanicHookInfo<'_>) -> Self {
+        let system_uptime = std::fs::read_to_string("/proc/uptime")
+            .ok()
+            .and_then(|s| s.split('.').next()?.parse().ok());
+        let active_tasks = crash_lock(&ACTIVE_TASKS)
+            .map(|tasks| {
+                tasks
+                    .iter()
+                    .map(|(topic, since)| CrashTask {
+                        topic: topic.clone(),
+                        since: *since,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            timestamp: Utc::now(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            thread: std::thread::current()
+                .name()
+                .unwrap_or("unnamed")
+                .to_string(),
+            message: panic_message(info),
+            location: info.location().map(|l| l.to_string()),
+            uptime: PROCESS_START
+                .get()
+                .map(|s| s.elapsed().as_secs())
+                .unwrap_or_default(),
+            system_uptime,
+            active_tasks,
+            log: crash_lock(&LOG_RING)
+                .map(|ring| ring.iter().cloned().collect())
+                .unwrap_or_default(),
+            backtrace: std::backtrace::Backtrace::force_capture().to_string(),
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "crash-{}.json",
+            self.timestamp.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        crash_prune(dir, CRASH_KEEP)?;
+        Ok(path)
+    }
+
+    fn state(&self, report: Option<PathBuf>) -> CrashState {
+        CrashState {
+            timestamp: self.timestamp,
+            version: self.version.clone(),
+            message: self.message.clone(),
+            location: self.location.clone(),
+            report,
+            reported: false,
+        }
+    }
+}
+
+/* names sort by time, keep the newest `keep` */
+fn crash_prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut reports: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("crash-") && n.ends_with(".json"))
+        })
+        .collect();
+    reports.sort();
+    let drop = reports.len().saturating_sub(keep);
+    for path in reports.drain(..drop) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/* sync redis, the runtime may be the one panicking */
+fn crash_state_set(database: &str, state: &CrashState) -> Result<()> {
+    let mut conn = redis::Client::open(database)?.get_connection_with_timeout(CRASH_DB_TIMEOUT)?;
+    conn.set_write_timeout(Some(CRASH_DB_TIMEOUT))?;
+    conn.set_read_timeout(Some(CRASH_DB_TIMEOUT))?;
+    redis::cmd("SET")
+        .arg(CRASH_STATE_KEY)
+        .arg(serde_json::to_string(state)?)
+        .query::<()>(&mut conn)?;
+    Ok(())
+}
+
+pub fn crash_hook_install(core: &RuleConfigCore) {
+    _ = PROCESS_START.set(Instant::now());
+    let dir = core.crash_dir.clone();
+    let database = core.database.clone();
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        let report = CrashReport::capture(info);
+        let path = dir.as_deref().and_then(|dir| {
+            report
+                .save(dir)
+                .map_err(|e| eprintln!("crash report into {} fail - {e}", dir.display()))
+                .ok()
+        });
+        if let Some(ref database) = database {
+            if let Err(e) = crash_state_set(database, &report.state(path.clone())) {
+                eprintln!("crash state set fail - {e}");
+            }
+        }
+        if let Some(ref path) = path {
+            eprintln!("crash report saved into {}", path.display());
+        }
+        previous(info);
+    }));
+}
+
+#[test]
+fn test_crash_ring_and_prune() {
+    {
+        let _a = TaskActive::enter("kap/task/a");
+        let _b = TaskActive::enter("kap/task/b");
+        assert_eq!(ACTIVE_TASKS.lock().unwrap().len(), 2);
+    }
+    assert!(ACTIVE_TASKS.lock().unwrap().is_empty());
+
+    for i in 0..CRASH_LOG_LINES + 5 {
+        LogRing
+            .make_writer()
+            .write_all(format!("line {i}\n").as_bytes())
+            .unwrap();
+    }
+    {
+        let ring = LOG_RING.lock().unwrap();
+        assert_eq!(ring.len(), CRASH_LOG_LINES);
+        assert_eq!(ring.front().unwrap(), "line 5");
+    }
+
+    let dir = std::env::temp_dir().join(format!("fika_crash_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for i in 0..7 {
+        std::fs::write(dir.join(format!("crash-2023010{i}T000000.000Z.json")), "{}").unwrap();
+    }
+    std::fs::write(dir.join("other.json"), "{}").unwrap();
+    crash_prune(&dir, 3).unwrap();
+    let mut left: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    left.sort();
+    assert_eq!(
+        left,
+        [
+            "crash-20230104T000000.000Z.json",
+            "crash-20230105T000000.000Z.json",
+            "crash-20230106T000000.000Z.json",
+            "other.json",
+        ]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,11 +1,184 @@
 use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::ErrorKind;
+use std::str::FromStr;
+use thiserror::Error;
 use tokio::fs;
-use tracing::warn;
+use tracing::{debug, info, warn};
+
+use crate::misc::{address_checksum, write_atomic};
+use crate::setup_logging;
+
+/*
+ * secrets at rest: the password/token fields sit in kdaemon.toml as
+ * `enc:v1:{base64(nonce|sealed)}`, AES-256-GCM under a key HKDF-derived
+ * from the device secret and core.serial_number, the field name bound as
+ * AAD, so a copied file or a swapped value does not open. The platform may
+ * unseal the secret from a TPM into KDAEMON_SECRET at boot; without the
+ * file kdaemon.toml stays plaintext as before. build_from opens, save seals
+ */
+pub const KDAEMON_SECRET: &str = "/userdata/.kdaemon.secret";
+const SECRET_PREFIX: &str = "enc:v1:";
+const SECRET_SALT: &[u8] = b"fika-kdaemon-secret-v1";
+const SECRET_MIN: usize = 16;
+/* rule core.config naming an OpenWrt UCI package instead of a file, kap_uci */
+const UCI_PREFIX: &str = "uci:";
+
+/*
+ * kdaemon.toml layout version, the top `version` key, a file without it is
+ * 0: the layout before versioning. MIGRATIONS[n] takes the toml table of a
+ * version n file to n + 1, build_from runs the missing steps before
+ * loading, in memory only. migrate_secret (boot, `config migrate`) also
+ * keeps the original as {path}.v{n}.bak and writes the upgraded file back
+ * in place. A file newer than this build loads as it is, unknown keys
+ * ignored, and is not rewritten
+ */
+pub const KDAEMON_VERSION: u32 = 1;
+
+type Migration = fn(&mut toml::value::Table) -> Result<()>;
+
+const MIGRATIONS: [Migration; KDAEMON_VERSION as usize] = [migrate_v0];
+
+/* same layout, only the version stamp is new */
+fn migrate_v0(_root: &mut toml::value::Table) -> Result<()> {
+    Ok(())
+}
+
+/* the version migrated from, none when nothing to do */
+fn config_migrate(root: &mut toml::value::Table, migrations: &[Migration]) -> Result<Option<u32>> {
+    let version = match root.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(v)) if *v >= 0 => *v as u32,
+        Some(v) => return Err(anyhow!("version {} invalid", v)),
+    };
+    let target = migrations.len() as u32;
+    if version >= target {
+        if version > target {
+            warn!(
+                "config version {} newer than {}, loaded as is",
+                version, target
+            );
+        }
+        return Ok(None);
+    }
+
+    for (n, migration) in migrations.iter().enumerate().skip(version as usize) {
+        migration(root).map_err(|e| anyhow!("migrate v{} to v{} fail - {e}", n, n + 1))?;
+    }
+    root.insert("version".to_string(), toml::Value::Integer(target as i64));
+    Ok(Some(version))
+}
+
+/*
+ * FIKA_{SECTION}__{FIELD}=value layered over the file at load, so one image
+ * is parameterized by CI, emulator or container (FIKA_CORE__SERIAL_NUMBER),
+ * typed like config set and validated with the file. The file value under an
+ * override is kept, save writes it back unless the field was changed since
+ */
+const ENV_PREFIX: &str = "FIKA_";
+
+#[derive(Debug, Clone, PartialEq)]
+struct ConfigOverride {
+    section: String,
+    field: String,
+    disk: Option<toml::Value>,
+    env: toml::Value,
+}
+
+/* `field` as the section keeps it from `root`, normalized by its type */
+fn field_take<T>(root: &toml::value::Table, section: &str, field: &str) -> Option<toml::Value>
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    let mut violations = vec![];
+    let loaded: T = section_load(root, section, &mut violations);
+    let path = format!("{}.{}", section, field);
+    if violations.iter().any(|v| v.path == path) {
+        return None;
+    }
+    toml::Value::try_from(loaded).ok()?.get(field).cloned()
+}
+
+/* the first of `candidates` section.field takes next to the rest of `root`, as loaded */
+pub(crate) fn field_typed(
+    root: &toml::value::Table,
+    section: &str,
+    field: &str,
+    candidates: &[Option<toml::Value>],
+) -> Option<toml::Value> {
+    let takes: fn(&toml::value::Table, &str, &str) -> Option<toml::Value> = match section {
+        "core" => field_take::<KCoreConfig>,
+        "network" => field_take::<KNetworkConfig>,
+        "por" => field_take::<KPorConfig>,
+        "boss" => field_take::<KBossConfig>,
+        "aws" => field_take::<KAwsConfig>,
+        _ => return None,
+    };
+    candidates.iter().flatten().find_map(|candidate| {
+        let mut probe = root.clone();
+        probe
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()?
+            .insert(field.to_string(), candidate.clone());
+        takes(&probe, section, field)
+    })
+}
+
+fn env_apply(
+    root: &mut toml::value::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Vec<ConfigOverride> {
+    let mut overrides = vec![];
+    for (var, value) in vars {
+        let (section, field) = match var
+            .strip_prefix(ENV_PREFIX)
+            .and_then(|k| k.split_once("__"))
+        {
+            Some((s, f)) if !s.is_empty() && !f.is_empty() => (s.to_lowercase(), f.to_lowercase()),
+            _ => continue,
+        };
+
+        let disk = root.get(&section).and_then(|s| s.get(&field)).cloned();
+        let env = match field_typed(root, &section, &field, &config_value(&value)) {
+            Some(env) => env,
+            None => {
+                warn!("{} not a {}.{} value, ignored", var, section, field);
+                continue;
+            }
+        };
+
+        if let Some(table) = root
+            .entry(section.clone())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+        {
+            table.insert(field.clone(), env.clone());
+        }
+        info!("config {}.{} from {}", section, field, var);
+        overrides.push(ConfigOverride {
+            section,
+            field,
+            disk,
+            env,
+        });
+    }
+    overrides
+}
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[allow(dead_code)]
 pub struct KdaemonConfig {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(skip)]
+    overrides: Vec<ConfigOverride>,
     pub core: KCoreConfig,
     pub network: KNetworkConfig,
     pub por: KPorConfig,
@@ -13,14 +186,142 @@ pub struct KdaemonConfig {
     pub aws: Option<KAwsConfig>,
 }
 
+/*
+ * device identity, checked and normalized once as it is deserialized: the
+ * mac lowercase XX:XX:XX:XX:XX:XX (dashes or no separator taken too), the
+ * wallets in EIP-55 checksum case. The serial keeps its case, the secrets
+ * key is derived from it as written; only its charset is checked here, the
+ * length is validate()'s. The defaults are placeholders only section_load
+ * probes over, a file without the field is invalid anyway
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(String);
+
+impl MacAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /* the thing name and provisioning form, aabbccddeeff */
+    pub fn compact(&self) -> String {
+        self.0.replace(':', "")
+    }
+}
+
+impl Default for MacAddress {
+    fn default() -> Self {
+        Self("00:00:00:00:00:00".to_string())
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(mac: &str) -> Result<Self> {
+        let hex = |p: &str| p.chars().all(|c| c.is_ascii_hexdigit());
+        let parts: Vec<&str> = mac.split([':', '-']).collect();
+        let valid = match parts.len() {
+            1 => mac.len() == 12 && hex(mac),
+            6 => parts.iter().all(|p| p.len() == 2 && hex(p)),
+            _ => false,
+        };
+        if !valid {
+            return Err(anyhow!("{:?} not XX:XX:XX:XX:XX:XX", mac));
+        }
+        let digits = parts.concat().to_ascii_lowercase();
+        let octets: Vec<&str> = (0..6).map(|i| &digits[i * 2..i * 2 + 2]).collect();
+        Ok(Self(octets.join(":")))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct SerialNumber(String);
+
+impl SerialNumber {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /* aws_iot provisions with it and takes its last 5 for the client id */
+    pub fn lowercase(&self) -> String {
+        self.0.to_ascii_lowercase()
+    }
+}
+
+impl Default for SerialNumber {
+    fn default() -> Self {
+        Self("00000".to_string())
+    }
+}
+
+impl FromStr for SerialNumber {
+    type Err = anyhow::Error;
+
+    fn from_str(serial: &str) -> Result<Self> {
+        let valid = serial
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(anyhow!("{:?} not A-Z a-z 0-9 - _", serial));
+        }
+        Ok(Self(serial.to_string()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct WalletAddress(String);
+
+impl WalletAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for WalletAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        Ok(Self(address_checksum(address)?))
+    }
+}
+
+macro_rules! identity_string {
+    ($($ty:ident),*) => {$(
+        impl TryFrom<String> for $ty {
+            type Error = anyhow::Error;
+
+            fn try_from(s: String) -> Result<Self> {
+                s.parse()
+            }
+        }
+
+        impl From<$ty> for String {
+            fn from(v: $ty) -> String {
+                v.0
+            }
+        }
+
+        impl std::fmt::Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    )*};
+}
+
+identity_string!(MacAddress, SerialNumber, WalletAddress);
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 #[allow(dead_code)]
 pub struct KCoreConfig {
-    pub wallet_address: Option<String>,
-    pub mac_address: String,
-    pub serial_number: String,
+    pub wallet_address: Option<WalletAddress>,
+    pub mac_address: MacAddress,
+    pub serial_number: SerialNumber,
     pub sku: String,
-    pub user_wallet: Option<String>,
+    pub user_wallet: Option<WalletAddress>,
 }
 
 impl KCoreConfig {
@@ -42,6 +343,84 @@ pub struct KNetworkConfig {
     pub wifi_ssid: Option<String>,
     pub wifi_password: Option<String>,
     pub password_overwrite: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wan: Vec<KWanProfile>,
+}
+
+/*
+ * [[network.wan]] uplinks of a dual-WAN gateway, the lowest `priority`
+ * number is the preferred one; without any, wan_type (0 dhcp, 1 pppoe,
+ * 2 static, 3 lte)/wan_username/wan_password are the one profile "wan".
+ * Which is up is the daemon's call, kap_wan carries the switch requests
+ * and its reports
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WanKind {
+    #[default]
+    Dhcp,
+    Pppoe,
+    Static,
+    Lte,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct KWanProfile {
+    pub name: String,
+    pub kind: WanKind,
+    #[serde(default)]
+    pub priority: u32,
+    pub interface: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ipaddr: Option<String>,
+    pub netmask: Option<String>,
+    pub gateway: Option<String>,
+    pub dns: Option<Vec<String>>,
+    pub apn: Option<String>,
+    pub pin: Option<String>,
+    pub disable: Option<bool>,
+}
+
+impl WanKind {
+    pub fn from_wan_type(wan_type: u8) -> Option<Self> {
+        match wan_type {
+            0 => Some(Self::Dhcp),
+            1 => Some(Self::Pppoe),
+            2 => Some(Self::Static),
+            3 => Some(Self::Lte),
+            _ => None,
+        }
+    }
+}
+
+impl KWanProfile {
+    pub fn enabled(&self) -> bool {
+        self.disable != Some(true)
+    }
+}
+
+impl KNetworkConfig {
+    /* preferred first (ascending priority), the order in the file among equals */
+    pub fn wan_profiles(&self) -> Vec<KWanProfile> {
+        if self.wan.is_empty() {
+            /* out of range is refused by validate */
+            return vec![KWanProfile {
+                name: "wan".to_string(),
+                kind: WanKind::from_wan_type(self.wan_type).unwrap_or_default(),
+                username: self.wan_username.clone(),
+                password: self.wan_password.clone(),
+                ..Default::default()
+            }];
+        }
+        let mut profiles = self.wan.clone();
+        profiles.sort_by_key(|p| p.priority);
+        profiles
+    }
+
+    pub fn wan_profile(&self, name: &str) -> Option<KWanProfile> {
+        self.wan_profiles().into_iter().find(|p| p.name == name)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
@@ -74,10 +453,594 @@ impl KBossConfig {
     }
 }
 
+/* one problem of a config file, `path` as section.field */
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConfigViolation {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigViolation {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/* every violation found in one pass, callers may downcast for the list */
+#[derive(Error, Debug)]
+#[error("{file} invalid - {}", .violations.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("; "))]
+pub struct ConfigInvalid {
+    pub file: String,
+    pub violations: Vec<ConfigViolation>,
+}
+
+/* scheme://host[:port][/...] with one of `schemes` */
+pub(crate) fn url_check(url: &str, schemes: &[&str]) -> std::result::Result<(), String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("{:?} not a url", url))?;
+    if !schemes.contains(&scheme) {
+        return Err(format!("{:?} scheme not one of {}", url, schemes.join("/")));
+    }
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) if !h.ends_with(']') || authority.starts_with('[') => (h, Some(p)),
+        _ => (authority, None),
+    };
+    let host_ok = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-_[]:".contains(c));
+    if !host_ok {
+        return Err(format!("{:?} host invalid", url));
+    }
+    if let Some(port) = port {
+        port.parse::<u16>()
+            .map_err(|_| format!("{:?} port invalid", url))?;
+    }
+    if url.chars().any(char::is_whitespace) {
+        return Err(format!("{:?} contains whitespace", url));
+    }
+    Ok(())
+}
+
+/*
+ * a section deserialized as a whole, or else field by field over its
+ * defaults so each bad or missing key is reported with its own path; the
+ * best effort result still goes through the format checks
+ */
+fn section_load<T>(
+    root: &toml::value::Table,
+    name: &str,
+    violations: &mut Vec<ConfigViolation>,
+) -> T
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    let user = match root.get(name) {
+        Some(toml::Value::Table(t)) => t.clone(),
+        Some(_) => {
+            violations.push(ConfigViolation::new(name, "not a table"));
+            return T::default();
+        }
+        None => {
+            violations.push(ConfigViolation::new(name, "section missing"));
+            return T::default();
+        }
+    };
+    if let Ok(section) = toml::Value::Table(user.clone()).try_into::<T>() {
+        return section;
+    }
+
+    let defaults = match toml::Value::try_from(T::default()) {
+        Ok(toml::Value::Table(t)) => t,
+        _ => toml::value::Table::new(),
+    };
+    for key in defaults.keys().filter(|k| !user.contains_key(*k)) {
+        violations.push(ConfigViolation::new(format!("{}.{}", name, key), "missing"));
+    }
+    let mut merged = defaults.clone();
+    for (key, value) in user {
+        let mut probe = defaults.clone();
+        probe.insert(key.clone(), value.clone());
+        match toml::Value::Table(probe).try_into::<T>() {
+            Ok(_) => {
+                merged.insert(key, value);
+            }
+            Err(e) => violations.push(ConfigViolation::new(
+                format!("{}.{}", name, key),
+                e.to_string(),
+            )),
+        }
+    }
+    toml::Value::Table(merged).try_into().unwrap_or_default()
+}
+
+fn secret_key(secret: &[u8], serial: &str) -> Result<LessSafeKey> {
+    if secret.len() < SECRET_MIN {
+        return Err(anyhow!("device secret shorter than {} bytes", SECRET_MIN));
+    }
+    if serial.is_empty() {
+        return Err(anyhow!("core.serial_number empty, no device bound key"));
+    }
+    let info = [serial.as_bytes()];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, SECRET_SALT).extract(secret);
+    let okm = prk
+        .expand(&info, &AES_256_GCM)
+        .map_err(|_| anyhow!("secret key derive fail"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn secret_seal(key: &LessSafeKey, field: &str, plain: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("secret nonce random fail"))?;
+    let mut sealed = plain.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(field.as_bytes()),
+        &mut sealed,
+    )
+    .map_err(|_| anyhow!("{} seal fail", field))?;
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&sealed);
+    Ok(format!("{}{}", SECRET_PREFIX, base64::encode(out)))
+}
+
+fn secret_open(key: &LessSafeKey, field: &str, value: &str) -> Result<String> {
+    let raw = base64::decode(&value[SECRET_PREFIX.len()..])
+        .map_err(|e| anyhow!("{} sealed value invalid - {e}", field))?;
+    if raw.len() < NONCE_LEN {
+        return Err(anyhow!("{} sealed value truncated", field));
+    }
+    let (nonce, sealed) = raw.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("{} sealed nonce invalid", field))?;
+    let mut sealed = sealed.to_vec();
+    let plain = key
+        .open_in_place(nonce, Aad::from(field.as_bytes()), &mut sealed)
+        .map_err(|_| anyhow!("{} open fail, other device or secret", field))?;
+    Ok(String::from_utf8(plain.to_vec())?)
+}
+
+fn sealed(value: &Option<String>) -> bool {
+    value
+        .as_deref()
+        .is_some_and(|v| v.starts_with(SECRET_PREFIX))
+}
+
+/* `uci:{package}` as config path, kap_uci; none for a toml file */
+#[cfg(feature = "uci")]
+async fn uci_read(path: &str) -> Result<Option<(String, toml::value::Table)>> {
+    match path.strip_prefix(UCI_PREFIX) {
+        Some(name) => crate::kap_uci::uci_read(name).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "uci"))]
+async fn uci_read(path: &str) -> Result<Option<(String, toml::value::Table)>> {
+    match path.starts_with(UCI_PREFIX) {
+        true => Err(anyhow!("{} needs the uci feature", path)),
+        false => Ok(None),
+    }
+}
+
+#[cfg(feature = "uci")]
+async fn uci_write(path: &str, root: &toml::Value, plain: &KNetworkConfig) -> Result<bool> {
+    match path.strip_prefix(UCI_PREFIX) {
+        Some(name) => crate::kap_uci::uci_save(name, root, plain)
+            .await
+            .map(|_| true),
+        None => Ok(false),
+    }
+}
+
+#[cfg(not(feature = "uci"))]
+async fn uci_write(path: &str, _root: &toml::Value, _plain: &KNetworkConfig) -> Result<bool> {
+    match path.starts_with(UCI_PREFIX) {
+        true => Err(anyhow!("{} needs the uci feature", path)),
+        false => Ok(false),
+    }
+}
+
+#[cfg(feature = "uci")]
+fn backup_path(path: &str, from: u32) -> String {
+    match path.strip_prefix(UCI_PREFIX) {
+        Some(name) => crate::kap_uci::uci_backup(name, from),
+        None => format!("{}.v{}.bak", path, from),
+    }
+}
+
+#[cfg(not(feature = "uci"))]
+fn backup_path(path: &str, from: u32) -> String {
+    format!("{}.v{}.bak", path, from)
+}
+
+async fn secret_read(path: &str) -> Result<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(secret) => Ok(Some(secret)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("device secret {} read fail - {e}", path)),
+    }
+}
+
+/* hex key of the device secret for another use, `purpose` keeps them apart */
+#[cfg(feature = "wallet")]
+pub(crate) async fn secret_derive(path: &str, purpose: &str) -> Result<String> {
+    let secret = secret_read(path)
+        .await?
+        .ok_or_else(|| anyhow!("device secret {} missing", path))?;
+    if secret.len() < SECRET_MIN {
+        return Err(anyhow!("device secret shorter than {} bytes", SECRET_MIN));
+    }
+    let info = [purpose.as_bytes()];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, SECRET_SALT).extract(&secret);
+    let mut out = [0u8; 32];
+    prk.expand(&info, hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| anyhow!("{} key derive fail", purpose))?;
+    Ok(out.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
 impl KdaemonConfig {
+    /* a profile's secrets are bound to its name */
+    fn secret_fields(&mut self) -> Vec<(String, &mut Option<String>)> {
+        let mut fields = vec![
+            (
+                "network.wan_password".to_string(),
+                &mut self.network.wan_password,
+            ),
+            (
+                "network.wifi_password".to_string(),
+                &mut self.network.wifi_password,
+            ),
+            (
+                "network.password_overwrite".to_string(),
+                &mut self.network.password_overwrite,
+            ),
+            ("boss.access_token".to_string(), &mut self.boss.access_token),
+            (
+                "boss.ap_access_token".to_string(),
+                &mut self.boss.ap_access_token,
+            ),
+        ];
+        for wan in self.network.wan.iter_mut() {
+            let name = wan.name.clone();
+            fields.push((format!("network.wan.{}.password", name), &mut wan.password));
+            fields.push((format!("network.wan.{}.pin", name), &mut wan.pin));
+        }
+        if let Some(ref mut aws) = self.aws {
+            fields.push(("aws.auth_token".to_string(), &mut aws.auth_token));
+        }
+        fields
+    }
+
+    /* the field names of secret_fields, to mask a config leaving the device */
+    pub fn secret_names() -> Vec<String> {
+        let mut cfg = Self::default();
+        cfg.network.wan.push(KWanProfile::default());
+        cfg.aws = Some(KAwsConfig::default());
+        let mut names: Vec<String> = cfg
+            .secret_fields()
+            .into_iter()
+            .filter_map(|(field, _)| field.rsplit('.').next().map(str::to_string))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn secrets_open(&mut self, secret: Option<&[u8]>) -> Result<()> {
+        let serial = self.core.serial_number.clone();
+        let mut key = None;
+        for (field, value) in self.secret_fields() {
+            if !sealed(value) {
+                continue;
+            }
+            let key = match key {
+                Some(ref key) => key,
+                None => {
+                    let secret = secret
+                        .ok_or_else(|| anyhow!("{} sealed but device secret missing", field))?;
+                    key.insert(secret_key(secret, serial.as_str())?)
+                }
+            };
+            *value = Some(secret_open(
+                key,
+                &field,
+                value.as_deref().unwrap_or_default(),
+            )?);
+        }
+        Ok(())
+    }
+
+    fn secrets_seal(&mut self, secret: &[u8]) -> Result<()> {
+        let key = secret_key(secret, self.core.serial_number.as_str())?;
+        for (field, value) in self.secret_fields() {
+            if let Some(plain) = value.as_deref().filter(|_| !sealed(value)) {
+                *value = Some(secret_seal(&key, &field, plain)?);
+            }
+        }
+        Ok(())
+    }
+
     pub async fn build_from(path: &str) -> Result<Self> {
-        let cfg = fs::read_to_string(path).await?;
-        toml::from_str(&cfg).or_else(|e| Err(anyhow!(e)))
+        Self::build_from_secret(path, KDAEMON_SECRET).await
+    }
+
+    pub fn validate(&self) -> Vec<ConfigViolation> {
+        let mut violations = vec![];
+        let mut bad = |path: &str, message: String| {
+            violations.push(ConfigViolation::new(path, message));
+        };
+
+        /* mac, wallets and the serial charset are checked by their types */
+        if self.core.sku.trim().is_empty() {
+            bad("core.sku", "empty".to_string());
+        }
+        let serial = self.core.serial_number.as_str().len();
+        if !(5..=32).contains(&serial) {
+            bad(
+                "core.serial_number",
+                format!("{} chars, 5-32 allowed", serial),
+            );
+        }
+
+        let network = &self.network;
+        match network.wan_type {
+            0 | 2 | 3 => {}
+            1 => {
+                if network
+                    .wan_username
+                    .as_deref()
+                    .unwrap_or_default()
+                    .is_empty()
+                {
+                    bad(
+                        "network.wan_username",
+                        "required by wan_type 1 (pppoe)".to_string(),
+                    );
+                }
+            }
+            n => bad(
+                "network.wan_type",
+                format!("{} out of range, 0 dhcp, 1 pppoe, 2 static or 3 lte", n),
+            ),
+        }
+        if let Some(ref ssid) = network.wifi_ssid {
+            if ssid.is_empty() || ssid.len() > 32 {
+                bad(
+                    "network.wifi_ssid",
+                    format!("{} bytes, 1-32 allowed", ssid.len()),
+                );
+            }
+        }
+        if let Some(ref password) = network.wifi_password {
+            let len = password.chars().count();
+            if !sealed(&network.wifi_password) && len > 0 && !(8..=63).contains(&len) {
+                bad(
+                    "network.wifi_password",
+                    format!("{} chars, 8-63 allowed", len),
+                );
+            }
+        }
+
+        let mut names: Vec<&str> = vec![];
+        for (i, wan) in network.wan.iter().enumerate() {
+            let path = |field: &str| format!("network.wan[{}].{}", i, field);
+            let name_ok = !wan.name.is_empty()
+                && wan
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !name_ok {
+                bad(&path("name"), format!("{:?} not A-Z a-z 0-9 - _", wan.name));
+            } else if names.contains(&wan.name.as_str()) {
+                bad(&path("name"), format!("{:?} given twice", wan.name));
+            }
+            names.push(&wan.name);
+
+            if wan.kind == WanKind::Pppoe && wan.username.as_deref().unwrap_or_default().is_empty()
+            {
+                bad(&path("username"), "required by pppoe".to_string());
+            }
+            if wan.kind == WanKind::Static && wan.ipaddr.is_none() {
+                bad(&path("ipaddr"), "required by static".to_string());
+            }
+            for (field, addr) in [
+                ("ipaddr", &wan.ipaddr),
+                ("netmask", &wan.netmask),
+                ("gateway", &wan.gateway),
+            ] {
+                if let Some(Err(e)) = addr.as_deref().map(str::parse::<std::net::IpAddr>) {
+                    bad(&path(field), format!("{:?} {e}", addr.as_deref().unwrap()));
+                }
+            }
+            for dns in wan.dns.iter().flatten() {
+                if let Err(e) = dns.parse::<std::net::IpAddr>() {
+                    bad(&path("dns"), format!("{:?} {e}", dns));
+                }
+            }
+            if let Some(ref pin) = wan.pin {
+                let digits =
+                    (4..=8).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit());
+                if !sealed(&wan.pin) && !digits {
+                    bad(&path("pin"), "not 4-8 digits".to_string());
+                }
+            }
+        }
+        violations
+    }
+
+    fn from_toml(path: &str, text: &str, migrations: &[Migration]) -> Result<(Self, Option<u32>)> {
+        match toml::from_str::<toml::Value>(text) {
+            Ok(toml::Value::Table(root)) => Self::from_root(path, root, migrations),
+            Ok(_) => Err(anyhow!("{} not a toml table", path)),
+            Err(e) => Err(anyhow!("{} toml syntax - {e}", path)),
+        }
+    }
+
+    fn from_root(
+        path: &str,
+        mut root: toml::value::Table,
+        migrations: &[Migration],
+    ) -> Result<(Self, Option<u32>)> {
+        let migrated =
+            config_migrate(&mut root, migrations).map_err(|e| anyhow!("{} {e}", path))?;
+        let overrides = env_apply(&mut root, std::env::vars());
+
+        let mut violations = vec![];
+        let cfg = Self {
+            version: root
+                .get("version")
+                .and_then(|v| v.as_integer())
+                .unwrap_or_default() as u32,
+            overrides,
+            core: section_load(&root, "core", &mut violations),
+            network: section_load(&root, "network", &mut violations),
+            por: section_load(&root, "por", &mut violations),
+            boss: section_load(&root, "boss", &mut violations),
+            aws: root
+                .contains_key("aws")
+                .then(|| section_load(&root, "aws", &mut violations)),
+        };
+        let format = cfg.validate();
+        if violations.is_empty() {
+            /* the file still loads, save and `rule check` refuse it */
+            for v in format {
+                warn!("{} {}", path, v);
+            }
+            return Ok((cfg, migrated));
+        }
+        for v in format {
+            if !violations.iter().any(|r| r.path == v.path) {
+                violations.push(v);
+            }
+        }
+        Err(ConfigInvalid {
+            file: path.to_string(),
+            violations,
+        }
+        .into())
+    }
+
+    async fn load_secret(path: &str, secret: &str) -> Result<(Self, String, Option<u32>)> {
+        let (text, (mut cfg, migrated)) = match uci_read(path).await? {
+            Some((text, root)) => (text, Self::from_root(path, root, &MIGRATIONS)?),
+            None => {
+                let text = fs::read_to_string(path).await?;
+                let loaded = Self::from_toml(path, &text, &MIGRATIONS)?;
+                (text, loaded)
+            }
+        };
+        if cfg.clone().secret_fields().iter().any(|(_, v)| sealed(v)) {
+            cfg.secrets_open(secret_read(secret).await?.as_deref())?;
+        }
+        Ok((cfg, text, migrated))
+    }
+
+    /* migrated in memory only, the file is left to migrate_secret */
+    pub async fn build_from_secret(path: &str, secret: &str) -> Result<Self> {
+        let (cfg, _, migrated) = Self::load_secret(path, secret).await?;
+        if let Some(from) = migrated {
+            debug!("{} v{} loaded as v{}", path, from, cfg.version);
+        }
+        Ok(cfg)
+    }
+
+    /* boot and `config migrate`: the upgraded file written back, the version it came from */
+    pub async fn migrate_secret(path: &str, secret: &str) -> Result<(Self, Option<u32>)> {
+        let (cfg, text, migrated) = Self::load_secret(path, secret).await?;
+        if let Some(from) = migrated {
+            let backup = backup_path(path, from);
+            write_atomic(&backup, &text).await?;
+            cfg.save_secret(path, secret)
+                .await
+                .map_err(|e| anyhow!("{} migrate write back fail - {e}", path))?;
+            info!(
+                "{} migrated v{} to v{}, original in {}",
+                path, from, cfg.version, backup
+            );
+        }
+        Ok((cfg, migrated))
+    }
+
+    /* sealed whenever the device secret is there */
+    pub async fn save(&self, path: &str) -> Result<()> {
+        self.save_secret(path, KDAEMON_SECRET).await
+    }
+
+    pub async fn save_secret(&self, path: &str, secret: &str) -> Result<()> {
+        self.save_with(path, secret_read(secret).await?.as_deref())
+            .await
+    }
+
+    /* nothing invalid goes to disk, it would not load again */
+    async fn save_with(&self, path: &str, secret: Option<&[u8]>) -> Result<()> {
+        let violations = self.validate();
+        if !violations.is_empty() {
+            return Err(ConfigInvalid {
+                file: path.to_string(),
+                violations,
+            }
+            .into());
+        }
+        let mut cfg = self.clone();
+        cfg.version = cfg.version.max(KDAEMON_VERSION);
+        if cfg.overrides.is_empty() && !path.starts_with(UCI_PREFIX) {
+            if let Some(secret) = secret {
+                cfg.secrets_seal(secret)?;
+            }
+            return write_atomic(path, &toml::to_string(&cfg)?).await;
+        }
+
+        /* still at the env value, told apart before sealing changes it */
+        let mut plain = toml::Value::try_from(&cfg)?;
+        let kept: Vec<ConfigOverride> = cfg
+            .overrides
+            .iter()
+            .filter(|o| plain.get(&o.section).and_then(|s| s.get(&o.field)) == Some(&o.env))
+            .cloned()
+            .collect();
+        let restore = |root: &mut toml::Value| {
+            for o in kept.iter() {
+                let table = match root.get_mut(&o.section).and_then(|s| s.as_table_mut()) {
+                    Some(t) => t,
+                    None => continue,
+                };
+                match o.disk {
+                    Some(ref disk) => table.insert(o.field.clone(), disk.clone()),
+                    None => table.remove(&o.field),
+                };
+            }
+        };
+        restore(&mut plain);
+        /* the native uci options get the disk ones too, not the env */
+        let network: KNetworkConfig = match plain.get("network") {
+            Some(network) => network.clone().try_into()?,
+            None => cfg.network.clone(),
+        };
+
+        if let Some(secret) = secret {
+            cfg.secrets_seal(secret)?;
+        }
+        let mut root = toml::Value::try_from(&cfg)?;
+        restore(&mut root);
+        if uci_write(path, &root, &network).await? {
+            return Ok(());
+        }
+        write_atomic(path, &toml::to_string(&root)?).await
     }
 
     pub async fn config_verify(&self) -> Result<()> {
@@ -90,3 +1053,633 @@ impl KdaemonConfig {
 pub struct KAwsConfig {
     pub auth_token: Option<String>,
 }
+
+#[derive(Subcommand, Debug)]
+enum SecretCommand {
+    #[clap(about = "create the device secret when missing and seal the fields")]
+    Seal,
+    #[clap(about = "write the fields back in plaintext and remove the device secret")]
+    Open,
+    #[clap(about = "list sealed and plaintext secret fields")]
+    Status,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager kdaemon.toml secrets encryption at rest")]
+pub struct SecretOpt {
+    #[clap(subcommand)]
+    command: SecretCommand,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[clap(short = 's', long = "secret", default_value = KDAEMON_SECRET)]
+    secret: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+async fn secret_create(path: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| anyhow!("device secret random fail"))?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(&secret).and_then(|_| f.sync_all()))
+        .map_err(|e| anyhow!("device secret {} create fail - {e}", path))?;
+    info!("device secret {} created", path);
+    Ok(())
+}
+
+pub async fn secret_tools(opt: SecretOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    match opt.command {
+        SecretCommand::Seal => {
+            let cfg = KdaemonConfig::build_from_secret(&opt.config, &opt.secret).await?;
+            if secret_read(&opt.secret).await?.is_none() {
+                secret_create(&opt.secret).await?;
+            }
+            cfg.save_secret(&opt.config, &opt.secret).await?;
+            info!("{} secret fields sealed", opt.config);
+        }
+        SecretCommand::Open => {
+            let cfg = KdaemonConfig::build_from_secret(&opt.config, &opt.secret).await?;
+            cfg.save_with(&opt.config, None).await?;
+            if let Err(e) = fs::remove_file(&opt.secret).await {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(anyhow!("device secret {} remove fail - {e}", opt.secret));
+                }
+            }
+            info!("{} secret fields in plaintext", opt.config);
+        }
+        SecretCommand::Status => {
+            let mut cfg: KdaemonConfig =
+                toml::from_str(&fs::read_to_string(&opt.config).await?).map_err(|e| anyhow!(e))?;
+            let (mut sealed_fields, mut plain) = (vec![], vec![]);
+            for (field, value) in cfg.secret_fields() {
+                match value {
+                    Some(_) if sealed(value) => sealed_fields.push(field),
+                    Some(_) => plain.push(field),
+                    None => {}
+                }
+            }
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json!({
+                    "config": opt.config,
+                    "secret": secret_read(&opt.secret).await?.is_some(),
+                    "sealed": sealed_fields,
+                    "plaintext": plain,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    #[clap(about = "print a field value, a section or the whole config")]
+    Get {
+        key: Option<String>,
+        #[clap(long = "json", action)]
+        json: bool,
+    },
+    #[clap(about = "update one field, the value typed by the field (quote strings)")]
+    Set {
+        key: String,
+        value: String,
+        #[clap(long = "post", help = "run as `{post} {config} {key}` once written")]
+        post: Option<String>,
+    },
+    #[clap(about = "remove an optional field")]
+    Unset {
+        key: String,
+        #[clap(long = "post", help = "run as `{post} {config} {key}` once written")]
+        post: Option<String>,
+    },
+    #[clap(about = "write an older layout back as this version, original kept as .bak")]
+    Migrate,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager kdaemon.toml fields get/set")]
+pub struct ConfigOpt {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[clap(short = 's', long = "secret", default_value = KDAEMON_SECRET)]
+    secret: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+fn config_key(key: &str) -> Result<(&str, &str)> {
+    match key.split_once('.') {
+        Some((section, field))
+            if !section.is_empty() && !field.is_empty() && !field.contains('.') =>
+        {
+            Ok((section, field))
+        }
+        _ => Err(anyhow!("{} not section.field", key)),
+    }
+}
+
+/*
+ * the first of `candidates` the schema takes for section.field, serde
+ * ignores unknown keys so a field is known once it survives the round trip;
+ * None as candidate removes the field
+ */
+fn config_put(
+    cfg: &KdaemonConfig,
+    key: &str,
+    candidates: &[Option<toml::Value>],
+) -> Result<KdaemonConfig> {
+    let (section, field) = config_key(key)?;
+    let root = match toml::Value::try_from(cfg)? {
+        toml::Value::Table(root) => root,
+        _ => return Err(anyhow!("config not a table")),
+    };
+
+    let mut first_err = None;
+    for candidate in candidates {
+        let mut root = root.clone();
+        let table = root
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        let table = match table.as_table_mut() {
+            Some(t) => t,
+            None => return Err(anyhow!("{} not a section", section)),
+        };
+        match candidate {
+            Some(v) => table.insert(field.to_string(), v.clone()),
+            None => table.remove(field),
+        };
+
+        match toml::Value::Table(root).try_into::<KdaemonConfig>() {
+            Ok(mut updated) => {
+                updated.overrides = cfg.overrides.clone();
+                let back = toml::Value::try_from(&updated)?;
+                let kept = back.get(section).and_then(|s| s.get(field)).is_some();
+                if kept == candidate.is_some() {
+                    return Ok(updated);
+                }
+                first_err.get_or_insert_with(|| anyhow!("{} unknown", key));
+            }
+            Err(e) => {
+                let e = match candidate {
+                    Some(_) => anyhow!("{} - {e}", key),
+                    None => anyhow!("{} not optional", key),
+                };
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_err.unwrap_or_else(|| anyhow!("{} no value", key)))
+}
+
+/* `1`/`true` as typed when the field takes it, a string otherwise */
+pub(crate) fn config_value(value: &str) -> Vec<Option<toml::Value>> {
+    let typed = toml::from_str::<toml::value::Table>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("v"));
+    let mut candidates = vec![];
+    if let Some(typed) = typed {
+        candidates.push(Some(typed));
+    }
+    candidates.push(Some(toml::Value::String(value.to_string())));
+    candidates
+}
+
+fn config_lookup(cfg: &KdaemonConfig, key: Option<&str>) -> Result<toml::Value> {
+    let root = toml::Value::try_from(cfg)?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(root),
+    };
+    if !key.contains('.') {
+        return root
+            .get(key)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} unknown", key));
+    }
+    let (section, field) = config_key(key)?;
+    if let Some(v) = root.get(section).and_then(|s| s.get(field)) {
+        return Ok(v.clone());
+    }
+    /* known but unset, the same probe as set */
+    let probe = [
+        Some(toml::Value::String(String::new())),
+        Some(toml::Value::Integer(0)),
+        Some(toml::Value::Boolean(false)),
+    ];
+    config_put(cfg, key, &probe)
+        .map(|_| toml::Value::String(String::new()))
+        .map_err(|_| anyhow!("{} unknown", key))
+}
+
+async fn config_post(post: &str, config: &str, key: &str) -> Result<()> {
+    let status = tokio::process::Command::new(post)
+        .arg(config)
+        .arg(key)
+        .status()
+        .await
+        .map_err(|e| anyhow!("post {} run fail - {e}", post))?;
+    if !status.success() {
+        return Err(anyhow!("post {} fail - {}", post, status));
+    }
+    Ok(())
+}
+
+pub async fn config_tools(opt: ConfigOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    if let ConfigCommand::Migrate = opt.command {
+        let (cfg, from) = KdaemonConfig::migrate_secret(&opt.config, &opt.secret).await?;
+        if from.is_none() {
+            info!("{} already v{}", opt.config, cfg.version);
+        }
+        return Ok(());
+    }
+    let cfg = KdaemonConfig::build_from_secret(&opt.config, &opt.secret).await?;
+    match opt.command {
+        ConfigCommand::Get { key, json } => {
+            let value = config_lookup(&cfg, key.as_deref())?;
+            match value {
+                _ if json => println!("{}", serde_json::to_string_pretty(&value)?),
+                toml::Value::String(s) => println!("{}", s),
+                toml::Value::Table(_) => print!("{}", toml::to_string_pretty(&value)?),
+                v => println!("{}", v),
+            }
+        }
+        ConfigCommand::Set { key, value, post } => {
+            let updated = config_put(&cfg, &key, &config_value(&value))?;
+            updated.save_secret(&opt.config, &opt.secret).await?;
+            info!("{} {} updated", opt.config, key);
+            if let Some(post) = post {
+                config_post(&post, &opt.config, &key).await?;
+            }
+        }
+        ConfigCommand::Unset { key, post } => {
+            let updated = config_put(&cfg, &key, &[None])?;
+            updated.save_secret(&opt.config, &opt.secret).await?;
+            info!("{} {} removed", opt.config, key);
+            if let Some(post) = post {
+                config_post(&post, &opt.config, &key).await?;
+            }
+        }
+        ConfigCommand::Migrate => {}
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kdaemon_secrets() {
+    let dir = std::env::temp_dir().join(format!("fika_secret_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("kdaemon.toml").to_string_lossy().to_string();
+    let secret = dir.join("secret").to_string_lossy().to_string();
+
+    let mut cfg = KdaemonConfig::default();
+    cfg.core.serial_number = "FK0001".parse().unwrap();
+    cfg.core.mac_address = "AA:BB:CC:DD:EE:01".parse().unwrap();
+    cfg.core.sku = "K1".to_string();
+    cfg.network.wifi_ssid = Some("fika".to_string());
+    cfg.network.wifi_password = Some("hunter22".to_string());
+    cfg.boss.ap_access_token = Some("ap-token".to_string());
+
+    /* no device secret, plaintext as before */
+    cfg.save_secret(&config, &secret).await.unwrap();
+    assert!(std::fs::read_to_string(&config)
+        .unwrap()
+        .contains("hunter22"));
+
+    secret_create(&secret).await.unwrap();
+    cfg.save_secret(&config, &secret).await.unwrap();
+    let raw = std::fs::read_to_string(&config).unwrap();
+    assert!(!raw.contains("hunter22") && !raw.contains("ap-token"));
+    assert!(raw.contains("wifi_ssid = \"fika\""));
+    let loaded = KdaemonConfig::build_from_secret(&config, &secret)
+        .await
+        .unwrap();
+    assert_eq!(loaded.network.wifi_password.as_deref(), Some("hunter22"));
+    assert_eq!(loaded.boss.ap_access_token.as_deref(), Some("ap-token"));
+
+    /* bound to the serial and the field */
+    let mut other: KdaemonConfig = toml::from_str(&raw).unwrap();
+    other.core.serial_number = "FK0002".parse().unwrap();
+    let key = std::fs::read(&secret).unwrap();
+    assert!(other.secrets_open(Some(&key)).is_err());
+    let mut swapped: KdaemonConfig = toml::from_str(&raw).unwrap();
+    swapped.boss.access_token = swapped.network.wifi_password.clone();
+    assert!(swapped.secrets_open(Some(&key)).is_err());
+
+    std::fs::remove_file(&secret).unwrap();
+    assert!(KdaemonConfig::build_from_secret(&config, &secret)
+        .await
+        .is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_kdaemon_validate() {
+    let err = KdaemonConfig::from_toml(
+        "kdaemon.toml",
+        r#"
+        [core]
+        mac_address = "AA:BB:CC:DD:EE"
+        serial_number = "SN1"
+        wallet_address = "0x123"
+        [network]
+        wan_type = 7
+        wifi_ssid = "fika"
+        wifi_password = "short"
+        [por]
+        state = "yes"
+        [boss]
+        "#,
+        &MIGRATIONS,
+    )
+    .unwrap_err();
+    let invalid = err.downcast_ref::<ConfigInvalid>().unwrap();
+    let paths: Vec<&str> = invalid.violations.iter().map(|v| v.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "core.sku",
+            "core.mac_address",
+            "core.wallet_address",
+            "por.state",
+            "core.serial_number",
+            "network.wan_type",
+            "network.wifi_password",
+        ]
+    );
+    assert!(err
+        .to_string()
+        .starts_with("kdaemon.toml invalid - core.sku: missing; core.mac_address: "));
+
+    /* format only, loaded with a warning and refused by save */
+    let (cfg, _) = KdaemonConfig::from_toml(
+        "kdaemon.toml",
+        r#"
+        [core]
+        mac_address = "aa:bb:cc:dd:ee:ff"
+        serial_number = "FK-0001"
+        sku = "K1"
+        [network]
+        wan_type = 1
+        [por]
+        state = true
+        [boss]
+        "#,
+        &MIGRATIONS,
+    )
+    .unwrap();
+    assert_eq!(
+        cfg.validate(),
+        [ConfigViolation::new(
+            "network.wan_username",
+            "required by wan_type 1 (pppoe)"
+        )]
+    );
+    assert!(KdaemonConfig::from_toml("kdaemon.toml", "[core\n", &MIGRATIONS).is_err());
+
+    assert!(url_check("https://boss.example.com:8443/api", &["https"]).is_ok());
+    assert!(url_check("http://[::1]:8080", &["http"]).is_ok());
+    assert!(url_check("ftp://boss.example.com", &["http", "https"]).is_err());
+    assert!(url_check("https://", &["https"]).is_err());
+    assert!(url_check("https://host:99999", &["https"]).is_err());
+    assert!(url_check("boss.example.com", &["https"]).is_err());
+}
+
+#[test]
+fn test_kdaemon_identity() {
+    for mac in ["AA:BB:CC:DD:EE:01", "aa-bb-cc-dd-ee-01", "AABBCCDDEE01"] {
+        let mac: MacAddress = mac.parse().unwrap();
+        assert_eq!(mac.as_str(), "aa:bb:cc:dd:ee:01");
+        assert_eq!(mac.compact(), "aabbccddee01");
+    }
+    for mac in [
+        "AA:BB:CC:DD:EE",
+        "AA:BB:CC:DD:EE:0G",
+        "AABBCCDDEE0",
+        "AA::BB:CC:DD:EE",
+    ] {
+        assert!(mac.parse::<MacAddress>().is_err(), "{}", mac);
+    }
+    let serial: SerialNumber = "FK-0001".parse().unwrap();
+    assert_eq!(
+        (serial.as_str(), serial.lowercase().as_str()),
+        ("FK-0001", "fk-0001")
+    );
+    /* too short still loads, validate() flags it */
+    assert!("SN1".parse::<SerialNumber>().is_ok());
+    assert!("FK 0001".parse::<SerialNumber>().is_err());
+
+    let core: KCoreConfig = toml::from_str(
+        r#"
+        mac_address = "AA-BB-CC-DD-EE-01"
+        serial_number = "FK0001"
+        sku = "K1"
+        wallet_address = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        core.wallet_address.as_ref().map(|w| w.as_str()),
+        Some("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed")
+    );
+    let raw = toml::to_string(&core).unwrap();
+    assert!(raw.contains("mac_address = \"aa:bb:cc:dd:ee:01\""));
+    assert_eq!(toml::from_str::<KCoreConfig>(&raw).unwrap(), core);
+    assert!(toml::from_str::<KCoreConfig>(&raw.replace("0x5aAeb", "0x5AAeb")).is_err());
+}
+
+#[tokio::test]
+async fn test_kdaemon_migrate() {
+    fn wifi_move(root: &mut toml::value::Table) -> Result<()> {
+        let wifi = root.remove("wifi");
+        let network = root
+            .get_mut("network")
+            .and_then(|n| n.as_table_mut())
+            .ok_or_else(|| anyhow!("network missing"))?;
+        if let Some(ssid) = wifi.as_ref().and_then(|w| w.get("ssid")) {
+            network.insert("wifi_ssid".to_string(), ssid.clone());
+        }
+        Ok(())
+    }
+
+    let v0 = r#"
+        [core]
+        mac_address = "AA:BB:CC:DD:EE:01"
+        serial_number = "FK0001"
+        sku = "K1"
+        [network]
+        wan_type = 0
+        [wifi]
+        ssid = "fika"
+        [por]
+        state = true
+        [boss]
+        "#;
+    let two: [Migration; 2] = [migrate_v0, wifi_move];
+    let (cfg, from) = KdaemonConfig::from_toml("kdaemon.toml", v0, &two).unwrap();
+    assert_eq!((cfg.version, from), (2, Some(0)));
+    assert_eq!(cfg.network.wifi_ssid.as_deref(), Some("fika"));
+    let v1 = v0.replacen("[core]", "version = 1\n[core]", 1);
+    let (_, from) = KdaemonConfig::from_toml("kdaemon.toml", &v1, &two).unwrap();
+    assert_eq!(from, Some(1));
+    /* newer than this build */
+    let v9 = v0.replacen("[core]", "version = 9\n[core]", 1);
+    let (cfg, from) = KdaemonConfig::from_toml("kdaemon.toml", &v9, &two).unwrap();
+    assert_eq!((cfg.version, from), (9, None));
+
+    let dir = std::env::temp_dir().join(format!("fika_migrate_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("kdaemon.toml").to_string_lossy().to_string();
+    let secret = dir.join("secret").to_string_lossy().to_string();
+    std::fs::write(&config, v0).unwrap();
+    /* a plain load leaves the file alone */
+    let cfg = KdaemonConfig::build_from_secret(&config, &secret)
+        .await
+        .unwrap();
+    assert_eq!(cfg.version, KDAEMON_VERSION);
+    assert_eq!(std::fs::read_to_string(&config).unwrap(), v0);
+    let (cfg, from) = KdaemonConfig::migrate_secret(&config, &secret)
+        .await
+        .unwrap();
+    assert_eq!((cfg.version, from), (KDAEMON_VERSION, Some(0)));
+    assert_eq!(
+        std::fs::read_to_string(format!("{}.v0.bak", config)).unwrap(),
+        v0
+    );
+    assert!(std::fs::read_to_string(&config)
+        .unwrap()
+        .starts_with(&format!("version = {}", KDAEMON_VERSION)));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_kdaemon_config_put() {
+    let mut cfg = KdaemonConfig::default();
+    cfg.network.wifi_ssid = Some("fika".to_string());
+
+    let cfg = config_put(&cfg, "network.wan_type", &config_value("1")).unwrap();
+    assert_eq!(cfg.network.wan_type, 1);
+    /* typed as integer first, the field wants a string */
+    let cfg = config_put(&cfg, "network.wifi_ssid", &config_value("1234")).unwrap();
+    assert_eq!(cfg.network.wifi_ssid.as_deref(), Some("1234"));
+    let cfg = config_put(&cfg, "aws.auth_token", &config_value("token")).unwrap();
+    assert_eq!(
+        cfg.aws.as_ref().unwrap().auth_token.as_deref(),
+        Some("token")
+    );
+
+    let e = config_put(&cfg, "network.wan_type", &config_value("pppoe")).unwrap_err();
+    assert!(e.to_string().starts_with("network.wan_type - "));
+    let e = config_put(&cfg, "network.wifi_bogus", &config_value("x")).unwrap_err();
+    assert_eq!(e.to_string(), "network.wifi_bogus unknown");
+    assert!(config_put(&cfg, "wifi_ssid", &config_value("x")).is_err());
+
+    let unset = config_put(&cfg, "network.wifi_ssid", &[None]).unwrap();
+    assert!(unset.network.wifi_ssid.is_none());
+    assert!(config_put(&cfg, "core.sku", &[None]).is_err());
+    let mut layered = cfg.clone();
+    layered.overrides = vec![ConfigOverride {
+        section: "core".to_string(),
+        field: "sku".to_string(),
+        disk: None,
+        env: toml::Value::String("K1".to_string()),
+    }];
+    let layered = config_put(&layered, "network.wan_type", &config_value("0")).unwrap();
+    assert_eq!(layered.overrides.len(), 1);
+
+    assert_eq!(
+        config_lookup(&cfg, Some("network.wifi_ssid")).unwrap(),
+        toml::Value::String("1234".to_string())
+    );
+    assert_eq!(
+        config_lookup(&unset, Some("network.wifi_ssid")).unwrap(),
+        toml::Value::String(String::new())
+    );
+    assert!(config_lookup(&cfg, Some("network.nope")).is_err());
+    assert!(config_lookup(&cfg, Some("boss")).unwrap().is_table());
+}
+
+#[tokio::test]
+async fn test_kdaemon_env_override() {
+    let disk = r#"
+        version = 1
+        [core]
+        mac_address = "AA:BB:CC:DD:EE:01"
+        serial_number = "FK0001"
+        sku = "K1"
+        [network]
+        wan_type = 0
+        [por]
+        state = true
+        [boss]
+        "#;
+    let mut root: toml::value::Table = toml::from_str(disk).unwrap();
+    let vars = [
+        ("FIKA_CORE__SERIAL_NUMBER", "FK0099"),
+        ("FIKA_NETWORK__WAN_TYPE", "1"),
+        ("FIKA_NETWORK__WAN_USERNAME", "1234"),
+        ("FIKA_NETWORK__WAN_PASSWORD", "from-env"),
+        ("FIKA_NETWORK__NOPE", "x"),
+        ("FIKA_POR__STATE", "maybe"),
+        ("FIKA_ROOT", "/"),
+        ("PATH", "/bin"),
+    ]
+    .map(|(k, v)| (k.to_string(), v.to_string()));
+    let overrides = env_apply(&mut root, vars.into_iter());
+    let keys: Vec<String> = overrides
+        .iter()
+        .map(|o| format!("{}.{}={}", o.section, o.field, o.env))
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "core.serial_number=\"FK0099\"",
+            "network.wan_type=1",
+            "network.wan_username=\"1234\"",
+            "network.wan_password=\"from-env\"",
+        ]
+    );
+    assert_eq!(root["por"]["state"], toml::Value::Boolean(true));
+
+    let (mut cfg, _) = KdaemonConfig::from_toml("kdaemon.toml", disk, &MIGRATIONS).unwrap();
+    cfg.overrides = overrides;
+    cfg.core.serial_number = "FK0099".parse().unwrap();
+    cfg.network.wan_type = 1;
+    cfg.network.wan_password = Some("from-env".to_string());
+    /* changed after load, written */
+    cfg.network.wan_username = Some("pppoe".to_string());
+    cfg.network.wifi_ssid = Some("fika".to_string());
+
+    let dir = std::env::temp_dir().join(format!("fika_env_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("kdaemon.toml").to_string_lossy().to_string();
+    let secret = dir.join("secret").to_string_lossy().to_string();
+    cfg.save_secret(&config, &secret).await.unwrap();
+    let saved: KdaemonConfig = toml::from_str(&std::fs::read_to_string(&config).unwrap()).unwrap();
+    assert_eq!(saved.core.serial_number.as_str(), "FK0001");
+    assert_eq!(saved.network.wan_password, None);
+    /* sealed it no longer equals the env value, still not written */
+    std::fs::write(&secret, [9u8; 32]).unwrap();
+    cfg.save_secret(&config, &secret).await.unwrap();
+    let saved: KdaemonConfig = toml::from_str(&std::fs::read_to_string(&config).unwrap()).unwrap();
+    assert_eq!(saved.network.wan_password, None);
+    assert_eq!(saved.network.wan_type, 0);
+    assert_eq!(saved.network.wan_username.as_deref(), Some("pppoe"));
+    assert_eq!(saved.network.wifi_ssid.as_deref(), Some("fika"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
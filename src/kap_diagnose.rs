@@ -0,0 +1,413 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::Args;
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::kap_audit::{redact, redact_key, toml_redacted};
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_health::health_report;
+use crate::kap_rule::{RuleConfig, RuleConfigCore};
+use crate::setup_logging;
+
+/*
+ * support bundle: rule/kdaemon toml and the effective config (secrets
+ * masked), redis keys under kap/, certificate metadata, health report and the
+ * tail of every log we keep, packed as one tar.gz; a part that fails is
+ * listed in manifest.json instead of failing the bundle
+ */
+
+const DIAGNOSE_DB_PATTERN: &str = "kap/*";
+const DIAGNOSE_DB_ITEMS: isize = 100;
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "FIKA manager support bundle")]
+pub struct DiagnoseOpt {
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(
+        short = 'o',
+        long = "output",
+        help = "default /tmp/fika-diagnose-{time}.tar.gz"
+    )]
+    output: Option<PathBuf>,
+    #[clap(
+        long = "log-bytes",
+        default_value = "262144",
+        help = "tail kept of each log file"
+    )]
+    log_bytes: u64,
+    #[clap(short = 'l', long = "log-level", default_value = "warn")]
+    log_level: String,
+}
+
+struct Bundle {
+    dir: PathBuf,
+    files: Vec<String>,
+    errors: serde_json::Map<String, Value>,
+}
+
+impl Bundle {
+    async fn write(&mut self, name: &str, content: impl AsRef<[u8]>) -> Result<()> {
+        let path = self.dir.join(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::write(&path, content).await?;
+        self.files.push(name.to_string());
+        Ok(())
+    }
+
+    /* a missing/broken part is noted, the rest still goes in */
+    async fn part(&mut self, name: &str, content: Result<impl AsRef<[u8]>>) {
+        let r = match content {
+            Ok(content) => self.write(name, content).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = r {
+            debug!("diagnose {} skipped - {e}", name);
+            self.errors.insert(name.to_string(), json!(e.to_string()));
+        }
+    }
+}
+
+async fn effective_config(rule: &RuleConfig) -> Result<String> {
+    let cfg = KdaemonConfig::build_from(&rule.core.config).await?;
+    let mut config = json!({
+        "rule": serde_json::to_value(rule)?,
+        "config": serde_json::to_value(&cfg)?,
+    });
+    redact(&mut config);
+    Ok(serde_json::to_string_pretty(&config)?)
+}
+
+fn db_value_redacted(key: &str, value: String) -> Value {
+    if redact_key(key) {
+        return json!("***");
+    }
+    match serde_json::from_str::<Value>(&value) {
+        Ok(mut v) if v.is_object() || v.is_array() => {
+            redact(&mut v);
+            v
+        }
+        _ => Value::String(value),
+    }
+}
+
+fn redis_json(value: redis::Value) -> Value {
+    match value {
+        redis::Value::Nil => Value::Null,
+        redis::Value::Int(n) => json!(n),
+        redis::Value::Data(d) => json!(String::from_utf8_lossy(&d)),
+        redis::Value::Bulk(items) => Value::Array(items.into_iter().map(redis_json).collect()),
+        redis::Value::Status(s) => json!(s),
+        redis::Value::Okay => json!("OK"),
+    }
+}
+
+async fn db_dump(database: &str) -> Result<String> {
+    let mut conn = redis::Client::open(database)?
+        .get_async_connection()
+        .await
+        .map_err(|e| anyhow!("db/redis {} connect fail - {e}", database))?;
+    let mut keys: Vec<String> = conn.keys(DIAGNOSE_DB_PATTERN).await?;
+    keys.retain(|k| k.starts_with(DIAGNOSE_DB_PATTERN.trim_end_matches('*')));
+    keys.sort();
+
+    let mut dump = serde_json::Map::new();
+    for key in keys {
+        let kind: String = redis::cmd("TYPE").arg(&key).query_async(&mut conn).await?;
+        let value = match kind.as_str() {
+            "string" => {
+                let v: Option<String> = conn.get(&key).await?;
+                v.map(|v| db_value_redacted(&key, v)).unwrap_or_default()
+            }
+            "list" => {
+                let items: Vec<String> = conn.lrange(&key, -DIAGNOSE_DB_ITEMS, -1).await?;
+                Value::Array(
+                    items
+                        .into_iter()
+                        .map(|v| db_value_redacted(&key, v))
+                        .collect(),
+                )
+            }
+            "stream" => {
+                let entries: redis::Value = redis::cmd("XREVRANGE")
+                    .arg(&key)
+                    .arg("+")
+                    .arg("-")
+                    .arg("COUNT")
+                    .arg(DIAGNOSE_DB_ITEMS)
+                    .query_async(&mut conn)
+                    .await?;
+                redis_json(entries)
+            }
+            other => json!({ "type": other }),
+        };
+        dump.insert(key, value);
+    }
+    Ok(serde_json::to_string_pretty(&dump)?)
+}
+
+#[cfg(feature = "aws-iot")]
+fn certificate_paths(rule: &RuleConfig) -> Vec<(&'static str, &str)> {
+    let mut paths = vec![
+        ("dedicated.cert", rule.aws.dedicated.cert.as_str()),
+        ("dedicated.ca", rule.aws.dedicated.ca.as_str()),
+    ];
+    if let Some(ref provision) = rule.aws.provision {
+        paths.push(("provision.cert", provision.cert.as_str()));
+        paths.push(("provision.ca", provision.ca.as_str()));
+    }
+    paths
+}
+
+#[cfg(not(feature = "aws-iot"))]
+fn certificate_paths(_rule: &RuleConfig) -> Vec<(&'static str, &str)> {
+    vec![]
+}
+
+/* metadata only, never the key material */
+async fn certificates(rule: &RuleConfig) -> Result<String> {
+    let mut certs = serde_json::Map::new();
+    for (name, path) in certificate_paths(rule) {
+        let meta = crate::activate::openssl_output(&[
+            "x509",
+            "-in",
+            path,
+            "-noout",
+            "-subject",
+            "-issuer",
+            "-serial",
+            "-startdate",
+            "-enddate",
+            "-fingerprint",
+            "-sha256",
+        ])
+        .await;
+        let value = match meta {
+            Ok(meta) => json!({
+                "path": path,
+                "meta": meta.lines().map(str::trim).collect::<Vec<_>>(),
+            }),
+            Err(e) => json!({ "path": path, "error": e.to_string() }),
+        };
+        certs.insert(name.to_string(), value);
+    }
+    Ok(serde_json::to_string_pretty(&certs)?)
+}
+
+async fn file_tail(path: &Path, limit: u64) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(path)
+        .await
+        .map_err(|e| anyhow!("{} open fail - {e}", path.display()))?;
+    let len = file.metadata().await?.len();
+    if len > limit {
+        file.seek(SeekFrom::Start(len - limit)).await?;
+    }
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn dir_files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if let Ok(mut entries) = fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == ext) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/* log file (and rotated ones), task logs, audit log and crash reports */
+fn log_sources(core: &RuleConfigCore) -> Vec<(&'static str, PathBuf)> {
+    let mut sources = vec![];
+    if let Some(ref log_file) = core.log_file {
+        sources.push(("log", log_file.path.clone()));
+        for n in 1..=log_file.keep.unwrap_or(0) {
+            let mut rotated = log_file.path.clone().into_os_string();
+            rotated.push(format!(".{}", n));
+            sources.push(("log", PathBuf::from(rotated)));
+        }
+    }
+    if let Some(ref audit) = core.audit_log {
+        sources.push(("audit", audit.clone()));
+    }
+    sources
+}
+
+async fn diagnose_logs(bundle: &mut Bundle, rule: &RuleConfig, limit: u64) {
+    let mut sources = log_sources(&rule.core);
+    if let Some(ref dir) = rule.core.task_log_dir {
+        for path in dir_files(dir, "log").await {
+            sources.push(("task", path));
+        }
+    }
+    if let Some(ref dir) = rule.core.crash_dir {
+        for path in dir_files(dir, "json").await {
+            sources.push(("crash", path));
+        }
+    }
+
+    for (kind, path) in sources {
+        let name = format!(
+            "logs/{}/{}",
+            kind,
+            path.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default()
+        );
+        let tail = file_tail(&path, limit).await;
+        bundle.part(&name, tail).await;
+    }
+}
+
+async fn diagnose_collect(bundle: &mut Bundle, opt: &DiagnoseOpt) {
+    bundle
+        .part("rule.toml", toml_redacted(&opt.rule).await)
+        .await;
+
+    let (_, health) = health_report(&opt.rule, 14).await;
+    bundle
+        .part(
+            "health.json",
+            serde_json::to_string_pretty(&health).map_err(|e| e.into()),
+        )
+        .await;
+
+    let rule = match RuleConfig::build_from(&opt.rule).await {
+        Ok(rule) => rule,
+        Err(e) => {
+            let e = anyhow!("rule build from {} fail - {e}", opt.rule);
+            bundle.part("effective.json", Err::<String, _>(e)).await;
+            return;
+        }
+    };
+    bundle
+        .part("kdaemon.toml", toml_redacted(&rule.core.config).await)
+        .await;
+    bundle
+        .part("effective.json", effective_config(&rule).await)
+        .await;
+    let db = match rule.core.database {
+        Some(ref database) => db_dump(database).await,
+        None => Err(anyhow!("rule/core/database none")),
+    };
+    bundle.part("db.json", db).await;
+    bundle
+        .part("certificates.json", certificates(&rule).await)
+        .await;
+    diagnose_logs(bundle, &rule, opt.log_bytes).await;
+}
+
+pub async fn diagnose_tools(opt: DiagnoseOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let now = Utc::now();
+    let name = format!("fika-diagnose-{}", now.format("%Y%m%dT%H%M%SZ"));
+    let output = opt
+        .output
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("{}.tar.gz", name)));
+    let staging = std::env::temp_dir().join(&name);
+    let mut bundle = Bundle {
+        dir: staging.join(&name),
+        files: vec![],
+        errors: serde_json::Map::new(),
+    };
+    fs::create_dir_all(&bundle.dir).await?;
+
+    diagnose_collect(&mut bundle, &opt).await;
+    let manifest = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "timestamp": now.to_rfc3339(),
+        "hostname": std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok(),
+        "files": bundle.files,
+        "errors": bundle.errors,
+    });
+    bundle
+        .write("manifest.json", serde_json::to_string_pretty(&manifest)?)
+        .await?;
+
+    let tar = Command::new("tar")
+        .arg("-czf")
+        .arg(&output)
+        .arg("-C")
+        .arg(&staging)
+        .arg(&name)
+        .output()
+        .await;
+    if let Err(e) = fs::remove_dir_all(&staging).await {
+        warn!("diagnose staging {} remove fail - {e}", staging.display());
+    }
+    let tar = tar.map_err(|e| anyhow!("tar run fail - {e}"))?;
+    if !tar.status.success() {
+        return Err(anyhow!(
+            "tar {} fail - {}",
+            output.display(),
+            String::from_utf8_lossy(&tar.stderr).trim()
+        ));
+    }
+
+    info!(
+        "diagnose {} files, {} skipped",
+        bundle.files.len(),
+        bundle.errors.len()
+    );
+    println!("{}", output.display());
+    Ok(())
+}
+
+#[test]
+fn test_diagnose_redact() {
+    assert_eq!(
+        db_value_redacted("kap/boss/ap_access_token", "t".into()),
+        "***"
+    );
+    assert_eq!(
+        db_value_redacted("kap/boss/hcs", r#"{"hcs":1,"token":"t"}"#.into()),
+        json!({ "hcs": 1, "token": "***" })
+    );
+    assert_eq!(db_value_redacted("kap/honest/state", "42".into()), "42");
+
+    let core: RuleConfigCore = toml::from_str(
+        r#"
+        thirdparty = "longdong2"
+        config = "/userdata/kdaemon.toml"
+        audit_log = "/userdata/log/fika_audit.log"
+        [log_file]
+        path = "/userdata/log/fika_manager.log"
+        keep = 2
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        log_sources(&core)
+            .into_iter()
+            .map(|(_, p)| p.to_string_lossy().to_string())
+            .collect::<Vec<_>>(),
+        [
+            "/userdata/log/fika_manager.log",
+            "/userdata/log/fika_manager.log.1",
+            "/userdata/log/fika_manager.log.2",
+            "/userdata/log/fika_audit.log",
+        ]
+    );
+}
@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_rule::{RuleConfig, RuleFeaturesConfig};
+use crate::misc::write_atomic;
+use crate::setup_logging;
+
+/*
+ * per-hardware feature flags, one firmware image for every SKU: the
+ * [features] default table, then each features.sku entry matching
+ * core.sku (exact, or a prefix ending in *, shorter prefixes first), then
+ * what the boss `endpoint` returns for the sku, cached for when it is
+ * unreachable. A flag nobody lists is on, so without tables nothing
+ * changes. features_init resolves at boot_config_load and on every rule
+ * reload, subsystems ask feature_enabled ("por", "portal", "wifi")
+ */
+
+const FEATURES_CACHE: &str = "/userdata/.features.json";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FeatureFlag {
+    pub enabled: bool,
+    pub from: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    pub sku: String,
+    pub flags: BTreeMap<String, FeatureFlag>,
+}
+
+impl FeatureFlags {
+    pub fn resolve(
+        cfg: &RuleFeaturesConfig,
+        sku: &str,
+        boss: Option<&BTreeMap<String, bool>>,
+    ) -> Self {
+        let mut out = Self {
+            sku: sku.to_string(),
+            flags: BTreeMap::new(),
+        };
+        let mut set = |table: &BTreeMap<String, bool>, from: &str| {
+            for (name, enabled) in table {
+                out.flags.insert(
+                    name.clone(),
+                    FeatureFlag {
+                        enabled: *enabled,
+                        from: from.to_string(),
+                    },
+                );
+            }
+        };
+
+        if let Some(ref default) = cfg.default {
+            set(default, "default");
+        }
+        /* exact after every prefix, longer prefixes over shorter */
+        let mut matched: Vec<(&String, &BTreeMap<String, bool>)> = cfg
+            .sku
+            .iter()
+            .flatten()
+            .filter(|(key, _)| match key.strip_suffix('*') {
+                Some(prefix) => sku.starts_with(prefix),
+                None => key.as_str() == sku,
+            })
+            .collect();
+        matched.sort_by_key(|(key, _)| match key.strip_suffix('*') {
+            Some(prefix) => prefix.len(),
+            None => usize::MAX,
+        });
+        for (key, table) in matched {
+            set(table, &format!("sku:{}", key));
+        }
+        if let Some(boss) = boss {
+            set(boss, "boss");
+        }
+        out
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags.get(name).is_none_or(|f| f.enabled)
+    }
+}
+
+/* bools of the response, anything else is not a flag */
+fn boss_flags(value: &Value) -> Result<BTreeMap<String, bool>> {
+    let map = value
+        .as_object()
+        .ok_or_else(|| anyhow!("boss features not object - {}", value))?;
+    Ok(map
+        .iter()
+        .filter_map(|(k, v)| match v.as_bool() {
+            Some(b) => Some((k.clone(), b)),
+            None => {
+                warn!("boss feature {} not bool - {}", k, v);
+                None
+            }
+        })
+        .collect())
+}
+
+#[cfg(feature = "boss-api")]
+async fn boss_fetch(
+    rule: &RuleConfig,
+    cfg: &KdaemonConfig,
+    name: &str,
+) -> Result<BTreeMap<String, bool>> {
+    let mut boss = crate::BossClient::from_config(rule, cfg)?;
+    boss.sign_from(&rule.boss, &rule.core.config).await?;
+    let ep = boss.endpoint(name)?;
+    let sku = serde_json::json!({ "sku": cfg.core.sku });
+    boss_flags(&boss.call_endpoint(&ep, Some(sku)).await?)
+}
+
+#[cfg(not(feature = "boss-api"))]
+async fn boss_fetch(
+    _rule: &RuleConfig,
+    _cfg: &KdaemonConfig,
+    _name: &str,
+) -> Result<BTreeMap<String, bool>> {
+    Err(anyhow!("not support due boss-api feature disable"))
+}
+
+/* flags of an earlier boss fetch */
+async fn boss_cached(cache: &str, sku: &str) -> Result<BTreeMap<String, bool>> {
+    let text = tokio::fs::read_to_string(cache).await?;
+    let cached: FeatureFlags = serde_json::from_str(&text)?;
+    /* flags fetched for another sku say nothing about this one */
+    if cached.sku != sku {
+        return Err(anyhow!("{} for sku {}", cache, cached.sku));
+    }
+    Ok(cached
+        .flags
+        .into_iter()
+        .map(|(k, f)| (k, f.enabled))
+        .collect())
+}
+
+/*
+ * fresh from boss into the cache, else the cache of an earlier fetch;
+ * `fetch` false goes to the cache right away
+ */
+async fn boss_overlay(
+    rule: &RuleConfig,
+    cfg: &KdaemonConfig,
+    features: &RuleFeaturesConfig,
+    fetch: bool,
+    refresh: bool,
+) -> Result<Option<BTreeMap<String, bool>>> {
+    let name = match features.endpoint {
+        Some(ref name) => name,
+        None => return Ok(None),
+    };
+    let cache = features.cache.as_deref().unwrap_or(FEATURES_CACHE);
+
+    let fetched = if fetch {
+        boss_fetch(rule, cfg, name).await
+    } else {
+        Err(anyhow!("boss not asked"))
+    };
+    match fetched {
+        Ok(flags) => {
+            let stored =
+                FeatureFlags::resolve(&RuleFeaturesConfig::default(), &cfg.core.sku, Some(&flags));
+            if let Err(e) = write_atomic(cache, serde_json::to_string_pretty(&stored)?).await {
+                warn!("features cache fail - {e}");
+            }
+            Ok(Some(flags))
+        }
+        Err(e) if refresh => Err(e),
+        Err(e) => match boss_cached(cache, &cfg.core.sku).await {
+            Ok(flags) => {
+                if fetch {
+                    warn!("boss features fail, {} used - {e}", cache);
+                }
+                Ok(Some(flags))
+            }
+            Err(c) if fetch => {
+                warn!("boss features fail, no cache ({c}) - {e}");
+                Ok(None)
+            }
+            Err(c) => {
+                warn!("boss features not fetched, no cache - {c}");
+                Ok(None)
+            }
+        },
+    }
+}
+
+async fn features_resolve_from(
+    rule: &RuleConfig,
+    fetch: bool,
+    refresh: bool,
+) -> Result<FeatureFlags> {
+    let cfg = KdaemonConfig::build_from(&rule.core.config).await?;
+    let features = rule.features.clone().unwrap_or_default();
+    let boss = boss_overlay(rule, &cfg, &features, fetch, refresh).await?;
+    Ok(FeatureFlags::resolve(
+        &features,
+        &cfg.core.sku,
+        boss.as_ref(),
+    ))
+}
+
+pub async fn features_resolve(rule: &RuleConfig, refresh: bool) -> Result<FeatureFlags> {
+    features_resolve_from(rule, true, refresh).await
+}
+
+static FEATURES: RwLock<Option<Arc<FeatureFlags>>> = RwLock::new(None);
+
+fn features_store(resolved: Result<FeatureFlags>) -> Arc<FeatureFlags> {
+    let flags = match resolved {
+        Ok(flags) => flags,
+        Err(e) => {
+            warn!("features unresolved, all on - {e}");
+            FeatureFlags::default()
+        }
+    };
+    let flags = Arc::new(flags);
+    *FEATURES.write().unwrap_or_else(|e| e.into_inner()) = Some(flags.clone());
+    flags
+}
+
+/* after the rule is in and again on each reload; unresolvable is every flag on */
+pub async fn features_init(rule: &RuleConfig) -> Arc<FeatureFlags> {
+    features_store(features_resolve(rule, false).await)
+}
+
+/* the rule tables and the boss cache only, for who must not wait on boss */
+pub async fn features_init_cached(rule: &RuleConfig) -> Arc<FeatureFlags> {
+    features_store(features_resolve_from(rule, false, false).await)
+}
+
+/* on until features_init says otherwise */
+pub fn feature_enabled(name: &str) -> bool {
+    FEATURES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_none_or(|f| f.enabled(name))
+}
+
+#[derive(Subcommand, Debug)]
+enum FeatureCommand {
+    #[clap(about = "the flags of this sku and where each comes from")]
+    List,
+    #[clap(about = "exit 0 if the feature is on for this sku")]
+    Check { name: String },
+    #[clap(about = "fetch the flags of this sku from boss into the cache")]
+    Fetch,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager feature flags by SKU")]
+pub struct FeatureOpt {
+    #[clap(subcommand)]
+    command: FeatureCommand,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(short = 'l', long = "log-level", default_value = "warn")]
+    log_level: String,
+}
+
+pub async fn feature_tools(opt: FeatureOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let rule = RuleConfig::build_from(&opt.rule).await?;
+    match opt.command {
+        FeatureCommand::List => {
+            let flags = features_resolve(&rule, false).await?;
+            println!("{}", serde_json::to_string_pretty(&flags)?);
+        }
+        FeatureCommand::Check { name } => {
+            let flags = features_resolve(&rule, false).await?;
+            if !flags.enabled(&name) {
+                return Err(anyhow!("feature {} off for sku {}", name, flags.sku));
+            }
+        }
+        FeatureCommand::Fetch => {
+            if rule
+                .features
+                .as_ref()
+                .and_then(|f| f.endpoint.as_ref())
+                .is_none()
+            {
+                return Err(anyhow!("rule/features/endpoint none, nothing to fetch"));
+            }
+            let flags = features_resolve(&rule, true).await?;
+            info!("features of sku {} fetched", flags.sku);
+            println!("{}", serde_json::to_string_pretty(&flags)?);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_feature_resolve() {
+    let cfg: RuleFeaturesConfig = toml::from_str(
+        r#"
+        [default]
+        portal = true
+        por = true
+        [sku."K2*"]
+        por = false
+        wifi = false
+        [sku."K2-LTE*"]
+        wifi = true
+        [sku."K2-LTE-B"]
+        portal = false
+        [sku.K1]
+        por = false
+        "#,
+    )
+    .unwrap();
+
+    let flags = FeatureFlags::resolve(&cfg, "K2-LTE-B", None);
+    assert!(!flags.enabled("por"));
+    assert!(!flags.enabled("portal"));
+    assert!(flags.enabled("wifi"));
+    assert_eq!(flags.flags["wifi"].from, "sku:K2-LTE*");
+    assert_eq!(flags.flags["portal"].from, "sku:K2-LTE-B");
+    /* nobody lists it */
+    assert!(flags.enabled("lte"));
+
+    let flags = FeatureFlags::resolve(&cfg, "K3", None);
+    assert!(flags.enabled("por") && flags.enabled("wifi"));
+    assert_eq!(flags.flags["por"].from, "default");
+
+    let boss = BTreeMap::from([("portal".to_string(), false), ("lte".to_string(), true)]);
+    let flags = FeatureFlags::resolve(&cfg, "K1", Some(&boss));
+    assert!(!flags.enabled("por") && !flags.enabled("portal"));
+    assert_eq!(flags.flags["portal"].from, "boss");
+    assert!(
+        FeatureFlags::resolve(&RuleFeaturesConfig::default(), "K1", None)
+            .flags
+            .is_empty()
+    );
+
+    let value = serde_json::json!({ "portal": false, "note": "x" });
+    assert_eq!(
+        boss_flags(&value).unwrap(),
+        BTreeMap::from([("portal".to_string(), false)])
+    );
+    assert!(boss_flags(&serde_json::json!([true])).is_err());
+}
@@ -0,0 +1,414 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::Args;
+use redis::AsyncCommands;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_monitor::{MonitorState, MONITOR_STATE_KEY};
+use crate::kap_rule::RuleConfig;
+use crate::kap_task::{task_history_key, TaskRecord};
+use crate::{setup_logging, RuleConfigTask, DAEMON_START_KEY, MQTT_STATE_KEY};
+
+/*
+ * one-shot report for external watchdogs: redis, mqtt state (as the daemon
+ * set it under MQTT_STATE_KEY), memory pressure from the self monitor,
+ * certificate expiry, rule/config validity and last task runs; printed as
+ * json, any degraded/fail check exits non-zero
+ */
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "FIKA manager health report")]
+pub struct HealthOpt {
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(
+        long = "cert-days",
+        default_value = "14",
+        help = "degraded when the certificate expires within these days"
+    )]
+    cert_days: i64,
+    #[clap(short = 'l', long = "log-level", default_value = "warn")]
+    log_level: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HealthStatus {
+    Skip,
+    Ok,
+    Degraded,
+    Fail,
+}
+
+#[derive(Serialize, Debug)]
+struct HealthCheck {
+    status: HealthStatus,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl HealthCheck {
+    fn new(status: HealthStatus, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: detail.into(),
+            data: None,
+        }
+    }
+
+    fn from_result(r: Result<String>) -> Self {
+        match r {
+            Ok(d) => Self::new(HealthStatus::Ok, d),
+            Err(e) => Self::new(HealthStatus::Fail, e.to_string()),
+        }
+    }
+}
+
+async fn check_config(rule: &Result<RuleConfig>) -> HealthCheck {
+    let r = async {
+        let rule = rule.as_ref().map_err(|e| anyhow!("{e}"))?;
+        let cfg = KdaemonConfig::build_from(&rule.core.config)
+            .await
+            .map_err(|e| anyhow!("{} load fail - {e}", rule.core.config))?;
+        cfg.config_verify().await?;
+        Ok(format!("rule and {} valid", rule.core.config))
+    };
+    HealthCheck::from_result(r.await)
+}
+
+/* a record from before the running daemon came up says nothing of now */
+fn check_mqtt(state: Option<String>, daemon_start: Option<i64>) -> HealthCheck {
+    let state = match state.and_then(|s| serde_json::from_str::<Value>(&s).ok()) {
+        Some(s) => s,
+        None => return HealthCheck::new(HealthStatus::Skip, "no connection state recorded"),
+    };
+    let stale = match (state["since"].as_i64(), daemon_start) {
+        (Some(since), Some(start)) => since < start,
+        (None, _) => true,
+        (Some(_), None) => false,
+    };
+    let status = match state["state"].as_str() {
+        Some("connected") if !stale => HealthStatus::Ok,
+        _ => HealthStatus::Degraded,
+    };
+    let mut check = HealthCheck::new(
+        status,
+        format!(
+            "{} {}{}",
+            state["thing"].as_str().unwrap_or_default(),
+            state["state"].as_str().unwrap_or("unknown"),
+            if stale { " (stale)" } else { "" }
+        ),
+    );
+    check.data = Some(state);
+    check
+}
+
+fn check_resources(state: Option<String>) -> HealthCheck {
+    let state = match state.and_then(|s| serde_json::from_str::<MonitorState>(&s).ok()) {
+        Some(s) => s,
+        None => return HealthCheck::new(HealthStatus::Skip, "no monitor state recorded"),
+    };
+    let status = if state.pressure {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    };
+    let mut check = HealthCheck::new(
+        status,
+        format!(
+            "rss {} KiB, available {} KiB{}",
+            state.rss / 1024,
+            state.mem_available / 1024,
+            if state.throttled { ", throttled" } else { "" }
+        ),
+    );
+    check.data = serde_json::to_value(&state).ok();
+    check
+}
+
+/* `openssl x509 -enddate`, notAfter=Oct 14 12:00:00 2027 GMT */
+fn cert_enddate_parse(s: &str) -> Result<DateTime<Utc>> {
+    let s = s.trim().trim_start_matches("notAfter=");
+    let s = s.strip_suffix(" GMT").unwrap_or(s);
+    let naive = NaiveDateTime::parse_from_str(s, "%b %e %H:%M:%S %Y")
+        .map_err(|e| anyhow!("certificate enddate {} invalid - {e}", s))?;
+    Ok(Utc.from_utc_datetime(&naive))
+}
+
+#[cfg(feature = "aws-iot")]
+fn certificate_path(rule: &RuleConfig) -> Option<&str> {
+    Some(&rule.aws.dedicated.cert)
+}
+
+#[cfg(not(feature = "aws-iot"))]
+fn certificate_path(_rule: &RuleConfig) -> Option<&str> {
+    None
+}
+
+async fn check_certificate(rule: &Result<RuleConfig>, days: i64) -> HealthCheck {
+    let cert = match rule.as_ref().map(certificate_path) {
+        Ok(Some(cert)) => cert,
+        Ok(None) => {
+            return HealthCheck::new(HealthStatus::Skip, "not support due aws feature disable")
+        }
+        Err(_) => return HealthCheck::new(HealthStatus::Skip, "rule invalid"),
+    };
+    let r = async {
+        let enddate =
+            crate::activate::openssl_output(&["x509", "-in", cert, "-noout", "-enddate"]).await?;
+        cert_enddate_parse(&enddate)
+    };
+    match r.await {
+        Ok(expiry) => certificate_health(cert, expiry, Utc::now(), days),
+        Err(e) => HealthCheck::new(HealthStatus::Fail, e.to_string()),
+    }
+}
+
+fn certificate_health(
+    cert: &str,
+    expiry: DateTime<Utc>,
+    now: DateTime<Utc>,
+    days: i64,
+) -> HealthCheck {
+    let left = (expiry - now).num_days();
+    let status = if expiry <= now {
+        HealthStatus::Fail
+    } else if left < days {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    };
+    let mut check = HealthCheck::new(status, format!("{} expires in {} days", cert, left));
+    check.data = Some(json!({ "expiry": expiry.to_rfc3339() }));
+    check
+}
+
+/*
+ * a periodic task is stale when its last run is older than twice the period
+ * (or it never ran that long after the daemon came up); on-demand tasks only
+ * count their last exit code
+ */
+fn task_health(
+    task: &RuleConfigTask,
+    record: Option<&TaskRecord>,
+    daemon_start: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> (HealthStatus, String) {
+    let period = task
+        .period
+        .and_then(|p| chrono::Duration::from_std(p * 2).ok());
+    match record {
+        Some(TaskRecord {
+            error: Some(e),
+            start,
+            ..
+        }) => (
+            HealthStatus::Degraded,
+            format!("{} at {}", e, start.to_rfc3339()),
+        ),
+        Some(r) if r.exit_code != Some(0) => (
+            HealthStatus::Degraded,
+            format!("exit {:?} at {}", r.exit_code, r.start.to_rfc3339()),
+        ),
+        Some(r) if period.is_some_and(|p| now - r.start > p) => (
+            HealthStatus::Degraded,
+            format!("stale since {}", r.start.to_rfc3339()),
+        ),
+        Some(r) => (HealthStatus::Ok, format!("ok at {}", r.start.to_rfc3339())),
+        None => {
+            let start_at = task
+                .start_at
+                .and_then(|s| chrono::Duration::from_std(s).ok())
+                .unwrap_or_else(chrono::Duration::zero);
+            match (period, daemon_start) {
+                (Some(p), Some(since)) if now - since > start_at + p => {
+                    (HealthStatus::Degraded, "never run".to_string())
+                }
+                _ => (HealthStatus::Ok, "not run yet".to_string()),
+            }
+        }
+    }
+}
+
+async fn check_tasks(rule: &RuleConfig, conn: &mut redis::aio::Connection) -> HealthCheck {
+    let now = Utc::now();
+    let daemon_start: Option<String> = conn.get(DAEMON_START_KEY).await.unwrap_or_default();
+    let daemon_start = daemon_start
+        .and_then(|s| s.parse::<i64>().ok())
+        .and_then(|s| Utc.timestamp_opt(s, 0).single());
+
+    let mut status = HealthStatus::Ok;
+    let mut tasks = vec![];
+    for task in rule.task.iter().flatten() {
+        let record: Option<String> = conn
+            .lindex(task_history_key(&task.topic), -1)
+            .await
+            .unwrap_or_default();
+        let record = record.and_then(|r| serde_json::from_str::<TaskRecord>(&r).ok());
+        let (s, detail) = task_health(task, record.as_ref(), daemon_start, now);
+        status = status.max(s);
+        tasks.push(json!({
+            "topic": task.topic,
+            "status": s,
+            "detail": detail,
+            "last_run": record.map(|r| r.start.to_rfc3339()),
+        }));
+    }
+
+    let degraded = tasks.iter().filter(|t| t["status"] != "ok").count();
+    let mut check = HealthCheck::new(
+        status,
+        format!("{} of {} tasks degraded", degraded, tasks.len()),
+    );
+    check.data = Some(Value::Array(tasks));
+    check
+}
+
+pub(crate) async fn health_report(rule_path: &str, cert_days: i64) -> (HealthStatus, Value) {
+    let rule = RuleConfig::build_from(rule_path)
+        .await
+        .map_err(|e| anyhow!("rule build from {} fail - {e}", rule_path));
+
+    let mut checks = vec![("config", check_config(&rule).await)];
+    checks.push(("certificate", check_certificate(&rule, cert_days).await));
+
+    let conn = async {
+        let rule = rule.as_ref().map_err(|e| anyhow!("{e}"))?;
+        let database = rule
+            .core
+            .database
+            .as_ref()
+            .ok_or_else(|| anyhow!("rule/core/database none invalid"))?;
+        let mut conn = redis::Client::open(database.as_str())?
+            .get_async_connection()
+            .await
+            .map_err(|e| anyhow!("db/redis {} connect fail - {e}", database))?;
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut conn)
+            .await?;
+        Ok::<_, anyhow::Error>((rule, database.clone(), conn))
+    };
+    match conn.await {
+        Ok((rule, database, mut conn)) => {
+            checks.push(("redis", HealthCheck::new(HealthStatus::Ok, database)));
+            let state: Option<String> = conn.get(MQTT_STATE_KEY).await.unwrap_or_default();
+            let start: Option<String> = conn.get(DAEMON_START_KEY).await.unwrap_or_default();
+            let start = start.and_then(|s| s.parse::<i64>().ok());
+            checks.push(("mqtt", check_mqtt(state, start)));
+            let state: Option<String> = conn.get(MONITOR_STATE_KEY).await.unwrap_or_default();
+            checks.push(("resources", check_resources(state)));
+            checks.push(("tasks", check_tasks(rule, &mut conn).await));
+        }
+        Err(e) => {
+            checks.push(("redis", HealthCheck::new(HealthStatus::Fail, e.to_string())));
+            checks.push(("mqtt", HealthCheck::new(HealthStatus::Skip, "redis down")));
+            checks.push((
+                "resources",
+                HealthCheck::new(HealthStatus::Skip, "redis down"),
+            ));
+            checks.push(("tasks", HealthCheck::new(HealthStatus::Skip, "redis down")));
+        }
+    }
+    debug!("health checks - {:?}", checks);
+
+    let status = checks
+        .iter()
+        .map(|(_, c)| c.status)
+        .max()
+        .unwrap_or(HealthStatus::Ok)
+        .max(HealthStatus::Ok);
+    let checks = checks
+        .into_iter()
+        .map(|(name, c)| (name.to_string(), json!(c)))
+        .collect::<serde_json::Map<String, Value>>();
+    let report = json!({
+        "status": status,
+        "timestamp": Utc::now().to_rfc3339(),
+        "checks": checks,
+    });
+    (status, report)
+}
+
+pub async fn health_tools(opt: HealthOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let (status, report) = health_report(&opt.rule, opt.cert_days).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    match status {
+        HealthStatus::Ok | HealthStatus::Skip => Ok(()),
+        s => Err(anyhow!("health {}", format!("{:?}", s).to_lowercase())),
+    }
+}
+
+#[test]
+fn test_health_task_and_cert() {
+    let task: RuleConfigTask =
+        toml::from_str("topic = \"kap/t\"\ninline_sh = \"true\"\nperiod = \"1h\"").unwrap();
+    let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    let record = |ago: i64, code: i32| TaskRecord {
+        topic: "kap/t".to_string(),
+        start: now - chrono::Duration::minutes(ago),
+        duration_ms: 1,
+        exit_code: Some(code),
+        output_hash: String::new(),
+        error: None,
+    };
+
+    let status = |r: Option<&TaskRecord>, since: Option<i64>| {
+        task_health(
+            &task,
+            r,
+            since.map(|m| now - chrono::Duration::minutes(m)),
+            now,
+        )
+        .0
+    };
+    assert_eq!(status(Some(&record(30, 0)), None), HealthStatus::Ok);
+    assert_eq!(status(Some(&record(30, 1)), None), HealthStatus::Degraded);
+    assert_eq!(status(Some(&record(150, 0)), None), HealthStatus::Degraded);
+    assert_eq!(status(None, Some(30)), HealthStatus::Ok);
+    assert_eq!(status(None, Some(90)), HealthStatus::Ok);
+    assert_eq!(status(None, Some(150)), HealthStatus::Degraded);
+
+    let expiry = cert_enddate_parse("notAfter=Nov 24 22:13:20 2023 GMT").unwrap();
+    assert_eq!(expiry, now + chrono::Duration::days(10));
+    let status = |days| certificate_health("c.pem", expiry, now, days).status;
+    assert_eq!(status(14), HealthStatus::Degraded);
+    assert_eq!(status(7), HealthStatus::Ok);
+    assert_eq!(
+        certificate_health("c.pem", expiry, expiry, 7).status,
+        HealthStatus::Fail
+    );
+
+    let state = |state: &str, since: Option<i64>| {
+        Some(json!({ "thing": "t", "state": state, "since": since }).to_string())
+    };
+    let status = |s, start| check_mqtt(s, start).status;
+    assert_eq!(
+        status(state("connected", Some(200)), Some(100)),
+        HealthStatus::Ok
+    );
+    assert_eq!(
+        status(state("connected", Some(50)), Some(100)),
+        HealthStatus::Degraded
+    );
+    assert_eq!(
+        status(state("connected", None), Some(100)),
+        HealthStatus::Degraded
+    );
+    assert_eq!(
+        status(state("failed", Some(200)), Some(100)),
+        HealthStatus::Degraded
+    );
+    assert_eq!(status(None, Some(100)), HealthStatus::Skip);
+}
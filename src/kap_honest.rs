@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, instrument, warn};
+
+use crate::kap_feature::feature_enabled;
+use crate::kap_rule::RuleHonestConfig;
+use crate::{publish_message, set_message, DbCommand, ShutdownGuard};
+
+/*
+ * honest/PoR check engine
+ *
+ * path is run every ok_cycle while it exits 0 (killed after `timeout`,
+ * 60s by default, which counts as a fail); once it failed
+ * fail_threshold times in a row the engine switches to fail_cycle until
+ * the next success. The state below is set under HONEST_STATE_KEY after
+ * every run and, if `shadow` is configured, published to
+ * kap/aws/shadow/{shadow} which the daemon forwards as reported state.
+ */
+
+pub const HONEST_STATE_KEY: &str = "kap/honest/state";
+const HONEST_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct HonestState {
+    pub ok: u64,
+    pub fail: u64,
+    pub consecutive_fail: u32,
+    pub score: u8,
+    pub healthy: bool,
+    pub cycle: u64,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_ok: Option<DateTime<Utc>>,
+}
+
+impl HonestState {
+    fn update(&mut self, cfg: &RuleHonestConfig, success: bool, now: DateTime<Utc>) -> Duration {
+        self.last_run = Some(now);
+        if success {
+            self.ok += 1;
+            self.consecutive_fail = 0;
+            self.last_ok = Some(now);
+        } else {
+            self.fail += 1;
+            self.consecutive_fail += 1;
+        }
+
+        self.score = (self.ok * 100 / (self.ok + self.fail)) as u8;
+        self.healthy = self.consecutive_fail < cfg.fail_threshold.unwrap_or(1);
+
+        let cycle = if self.healthy {
+            cfg.ok_cycle
+        } else {
+            cfg.fail_cycle
+        };
+        self.cycle = cycle.as_secs();
+        cycle
+    }
+}
+
+/* a hung check counts as failed, it is killed on the timeout */
+async fn honest_check(cfg: &RuleHonestConfig) -> Result<bool> {
+    let timeout = cfg.timeout.unwrap_or(HONEST_CHECK_TIMEOUT);
+    let mut child = Command::new(&cfg.path)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("honest/{:?} run fail - {e}", &cfg.path))?;
+    let status = match time::timeout(timeout, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => return Err(anyhow!("honest/{:?} timeout in {:?}", &cfg.path, timeout)),
+    };
+    debug!("honest/{:?} run completed - {}", &cfg.path, status);
+
+    Ok(status.success())
+}
+
+#[instrument(name = "honest", skip_all)]
+pub async fn honest_start(
+    cfg: RuleHonestConfig,
+    db_chan: mpsc::Sender<DbCommand>,
+    mut guard: ShutdownGuard,
+) -> Result<()> {
+    if cfg.disable == Some(true) {
+        info!("honest check disabled");
+        return Ok(());
+    }
+    if !feature_enabled("por") {
+        info!("honest check off for this sku");
+        return Ok(());
+    }
+
+    let mut state = HonestState::default();
+
+    loop {
+        let success = honest_check(&cfg).await.unwrap_or_else(|e| {
+            warn!("{e}");
+            false
+        });
+        let cycle = state.update(&cfg, success, Utc::now());
+        debug!("honest state - {:?}", state);
+
+        /* a lost report is no reason to stop checking */
+        let payload = serde_json::to_string(&state)?;
+        if let Err(e) = set_message(
+            db_chan.clone(),
+            HONEST_STATE_KEY.to_string(),
+            payload.clone(),
+        )
+        .await
+        {
+            warn!("honest state set fail - {e}");
+        }
+        if let Some(ref shadow) = cfg.shadow {
+            if let Err(e) =
+                publish_message(&db_chan, format!("kap/aws/shadow/{}", shadow), payload).await
+            {
+                warn!("honest shadow publish fail - {e}");
+            }
+        }
+
+        tokio::select! {
+            _ = guard.stopped() => return Ok(()),
+            _ = time::sleep(cycle) => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_honest_cycle_switch() {
+    let cfg: RuleHonestConfig = toml::from_str(
+        r#"
+        ok_cycle = { secs = 3600, nanos = 0 }
+        fail_cycle = { secs = 60, nanos = 0 }
+        path = "/bin/true"
+        fail_threshold = 2
+        "#,
+    )
+    .unwrap();
+    let mut state = HonestState::default();
+    let now = Utc::now();
+
+    assert_eq!(state.update(&cfg, true, now).as_secs(), 3600);
+    assert_eq!(state.update(&cfg, false, now).as_secs(), 3600);
+    assert_eq!(state.update(&cfg, false, now).as_secs(), 60);
+    assert!(!state.healthy);
+    assert_eq!(state.score, 33);
+    assert_eq!(state.update(&cfg, true, now).as_secs(), 3600);
+    assert_eq!(state.consecutive_fail, 0);
+
+    /* a hung check is killed and counts as an error */
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("fika_honest_{}.sh", std::process::id()));
+    std::fs::write(&path, "#!/bin/sh\nsleep 5\n").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let cfg = RuleHonestConfig {
+        path: path.clone(),
+        timeout: Some(Duration::from_millis(100)),
+        ..cfg
+    };
+    let started = std::time::Instant::now();
+    assert!(honest_check(&cfg).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info, instrument, warn};
+
+use crate::kap_rule::RuleMonitorConfig;
+use crate::{publish_message, set_message, DbCommand, ShutdownGuard};
+
+/*
+ * self resource monitor
+ *
+ * every interval the daemon's own RSS, CPU share, threads and fds plus the
+ * system MemAvailable are set under MONITOR_STATE_KEY. tokio runtime
+ * metrics need tokio_unstable, so the runtime is seen through the timer
+ * lag of this loop and the task/subscribe runs in flight. Crossing
+ * rss_limit/mem_available_min flags pressure, released again 10% below,
+ * and with `throttle` task_run refuses new runs meanwhile. With `shadow`
+ * the state goes to kap/aws/shadow/{shadow} every shadow_every samples
+ * and on each pressure change.
+ */
+
+pub const MONITOR_STATE_KEY: &str = "kap/monitor/self";
+const MONITOR_INTERVAL: Duration = Duration::from_secs(60);
+const MONITOR_SHADOW_EVERY: u32 = 10;
+
+static THROTTLED: AtomicBool = AtomicBool::new(false);
+
+/* task_run asks before spawning */
+pub fn throttled() -> bool {
+    THROTTLED.load(Ordering::Relaxed)
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct MonitorState {
+    pub rss: u64,
+    pub rss_peak: u64,
+    pub cpu_percent: f64,
+    pub threads: u64,
+    pub fds: u64,
+    pub mem_total: u64,
+    pub mem_available: u64,
+    pub timer_lag_ms: u64,
+    pub active_tasks: usize,
+    pub pressure: bool,
+    pub throttled: bool,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/* `Name:   1234 kB` style lines of /proc/self/status and /proc/meminfo */
+fn proc_field(text: &str, name: &str) -> u64 {
+    text.lines()
+        .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|v| v.split_whitespace().next()?.parse().ok())
+        .unwrap_or_default()
+}
+
+/* VmRSS/VmHWM (kB) and Threads */
+fn status_parse(status: &str) -> (u64, u64, u64) {
+    (
+        proc_field(status, "VmRSS") * 1024,
+        proc_field(status, "VmHWM") * 1024,
+        proc_field(status, "Threads"),
+    )
+}
+
+fn meminfo_parse(meminfo: &str) -> (u64, u64) {
+    (
+        proc_field(meminfo, "MemTotal") * 1024,
+        proc_field(meminfo, "MemAvailable") * 1024,
+    )
+}
+
+/* utime+stime clock ticks, fields 14/15 counted after the `(comm)` */
+fn stat_cpu_ticks(stat: &str) -> Option<u64> {
+    let rest = &stat[stat.rfind(')')? + 2..];
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+impl MonitorState {
+    /* pressure sets at the limits, clears 10% inside them */
+    fn pressure_update(&mut self, cfg: &RuleMonitorConfig) -> bool {
+        let over = |margin: f64| {
+            cfg.rss_limit
+                .is_some_and(|l| self.rss as f64 > l as f64 * margin)
+                || cfg.mem_available_min.is_some_and(|m| {
+                    self.mem_available > 0 && (self.mem_available as f64) < m as f64 / margin
+                })
+        };
+        let pressure = if self.pressure { over(0.9) } else { over(1.0) };
+        let changed = pressure != self.pressure;
+        self.pressure = pressure;
+        self.throttled = pressure && cfg.throttle == Some(true);
+        changed
+    }
+}
+
+struct CpuSample {
+    ticks: u64,
+    at: Instant,
+}
+
+fn monitor_sample(state: &mut MonitorState, cpu: &mut Option<CpuSample>) -> Result<()> {
+    let status = std::fs::read_to_string("/proc/self/status")
+        .map_err(|e| anyhow!("/proc/self/status read fail - {e}"))?;
+    (state.rss, state.rss_peak, state.threads) = status_parse(&status);
+    (state.mem_total, state.mem_available) =
+        meminfo_parse(&std::fs::read_to_string("/proc/meminfo").unwrap_or_default());
+    state.fds = std::fs::read_dir("/proc/self/fd")
+        .map(|d| d.count() as u64)
+        .unwrap_or_default();
+    state.active_tasks = crate::kap_crash::active_task_count();
+
+    let ticks = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|s| stat_cpu_ticks(&s));
+    if let Some(ticks) = ticks {
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let now = Instant::now();
+        if let Some(ref last) = cpu {
+            let wall = now.duration_since(last.at).as_secs_f64();
+            if wall > 0.0 {
+                let busy = ticks.saturating_sub(last.ticks) as f64 / hz;
+                state.cpu_percent = (busy / wall * 1000.0).round() / 10.0;
+            }
+        }
+        *cpu = Some(CpuSample { ticks, at: now });
+    }
+    state.timestamp = Some(Utc::now());
+    Ok(())
+}
+
+#[instrument(name = "monitor", skip_all)]
+pub async fn monitor_start(
+    cfg: RuleMonitorConfig,
+    db_chan: mpsc::Sender<DbCommand>,
+    mut guard: ShutdownGuard,
+) -> Result<()> {
+    if cfg.disable == Some(true) {
+        info!("self monitor disabled");
+        return Ok(());
+    }
+
+    let interval = cfg.interval.unwrap_or(MONITOR_INTERVAL);
+    let shadow_every = cfg.shadow_every.unwrap_or(MONITOR_SHADOW_EVERY).max(1);
+    let mut state = MonitorState::default();
+    let mut cpu = None;
+    let mut samples: u32 = 0;
+
+    loop {
+        if let Err(e) = monitor_sample(&mut state, &mut cpu) {
+            warn!("{e}");
+        }
+        let changed = state.pressure_update(&cfg);
+        THROTTLED.store(state.throttled, Ordering::Relaxed);
+        if changed {
+            if state.pressure {
+                warn!(
+                    "memory pressure, rss {} available {}{}",
+                    state.rss,
+                    state.mem_available,
+                    if state.throttled {
+                        ", throttle tasks"
+                    } else {
+                        ""
+                    }
+                );
+            } else {
+                info!("memory pressure cleared, rss {}", state.rss);
+            }
+        }
+        debug!("monitor state - {:?}", state);
+
+        /* a lost sample is no reason to stop watching */
+        let payload = serde_json::to_string(&state)?;
+        if let Err(e) = set_message(
+            db_chan.clone(),
+            MONITOR_STATE_KEY.to_string(),
+            payload.clone(),
+        )
+        .await
+        {
+            warn!("monitor state set fail - {e}");
+        }
+        if let Some(ref shadow) = cfg.shadow {
+            if changed || samples.is_multiple_of(shadow_every) {
+                if let Err(e) =
+                    publish_message(&db_chan, format!("kap/aws/shadow/{}", shadow), payload).await
+                {
+                    warn!("monitor shadow publish fail - {e}");
+                }
+            }
+        }
+        samples = samples.wrapping_add(1);
+
+        let before = Instant::now();
+        tokio::select! {
+            _ = guard.stopped() => return Ok(()),
+            _ = time::sleep(interval) => {}
+        }
+        state.timer_lag_ms = before.elapsed().saturating_sub(interval).as_millis() as u64;
+    }
+}
+
+#[test]
+fn test_monitor_parse_and_pressure() {
+    let status = "Name:\tfika_manager\nVmHWM:\t   30000 kB\nVmRSS:\t   20000 kB\nThreads:\t7\n";
+    assert_eq!(status_parse(status), (20000 * 1024, 30000 * 1024, 7));
+    assert_eq!(
+        meminfo_parse("MemTotal:  262144 kB\nMemFree: 1 kB\nMemAvailable:   65536 kB\n"),
+        (262144 * 1024, 65536 * 1024)
+    );
+    let stat = "1234 (fika (mgr)) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 7 0";
+    assert_eq!(stat_cpu_ticks(stat), Some(300));
+
+    let cfg = RuleMonitorConfig {
+        rss_limit: Some(100),
+        throttle: Some(true),
+        ..Default::default()
+    };
+    let mut state = MonitorState {
+        rss: 95,
+        mem_available: 1000,
+        ..Default::default()
+    };
+    assert!(!state.pressure_update(&cfg));
+    state.rss = 101;
+    assert!(state.pressure_update(&cfg));
+    assert!(state.pressure && state.throttled);
+    state.rss = 95;
+    assert!(!state.pressure_update(&cfg));
+    state.rss = 89;
+    assert!(state.pressure_update(&cfg));
+    assert!(!state.throttled);
+
+    let cfg = RuleMonitorConfig {
+        mem_available_min: Some(100),
+        ..Default::default()
+    };
+    state.mem_available = 99;
+    assert!(state.pressure_update(&cfg));
+    assert!(state.pressure && !state.throttled);
+}
@@ -0,0 +1,429 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration};
+use tracing::{debug, info, instrument, warn};
+
+use crate::kap_rule::{RuleConfig, RuleNotifyConfig};
+use crate::{db_serve, publish_message, DbCommand, ShutdownGuard, MQTT_STATE_KEY};
+
+/*
+ * important events routed to the rule/notify sinks - a redis topic, a
+ * boss.endpoint called as webhook and `alarms` of a named shadow - so no
+ * shell glue per event. notify_init wires it once per process, one that
+ * never calls it notifies nobody. task_failed and offline are alarms,
+ * raised once and sent again `cleared` when the condition recovers
+ */
+
+pub const NOTIFY_TOPIC: &str = "kap/notify";
+const NOTIFY_TASK_FAILURES: u32 = 3;
+const NOTIFY_OFFLINE_AFTER: Duration = Duration::from_secs(600);
+const NOTIFY_OFFLINE_CHECK: Duration = Duration::from_secs(60);
+/* a db task waiting for redis does not hold the event up */
+const NOTIFY_DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyKind {
+    Provision,
+    CertRotated,
+    TaskFailed,
+    Offline,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifySeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Notification {
+    pub kind: NotifyKind,
+    pub severity: NotifySeverity,
+    pub message: String,
+    pub cleared: bool,
+    pub data: Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Notification {
+    pub fn new(
+        kind: NotifyKind,
+        severity: NotifySeverity,
+        message: impl Into<String>,
+        data: Value,
+    ) -> Self {
+        Self {
+            kind,
+            severity,
+            message: message.into(),
+            cleared: false,
+            data,
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn cleared(kind: NotifyKind, message: impl Into<String>, data: Value) -> Self {
+        Self {
+            cleared: true,
+            ..Self::new(kind, NotifySeverity::Info, message, data)
+        }
+    }
+
+    /*
+     * the shadow keeps standing alarms only, info and cleared remove it; a
+     * task alarm sits under task_failed/{topic} so clearing one topic leaves
+     * the others standing
+     */
+    fn alarm(&self) -> Value {
+        let mut alarm = if self.cleared || self.severity == NotifySeverity::Info {
+            Value::Null
+        } else {
+            json!(self)
+        };
+        if self.kind == NotifyKind::TaskFailed {
+            if let Some(topic) = self.data["topic"].as_str() {
+                alarm = json!({ topic: alarm });
+            }
+        }
+        let mut alarms = serde_json::Map::new();
+        alarms.insert(
+            serde_json::to_value(self.kind)
+                .ok()
+                .and_then(|k| k.as_str().map(|k| k.to_string()))
+                .unwrap_or_default(),
+            alarm,
+        );
+        json!({ "alarms": alarms })
+    }
+}
+
+#[cfg(feature = "boss-api")]
+type NotifyWebhook = (crate::BossClient, crate::web_api::RuleBossEndpoint);
+#[cfg(not(feature = "boss-api"))]
+type NotifyWebhook = ();
+
+struct Notifier {
+    cfg: RuleNotifyConfig,
+    db_chan: Option<mpsc::Sender<DbCommand>>,
+    webhook: Option<NotifyWebhook>,
+}
+
+static NOTIFIER: RwLock<Option<Arc<Notifier>>> = RwLock::new(None);
+static TASK_FAILURES: Mutex<Option<HashMap<String, u32>>> = Mutex::new(None);
+
+fn notifier() -> Option<Arc<Notifier>> {
+    NOTIFIER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(feature = "boss-api")]
+async fn notify_webhook(rule: &RuleConfig, name: &str) -> Result<NotifyWebhook> {
+    let cfg = crate::kap_daemon::KdaemonConfig::build_from(&rule.core.config).await?;
+    let mut boss = crate::BossClient::from_config(rule, &cfg)?;
+    boss.sign_from(&rule.boss, &rule.core.config).await?;
+    let ep = boss.endpoint(name)?;
+    Ok((boss, ep))
+}
+
+#[cfg(not(feature = "boss-api"))]
+async fn notify_webhook(_rule: &RuleConfig, _name: &str) -> Result<NotifyWebhook> {
+    Err(anyhow!("not support due boss-api feature disable"))
+}
+
+#[cfg(feature = "boss-api")]
+async fn webhook_send(webhook: &NotifyWebhook, n: &Notification) -> Result<()> {
+    let (boss, ep) = webhook;
+    boss.call_endpoint(ep, Some(json!(n))).await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "boss-api"))]
+async fn webhook_send(_webhook: &NotifyWebhook, _n: &Notification) -> Result<()> {
+    Ok(())
+}
+
+/* one kept connection behind a db task of our own, retried while redis is away */
+fn notify_db(database: &str) -> Result<mpsc::Sender<DbCommand>> {
+    let db = redis::Client::open(database).map_err(|e| anyhow!("db/redis open fail - {e}"))?;
+    let (db_chan, db_rx) = mpsc::channel(32);
+    let db_rx = Arc::new(tokio::sync::Mutex::new(db_rx));
+    tokio::spawn(async move {
+        while let Err(e) = db_serve(db.clone(), db_rx.clone(), None).await {
+            warn!("notify db fail, again in {:?} - {e}", NOTIFY_OFFLINE_CHECK);
+            time::sleep(NOTIFY_OFFLINE_CHECK).await;
+        }
+    });
+    Ok(db_chan)
+}
+
+/*
+ * a webhook that cannot be set up only drops that sink; the daemon hands
+ * its db task in, a tool without one gets its own on rule/core/database
+ */
+pub async fn notify_init(rule: &RuleConfig, db_chan: Option<mpsc::Sender<DbCommand>>) {
+    let notifier = match rule.notify {
+        Some(ref cfg) if cfg.disable != Some(true) => {
+            let webhook = match cfg.webhook {
+                Some(ref name) => match notify_webhook(rule, name).await {
+                    Ok(w) => Some(w),
+                    Err(e) => {
+                        warn!("notify webhook {} unavailable - {e}", name);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let db_chan = match (db_chan, rule.core.database.as_deref()) {
+                (Some(chan), _) => Some(chan),
+                (None, Some(database)) => notify_db(database)
+                    .map_err(|e| warn!("notify topic and shadow unavailable - {e}"))
+                    .ok(),
+                (None, None) => None,
+            };
+            Some(Arc::new(Notifier {
+                cfg: cfg.clone(),
+                db_chan,
+                webhook,
+            }))
+        }
+        _ => None,
+    };
+    *NOTIFIER.write().unwrap_or_else(|e| e.into_inner()) = notifier;
+}
+
+async fn notify_publish(
+    db_chan: &mpsc::Sender<DbCommand>,
+    topic: String,
+    payload: String,
+) -> Result<()> {
+    time::timeout(NOTIFY_DB_TIMEOUT, publish_message(db_chan, topic, payload))
+        .await
+        .map_err(|_| anyhow!("db no answer in {:?}", NOTIFY_DB_TIMEOUT))?
+}
+
+/* best effort on every sink */
+pub async fn notify(n: Notification) {
+    let notifier = match notifier() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    if let Some(ref events) = notifier.cfg.events {
+        if !events.contains(&n.kind) {
+            debug!("notify {:?} filtered", n.kind);
+            return;
+        }
+    }
+    info!("notify {:?} {:?} - {}", n.kind, n.severity, n.message);
+
+    if let Some(ref db_chan) = notifier.db_chan {
+        let topic = notifier.cfg.topic.as_deref().unwrap_or(NOTIFY_TOPIC);
+        if let Err(e) = notify_publish(db_chan, topic.to_string(), json!(n).to_string()).await {
+            warn!("notify topic {} fail - {e}", topic);
+        }
+        if let Some(ref shadow) = notifier.cfg.shadow {
+            let r = notify_publish(
+                db_chan,
+                format!("kap/aws/shadow/{}", shadow),
+                n.alarm().to_string(),
+            )
+            .await;
+            if let Err(e) = r {
+                warn!("notify shadow {} fail - {e}", shadow);
+            }
+        }
+    }
+    if let Some(ref webhook) = notifier.webhook {
+        if let Err(e) = webhook_send(webhook, &n).await {
+            warn!("notify webhook fail - {e}");
+        }
+    }
+}
+
+/* the alarm goes once at `threshold` failures in a row, cleared by the next success */
+fn task_failure_count(
+    failures: &mut HashMap<String, u32>,
+    topic: &str,
+    error: Option<&str>,
+    threshold: u32,
+) -> Option<Notification> {
+    match error {
+        Some(error) => {
+            let count = failures.entry(topic.to_string()).or_default();
+            *count += 1;
+            (*count == threshold).then(|| {
+                Notification::new(
+                    NotifyKind::TaskFailed,
+                    NotifySeverity::Warning,
+                    format!("{} failed {} times in a row - {}", topic, count, error),
+                    json!({ "topic": topic, "failures": *count, "error": error }),
+                )
+            })
+        }
+        None => match failures.remove(topic) {
+            Some(count) if count >= threshold => Some(Notification::cleared(
+                NotifyKind::TaskFailed,
+                format!("{} recovered after {} failures", topic, count),
+                json!({ "topic": topic, "failures": count }),
+            )),
+            _ => None,
+        },
+    }
+}
+
+pub async fn notify_task_result<T>(topic: &str, result: &Result<T>) {
+    let notifier = match notifier() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    let threshold = notifier
+        .cfg
+        .task_failures
+        .unwrap_or(NOTIFY_TASK_FAILURES)
+        .max(1);
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let n = {
+        let mut failures = TASK_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+        task_failure_count(
+            failures.get_or_insert_with(HashMap::new),
+            topic,
+            error.as_deref(),
+            threshold,
+        )
+    };
+    if let Some(n) = n {
+        notify(n).await;
+    }
+}
+
+/* MQTT_STATE_KEY as aws_iot sets it, no record yet counts from `started` */
+fn offline_since(state: Option<&str>, started: i64) -> Option<i64> {
+    let state = match state.and_then(|s| serde_json::from_str::<Value>(s).ok()) {
+        Some(state) => state,
+        None => return Some(started),
+    };
+    if state["state"] == "connected" {
+        None
+    } else {
+        Some(state["since"].as_i64().unwrap_or(started))
+    }
+}
+
+/* None for no record as for a failed read, both count as offline */
+async fn mqtt_state(db_chan: &mpsc::Sender<DbCommand>) -> Result<Option<String>> {
+    let (resp, rx) = oneshot::channel();
+    db_chan
+        .send(DbCommand::Get {
+            key: MQTT_STATE_KEY.to_string(),
+            resp,
+        })
+        .await?;
+    Ok(time::timeout(NOTIFY_DB_TIMEOUT, rx)
+        .await
+        .map_err(|_| anyhow!("db no answer in {:?}", NOTIFY_DB_TIMEOUT))??)
+}
+
+#[instrument(name = "notify::offline", skip_all)]
+pub async fn notify_offline_watch(mut guard: ShutdownGuard) -> Result<()> {
+    let notifier = notifier().ok_or_else(|| anyhow!("notify not configured"))?;
+    let db_chan = notifier
+        .db_chan
+        .clone()
+        .ok_or_else(|| anyhow!("rule/core/database none invalid"))?;
+    let after = notifier.cfg.offline_after.unwrap_or(NOTIFY_OFFLINE_AFTER);
+    let started = Utc::now().timestamp();
+    let mut raised = false;
+
+    let mut check = time::interval(NOTIFY_OFFLINE_CHECK.min(after));
+    loop {
+        tokio::select! {
+            _ = guard.stopped() => return Ok(()),
+            _ = check.tick() => {}
+        }
+        let state = match mqtt_state(&db_chan).await {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("{} read fail - {e}", MQTT_STATE_KEY);
+                continue;
+            }
+        };
+        let now = Utc::now().timestamp();
+        match offline_since(state.as_deref(), started) {
+            Some(since) if !raised && now - since >= after.as_secs() as i64 => {
+                raised = true;
+                notify(Notification::new(
+                    NotifyKind::Offline,
+                    NotifySeverity::Critical,
+                    format!("mqtt offline for {}s", now - since),
+                    json!({ "since": since, "state": state }),
+                ))
+                .await;
+            }
+            None if raised => {
+                raised = false;
+                notify(Notification::cleared(
+                    NotifyKind::Offline,
+                    "mqtt connected again",
+                    json!({ "state": state }),
+                ))
+                .await;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_notify_alarms() {
+    let mut failures = HashMap::new();
+    assert!(task_failure_count(&mut failures, "kap/task/a", Some("exit 1"), 3).is_none());
+    assert!(task_failure_count(&mut failures, "kap/task/a", Some("exit 1"), 3).is_none());
+    let n = task_failure_count(&mut failures, "kap/task/a", Some("exit 2"), 3).unwrap();
+    assert_eq!(
+        (n.kind, n.severity, n.cleared),
+        (NotifyKind::TaskFailed, NotifySeverity::Warning, false)
+    );
+    assert_eq!(n.data["failures"], 3);
+    assert_eq!(
+        n.alarm()["alarms"]["task_failed"]["kap/task/a"]["data"]["error"],
+        "exit 2"
+    );
+    assert!(task_failure_count(&mut failures, "kap/task/a", Some("exit 2"), 3).is_none());
+    /* a second topic failing stands beside the first, not over it */
+    for _ in 0..2 {
+        assert!(task_failure_count(&mut failures, "kap/task/c", Some("exit 3"), 3).is_none());
+    }
+    let n = task_failure_count(&mut failures, "kap/task/c", Some("exit 3"), 3).unwrap();
+    assert_eq!(
+        n.alarm()["alarms"]["task_failed"],
+        json!({ "kap/task/c": json!(n) })
+    );
+    let n = task_failure_count(&mut failures, "kap/task/a", None, 3).unwrap();
+    assert!(n.cleared);
+    assert_eq!(
+        n.alarm()["alarms"]["task_failed"],
+        json!({ "kap/task/a": null })
+    );
+    assert_eq!(failures.get("kap/task/c"), Some(&3));
+    assert!(task_failure_count(&mut failures, "kap/task/c", None, 3).is_some());
+    /* a success below the threshold clears silently */
+    assert!(task_failure_count(&mut failures, "kap/task/b", Some("exit 1"), 3).is_none());
+    assert!(task_failure_count(&mut failures, "kap/task/b", None, 3).is_none());
+    assert!(failures.is_empty());
+
+    assert_eq!(offline_since(None, 100), Some(100));
+    assert_eq!(
+        offline_since(Some(r#"{"state":"disconnected","since":42}"#), 100),
+        Some(42)
+    );
+    assert_eq!(
+        offline_since(Some(r#"{"state":"connected","since":42}"#), 100),
+        None
+    );
+}
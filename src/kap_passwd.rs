@@ -0,0 +1,323 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::{Args, Subcommand};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::kap_daemon::{KdaemonConfig, KDAEMON_SECRET};
+use crate::misc::write_atomic;
+use crate::setup_logging;
+
+/*
+ * network.password_overwrite to the system account: hashed here as
+ * SHA-512 crypt ($6$, fresh salt), handed to `chpasswd -e` or written into
+ * a shadow file, so the plaintext never reaches a command line. Once set
+ * the record file says who and when, and the field is cleared from the
+ * config; applied but not cleared is an error, the next run sets it again.
+ * The crypt is done here as sha-crypt/pwhash are not among the vendored
+ * crates, it is a few sha2 rounds checked against `openssl passwd -6`
+ */
+
+const PASSWD_RECORD: &str = "/userdata/.password_overwrite.info";
+const CRYPT_ROUNDS: usize = 5000;
+const CRYPT_ITOA64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/* byte order of the $6$ encoding, 3 bytes to 4 chars and the last to 2 */
+const CRYPT_ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42),
+    (22, 43, 1),
+    (44, 2, 23),
+    (3, 24, 45),
+    (25, 46, 4),
+    (47, 5, 26),
+    (6, 27, 48),
+    (28, 49, 7),
+    (50, 8, 29),
+    (9, 30, 51),
+    (31, 52, 10),
+    (53, 11, 32),
+    (12, 33, 54),
+    (34, 55, 13),
+    (56, 14, 35),
+    (15, 36, 57),
+    (37, 58, 16),
+    (59, 17, 38),
+    (18, 39, 60),
+    (40, 61, 19),
+    (62, 20, 41),
+];
+
+fn crypt_repeat(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().cycle().take(len).copied().collect()
+}
+
+fn crypt_b64(out: &mut String, w: u32, n: usize) {
+    let mut w = w;
+    for _ in 0..n {
+        out.push(CRYPT_ITOA64[(w & 0x3f) as usize] as char);
+        w >>= 6;
+    }
+}
+
+/* glibc crypt(3) SHA-512 with the default rounds, salt up to 16 chars */
+pub fn sha512_crypt(password: &str, salt: &str) -> String {
+    let key = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(16)];
+
+    let b = Sha512::new()
+        .chain_update(key)
+        .chain_update(salt)
+        .chain_update(key)
+        .finalize();
+    let mut ctx = Sha512::new().chain_update(key).chain_update(salt);
+    ctx.update(crypt_repeat(&b, key.len()));
+    let mut n = key.len();
+    while n > 0 {
+        if n & 1 == 1 {
+            ctx.update(b);
+        } else {
+            ctx.update(key);
+        }
+        n >>= 1;
+    }
+    let a = ctx.finalize();
+
+    let mut dp = Sha512::new();
+    key.iter().for_each(|_| dp.update(key));
+    let p = crypt_repeat(&dp.finalize(), key.len());
+    let mut ds = Sha512::new();
+    (0..16 + a[0] as usize).for_each(|_| ds.update(salt));
+    let s = crypt_repeat(&ds.finalize(), salt.len());
+
+    let mut c = a;
+    for i in 0..CRYPT_ROUNDS {
+        let mut ctx = Sha512::new();
+        if i & 1 == 1 {
+            ctx.update(&p);
+        } else {
+            ctx.update(c);
+        }
+        if i % 3 != 0 {
+            ctx.update(&s);
+        }
+        if i % 7 != 0 {
+            ctx.update(&p);
+        }
+        if i & 1 == 1 {
+            ctx.update(c);
+        } else {
+            ctx.update(&p);
+        }
+        c = ctx.finalize();
+    }
+
+    let mut out = format!("$6${}$", String::from_utf8_lossy(salt));
+    for (b2, b1, b0) in CRYPT_ORDER {
+        let w = (c[b2] as u32) << 16 | (c[b1] as u32) << 8 | c[b0] as u32;
+        crypt_b64(&mut out, w, 4);
+    }
+    crypt_b64(&mut out, c[63] as u32, 2);
+    out
+}
+
+fn crypt_salt() -> Result<String> {
+    let mut raw = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut raw)
+        .map_err(|_| anyhow!("password salt random fail"))?;
+    Ok(raw
+        .iter()
+        .map(|b| CRYPT_ITOA64[(b & 0x3f) as usize] as char)
+        .collect())
+}
+
+/* the hash and last change (days since epoch) of `user`, the rest kept */
+fn shadow_update(text: &str, user: &str, hash: &str, days: i64) -> Result<String> {
+    let mut found = false;
+    let mut lines = vec![];
+    for line in text.lines() {
+        let mut fields: Vec<String> = line.split(':').map(String::from).collect();
+        if fields[0] == user {
+            if fields.len() < 3 {
+                return Err(anyhow!("shadow entry of {} malformed", user));
+            }
+            fields[1] = hash.to_string();
+            fields[2] = days.to_string();
+            found = true;
+        }
+        lines.push(fields.join(":"));
+    }
+    if !found {
+        return Err(anyhow!("user {} not in shadow", user));
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+async fn chpasswd(user: &str, hash: &str) -> Result<()> {
+    let mut child = Command::new("chpasswd")
+        .arg("-e")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("chpasswd run fail - {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(format!("{}:{}\n", user, hash).as_bytes())
+            .await?;
+    }
+    let out = child.wait_with_output().await?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "chpasswd {} fail - {}",
+            user,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PasswdRecord {
+    pub user: String,
+    pub via: String,
+    pub at: DateTime<Utc>,
+}
+
+/* hashed onto `user` through `shadow` if given, else chpasswd */
+pub async fn passwd_set(user: &str, password: &str, shadow: Option<&str>) -> Result<PasswdRecord> {
+    if user.is_empty() || user.contains([':', '\n']) {
+        return Err(anyhow!("user {:?} invalid", user));
+    }
+    if password.is_empty() {
+        return Err(anyhow!("password of {} empty", user));
+    }
+    let hash = sha512_crypt(password, &crypt_salt()?);
+    let now = Utc::now();
+    let via = match shadow {
+        Some(path) => {
+            let text = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow!("{} read fail - {e}", path))?;
+            let days = now.timestamp().div_euclid(86400);
+            write_atomic(path, shadow_update(&text, user, &hash, days)?).await?;
+            path.to_string()
+        }
+        None => {
+            chpasswd(user, &hash).await?;
+            "chpasswd".to_string()
+        }
+    };
+    Ok(PasswdRecord {
+        user: user.to_string(),
+        via,
+        at: now,
+    })
+}
+
+#[derive(Subcommand, Debug)]
+enum PasswdCommand {
+    #[clap(about = "set network.password_overwrite on the account and clear it")]
+    Apply,
+    #[clap(about = "whether one is pending and the last applied")]
+    Status,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager system password from password_overwrite")]
+pub struct PasswdOpt {
+    #[clap(subcommand)]
+    command: PasswdCommand,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[clap(short = 's', long = "secret", default_value = KDAEMON_SECRET)]
+    secret: String,
+    #[clap(short = 'u', long = "user", default_value = "root")]
+    user: String,
+    #[clap(long = "shadow", help = "write this shadow file instead of chpasswd")]
+    shadow: Option<String>,
+    #[clap(long = "record", default_value = PASSWD_RECORD)]
+    record: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+pub async fn passwd_tools(opt: PasswdOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let mut cfg = KdaemonConfig::build_from_secret(&opt.config, &opt.secret).await?;
+    match opt.command {
+        PasswdCommand::Apply => {
+            let password = match cfg.network.password_overwrite.take() {
+                Some(password) => password,
+                None => {
+                    info!(
+                        "{} without password_overwrite, nothing to apply",
+                        opt.config
+                    );
+                    return Ok(());
+                }
+            };
+            let record = passwd_set(&opt.user, &password, opt.shadow.as_deref()).await?;
+            info!("password of {} set via {}", record.user, record.via);
+            if let Err(e) = write_atomic(&opt.record, serde_json::to_string_pretty(&record)?).await
+            {
+                warn!("password record fail - {e}");
+            }
+            cfg.save_secret(&opt.config, &opt.secret)
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "password of {} set, {} password_overwrite not cleared - {e}",
+                        opt.user,
+                        opt.config
+                    )
+                })?;
+            info!("{} password_overwrite cleared", opt.config);
+        }
+        PasswdCommand::Status => {
+            let record: Option<PasswdRecord> = match tokio::fs::read_to_string(&opt.record).await {
+                Ok(text) => serde_json::from_str(&text).ok(),
+                Err(_) => None,
+            };
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "pending": cfg.network.password_overwrite.is_some(),
+                    "applied": record,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_passwd_shadow() {
+    /* as the SHA-crypt specification and `openssl passwd -6` have them */
+    assert_eq!(
+        sha512_crypt("Hello world!", "saltstring"),
+        "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+    );
+    assert_eq!(
+        sha512_crypt("p", "abcdefghijklmnopq"),
+        "$6$abcdefghijklmnop$HLVlxtT/4mLydPU0OYqoP7DSzbUJTEChtJDdoLae2PXAl2JWMq4F3no94Hu8jLil/d/18.DE6jKSpQm/UZTwK/"
+    );
+    let salt = crypt_salt().unwrap();
+    assert_eq!(salt.len(), 16);
+    assert!(salt.bytes().all(|b| CRYPT_ITOA64.contains(&b)));
+
+    let shadow = "root:$1$old$x:19000:0:99999:7:::\nnobody:*:0:0:99999:7:::\n";
+    let text = shadow_update(shadow, "root", "$6$s$h", 19500).unwrap();
+    assert_eq!(
+        text,
+        "root:$6$s$h:19500:0:99999:7:::\nnobody:*:0:0:99999:7:::\n"
+    );
+    assert!(shadow_update(shadow, "admin", "$6$s$h", 19500).is_err());
+    assert!(shadow_update("root\n", "root", "$6$s$h", 19500).is_err());
+}
@@ -0,0 +1,382 @@
+use anyhow::{anyhow, Result};
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::Args;
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::activate::{activate_portal, ACTIVATE_PROGRESS_CHANNEL};
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_feature::features_init_cached;
+use crate::kap_rule::RuleConfig;
+use crate::{setup_logging_format, LogFormat};
+
+/*
+ * captive-portal setup flow: device info, network credentials into
+ * kdaemon.toml, activation kick-off and its progress relayed from redis as
+ * server-sent events. Bound to the AP interface; a --listen beyond it needs
+ * --token-file, the POST routes then want `Authorization: Bearer {token}`
+ * (the page takes it from its ?token= query)
+ */
+
+/* what the form may change, the rest of [network] stays as it is */
+const PORTAL_NETWORK_FIELDS: [&str; 5] = [
+    "wan_type",
+    "wan_username",
+    "wan_password",
+    "wifi_ssid",
+    "wifi_password",
+];
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "FIKA manager local activation portal")]
+pub struct PortalOpt {
+    #[clap(
+        long = "interface",
+        default_value = "br-lan",
+        help = "AP interface to bind"
+    )]
+    interface: String,
+    #[clap(long = "port", default_value = "80")]
+    port: u16,
+    #[clap(long = "listen", help = "bind here instead of the AP interface")]
+    listen: Option<SocketAddr>,
+    #[clap(
+        long = "token-file",
+        help = "POST routes require the token in this file"
+    )]
+    token_file: Option<String>,
+    #[clap(
+        short = 'p',
+        long = "activate-rule",
+        default_value = "/etc/fika_manager/activate.toml"
+    )]
+    active: String,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(long = "database", default_value = "redis://127.0.0.1:6379")]
+    database: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+    #[clap(
+        long = "log-format",
+        value_enum,
+        help = "default rule/core/log_format or text"
+    )]
+    log_format: Option<LogFormat>,
+}
+
+struct PortalState {
+    opt: PortalOpt,
+    token: Option<String>,
+    busy: AtomicBool,
+    result: Mutex<Option<Value>>,
+}
+
+struct PortalError(StatusCode, anyhow::Error);
+
+impl IntoResponse for PortalError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1.to_string() }))).into_response()
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for PortalError {
+    fn from(e: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.into())
+    }
+}
+
+type PortalResult<T> = std::result::Result<T, PortalError>;
+
+const PORTAL_INDEX: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width">
+<title>FIKA setup</title></head>
+<body>
+<h2>FIKA setup</h2>
+<pre id="info"></pre>
+<form id="net">
+<select name="wan_type"><option value="0">DHCP</option><option value="1">PPPoE</option></select>
+<input name="wan_username" placeholder="WAN username">
+<input name="wan_password" placeholder="WAN password" type="password">
+<input name="wifi_ssid" placeholder="Wi-Fi SSID">
+<input name="wifi_password" placeholder="Wi-Fi password" type="password">
+<button>Save</button>
+</form>
+<button id="go">Activate</button>
+<progress id="bar" value="0" max="1"></progress>
+<pre id="log"></pre>
+<script>
+const $ = (id) => document.getElementById(id);
+const token = new URLSearchParams(location.search).get("token");
+const auth = token ? {"Authorization": "Bearer " + token} : {};
+fetch("/api/info").then(r => r.json()).then(j => $("info").textContent = JSON.stringify(j, null, 2));
+$("net").onsubmit = (e) => {
+  e.preventDefault();
+  const cfg = Object.fromEntries(new FormData(e.target));
+  cfg.wan_type = Number(cfg.wan_type);
+  for (const k in cfg) if (cfg[k] === "") delete cfg[k];
+  fetch("/api/network", {method: "POST", headers: {...auth, "Content-Type": "application/json"}, body: JSON.stringify(cfg)})
+    .then(r => r.json()).then(j => $("log").textContent = JSON.stringify(j));
+};
+$("go").onclick = () => {
+  const es = new EventSource("/api/progress");
+  es.onmessage = (m) => { const p = JSON.parse(m.data); $("bar").max = p.total; $("bar").value = p.index;
+    $("log").textContent = p.section + "/" + p.step + " " + p.state + (p.error ? " " + p.error : ""); };
+  fetch("/api/activate", {method: "POST", headers: auth}).then(r => r.json())
+    .then(j => { es.close(); $("log").textContent = JSON.stringify(j, null, 2); });
+};
+</script>
+</body></html>
+"#;
+
+async fn portal_index() -> Html<&'static str> {
+    Html(PORTAL_INDEX)
+}
+
+async fn portal_info(State(st): State<Arc<PortalState>>) -> PortalResult<Json<Value>> {
+    let cfg = KdaemonConfig::build_from(&st.opt.config).await?;
+
+    Ok(Json(json!({
+        "mac_address": cfg.core.mac_address,
+        "serial_number": cfg.core.serial_number,
+        "sku": cfg.core.sku,
+        "wallet_address": cfg.core.wallet_address,
+        "network": {
+            "wan_type": cfg.network.wan_type,
+            "wifi_ssid": cfg.network.wifi_ssid,
+        },
+        "version": env!("CARGO_PKG_VERSION"),
+    })))
+}
+
+fn portal_auth(st: &PortalState, headers: &HeaderMap) -> PortalResult<()> {
+    let token = match st.token {
+        Some(ref token) => token,
+        None => return Ok(()),
+    };
+    let given = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes())
+        .map_err(|_| PortalError(StatusCode::UNAUTHORIZED, anyhow!("portal token invalid")))
+}
+
+/* the submitted fields over the current ones, others untouched */
+fn network_merge(current: &mut Value, submitted: &Value) -> Result<()> {
+    let submitted = submitted
+        .as_object()
+        .ok_or_else(|| anyhow!("network not object"))?;
+    for (k, v) in submitted {
+        if !PORTAL_NETWORK_FIELDS.contains(&k.as_str()) {
+            return Err(anyhow!("network.{} not settable here", k));
+        }
+        current[k.as_str()] = v.clone();
+    }
+    Ok(())
+}
+
+async fn portal_network(
+    State(st): State<Arc<PortalState>>,
+    headers: HeaderMap,
+    Json(submitted): Json<Value>,
+) -> PortalResult<Json<Value>> {
+    portal_auth(&st, &headers)?;
+    let mut cfg = KdaemonConfig::build_from(&st.opt.config).await?;
+    let mut network = serde_json::to_value(&cfg.network)?;
+    network_merge(&mut network, &submitted).map_err(|e| PortalError(StatusCode::BAD_REQUEST, e))?;
+    cfg.network = serde_json::from_value(network)
+        .map_err(|e| PortalError(StatusCode::BAD_REQUEST, e.into()))?;
+    cfg.save(&st.opt.config).await?;
+    info!("portal network saved into {}", &st.opt.config);
+
+    Ok(Json(json!({ "saved": true })))
+}
+
+/* on its own task, a client gone mid-way does not leave it half-run or busy */
+async fn portal_activate(
+    State(st): State<Arc<PortalState>>,
+    headers: HeaderMap,
+) -> PortalResult<Json<Value>> {
+    portal_auth(&st, &headers)?;
+    if st.busy.swap(true, Ordering::SeqCst) {
+        return Err(PortalError(
+            StatusCode::CONFLICT,
+            anyhow!("activation already running"),
+        ));
+    }
+
+    let task = st.clone();
+    let run = tokio::spawn(async move {
+        let opt = &task.opt;
+        let r = activate_portal(&opt.active, &opt.config, &opt.rule).await;
+        let result = match r {
+            Ok(ref result) => result.clone(),
+            Err(ref e) => json!({ "error": e.to_string() }),
+        };
+        *task.result.lock().await = Some(result);
+        task.busy.store(false, Ordering::SeqCst);
+        r
+    });
+
+    Ok(Json(run.await??))
+}
+
+async fn portal_result(State(st): State<Arc<PortalState>>) -> Json<Value> {
+    Json(st.result.lock().await.clone().unwrap_or(Value::Null))
+}
+
+async fn portal_progress(
+    State(st): State<Arc<PortalState>>,
+) -> PortalResult<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let mut sub = redis::Client::open(st.opt.database.as_str())?
+        .get_async_connection()
+        .await?
+        .into_pubsub();
+    sub.subscribe(ACTIVATE_PROGRESS_CHANNEL).await?;
+
+    let stream = sub.into_on_message().map(|msg| {
+        let payload: String = msg.get_payload().unwrap_or_default();
+        Ok(Event::default().data(payload))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/* first IPv4 address of the interface */
+fn interface_addr(name: &str) -> Result<IpAddr> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(anyhow!(
+            "getifaddrs fail - {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let mut found = None;
+    let mut cur = addrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        let ifname = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        if !ifa.ifa_addr.is_null()
+            && ifname.to_bytes() == name.as_bytes()
+            && unsafe { (*ifa.ifa_addr).sa_family } as i32 == libc::AF_INET
+        {
+            let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+            found = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                sin.sin_addr.s_addr,
+            ))));
+            break;
+        }
+        cur = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    found.ok_or_else(|| anyhow!("interface {} without ipv4 address", name))
+}
+
+pub async fn portal_tools(opt: PortalOpt) -> Result<()> {
+    let rule = RuleConfig::build_from(&opt.rule).await;
+    let format = match rule {
+        Ok(ref rule) => opt.log_format.or(rule.core.log_format),
+        Err(_) => opt.log_format,
+    };
+    setup_logging_format(&opt.log_level, format.unwrap_or_default())?;
+
+    /* no rule yet is no reason to keep a new device from activation */
+    match rule {
+        Ok(rule) => {
+            /* boss may be what this device cannot reach yet */
+            let features = features_init_cached(&rule).await;
+            if !features.enabled("portal") {
+                return Err(anyhow!("portal off for sku {}", features.sku));
+            }
+        }
+        Err(e) => warn!("{} unread, portal feature unchecked - {e}", opt.rule),
+    }
+
+    let token = match opt.token_file {
+        Some(ref path) => {
+            let token = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow!("portal token {} read fail - {e}", path))?;
+            let token = token.trim().to_string();
+            if token.is_empty() {
+                return Err(anyhow!("portal token {} empty", path));
+            }
+            Some(token)
+        }
+        None => None,
+    };
+    let listen = match opt.listen {
+        Some(listen) if token.is_none() && !listen.ip().is_loopback() => {
+            return Err(anyhow!(
+                "portal on {} without --token-file, bind the AP interface instead",
+                listen
+            ))
+        }
+        Some(listen) => listen,
+        None => SocketAddr::new(interface_addr(&opt.interface)?, opt.port),
+    };
+    let state = Arc::new(PortalState {
+        opt,
+        token,
+        busy: AtomicBool::new(false),
+        result: Mutex::new(None),
+    });
+
+    let app = Router::new()
+        .route("/", get(portal_index))
+        .route("/api/info", get(portal_info))
+        .route("/api/network", post(portal_network))
+        .route("/api/activate", post(portal_activate))
+        .route("/api/result", get(portal_result))
+        .route("/api/progress", get(portal_progress))
+        .with_state(state);
+
+    info!("portal listen on {}", listen);
+    axum::Server::bind(&listen)
+        .serve(app.into_make_service())
+        .await
+        .map_err(|e| {
+            warn!("portal serve fail - {e}");
+            anyhow!("portal serve fail - {e}")
+        })
+}
+
+#[test]
+fn test_portal_network_merge() {
+    let mut network = json!({
+        "wan_type": 1,
+        "wan_username": "isp",
+        "wan_password": "secret",
+        "wifi_ssid": "fika",
+        "wifi_password": "hunter22",
+        "password_overwrite": null,
+    });
+    network_merge(&mut network, &json!({ "wifi_ssid": "fika-home" })).unwrap();
+    assert_eq!(network["wifi_ssid"], "fika-home");
+    assert_eq!(network["wan_password"], "secret");
+    assert_eq!(network["wifi_password"], "hunter22");
+
+    assert!(network_merge(&mut network, &json!({ "password_overwrite": "x" })).is_err());
+    assert!(network_merge(&mut network, &json!(["wifi_ssid"])).is_err());
+    assert!(interface_addr("lo").unwrap().is_loopback());
+}
@@ -1,10 +1,21 @@
 use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::{Args, Subcommand, ValueEnum};
+use colored_json::to_colored_json_auto;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use tokio::fs;
 use tokio::time::Duration;
+use tracing::warn;
 
-use crate::RuleConfigTask;
+use crate::activate::activate_template;
+use crate::kap_audit::redact;
+use crate::kap_daemon::{url_check, ConfigInvalid, ConfigViolation, KdaemonConfig};
+use crate::kap_notify::NotifyKind;
+use crate::web_api::{CurlClientConfig, RuleBossEndpoint, RuleOAuthConfig, BOSS_HCS_QUEUE_MAX_AGE};
+use crate::{setup_logging, LogFormat, RuleConfigTask};
 #[cfg(feature = "aws-iot")]
 use {
     crate::aws_iot::{RuleAwsIotDedicatedConfig, RuleAwsIotProvisionConfig},
@@ -19,7 +30,15 @@ pub struct RuleConfig {
     pub boss: RuleConfigBoss,
     pub subscribe: Option<Vec<RuleConfigSubscribe>>,
     pub task: Option<Vec<RuleConfigTask>>,
+    pub oauth: Option<Vec<RuleOAuthConfig>>,
     pub honest: Option<RuleHonestConfig>,
+    pub chain: Option<RuleChainConfig>,
+    pub api: Option<RuleApiConfig>,
+    pub monitor: Option<RuleMonitorConfig>,
+    pub notify: Option<RuleNotifyConfig>,
+    pub cfgsync: Option<RuleCfgSyncConfig>,
+    pub wifi: Option<RuleWifiConfig>,
+    pub features: Option<RuleFeaturesConfig>,
     pub aws: RuleAwsIotConfig,
 }
 
@@ -29,6 +48,31 @@ impl RuleConfig {
         self.boss.mirrow_default()?;
         self.aws.mirrow_default()?;
 
+        for task in self.task.iter_mut().flatten() {
+            if task.builtin == Some(RuleTaskBuiltin::Clock) {
+                let clock = task.clock.get_or_insert_with(RuleTaskClock::default);
+                if clock.http_url.is_none() {
+                    clock.http_url = self.boss.root_url.clone();
+                }
+                if clock.client.is_none() {
+                    clock.client = self.boss.client.clone();
+                }
+            }
+        }
+        if let Some(ref chain) = self.chain {
+            for task in self.task.iter_mut().flatten() {
+                if task.builtin == Some(RuleTaskBuiltin::Txwatch) || task.txwatch.is_some() {
+                    let txwatch = task.txwatch.get_or_insert_with(RuleTaskTxwatch::default);
+                    if txwatch.rpc_url.is_none() {
+                        txwatch.rpc_url = chain.rpc_url.clone();
+                    }
+                    if txwatch.confirmations.is_none() {
+                        txwatch.confirmations = chain.confirmations;
+                    }
+                }
+            }
+        }
+
         Ok(self)
     }
 
@@ -39,6 +83,65 @@ impl RuleConfig {
             Err(e) => Err(anyhow!("rule format invalid - {:?}", e)),
         }
     }
+
+    /*
+     * swap in what a running daemon re-reads (task/subscribe/honest/api,
+     * features and core.log_level), returns the changed ones; other sections are wired at
+     * start and only warn
+     */
+    pub fn reload_sections(&mut self, fresh: RuleConfig) -> Vec<&'static str> {
+        fn changed<T: Serialize>(a: &T, b: &T) -> bool {
+            serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+        }
+        let mut reloaded = vec![];
+
+        if changed(&self.task, &fresh.task) {
+            self.task = fresh.task;
+            reloaded.push("task");
+        }
+        if changed(&self.subscribe, &fresh.subscribe) {
+            self.subscribe = fresh.subscribe;
+            reloaded.push("subscribe");
+        }
+        if changed(&self.honest, &fresh.honest) {
+            self.honest = fresh.honest;
+            reloaded.push("honest");
+        }
+        if changed(&self.api, &fresh.api) {
+            self.api = fresh.api;
+            reloaded.push("api");
+        }
+        /* read by each `wifi` run, nothing held */
+        if changed(&self.wifi, &fresh.wifi) {
+            self.wifi = fresh.wifi;
+            reloaded.push("wifi");
+        }
+        /* re-resolved by reload_listen, asked where used */
+        if changed(&self.features, &fresh.features) {
+            self.features = fresh.features;
+            reloaded.push("features");
+        }
+        if self.core.log_level != fresh.core.log_level {
+            self.core.log_level = fresh.core.log_level.clone();
+            reloaded.push("core.log_level");
+        }
+
+        for (name, differs) in [
+            ("core", changed(&self.core, &fresh.core)),
+            ("boss", changed(&self.boss, &fresh.boss)),
+            ("oauth", changed(&self.oauth, &fresh.oauth)),
+            ("chain", changed(&self.chain, &fresh.chain)),
+            ("monitor", changed(&self.monitor, &fresh.monitor)),
+            ("notify", changed(&self.notify, &fresh.notify)),
+            ("cfgsync", changed(&self.cfgsync, &fresh.cfgsync)),
+            ("aws", changed(&self.aws, &fresh.aws)),
+        ] {
+            if differs {
+                warn!("rule/{} changed, restart to apply", name);
+            }
+        }
+        reloaded
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -47,6 +150,22 @@ pub struct RuleConfigCore {
     pub thirdparty: String,
     pub database: Option<String>,
     pub config: String,
+    pub task_log_dir: Option<PathBuf>,
+    pub task_log_size: Option<u64>,
+    pub task_log_keep: Option<u32>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub log_file: Option<RuleLogFile>,
+    pub syslog: Option<RuleSyslogConfig>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_size: Option<u64>,
+    pub audit_log_keep: Option<u32>,
+    pub audit_stream: Option<String>,
+    pub audit_stream_maxlen: Option<usize>,
+    pub crash_dir: Option<PathBuf>,
+    pub boot_shadow: Option<String>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub shutdown_grace: Option<Duration>,
 }
 
 impl RuleConfigCore {
@@ -56,17 +175,103 @@ impl RuleConfigCore {
         if self.database.is_none() {
             self.database = def.database;
         }
+        if self.task_log_dir.is_none() {
+            self.task_log_dir = def.task_log_dir;
+        }
+        if self.task_log_size.is_none() {
+            self.task_log_size = def.task_log_size;
+        }
+        if self.task_log_keep.is_none() {
+            self.task_log_keep = def.task_log_keep;
+        }
+        if self.audit_log.is_none() {
+            self.audit_log = def.audit_log;
+        }
+        if self.audit_log_size.is_none() {
+            self.audit_log_size = def.audit_log_size;
+        }
+        if self.audit_log_keep.is_none() {
+            self.audit_log_keep = def.audit_log_keep;
+        }
+        if self.audit_stream.is_none() {
+            self.audit_stream = def.audit_stream;
+        }
+        if self.crash_dir.is_none() {
+            self.crash_dir = def.crash_dir;
+        }
+        if let Some(ref mut syslog) = self.syslog {
+            if syslog.facility.is_none() {
+                syslog.facility = Some("daemon".to_string());
+            }
+            if syslog.app_name.is_none() {
+                syslog.app_name = Some("fika_manager".to_string());
+            }
+        }
+        if let Some(ref mut log_file) = self.log_file {
+            let def = RuleLogFile::default();
+            if log_file.size.is_none() {
+                log_file.size = def.size;
+            }
+            if log_file.keep.is_none() {
+                log_file.keep = def.keep;
+            }
+        }
 
         Ok(())
     }
 }
 
+/* journald is volatile on the device, keep a rotated copy on flash */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RuleLogFile {
+    pub path: PathBuf,
+    pub size: Option<u64>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub age: Option<Duration>,
+    pub keep: Option<u32>,
+}
+
+impl Default for RuleLogFile {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/userdata/log/fika_manager.log"),
+            size: Some(1024 * 1024),
+            age: None,
+            keep: Some(3),
+        }
+    }
+}
+
+/* RFC5424 forwarding, url = "udp://host:514", "tcp://host:601" or "tls://host:6514" */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RuleSyslogConfig {
+    pub url: String,
+    pub facility: Option<String>,
+    pub app_name: Option<String>,
+    pub cacert: Option<PathBuf>,
+}
+
 impl Default for RuleConfigCore {
     fn default() -> Self {
         Self {
             thirdparty: "longdong2".to_string(),
             database: Some("redis://127.0.0.1:6379".to_string()),
             config: "/userdata/kdaemon.toml".to_string(),
+            task_log_dir: Some(PathBuf::from("/var/log/fika_manager")),
+            task_log_size: Some(64 * 1024),
+            task_log_keep: Some(2),
+            log_level: None,
+            log_format: None,
+            log_file: None,
+            syslog: None,
+            audit_log: None,
+            audit_log_size: Some(256 * 1024),
+            audit_log_keep: Some(8),
+            audit_stream: Some("kap/audit".to_string()),
+            audit_stream_maxlen: None,
+            crash_dir: Some(PathBuf::from("/userdata/crash")),
+            boot_shadow: None,
+            shutdown_grace: None,
         }
     }
 }
@@ -80,6 +285,19 @@ pub struct RuleConfigBoss {
     pub hcs_path: Option<String>,
     pub ap_hcs_path: Option<String>,
     pub ap_info_path: Option<String>,
+    pub hcs_queue_max_age: Option<u64>,
+    pub client: Option<CurlClientConfig>,
+    pub endpoint: Option<Vec<RuleBossEndpoint>>,
+    pub sign: Option<RuleBossSign>,
+}
+
+/* wallet signed BOSS requests (wallet feature) */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct RuleBossSign {
+    pub keystore: Option<String>,
+    pub dir: Option<String>,
+    pub password_file: Option<String>,
 }
 
 impl RuleConfigBoss {
@@ -104,6 +322,9 @@ impl RuleConfigBoss {
         if self.ap_info_path.is_none() {
             self.ap_info_path = def.ap_info_path;
         }
+        if self.hcs_queue_max_age.is_none() {
+            self.hcs_queue_max_age = def.hcs_queue_max_age;
+        }
 
         Ok(())
     }
@@ -118,6 +339,10 @@ impl Default for RuleConfigBoss {
             hcs_path: Some("v0/hcs/pair".to_string()),
             ap_hcs_path: Some("v0/ap/hcs".to_string()),
             ap_info_path: Some("v0/ap/info".to_string()),
+            hcs_queue_max_age: Some(BOSS_HCS_QUEUE_MAX_AGE),
+            client: None,
+            endpoint: None,
+            sign: None,
         }
     }
 }
@@ -127,24 +352,170 @@ impl Default for RuleConfigBoss {
 pub struct RuleConfigSubscribe {
     pub topic: String,
     pub path: PathBuf,
+    pub args: Option<RuleSubscribeArgs>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleTaskBuiltin {
+    Sysinfo,
+    Netif,
+    Disk,
+    Txwatch,
+    Clock,
+}
+
+/* builtin = "txwatch", rpc_url/confirmations from [chain] when unset */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleTaskTxwatch {
+    pub list: Option<String>,
+    pub topic: Option<String>,
+    pub rpc_url: Option<String>,
+    pub confirmations: Option<usize>,
+}
+
+/*
+ * builtin = "clock", NTP first, Date header of http_url as fallback;
+ * http_url and client from [boss] root_url and [boss.client] when unset
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleTaskClock {
+    pub server: Option<String>,
+    pub http_url: Option<String>,
+    pub client: Option<CurlClientConfig>,
+    pub shadow: Option<String>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub timeout: Option<Duration>,
+}
+
+/* kap_feature flags by core.sku, a `sku` key may end in * to match a prefix */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleFeaturesConfig {
+    pub default: Option<BTreeMap<String, bool>>,
+    pub sku: Option<BTreeMap<String, BTreeMap<String, bool>>>,
+    pub endpoint: Option<String>,
+    pub cache: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleTaskTruncate {
+    Head,
+    Tail,
+    Both,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSubscribeArgs {
+    Argv,
+    Stdin,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(dead_code)]
 pub struct RuleHonestConfig {
+    #[serde(deserialize_with = "crate::misc::de_duration")]
     pub ok_cycle: Duration,
+    #[serde(deserialize_with = "crate::misc::de_duration")]
     pub fail_cycle: Duration,
     pub path: PathBuf,
     pub disable: Option<bool>,
+    pub fail_threshold: Option<u32>,
+    pub shadow: Option<String>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub timeout: Option<Duration>,
+}
+
+/* local control API, keys as is or prefix*; tasks by topic, all when unset */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleApiConfig {
+    pub db_read: Option<Vec<String>>,
+    pub db_write: Option<Vec<String>>,
+    pub tasks: Option<Vec<String>>,
+}
+
+/* own RSS/CPU sampling; pressure when rss_limit or mem_available_min (bytes) is crossed */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleMonitorConfig {
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub interval: Option<Duration>,
+    pub rss_limit: Option<u64>,
+    pub mem_available_min: Option<u64>,
+    pub throttle: Option<bool>,
+    pub shadow: Option<String>,
+    pub shadow_every: Option<u32>,
+    pub disable: Option<bool>,
+}
+
+/* kdaemon.toml and redis `keys` reported to the shadow when they change */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleCfgSyncConfig {
+    pub shadow: Option<String>,
+    pub keys: Option<String>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub interval: Option<Duration>,
+    pub redact: Option<Vec<String>>,
+    pub disable: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleWifiBackend {
+    Uci,
+    Script,
+}
+
+/* how kap_wifi puts network.wifi_* on the radio and tells it came up */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleWifiConfig {
+    pub backend: Option<RuleWifiBackend>,
+    pub apply: Option<String>,
+    pub verify: Option<String>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub timeout: Option<Duration>,
+}
+
+/* routing of kap_notify events, every kind unless `events` is given */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleNotifyConfig {
+    pub events: Option<Vec<NotifyKind>>,
+    pub topic: Option<String>,
+    pub webhook: Option<String>,
+    pub shadow: Option<String>,
+    pub task_failures: Option<u32>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub offline_after: Option<Duration>,
+    pub disable: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RuleChainConfig {
+    pub rpc_url: Option<String>,
+    pub chain_id: Option<u64>,
+    pub explorer_url: Option<String>,
+    pub confirmations: Option<usize>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(not(any(feature = "aws-cli", feature = "aws-iot")), derive(Default))]
 #[allow(dead_code)]
 pub struct RuleAwsIotConfig {
     #[cfg(feature = "aws-cli")]
     pub root_url: Option<String>,
     #[cfg(feature = "aws-cli")]
     pub device_path: Option<String>,
+    #[cfg(feature = "aws-cli")]
+    pub client: Option<CurlClientConfig>,
 
     #[cfg(feature = "aws-iot")]
     pub endpoint: Option<String>,
@@ -199,7 +570,7 @@ impl RuleAwsIotConfig {
     }
 
     #[cfg(feature = "aws-iot")]
-    pub fn thing_name(&self, postfix: &str) -> Result<String> {
+    pub fn thing_name(&self, mac: &crate::kap_daemon::MacAddress) -> Result<String> {
         let thing = if let Some(ref thing) = self.dedicated.thing {
             thing.clone()
         } else {
@@ -209,7 +580,7 @@ impl RuleAwsIotConfig {
                 "Fake"
             };
 
-            format!("{}_{}", prefix, postfix.to_lowercase().replace(":", ""))
+            format!("{}_{}", prefix, mac.compact())
         };
         Ok(thing)
     }
@@ -234,6 +605,8 @@ impl Default for RuleAwsIotConfig {
             ),
             #[cfg(feature = "aws-cli")]
             device_path: Some("prod/api/v1/devices".to_string()),
+            #[cfg(feature = "aws-cli")]
+            client: None,
 
             #[cfg(feature = "aws-iot")]
             provision: None,
@@ -242,3 +615,845 @@ impl Default for RuleAwsIotConfig {
         }
     }
 }
+
+const DRY_RUN_SCHEDULE_COUNT: u32 = 3;
+
+const RULE_COMMENTS: &[(&str, &str)] = &[
+    ("core.thirdparty", "OEM/third-party identifier"),
+    (
+        "core.database",
+        "redis connection shared by daemon and tools",
+    ),
+    ("core.config", "kdaemon configuration written by activation"),
+    (
+        "core.task_log_dir",
+        "task/subscribe stderr, one {topic}.log per topic",
+    ),
+    (
+        "core.task_log_size",
+        "rotate a task log beyond this many bytes",
+    ),
+    ("core.task_log_keep", "rotated task logs kept"),
+    (
+        "core.log_level",
+        "log filter applied on SIGHUP/kap/ctrl/reload, e.g. info,fika_utils::kap_task=debug",
+    ),
+    (
+        "core.log_format",
+        "text or json (one object per line, span fields included)",
+    ),
+    (
+        "core.log_file.path",
+        "log also into this file, survives reboot",
+    ),
+    (
+        "core.log_file.size",
+        "rotate the log file beyond this many bytes",
+    ),
+    (
+        "core.log_file.age",
+        "rotate the log file once older than this, e.g. \"1d\"",
+    ),
+    ("core.log_file.keep", "rotated log files kept"),
+    (
+        "core.syslog.url",
+        "remote syslog collector, udp://, tcp:// or tls://host[:port]",
+    ),
+    (
+        "core.syslog.facility",
+        "syslog facility name, daemon or local0..7",
+    ),
+    ("core.syslog.app_name", "RFC5424 APP-NAME"),
+    (
+        "core.syslog.cacert",
+        "CA of a tls:// collector, bundled web roots when unset",
+    ),
+    (
+        "core.audit_log",
+        "record of every desired state handed to a subscribe script, off unless set",
+    ),
+    (
+        "core.audit_log_size",
+        "rotate the audit log beyond this many bytes",
+    ),
+    (
+        "core.audit_log_keep",
+        "rotated audit logs kept, the oldest beyond this is deleted",
+    ),
+    (
+        "core.audit_stream",
+        "redis stream (XADD) of the same records",
+    ),
+    (
+        "core.audit_stream_maxlen",
+        "approximate MAXLEN of the audit stream, older entries are dropped, unbounded when unset",
+    ),
+    (
+        "core.crash_dir",
+        "panic reports (backtrace, last log lines, running tasks), newest 5 kept",
+    ),
+    (
+        "core.boot_shadow",
+        "named shadow also getting the kap/status/boot bring-up report, e.g. name/boot",
+    ),
+    (
+        "core.shutdown_grace",
+        "how long a stop waits for the loops to leave, 10s when unset",
+    ),
+    (
+        "boss.root_url",
+        "BOSS backend root, paths below are relative to it",
+    ),
+    ("boss.otp_path", "GetOtp endpoint"),
+    ("boss.ap_token_path", "GetApToken endpoint"),
+    ("boss.hcs_path", "GetHcs endpoint"),
+    ("boss.ap_hcs_path", "PostApHcs endpoint"),
+    ("boss.ap_info_path", "GetApInfo endpoint"),
+    (
+        "boss.hcs_queue_max_age",
+        "seconds an offline-queued PostApHcs is kept for replay",
+    ),
+    ("aws.root_url", "AWS device API root (aws-cli)"),
+    ("aws.device_path", "AWS device list path (aws-cli)"),
+    ("aws.endpoint", "AWS/IoT ATS endpoint"),
+    ("aws.port", "AWS/IoT MQTT port"),
+    ("aws.dedicated.ca", "root CA of the production connection"),
+    (
+        "aws.dedicated.cert",
+        "production certificate written by fleet provision",
+    ),
+    (
+        "aws.dedicated.private",
+        "production private key written by fleet provision",
+    ),
+    ("aws.provision.ca", "root CA of the claim connection"),
+    ("aws.provision.cert", "bootstrap/claim certificate"),
+    ("aws.provision.private", "bootstrap/claim private key"),
+    ("aws.provision.template", "fleet provisioning template name"),
+    (
+        "aws.provision.thing_prefix",
+        "thing name prefix, thing = {prefix}_{mac}",
+    ),
+    (
+        "aws.provision.wipe",
+        "claim cert/key after provision: keep, delete or shred (overwrite+delete)",
+    ),
+];
+
+const RULE_EXAMPLES: &str = r#"
+# log also into a file rotated by size and/or age, journald is lost on reboot
+# [core.log_file]
+# path = "/userdata/log/fika_manager.log"
+# size = 1048576
+# age = "1d"
+# keep = 3
+
+# forward logs to a remote syslog collector (RFC5424)
+# [core.syslog]
+# url = "tls://syslog.example.com:6514" # or udp://host:514, tcp://host:601
+# facility = "local0"
+# app_name = "fika_manager"
+# cacert = "/etc/fika_manager/syslog-ca.pem"
+
+# private BOSS/AWS deployments or proxies, same keys as curl --cacert/--cert/--key/--proxy
+# [boss.client]
+# cacert = "/etc/fika_manager/boss-ca.pem"
+# cert = "/userdata/boss-client.pem"
+# key = "/userdata/boss-client.key"
+# proxy = "socks5://10.0.0.1:1080"
+# no_proxy = true
+
+# sign the BOSS requests of these tools with the device wallet (wallet
+# feature; the daemon's own BOSS client does not sign),
+# X-AP-SIGNATURE = personal_sign("{X-AP-TIMESTAMP}\n{path}\n{sha256(body) hex}")
+# [boss.sign]
+# keystore = "/userdata/wallet/<uuid>" # or the one of core.wallet_address under dir
+# dir = "/userdata/wallet"
+# password_file = "/etc/fika_manager/wallet.pass" # or "secret:" (device secret), "keyring:/etc/fika_manager/keyring#wallet"
+
+# BOSS endpoints without a dedicated command, `boss call ap_status`
+# [[boss.endpoint]]
+# name = "ap_status"
+# path = "v0/ap/status"
+# method = "get" # or "post"
+# ap_token = true # send ACCESSTOKEN-AP
+# wallet = "query" # ap_wallet as query, "json" into -J data, or "none"
+# field = "data" # return only this field of the reply
+
+# partner backends, `curl ... --oauth partner` or `oauth partner`
+# [[oauth]]
+# name = "partner"
+# token_url = "https://auth.example.com/oauth2/token"
+# client_id = "fika-ap"
+# client_secret = "secret"
+# scope = "device"
+# cache = "/tmp/oauth_partner.json" # redis core.database when unset
+
+# chain of the wallet subcommands, --rpc-url overrides rpc_url
+# [chain]
+# rpc_url = "https://rpc.example.com"
+# chain_id = 137 # queried from rpc_url when unset
+# explorer_url = "https://polygonscan.com"
+# confirmations = 3 # blocks a --wait transaction waits for
+
+# [[subscribe]]
+# topic = "aws/kap/shadow/name/example/state"
+# path = "/etc/fika_manager/subscribe_example.sh"
+# {"topic","payload","timestamp","version"} json as argv[1] or on stdin
+# args = "argv"
+
+# cloud triggered re-activation, the script runs `activate remote "$1"`
+# [[subscribe]]
+# topic = "aws/kap/shadow/name/activate/state"
+# path = "/etc/fika_manager/activate_remote.sh"
+
+# [[task]]
+# topic = "kap/task/example"
+# exactly one of path/command/inline_sh/builtin
+# path = "/etc/fika_manager/task_example.sh"
+# command = ["/usr/bin/curl", "-s", "http://127.0.0.1"]
+# inline_sh = "cat /proc/loadavg"
+# builtin = "sysinfo" # or "netif", "disk", "txwatch", "clock"
+# durations as "90s", "10m", "1d2h", "PT6H", seconds or { secs = 600, nanos = 0 }
+# start_at = "10s"
+# period = "10m"
+# run on change instead of/besides period
+# watch = ["/var/run/dhcp.leases"]
+# debounce = "1s"
+# captured stdout limit in bytes, keep head/tail/both around the marker
+# output_limit = 4096
+# truncate = "head"
+# db_publish = false
+# db_set = true
+# aws_publish = false
+
+# receipts of the tx hashes pushed to list, one event per confirmed/failed tx
+# [[task]]
+# topic = "kap/task/txwatch"
+# builtin = "txwatch"
+# period = "30s"
+# [task.txwatch]
+# list = "kap/wallet/tx_watch"
+# topic = "kap/wallet/tx"
+# confirmations = 3 # [chain] confirmations/rpc_url when unset
+
+# clock offset by NTP (Date header of http_url when NTP is blocked),
+# also reported to the named shadow for the fleet dashboards
+# [[task]]
+# topic = "kap/task/clock"
+# builtin = "clock"
+# period = "6h"
+# [task.clock]
+# server = "pool.ntp.org:123"
+# http_url = "https://boss.example.com" # [boss] root_url when unset, via [boss.client]
+# shadow = "name/clock"
+# timeout = "3s"
+
+# local control API (`api` command, api feature) for LuCI/installer apps
+# [api]
+# db_read = ["kap/honest/state", "kap/daemon/start_at", "kap/activate/*"]
+# db_write = ["kap/luci/*"]
+# tasks = ["kap/task/sysinfo"]
+
+# [honest]
+# ok_cycle = "1h"
+# fail_cycle = "5m"
+# path = "/etc/fika_manager/honest.sh"
+# disable = false
+# consecutive failures before switching to fail_cycle
+# fail_threshold = 1
+# report state as shadow reported, e.g. name/honest
+# shadow = "name/honest"
+
+# own RSS/CPU/fd sampling into kap/monitor/self, for 128-256MB devices
+# [monitor]
+# interval = "1m"
+# pressure once RSS is above rss_limit or MemAvailable below mem_available_min
+# rss_limit = 67108864
+# mem_available_min = 16777216
+# skip task runs while under pressure (subscribe scripts still run)
+# throttle = true
+# shadow = "name/monitor"
+# shadow_every = 10 # samples between shadow reports, pressure changes go at once
+
+# important events to sinks instead of per-event shell glue
+# [notify]
+# events = ["provision", "cert_rotated", "task_failed", "offline"]
+# topic = "kap/notify"
+# webhook = "notify" # a boss.endpoint name, called with the event as json
+# shadow = "name/alarm" # reported as alarms.{kind}, null once cleared
+# task_failures = 3 # consecutive failures of one task before the alarm
+# offline_after = "10m"
+
+# on-device config mirrored to the shadow, no report scripts needed
+# [cfgsync]
+# shadow = "name/config"
+# keys = "kap/cfg/*" # redis keys reported next to kdaemon.toml
+# interval = "30s" # keys poll, kdaemon.toml changes go at once
+# redact = ["nickname"] # on top of password/token/secret/private/key names
+
+# network.wifi_ssid/wifi_password onto the radio by `wifi`, rolled back when
+# the AP does not come up
+# [wifi]
+# backend = "script" # or "uci", wireless ap interfaces and `wifi reload`
+# apply = "/etc/fika_manager/wifi_apply.sh" # script, FIKA_WIFI_SSID/FIKA_WIFI_PASSWORD in env
+# verify = "/etc/fika_manager/wifi_verify.sh" # script, exit 0 once the AP is up
+# timeout = "30s"
+
+# which features this hardware has, by core.sku; a flag not listed is on
+# [features]
+# endpoint = "features" # a boss.endpoint name, flags of the sku over the tables
+# cache = "/userdata/.features.json" # last flags from boss, when it is unreachable
+# [features.default]
+# portal = true
+# [features.sku."K1-LTE"]
+# wifi = false
+# [features.sku."K2*"]
+# por = false
+"#;
+
+/* render one section (and its sub-tables) with a comment line above each known key */
+pub(crate) fn toml_commented<T: Serialize>(
+    section: &str,
+    value: &T,
+    comments: &[(&str, &str)],
+) -> Result<String> {
+    let table = match toml::Value::try_from(value)? {
+        toml::Value::Table(t) => t,
+        _ => return Err(anyhow!("section {} not a table", section)),
+    };
+
+    let mut out = format!("[{}]\n", section);
+    let mut subs = String::new();
+    for (k, v) in table {
+        let path = format!("{}.{}", section, k);
+        if let toml::Value::Table(_) = v {
+            subs.push('\n');
+            subs.push_str(&toml_commented(&path, &v, comments)?);
+            continue;
+        }
+
+        if let Some((_, c)) = comments.iter().find(|(p, _)| *p == path) {
+            out.push_str(&format!("# {}\n", c));
+        }
+        let mut line = toml::value::Table::new();
+        line.insert(k, v);
+        out.push_str(&toml::to_string(&line)?);
+    }
+    out.push_str(&subs);
+
+    Ok(out)
+}
+
+pub(crate) fn toml_commented_out(s: &str) -> String {
+    s.lines()
+        .map(|l| {
+            if l.is_empty() {
+                "#\n".to_string()
+            } else {
+                format!("# {}\n", l)
+            }
+        })
+        .collect()
+}
+
+pub fn rule_template() -> Result<String> {
+    let mut out = String::from("# FIKA manager rule.toml, compiled-in defaults\n\n");
+
+    out.push_str(&toml_commented(
+        "core",
+        &RuleConfigCore::default(),
+        RULE_COMMENTS,
+    )?);
+    out.push('\n');
+    out.push_str(&toml_commented(
+        "boss",
+        &RuleConfigBoss::default(),
+        RULE_COMMENTS,
+    )?);
+    out.push('\n');
+    out.push_str(&toml_commented(
+        "aws",
+        &RuleAwsIotConfig::default(),
+        RULE_COMMENTS,
+    )?);
+    #[cfg(feature = "aws-iot")]
+    {
+        out.push_str("# thing = \"LD2_{mac}\"\n");
+        out.push_str("# pull_topic = [\"name/example\"]\n\n");
+        out.push_str(&toml_commented_out(&toml_commented(
+            "aws.provision",
+            &RuleAwsIotProvisionConfig::default(),
+            RULE_COMMENTS,
+        )?));
+    }
+    out.push_str(RULE_EXAMPLES);
+
+    Ok(out)
+}
+
+impl RuleConfig {
+    /* URL fields after defaults, task[i] by position in the rule */
+    pub fn validate(&self) -> Vec<ConfigViolation> {
+        const HTTP: &[&str] = &["http", "https"];
+        const RPC: &[&str] = &["http", "https", "ws", "wss"];
+
+        let mut urls = vec![("boss.root_url".to_string(), &self.boss.root_url, HTTP)];
+        if let Some(ref chain) = self.chain {
+            urls.push(("chain.rpc_url".to_string(), &chain.rpc_url, RPC));
+            urls.push(("chain.explorer_url".to_string(), &chain.explorer_url, HTTP));
+        }
+        for (i, task) in self.task.iter().flatten().enumerate() {
+            if let Some(ref clock) = task.clock {
+                urls.push((format!("task[{}].clock.http_url", i), &clock.http_url, HTTP));
+            }
+            if let Some(ref txwatch) = task.txwatch {
+                urls.push((
+                    format!("task[{}].txwatch.rpc_url", i),
+                    &txwatch.rpc_url,
+                    RPC,
+                ));
+            }
+        }
+
+        urls.into_iter()
+            .filter_map(|(path, url, schemes)| {
+                let e = url_check(url.as_deref()?, schemes).err()?;
+                Some(ConfigViolation::new(path, e))
+            })
+            .collect()
+    }
+
+    fn dry_run_tasks(&self, now: DateTime<Utc>) -> Vec<Value> {
+        let tasks = if let Some(ref tasks) = self.task {
+            tasks
+        } else {
+            return vec![];
+        };
+
+        tasks
+            .iter()
+            .map(|t| {
+                let command = if let Some(builtin) = t.builtin {
+                    format!("builtin/{:?}", builtin)
+                } else {
+                    t.build_command()
+                        .map(|c| format!("{:?}", c.as_std()))
+                        .unwrap_or_else(|e| format!("invalid - {e}"))
+                };
+                let first = now
+                    + chrono::Duration::from_std(t.start_at.unwrap_or_default())
+                        .unwrap_or_else(|_| chrono::Duration::zero());
+                let schedule = if let Some(period) = t.period {
+                    let period = chrono::Duration::from_std(period)
+                        .unwrap_or_else(|_| chrono::Duration::zero());
+                    (0..DRY_RUN_SCHEDULE_COUNT)
+                        .map(|i| (first + period * i as i32).to_rfc3339())
+                        .collect::<Vec<String>>()
+                } else {
+                    vec![first.to_rfc3339()]
+                };
+
+                json!({
+                    "topic": t.topic,
+                    "command": command,
+                    "schedule": schedule,
+                    "watch": t.watch,
+                    "db_publish": t.db_publish.unwrap_or(false),
+                    "db_set": t.db_set.unwrap_or(false),
+                    "aws_publish": t.aws_publish.unwrap_or(false),
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "aws-iot")]
+    fn dry_run_aws(&self, cfg: Option<&KdaemonConfig>) -> Value {
+        let thing = cfg
+            .and_then(|c| self.aws.thing_name(&c.core.mac_address).ok())
+            .unwrap_or_else(|| "{thing}".to_string());
+        let pull = self
+            .aws
+            .dedicated
+            .pull_topic
+            .clone()
+            .unwrap_or_default()
+            .iter()
+            .map(|t| format!("$aws/things/{}/shadow/{}/get", &thing, t))
+            .collect::<Vec<String>>();
+
+        json!({
+            "endpoint": self.aws.endpoint,
+            "thing": thing,
+            "subscribe": [
+                format!("$aws/things/{}/shadow/#", &thing),
+                format!("$aws/things/{}/jobs/#", &thing),
+            ],
+            "pull": pull,
+        })
+    }
+
+    #[cfg(not(feature = "aws-iot"))]
+    fn dry_run_aws(&self, _cfg: Option<&KdaemonConfig>) -> Value {
+        Value::Null
+    }
+
+    pub fn dry_run(&self, cfg: Option<&KdaemonConfig>, now: DateTime<Utc>) -> Value {
+        let subscribe = self
+            .subscribe
+            .as_ref()
+            .map(|subs| {
+                subs.iter()
+                    .map(|s| json!({ "topic": s.topic, "path": s.path, "args": s.args }))
+                    .collect::<Vec<Value>>()
+            })
+            .unwrap_or_default();
+
+        let honest = match self.honest {
+            Some(ref h) if h.disable != Some(true) => json!({
+                "path": h.path,
+                "ok_cycle": h.ok_cycle.as_secs(),
+                "fail_cycle": h.fail_cycle.as_secs(),
+                "fail_threshold": h.fail_threshold.unwrap_or(1),
+                "shadow": h.shadow,
+            }),
+            _ => Value::Null,
+        };
+
+        json!({
+            "database": self.core.database,
+            "config": self.core.config,
+            "task": self.dry_run_tasks(now),
+            "subscribe": subscribe,
+            "honest": honest,
+            "aws": self.dry_run_aws(cfg),
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Load and validate rule")]
+pub struct RuleCheckOpt {
+    #[clap(long = "dry-run", action)]
+    dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Generate default rule/activate template")]
+pub struct RuleInitOpt {
+    #[clap(long = "activate", action, help = "activate.toml instead of rule.toml")]
+    activate: bool,
+
+    #[clap(short = 'o', long = "output")]
+    output: Option<String>,
+
+    #[clap(short, long, action)]
+    force: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleShowFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Show loaded rule/kdaemon config")]
+pub struct RuleShowOpt {
+    #[clap(
+        long = "effective",
+        action,
+        help = "after compiled-in defaults merged, secrets masked"
+    )]
+    effective: bool,
+
+    #[clap(long = "format", value_enum, default_value = "toml")]
+    format: RuleShowFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum RuleCommand {
+    Check(RuleCheckOpt),
+    Init(RuleInitOpt),
+    Show(RuleShowOpt),
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA Rule Toolset")]
+pub struct RuleOpt {
+    #[clap(subcommand)]
+    commands: RuleCommand,
+
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+
+    #[clap(short = 'c', long = "config")]
+    config: Option<String>,
+
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+async fn do_check(
+    rule_path: &str,
+    rule: RuleConfig,
+    config: Option<String>,
+    opt: RuleCheckOpt,
+) -> Result<()> {
+    let cfg_path = config.unwrap_or_else(|| rule.core.config.clone());
+    /* a config that loads with format warnings still fails the check */
+    let cfg = match KdaemonConfig::build_from(&cfg_path).await {
+        Ok(c) => {
+            let violations = c.validate();
+            if !violations.is_empty() {
+                return Err(ConfigInvalid {
+                    file: cfg_path,
+                    violations,
+                }
+                .into());
+            }
+            Some(c)
+        }
+        Err(e) => {
+            if e.downcast_ref::<ConfigInvalid>().is_some() {
+                return Err(e);
+            }
+            warn!("cfg build from {} fail - {:?}", cfg_path, e);
+            None
+        }
+    };
+
+    let violations = rule.validate();
+    if !violations.is_empty() {
+        return Err(ConfigInvalid {
+            file: rule_path.to_string(),
+            violations,
+        }
+        .into());
+    }
+
+    /* every unbuildable command is listed before the check fails */
+    let mut failed = 0;
+    for t in rule.task.iter().flatten().filter(|t| t.builtin.is_none()) {
+        if let Err(e) = t.build_command() {
+            warn!("{e}");
+            failed += 1;
+        }
+    }
+
+    if opt.dry_run {
+        let plan = rule.dry_run(cfg.as_ref(), Utc::now());
+        println!("{}", to_colored_json_auto(&plan)?);
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} {} task command fail", rule_path, failed));
+    }
+    if !opt.dry_run {
+        println!("ok");
+    }
+
+    Ok(())
+}
+
+async fn do_init(opt: RuleInitOpt) -> Result<()> {
+    let template = if opt.activate {
+        activate_template()?
+    } else {
+        rule_template()?
+    };
+
+    if let Some(output) = opt.output {
+        if !opt.force && fs::metadata(&output).await.is_ok() {
+            return Err(anyhow!("{} exist, use --force to overwrite", output));
+        }
+        fs::write(&output, template)
+            .await
+            .map_err(|e| anyhow!("{} write fail - {e}", output))?;
+    } else {
+        print!("{}", template);
+    }
+
+    Ok(())
+}
+
+async fn do_show(rule_path: &str, config: Option<String>, opt: RuleShowOpt) -> Result<()> {
+    let (rule, cfg, cfg_path) = if opt.effective {
+        let rule = RuleConfig::build_from(rule_path)
+            .await
+            .map_err(|e| anyhow!("rule build from {} fail - {:?}", rule_path, e))?;
+        let cfg_path = config.unwrap_or_else(|| rule.core.config.clone());
+        let cfg = KdaemonConfig::build_from(&cfg_path)
+            .await
+            .map_err(|e| anyhow!("cfg build from {} fail - {:?}", cfg_path, e))?;
+        /* what was merged in is shown, the secrets in it are not */
+        let redacted = |value: toml::Value| -> Result<toml::Value> {
+            let mut value = serde_json::to_value(value)?;
+            redact(&mut value);
+            Ok(toml::Value::try_from(value)?)
+        };
+        (
+            redacted(toml::Value::try_from(&rule)?)?,
+            redacted(toml::Value::try_from(&cfg)?)?,
+            cfg_path,
+        )
+    } else {
+        let rule: toml::Value = toml::from_str(&fs::read_to_string(rule_path).await?)
+            .map_err(|e| anyhow!("rule format invalid - {:?}", e))?;
+        let cfg_path = config
+            .or_else(|| {
+                rule.get("core")
+                    .and_then(|c| c.get("config"))
+                    .and_then(|c| c.as_str())
+                    .map(|c| c.to_string())
+            })
+            .unwrap_or_else(|| RuleConfigCore::default().config);
+        let cfg: toml::Value = toml::from_str(&fs::read_to_string(&cfg_path).await?)
+            .map_err(|e| anyhow!("cfg format invalid - {:?}", e))?;
+        (rule, cfg, cfg_path)
+    };
+
+    match opt.format {
+        RuleShowFormat::Toml => {
+            println!("# {}", rule_path);
+            println!("{}", toml::to_string_pretty(&rule)?);
+            println!("# {}", cfg_path);
+            println!("{}", toml::to_string_pretty(&cfg)?);
+        }
+        RuleShowFormat::Json => {
+            let all = json!({
+                "rule": rule,
+                "config": cfg,
+            });
+            println!("{}", to_colored_json_auto(&all)?);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn rule_tools(opt: RuleOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    match opt.commands {
+        RuleCommand::Check(c) => {
+            let rule = RuleConfig::build_from(&opt.rule)
+                .await
+                .map_err(|e| anyhow!("rule build from {} fail - {:?}", &opt.rule, e))?;
+            do_check(&opt.rule, rule, opt.config, c).await?
+        }
+        RuleCommand::Init(i) => do_init(i).await?,
+        RuleCommand::Show(i) => do_show(&opt.rule, opt.config, i).await?,
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_rule_template_roundtrip() {
+    let rule: RuleConfig = toml::from_str(&rule_template().unwrap()).unwrap();
+    assert_eq!(rule.core.database, RuleConfigCore::default().database);
+    assert_eq!(rule.boss.root_url, RuleConfigBoss::default().root_url);
+    assert!(rule.task.is_none());
+}
+
+#[test]
+fn test_rule_clock_default() {
+    let rule: RuleConfig = toml::from_str(
+        r#"
+        [core]
+        thirdparty = "x"
+        config = "/userdata/kdaemon.toml"
+        [boss]
+        root_url = "https://boss.example.com"
+        [boss.client]
+        proxy = "socks5://10.0.0.1:1080"
+        [aws]
+        [aws.dedicated]
+        cert = "/userdata/cert.pem"
+        private = "/userdata/private.key"
+        ca = "/etc/fika_manager/AmazonRootCA1.pem"
+        [chain]
+        rpc_url = "https://rpc.example.com"
+        confirmations = 3
+        [[task]]
+        topic = "kap/task/clock"
+        builtin = "clock"
+        period = "6h"
+        [[task]]
+        topic = "kap/task/txwatch"
+        builtin = "txwatch"
+        "#,
+    )
+    .unwrap();
+    let rule = rule.mirrow_default().unwrap();
+    let tasks = rule.task.unwrap();
+    let clock = tasks[0].clock.clone().unwrap();
+    assert_eq!(clock.http_url.as_deref(), Some("https://boss.example.com"));
+    assert_eq!(
+        clock.client.and_then(|c| c.proxy).as_deref(),
+        Some("socks5://10.0.0.1:1080")
+    );
+    /* without a [task.txwatch] table of its own */
+    let txwatch = tasks[1].txwatch.clone().unwrap();
+    assert_eq!(txwatch.rpc_url.as_deref(), Some("https://rpc.example.com"));
+    assert_eq!(txwatch.confirmations, Some(3));
+}
+
+#[test]
+fn test_rule_reload_sections() {
+    let base = r#"
+        [core]
+        thirdparty = "x"
+        config = "/userdata/kdaemon.toml"
+        [boss]
+        root_url = "https://boss.example.com"
+        [aws]
+        [aws.dedicated]
+        cert = "/userdata/cert.pem"
+        private = "/userdata/private.key"
+        ca = "/etc/fika_manager/AmazonRootCA1.pem"
+        [[task]]
+        topic = "kap/task/a"
+        inline_sh = "true"
+        "#;
+    let mut rule: RuleConfig = toml::from_str(base).unwrap();
+    let fresh: RuleConfig = toml::from_str(
+        &base
+            .replace("kap/task/a", "kap/task/b")
+            .replace("boss.example.com", "boss2.example.com"),
+    )
+    .unwrap();
+    let mut fresh_core: RuleConfig = toml::from_str(base).unwrap();
+    fresh_core.core.log_level = Some("debug".to_string());
+
+    assert_eq!(rule.reload_sections(fresh), vec!["task"]);
+    assert_eq!(rule.task.as_ref().unwrap()[0].topic, "kap/task/b");
+    assert_eq!(
+        rule.boss.root_url.as_deref(),
+        Some("https://boss.example.com")
+    );
+    assert_eq!(
+        rule.reload_sections(fresh_core),
+        vec!["task", "core.log_level"]
+    );
+    assert_eq!(rule.core.log_level.as_deref(), Some("debug"));
+}
+
+#[test]
+fn test_rule_validate_urls() {
+    let mut rule: RuleConfig = toml::from_str(&rule_template().unwrap()).unwrap();
+    assert!(rule.validate().is_empty());
+
+    rule.boss.root_url = Some("boss.example.com".to_string());
+    rule.chain = Some(RuleChainConfig {
+        rpc_url: Some("wss://rpc.example.com:8546".to_string()),
+        explorer_url: Some("https://polygon scan.com".to_string()),
+        ..Default::default()
+    });
+    let paths: Vec<String> = rule.validate().into_iter().map(|v| v.path).collect();
+    assert_eq!(paths, ["boss.root_url", "chain.explorer_url"]);
+}
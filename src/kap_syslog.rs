@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::kap_rule::RuleSyslogConfig;
+
+/*
+ * RFC5424 syslog forwarding: events are queued to a sender thread so a slow
+ * or unreachable collector never blocks the daemon, a full queue drops.
+ * tcp/tls frame by octet-counting (RFC6587/5425), udp is one datagram each
+ */
+
+const SYSLOG_QUEUE: usize = 256;
+const SYSLOG_TIMEOUT: Duration = Duration::from_secs(5);
+const SYSLOG_RETRY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyslogTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct SyslogTarget {
+    transport: SyslogTransport,
+    host: String,
+    port: u16,
+}
+
+fn syslog_target(url: &str) -> Result<SyslogTarget> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow!("syslog url {} invalid, udp/tcp/tls://host[:port]", url))?;
+    let (transport, default_port) = match scheme {
+        "udp" => (SyslogTransport::Udp, 514),
+        "tcp" => (SyslogTransport::Tcp, 601),
+        "tls" => (SyslogTransport::Tls, 6514),
+        s => return Err(anyhow!("syslog transport {} not support", s)),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((h, p)) => (
+            h,
+            p.parse::<u16>()
+                .map_err(|e| anyhow!("syslog url {} port invalid - {e}", url))?,
+        ),
+        None => (rest, default_port),
+    };
+    if host.is_empty() {
+        return Err(anyhow!("syslog url {} host empty", url));
+    }
+
+    Ok(SyslogTarget {
+        transport,
+        host: host.to_string(),
+        port,
+    })
+}
+
+fn syslog_facility(name: &str) -> Result<u8> {
+    let facility = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        _ => match name
+            .strip_prefix("local")
+            .and_then(|n| n.parse::<u8>().ok())
+        {
+            Some(n) if n <= 7 => 16 + n,
+            _ => return Err(anyhow!("syslog facility {} invalid", name)),
+        },
+    };
+    Ok(facility)
+}
+
+fn syslog_severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+struct SyslogHeader {
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl SyslogHeader {
+    /* <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG */
+    fn format(&self, severity: u8, now: DateTime<Utc>, msg: &str) -> Vec<u8> {
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.facility * 8 + severity,
+            now.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            msg
+        )
+        .into_bytes()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SyslogWriter {
+    header: Arc<SyslogHeader>,
+    tx: SyncSender<Vec<u8>>,
+}
+
+pub(crate) struct SyslogEvent<'a> {
+    writer: &'a SyslogWriter,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogEvent<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* the fmt layer writes a whole event then drops its writer */
+impl Drop for SyslogEvent<'_> {
+    fn drop(&mut self) {
+        let msg = String::from_utf8_lossy(&self.buf);
+        let msg = msg.trim();
+        if msg.is_empty() {
+            return;
+        }
+        let record = self.writer.header.format(self.severity, Utc::now(), msg);
+        _ = self.writer.tx.try_send(record);
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogEvent<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogEvent {
+            writer: self,
+            severity: syslog_severity(&Level::INFO),
+            buf: vec![],
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogEvent {
+            writer: self,
+            severity: syslog_severity(meta.level()),
+            buf: vec![],
+        }
+    }
+}
+
+fn syslog_tls_config(cfg: &RuleSyslogConfig) -> Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    match cfg.cacert {
+        Some(ref cacert) => {
+            let pem = std::fs::read(cacert)
+                .map_err(|e| anyhow!("syslog cacert {} read fail - {e}", cacert.display()))?;
+            for cert in rustls_pemfile::certs(&mut pem.as_slice())? {
+                roots
+                    .add(&rustls::Certificate(cert))
+                    .map_err(|e| anyhow!("syslog cacert {} invalid - {e}", cacert.display()))?;
+            }
+        }
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+
+    Ok(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+enum SyslogConn {
+    Udp(UdpSocket),
+    Stream(Box<dyn Write + Send>),
+}
+
+impl SyslogConn {
+    fn open(target: &SyslogTarget, tls: Option<&Arc<rustls::ClientConfig>>) -> Result<Self> {
+        let addr = (target.host.as_str(), target.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("syslog {} resolve none", target.host))?;
+
+        if target.transport == SyslogTransport::Udp {
+            let socket = UdpSocket::bind(if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })?;
+            socket.connect(addr)?;
+            return Ok(Self::Udp(socket));
+        }
+
+        let stream = TcpStream::connect_timeout(&addr, SYSLOG_TIMEOUT)?;
+        stream.set_write_timeout(Some(SYSLOG_TIMEOUT))?;
+        match tls {
+            Some(tls) => {
+                let name = rustls::ServerName::try_from(target.host.as_str())
+                    .map_err(|e| anyhow!("syslog tls name {} invalid - {e}", target.host))?;
+                let conn = rustls::ClientConnection::new(tls.clone(), name)?;
+                Ok(Self::Stream(Box::new(rustls::StreamOwned::new(
+                    conn, stream,
+                ))))
+            }
+            None => Ok(Self::Stream(Box::new(stream))),
+        }
+    }
+
+    fn send(&mut self, record: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(record).map(|_| ()),
+            Self::Stream(stream) => {
+                stream.write_all(format!("{} ", record.len()).as_bytes())?;
+                stream.write_all(record)?;
+                stream.flush()
+            }
+        }
+    }
+}
+
+/* no tracing in here, it would feed the queue it drains */
+fn syslog_send_loop(
+    target: SyslogTarget,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    rx: Receiver<Vec<u8>>,
+) {
+    let mut conn: Option<SyslogConn> = None;
+    let mut retry_at = Instant::now();
+
+    for record in rx {
+        if conn.is_none() && Instant::now() >= retry_at {
+            match SyslogConn::open(&target, tls.as_ref()) {
+                Ok(c) => conn = Some(c),
+                Err(e) => {
+                    eprintln!("syslog {}:{} connect fail - {e}", target.host, target.port);
+                    retry_at = Instant::now() + SYSLOG_RETRY;
+                }
+            }
+        }
+        if let Some(ref mut c) = conn {
+            if let Err(e) = c.send(&record) {
+                eprintln!("syslog {}:{} send fail - {e}", target.host, target.port);
+                conn = None;
+            }
+        }
+    }
+}
+
+impl SyslogWriter {
+    pub(crate) fn open(cfg: &RuleSyslogConfig) -> Result<Self> {
+        let target = syslog_target(&cfg.url)?;
+        let tls = match target.transport {
+            SyslogTransport::Tls => Some(syslog_tls_config(cfg)?),
+            _ => None,
+        };
+        let header = SyslogHeader {
+            facility: syslog_facility(cfg.facility.as_deref().unwrap_or("daemon"))?,
+            hostname: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|h| h.trim().to_string())
+                .unwrap_or_else(|_| "-".to_string()),
+            app_name: cfg
+                .app_name
+                .clone()
+                .unwrap_or_else(|| "fika_manager".to_string()),
+            pid: std::process::id(),
+        };
+
+        let (tx, rx) = sync_channel(SYSLOG_QUEUE);
+        std::thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || syslog_send_loop(target, tls, rx))?;
+
+        Ok(Self {
+            header: Arc::new(header),
+            tx,
+        })
+    }
+}
+
+#[test]
+fn test_syslog_format() {
+    assert_eq!(
+        syslog_target("tls://logs.example.com").unwrap(),
+        SyslogTarget {
+            transport: SyslogTransport::Tls,
+            host: "logs.example.com".to_string(),
+            port: 6514,
+        }
+    );
+    assert_eq!(syslog_target("udp://10.0.0.1:5514").unwrap().port, 5514);
+    assert!(syslog_target("http://10.0.0.1").is_err());
+    assert!(syslog_target("udp://:514").is_err());
+
+    assert_eq!(syslog_facility("daemon").unwrap(), 3);
+    assert_eq!(syslog_facility("local7").unwrap(), 23);
+    assert!(syslog_facility("local8").is_err());
+
+    let header = SyslogHeader {
+        facility: 16,
+        hostname: "kap".to_string(),
+        app_name: "fika_manager".to_string(),
+        pid: 42,
+    };
+    let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    assert_eq!(
+        String::from_utf8(header.format(syslog_severity(&Level::WARN), now, "hello")).unwrap(),
+        "<132>1 2023-11-14T22:13:20.000Z kap fika_manager 42 - - hello"
+    );
+
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_read_timeout(Some(SYSLOG_TIMEOUT)).unwrap();
+    let writer = SyslogWriter::open(&RuleSyslogConfig {
+        url: format!("udp://{}", collector.local_addr().unwrap()),
+        ..Default::default()
+    })
+    .unwrap();
+    writer.make_writer().write_all(b"over udp\n").unwrap();
+    let mut buf = [0u8; 512];
+    let n = collector.recv(&mut buf).unwrap();
+    let record = String::from_utf8_lossy(&buf[..n]);
+    assert!(record.starts_with("<30>1 "));
+    assert!(record.ends_with(&format!(
+        " fika_manager {} - - over udp",
+        std::process::id()
+    )));
+}
@@ -0,0 +1,704 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::{Args, Subcommand};
+use colored_json::to_colored_json_auto;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, instrument, warn};
+
+use crate::kap_audit::{audit_record, AuditRecord};
+use crate::kap_collect::builtin_collect;
+use crate::kap_crash::TaskActive;
+use crate::kap_rule::{
+    RuleConfig, RuleConfigCore, RuleConfigSubscribe, RuleSubscribeArgs, RuleTaskTruncate,
+};
+use crate::setup_logging;
+use crate::{DbCommand, RuleConfigTask};
+
+const TASK_HISTORY_LIMIT: usize = 100;
+const TASK_OUTPUT_LIMIT: usize = 4096;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TaskRecord {
+    pub topic: String,
+    pub start: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub exit_code: Option<i32>,
+    pub output_hash: String,
+    /* set when the command could not be built or spawned, no exit_code then */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn task_history_key(topic: &str) -> String {
+    format!("history/task/{}", topic)
+}
+
+pub(crate) fn output_hash(output: &[u8]) -> String {
+    let digest = format!("{:x}", Sha256::digest(output));
+    digest[..16].to_string()
+}
+
+async fn history_record(
+    db_chan: &mpsc::Sender<DbCommand>,
+    kind: &str,
+    topic: &str,
+    start: DateTime<Utc>,
+    instant: Instant,
+    exit_code: Option<i32>,
+    stdout: &[u8],
+) -> Result<()> {
+    let record = TaskRecord {
+        topic: topic.to_string(),
+        start,
+        duration_ms: instant.elapsed().as_millis() as u64,
+        exit_code,
+        output_hash: output_hash(stdout),
+        error: None,
+    };
+    history_push(db_chan, kind, record, stdout).await
+}
+
+async fn history_error(
+    db_chan: &mpsc::Sender<DbCommand>,
+    kind: &str,
+    topic: &str,
+    start: DateTime<Utc>,
+    instant: Instant,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let record = TaskRecord {
+        topic: topic.to_string(),
+        start,
+        duration_ms: instant.elapsed().as_millis() as u64,
+        exit_code: None,
+        output_hash: output_hash(b""),
+        error: Some(error.to_string()),
+    };
+    history_push(db_chan, kind, record, b"").await
+}
+
+async fn history_push(
+    db_chan: &mpsc::Sender<DbCommand>,
+    kind: &str,
+    record: TaskRecord,
+    stdout: &[u8],
+) -> Result<()> {
+    let topic = &record.topic;
+    debug!("{} completed - {:?}", topic, record);
+
+    if let Err(e) = db_chan
+        .send(DbCommand::Rpush {
+            key: task_history_key(topic),
+            val: serde_json::to_string(&record)?,
+            limit: TASK_HISTORY_LIMIT,
+        })
+        .await
+    {
+        warn!("{} history record fail - {e}", topic);
+    }
+
+    let mut event = serde_json::to_value(&record)?;
+    event["output"] = Value::String(output_truncate(
+        &String::from_utf8_lossy(stdout),
+        TASK_OUTPUT_LIMIT,
+        RuleTaskTruncate::Tail,
+    ));
+    if let Err(e) = crate::event_publish(db_chan, kind, event).await {
+        warn!("{} event publish fail - {e}", topic);
+    }
+
+    Ok(())
+}
+
+fn floor_char_boundary(s: &str, mut idx: usize) -> usize {
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+fn ceil_char_boundary(s: &str, mut idx: usize) -> usize {
+    while !s.is_char_boundary(idx) {
+        idx += 1;
+    }
+    idx
+}
+
+pub fn output_truncate(output: &str, limit: usize, policy: RuleTaskTruncate) -> String {
+    if output.len() <= limit {
+        return output.to_string();
+    }
+
+    let marker = |dropped: usize| format!("...[truncated {} bytes]...", dropped);
+    match policy {
+        RuleTaskTruncate::Head => {
+            let end = floor_char_boundary(output, limit);
+            format!("{}{}", &output[..end], marker(output.len() - end))
+        }
+        RuleTaskTruncate::Tail => {
+            let start = ceil_char_boundary(output, output.len() - limit);
+            format!("{}{}", marker(start), &output[start..])
+        }
+        RuleTaskTruncate::Both => {
+            let end = floor_char_boundary(output, limit / 2);
+            let start = ceil_char_boundary(output, output.len() - (limit - limit / 2));
+            format!(
+                "{}{}{}",
+                &output[..end],
+                marker(start - end),
+                &output[start..]
+            )
+        }
+    }
+}
+
+fn task_output(task: &RuleConfigTask, output: &str) -> String {
+    let limit = task.output_limit.unwrap_or(TASK_OUTPUT_LIMIT);
+    let policy = task.truncate.unwrap_or(RuleTaskTruncate::Head);
+    if output.len() > limit {
+        warn!(
+            "task/{} output {} bytes over limit {}, truncated",
+            &task.topic,
+            output.len(),
+            limit
+        );
+    }
+    output_truncate(output, limit, policy)
+}
+
+pub fn task_log_path(core: &RuleConfigCore, topic: &str) -> Option<PathBuf> {
+    core.task_log_dir
+        .as_ref()
+        .map(|d| d.join(format!("{}.log", topic.replace('/', "_"))))
+}
+
+async fn task_log_rotate(path: &Path, keep: u32) -> Result<()> {
+    for i in (1..keep).rev() {
+        let from = PathBuf::from(format!("{}.{}", path.display(), i));
+        if fs::metadata(&from).await.is_ok() {
+            fs::rename(&from, format!("{}.{}", path.display(), i + 1)).await?;
+        }
+    }
+
+    if keep == 0 {
+        fs::remove_file(path).await?;
+    } else {
+        fs::rename(path, format!("{}.1", path.display())).await?;
+    }
+
+    Ok(())
+}
+
+async fn task_log_append(
+    core: &RuleConfigCore,
+    topic: &str,
+    exit_code: Option<i32>,
+    stderr: &[u8],
+) -> Result<()> {
+    let path = if let Some(p) = task_log_path(core, topic) {
+        p
+    } else {
+        return Ok(());
+    };
+
+    if stderr.is_empty() && exit_code == Some(0) {
+        return Ok(());
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    if let Ok(metadata) = fs::metadata(&path).await {
+        if metadata.len() > core.task_log_size.unwrap_or(u64::MAX) {
+            task_log_rotate(&path, core.task_log_keep.unwrap_or(0)).await?;
+        }
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    let header = format!(
+        "[{}] exit {:?}\n",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        exit_code
+    );
+    file.write_all(header.as_bytes()).await?;
+    file.write_all(stderr).await?;
+    if !stderr.ends_with(b"\n") && !stderr.is_empty() {
+        file.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(name = "task", skip(task, core, db_chan), fields(topic = %task.topic))]
+pub async fn task_run(
+    task: &RuleConfigTask,
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<String> {
+    if crate::kap_monitor::throttled() {
+        return Err(anyhow!("task/{} skipped, memory pressure", &task.topic));
+    }
+    let r = task_execute(task, core, db_chan).await;
+    crate::kap_notify::notify_task_result(&task.topic, &r).await;
+    r
+}
+
+async fn task_execute(
+    task: &RuleConfigTask,
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<String> {
+    let _active = TaskActive::enter(&task.topic);
+    let start = Utc::now();
+    let instant = Instant::now();
+
+    if let Some(builtin) = task.builtin {
+        if task.path.is_some() || task.command.is_some() || task.inline_sh.is_some() {
+            let e = anyhow!(
+                "task/{} builtin and path/command/inline_sh are exclusive",
+                &task.topic
+            );
+            history_error(db_chan, "task", &task.topic, start, instant, &e).await?;
+            return Err(e);
+        }
+
+        let (code, result) = match builtin_collect(task, builtin, core, db_chan).await {
+            Ok(v) => (0, serde_json::to_string(&v)?),
+            Err(e) => (1, format!("{e}")),
+        };
+        history_record(
+            db_chan,
+            "task",
+            &task.topic,
+            start,
+            instant,
+            Some(code),
+            result.as_bytes(),
+        )
+        .await?;
+        if code != 0 {
+            return Err(anyhow!("task/{} builtin fail - {}", &task.topic, result));
+        }
+        return Ok(task_output(task, &result));
+    }
+
+    let output = match task.build_command() {
+        Ok(mut cmd) => cmd
+            .output()
+            .await
+            .map_err(|e| anyhow!("task/{} run fail - {e}", &task.topic)),
+        Err(e) => Err(e),
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            history_error(db_chan, "task", &task.topic, start, instant, &e).await?;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = task_log_append(core, &task.topic, output.status.code(), &output.stderr).await {
+        warn!("task/{} log append fail - {e}", &task.topic);
+    }
+
+    history_record(
+        db_chan,
+        "task",
+        &task.topic,
+        start,
+        instant,
+        output.status.code(),
+        &output.stdout,
+    )
+    .await?;
+
+    if !output.status.success() {
+        return Err(anyhow!("task/{} exit with {}", &task.topic, output.status));
+    }
+
+    Ok(task_output(task, &String::from_utf8_lossy(&output.stdout)))
+}
+
+const TASK_WATCH_DEBOUNCE: Duration = Duration::from_secs(1);
+
+pub struct TaskWatcher {
+    _watcher: RecommendedWatcher,
+    rx: mpsc::Receiver<notify::Result<notify::Event>>,
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    debounce: Duration,
+}
+
+impl TaskWatcher {
+    pub fn new(task: &RuleConfigTask) -> Result<Self> {
+        let paths = task
+            .watch
+            .as_ref()
+            .ok_or_else(|| anyhow!("task/{} without watch", &task.topic))?;
+
+        let (tx, rx) = mpsc::channel(16);
+        let mut watcher = notify::recommended_watcher(move |ev| {
+            _ = tx.blocking_send(ev);
+        })?;
+
+        /* files are replaced by rename often, watch the parent instead */
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for path in paths {
+            if path.is_dir() {
+                watcher.watch(path, RecursiveMode::NonRecursive)?;
+                dirs.push(path.clone());
+            } else {
+                let parent = path
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .ok_or_else(|| anyhow!("task/{} watch {:?} invalid", &task.topic, path))?;
+                watcher
+                    .watch(parent, RecursiveMode::NonRecursive)
+                    .map_err(|e| anyhow!("task/{} watch {:?} fail - {e}", &task.topic, parent))?;
+                files.push(path.clone());
+            }
+        }
+
+        Ok(Self {
+            _watcher: watcher,
+            rx,
+            files,
+            dirs,
+            debounce: task.debounce.unwrap_or(TASK_WATCH_DEBOUNCE),
+        })
+    }
+
+    fn matched(&self, ev: &notify::Event) -> bool {
+        if ev.kind.is_access() {
+            return false;
+        }
+        ev.paths.iter().any(|p| {
+            self.files.iter().any(|f| f == p) || self.dirs.iter().any(|d| p.starts_with(d))
+        })
+    }
+
+    async fn next_matched(&mut self) -> Result<()> {
+        loop {
+            match self.rx.recv().await {
+                Some(Ok(ev)) if self.matched(&ev) => return Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => warn!("watch event error - {e}"),
+                None => return Err(anyhow!("watch channel closed")),
+            }
+        }
+    }
+
+    /* resolve once the watched paths changed and stayed quiet for debounce */
+    pub async fn changed(&mut self) -> Result<()> {
+        self.next_matched().await?;
+
+        loop {
+            match time::timeout(self.debounce, self.next_matched()).await {
+                Ok(r) => r?,
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubscribeArgs {
+    pub topic: String,
+    pub payload: Value,
+    pub timestamp: DateTime<Utc>,
+    pub version: Option<u16>,
+}
+
+impl SubscribeArgs {
+    pub fn new(topic: &str, msg: &str, version: Option<u16>) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload: serde_json::from_str(msg).unwrap_or_else(|_| Value::String(msg.to_string())),
+            timestamp: Utc::now(),
+            version,
+        }
+    }
+}
+
+#[instrument(name = "subscribe", skip(sub, args, core, db_chan), fields(topic = %sub.topic))]
+pub async fn subscribe_run(
+    sub: &RuleConfigSubscribe,
+    args: &SubscribeArgs,
+    core: &RuleConfigCore,
+    db_chan: &mpsc::Sender<DbCommand>,
+) -> Result<()> {
+    let _active = TaskActive::enter(&sub.topic);
+    let start = Utc::now();
+    let instant = Instant::now();
+    let json = serde_json::to_string(args)?;
+    let mut cmd = Command::new(&sub.path);
+
+    let output = match sub.args.unwrap_or(RuleSubscribeArgs::Argv) {
+        RuleSubscribeArgs::Argv => cmd.arg(&json).output().await,
+        RuleSubscribeArgs::Stdin => {
+            let mut child = cmd
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| anyhow!("subscribe/{} run fail - {e}", &sub.topic))?;
+            if let Some(mut stdin) = child.stdin.take() {
+                if let Err(e) = stdin.write_all(json.as_bytes()).await {
+                    warn!("subscribe/{} stdin write fail - {e}", &sub.topic);
+                }
+            }
+            child.wait_with_output().await
+        }
+    }
+    .map_err(|e| anyhow!("subscribe/{} run fail - {e}", &sub.topic));
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            let record = AuditRecord::new(sub, args, start, instant, None, Some(e.to_string()));
+            audit_record(core, db_chan, &record).await;
+            history_error(db_chan, "subscribe", &sub.topic, start, instant, &e).await?;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = task_log_append(core, &sub.topic, output.status.code(), &output.stderr).await {
+        warn!("subscribe/{} log append fail - {e}", &sub.topic);
+    }
+    let record = AuditRecord::new(sub, args, start, instant, output.status.code(), None);
+    audit_record(core, db_chan, &record).await;
+
+    history_record(
+        db_chan,
+        "subscribe",
+        &sub.topic,
+        start,
+        instant,
+        output.status.code(),
+        &output.stdout,
+    )
+    .await?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "subscribe/{} exit with {}",
+            &sub.topic,
+            output.status
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Task run history")]
+pub struct TaskHistoryOpt {
+    topic: String,
+
+    #[clap(short = 'n', long = "count", default_value = "20")]
+    count: isize,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Task stderr log")]
+pub struct TaskLogsOpt {
+    topic: String,
+
+    #[clap(short = 'n', long = "lines", default_value = "50")]
+    lines: usize,
+
+    #[clap(short, long, action)]
+    follow: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum TaskCommand {
+    History(TaskHistoryOpt),
+    Logs(TaskLogsOpt),
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA Task Toolset")]
+pub struct TaskOpt {
+    #[clap(subcommand)]
+    commands: TaskCommand,
+
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+async fn do_history(database: &str, opt: TaskHistoryOpt) -> Result<()> {
+    let mut db_conn = redis::Client::open(database)
+        .map_err(|e| anyhow!("db/redis open fail - {e}"))?
+        .get_async_connection()
+        .await
+        .map_err(|e| anyhow!("db/redis async connect fail - {e}"))?;
+
+    let key = task_history_key(&opt.topic);
+    let records: Vec<String> = db_conn
+        .lrange(&key, -opt.count, -1)
+        .await
+        .map_err(|e| anyhow!("db/redis lrange {key} fail - {e}"))?;
+
+    let records = records
+        .iter()
+        .filter_map(|r| serde_json::from_str::<TaskRecord>(r).ok())
+        .collect::<Vec<TaskRecord>>();
+
+    println!(
+        "{}",
+        to_colored_json_auto(&serde_json::to_value(&records)?)?
+    );
+    Ok(())
+}
+
+async fn do_logs(core: &RuleConfigCore, opt: TaskLogsOpt) -> Result<()> {
+    let path = task_log_path(core, &opt.topic)
+        .ok_or_else(|| anyhow!("rule/core/task_log_dir none invalid"))?;
+    let content = fs::read(&path)
+        .await
+        .map_err(|e| anyhow!("{} open/read fail - {e}", path.display()))?;
+
+    let content = String::from_utf8_lossy(&content);
+    let lines = content.lines().collect::<Vec<&str>>();
+    for line in &lines[lines.len().saturating_sub(opt.lines)..] {
+        println!("{}", line);
+    }
+
+    if opt.follow {
+        let mut file = fs::File::open(&path).await?;
+        let mut pos = file.seek(SeekFrom::End(0)).await?;
+        loop {
+            time::sleep(Duration::from_secs(1)).await;
+            let len = fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+            if len < pos {
+                /* rotated */
+                file = fs::File::open(&path).await?;
+                pos = 0;
+            }
+            let mut buf = vec![];
+            file.seek(SeekFrom::Start(pos)).await?;
+            pos += file.read_to_end(&mut buf).await? as u64;
+            print!("{}", String::from_utf8_lossy(&buf));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn task_tools(opt: TaskOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let rule = RuleConfig::build_from(&opt.rule).await?;
+
+    match opt.commands {
+        TaskCommand::History(h) => {
+            let database = rule
+                .core
+                .database
+                .as_ref()
+                .ok_or_else(|| anyhow!("rule/core/database none invalid"))?;
+            do_history(database, h).await?
+        }
+        TaskCommand::Logs(l) => do_logs(&rule.core, l).await?,
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_task_watch_debounce() {
+    let dir = std::env::temp_dir().join(format!("fika-watch-{}", std::process::id()));
+    fs::create_dir_all(&dir).await.unwrap();
+    let file = dir.join("leases");
+    tokio::fs::write(&file, "0").await.unwrap();
+
+    let task: RuleConfigTask = toml::from_str(&format!(
+        "topic = \"kap/test\"\ninline_sh = \"true\"\nwatch = [{:?}]\ndebounce = {{ secs = 0, nanos = 200000000 }}",
+        file
+    ))
+    .unwrap();
+    let mut watcher = TaskWatcher::new(&task).unwrap();
+
+    tokio::fs::write(dir.join("other"), "0").await.unwrap();
+    tokio::fs::write(&file, "1").await.unwrap();
+    tokio::fs::write(&file, "2").await.unwrap();
+    let r = time::timeout(Duration::from_secs(5), watcher.changed()).await;
+    assert!(matches!(r, Ok(Ok(()))));
+
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[test]
+fn test_output_truncate() {
+    let out = "0123456789";
+    assert_eq!(output_truncate(out, 16, RuleTaskTruncate::Head), out);
+    assert_eq!(
+        output_truncate(out, 4, RuleTaskTruncate::Head),
+        "0123...[truncated 6 bytes]..."
+    );
+    assert_eq!(
+        output_truncate(out, 4, RuleTaskTruncate::Tail),
+        "...[truncated 6 bytes]...6789"
+    );
+    assert_eq!(
+        output_truncate(out, 4, RuleTaskTruncate::Both),
+        "01...[truncated 6 bytes]...89"
+    );
+    /* never split a multi-byte char */
+    assert_eq!(
+        output_truncate("台北台北", 4, RuleTaskTruncate::Head),
+        "台...[truncated 9 bytes]..."
+    );
+}
+
+#[tokio::test]
+async fn test_task_history_error() {
+    let task: RuleConfigTask =
+        toml::from_str("topic = \"kap/t\"\npath = \"/nonexistent/fika_task\"").unwrap();
+    let (tx, mut rx) = mpsc::channel(8);
+    let served = tokio::spawn(async move {
+        let mut history = Vec::new();
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                DbCommand::Rpush { key, val, .. } => history.push((key, val)),
+                DbCommand::Publish { resp, .. } => _ = resp.send(Some(1)),
+                _ => {}
+            }
+        }
+        history
+    });
+
+    let r = task_execute(&task, &RuleConfigCore::default(), &tx).await;
+    assert!(r.unwrap_err().to_string().contains("run fail"));
+    drop(tx);
+    let history = served.await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].0, task_history_key("kap/t"));
+    let record: TaskRecord = serde_json::from_str(&history[0].1).unwrap();
+    assert_eq!(record.exit_code, None);
+    assert!(record.error.unwrap().contains("run fail"));
+}
@@ -0,0 +1,619 @@
+use anyhow::{anyhow, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::kap_daemon::{config_value, field_typed, KNetworkConfig};
+
+/*
+ * OpenWrt UCI as kdaemon config backend, rule core.config "uci:{package}":
+ * the fields sit in /etc/config/{package} as named sections core, network,
+ * por, boss and aws (the layout version in section kdaemon), except the
+ * ones LuCI owns, which map to the native options: network.wan proto (dhcp
+ * wan_type 0, pppoe 1, static 2, a modem proto 3; static and modem details
+ * stay LuCI's, as does any other proto, read as 0 and kept until wan_type
+ * is set; 0 on a static or modem wan writes dhcp), username and password, ssid and key of the wireless ap interfaces
+ * (read from the first, written to all). Those stay plaintext for the
+ * radio and pppd. All through the uci cli and committed, reloading the
+ * network is up to the caller (config set --post). [[network.wan]]
+ * profiles have no option form, a save with them fails
+ */
+
+/* wan_type 3, the uplinks of an lte modem */
+const UCI_MODEM_PROTOS: [&str; 6] = ["3g", "qmi", "ncm", "mbim", "modemmanager", "wwan"];
+const UCI_SECTIONS: [&str; 5] = ["core", "network", "por", "boss", "aws"];
+const UCI_NATIVE: [&str; 5] = [
+    "wan_type",
+    "wan_username",
+    "wan_password",
+    "wifi_ssid",
+    "wifi_password",
+];
+
+/* `uci show` lines, package.section[.option] and the unquoted value */
+#[derive(Debug, Default, Clone)]
+struct UciState {
+    package: Vec<(String, String)>,
+    network: Vec<(String, String)>,
+    wireless: Vec<(String, String)>,
+}
+
+/* 'a'\''b' is a'b, list items joined by a space */
+fn uci_unquote(raw: &str) -> String {
+    let (mut out, mut quoted, mut escaped) = (String::new(), false, false);
+    for c in raw.chars() {
+        match c {
+            _ if escaped => {
+                out.push(c);
+                escaped = false;
+            }
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => escaped = true,
+            ' ' if !quoted => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn uci_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn uci_parse(show: &str) -> Vec<(String, String)> {
+    show.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), uci_unquote(v)))
+        .collect()
+}
+
+fn uci_get<'a>(list: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    list.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+/* wireless sections of type wifi-iface in mode ap */
+fn uci_ap_ifaces(wireless: &[(String, String)]) -> Vec<String> {
+    wireless
+        .iter()
+        .filter(|(k, v)| v == "wifi-iface" && k.matches('.').count() == 1)
+        .map(|(k, _)| k.clone())
+        .filter(|s| uci_get(wireless, &format!("{}.mode", s)) == Some("ap"))
+        .collect()
+}
+
+fn uci_candidates(value: &str) -> Vec<Option<toml::Value>> {
+    let mut candidates = config_value(value);
+    let flag = match value {
+        "1" | "on" | "yes" | "enabled" => Some(true),
+        "0" | "off" | "no" | "disabled" => Some(false),
+        _ => None,
+    };
+    candidates.push(flag.map(toml::Value::Boolean));
+    candidates
+}
+
+fn uci_root(state: &UciState) -> toml::value::Table {
+    let mut root = toml::value::Table::new();
+    let put = |root: &mut toml::value::Table, section: &str, field: &str, v: toml::Value| {
+        if let Some(t) = root
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+        {
+            t.insert(field.to_string(), v);
+        }
+    };
+
+    for (key, value) in state.package.iter() {
+        let mut parts = key.splitn(3, '.').skip(1);
+        let (section, option) = match (parts.next(), parts.next()) {
+            (Some(s), Some(o)) => (s, o),
+            /* the section line, a section without options still counts */
+            (Some(s), None) if UCI_SECTIONS.contains(&s) => {
+                root.entry(s.to_string())
+                    .or_insert_with(|| toml::Value::Table(Default::default()));
+                continue;
+            }
+            _ => continue,
+        };
+        if section == "kdaemon" {
+            if let ("version", Ok(v)) = (option, value.parse::<i64>()) {
+                root.insert("version".to_string(), toml::Value::Integer(v));
+            }
+            continue;
+        }
+        if section == "network" && UCI_NATIVE.contains(&option) {
+            warn!("{} kept in network/wireless, ignored", key);
+            continue;
+        }
+        match field_typed(&root, section, option, &uci_candidates(value)) {
+            Some(v) => put(&mut root, section, option, v),
+            None => warn!("{} not a kdaemon field, ignored", key),
+        }
+    }
+
+    let network = &state.network;
+    match uci_get(network, "network.wan.proto") {
+        Some("dhcp") => put(&mut root, "network", "wan_type", toml::Value::Integer(0)),
+        Some("pppoe") => put(&mut root, "network", "wan_type", toml::Value::Integer(1)),
+        Some("static") => put(&mut root, "network", "wan_type", toml::Value::Integer(2)),
+        Some(proto) if UCI_MODEM_PROTOS.contains(&proto) => {
+            put(&mut root, "network", "wan_type", toml::Value::Integer(3))
+        }
+        proto => {
+            warn!("network.wan.proto {:?} not a wan_type, kept", proto);
+            put(&mut root, "network", "wan_type", toml::Value::Integer(0));
+        }
+    }
+    for (option, field) in [("username", "wan_username"), ("password", "wan_password")] {
+        if let Some(v) = uci_get(network, &format!("network.wan.{}", option)) {
+            put(
+                &mut root,
+                "network",
+                field,
+                toml::Value::String(v.to_string()),
+            );
+        }
+    }
+    if let Some(iface) = uci_ap_ifaces(&state.wireless).first() {
+        for (option, field) in [("ssid", "wifi_ssid"), ("key", "wifi_password")] {
+            if let Some(v) = uci_get(&state.wireless, &format!("{}.{}", iface, option)) {
+                put(
+                    &mut root,
+                    "network",
+                    field,
+                    toml::Value::String(v.to_string()),
+                );
+            }
+        }
+    }
+    root
+}
+
+fn uci_scalar(key: &str, value: &toml::Value) -> Result<String> {
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Boolean(b) => if *b { "1" } else { "0" }.to_string(),
+        toml::Value::Integer(_) | toml::Value::Float(_) => value.to_string(),
+        _ => return Err(anyhow!("{} not a uci option value", key)),
+    };
+    if value.contains('\n') {
+        return Err(anyhow!("{} multi-line, not a uci option value", key));
+    }
+    Ok(uci_quote(&value))
+}
+
+/* deletes only what exists, uci batch fails on a missing entry */
+/* a proto uci_root reads as a wan_type of its own */
+fn uci_wan_typed(proto: &str) -> bool {
+    matches!(proto, "dhcp" | "pppoe" | "static") || UCI_MODEM_PROTOS.contains(&proto)
+}
+
+fn uci_batch(
+    name: &str,
+    state: &UciState,
+    root: &toml::Value,
+    plain: &KNetworkConfig,
+) -> Result<String> {
+    let mut batch = vec![];
+    let exists = |list: &[(String, String)], key: &str| list.iter().any(|(k, _)| k == key);
+
+    for section in UCI_SECTIONS {
+        let key = format!("{}.{}", name, section);
+        if exists(&state.package, &key) {
+            batch.push(format!("delete {}", key));
+        }
+        let table = match root.get(section).and_then(|s| s.as_table()) {
+            Some(t) => t,
+            None => continue,
+        };
+        batch.push(format!("set {}={}", key, section));
+        for (field, value) in table {
+            if section == "network" && UCI_NATIVE.contains(&field.as_str()) {
+                continue;
+            }
+            let option = format!("{}.{}", key, field);
+            batch.push(format!("set {}={}", option, uci_scalar(&option, value)?));
+        }
+    }
+    let version = root
+        .get("version")
+        .and_then(|v| v.as_integer())
+        .unwrap_or_default();
+    batch.push(format!("set {}.kdaemon=kdaemon", name));
+    batch.push(format!("set {}.kdaemon.version='{}'", name, version));
+
+    /* the plaintext ones, the sealed copy in root is of no use to pppd */
+    let network = root.get("network");
+    let text = |field: &str| network.and_then(|n| n.get(field)).and_then(|v| v.as_str());
+    let native = uci_get(&state.network, "network.wan.proto");
+    match network
+        .and_then(|n| n.get("wan_type"))
+        .and_then(|v| v.as_integer())
+    {
+        Some(1) => batch.push("set network.wan.proto='pppoe'".to_string()),
+        /* static and modem addressing is not ours to write */
+        Some(2) | Some(3) => {}
+        /* 0 on a static or modem wan switches it back, any other proto read as 0 is kept */
+        Some(0) if native.is_none_or(uci_wan_typed) => {
+            batch.push("set network.wan.proto='dhcp'".to_string())
+        }
+        None if matches!(native, None | Some("dhcp") | Some("pppoe")) => {
+            batch.push("set network.wan.proto='dhcp'".to_string())
+        }
+        _ => {}
+    }
+    for (option, value) in [
+        ("username", text("wan_username")),
+        ("password", plain.wan_password.as_deref()),
+    ] {
+        let key = format!("network.wan.{}", option);
+        match value {
+            Some(v) => batch.push(format!("set {}={}", key, uci_quote(v))),
+            None if exists(&state.network, &key) => batch.push(format!("delete {}", key)),
+            None => {}
+        }
+    }
+    for iface in uci_ap_ifaces(&state.wireless) {
+        if let Some(ssid) = text("wifi_ssid") {
+            batch.push(format!("set {}.ssid={}", iface, uci_quote(ssid)));
+        }
+        if let Some(key) = plain.wifi_password.as_deref() {
+            batch.push(format!("set {}.key={}", iface, uci_quote(key)));
+        }
+    }
+
+    for package in [name, "network", "wireless"] {
+        batch.push(format!("commit {}", package));
+    }
+    Ok(batch.join("\n") + "\n")
+}
+
+/* a missing package reads empty, the load reports the missing sections */
+async fn uci_show(target: &str) -> Result<Vec<(String, String)>> {
+    let output = Command::new("uci")
+        .args(["-q", "show", target])
+        .output()
+        .await
+        .map_err(|e| anyhow!("uci show {} run fail - {e}", target))?;
+    if !output.status.success() {
+        debug!("uci show {} empty - {}", target, output.status);
+        return Ok(vec![]);
+    }
+    Ok(uci_parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+async fn uci_state(name: &str) -> Result<UciState> {
+    Ok(UciState {
+        package: uci_show(name).await?,
+        network: uci_show("network.wan").await?,
+        wireless: uci_show("wireless").await?,
+    })
+}
+
+/* the package as `uci show` text for the migration backup, and the toml form */
+pub(crate) async fn uci_read(name: &str) -> Result<(String, toml::value::Table)> {
+    let state = uci_state(name).await?;
+    let text = state
+        .package
+        .iter()
+        .map(|(k, v)| format!("{}={}\n", k, uci_quote(v)))
+        .collect();
+    Ok((text, uci_root(&state)))
+}
+
+pub(crate) async fn uci_save(name: &str, root: &toml::Value, plain: &KNetworkConfig) -> Result<()> {
+    let batch = uci_batch(name, &uci_state(name).await?, root, plain)?;
+    uci_batch_run(name, &batch).await
+}
+
+async fn uci_batch_run(name: &str, batch: &str) -> Result<()> {
+    let mut child = Command::new("uci")
+        .arg("batch")
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("uci batch run fail - {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(batch.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "uci batch {} fail - {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/* ssid and key of the first wireless ap interface, kap_wifi rolls back to them */
+pub(crate) async fn uci_wifi_read() -> Result<(Option<String>, Option<String>)> {
+    let wireless = uci_show("wireless").await?;
+    let iface = uci_ap_ifaces(&wireless)
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("wireless without ap interface"))?;
+    let get = |option: &str| uci_get(&wireless, &format!("{}.{}", iface, option)).map(String::from);
+    Ok((get("ssid"), get("key")))
+}
+
+/* encryption of each ap interface before we touched it */
+fn uci_wifi_encryption(wireless: &[(String, String)]) -> Vec<(String, String)> {
+    uci_ap_ifaces(wireless)
+        .into_iter()
+        .filter_map(|iface| {
+            let encryption = uci_get(wireless, &format!("{}.encryption", iface))?;
+            Some((iface.clone(), encryption.to_string()))
+        })
+        .collect()
+}
+
+/*
+ * every ap interface; with a key the encryption it had before we touched
+ * it (`saved`), else its own, else psk2; open without a key
+ */
+fn uci_wifi_batch(
+    wireless: &[(String, String)],
+    ssid: Option<&str>,
+    key: Option<&str>,
+    saved: &[(String, String)],
+) -> Result<String> {
+    let ifaces = uci_ap_ifaces(wireless);
+    if ifaces.is_empty() {
+        return Err(anyhow!("wireless without ap interface"));
+    }
+    let mut batch = vec![];
+    for iface in ifaces {
+        if let Some(ssid) = ssid {
+            batch.push(format!("set {}.ssid={}", iface, uci_quote(ssid)));
+        }
+        let encryption = uci_get(wireless, &format!("{}.encryption", iface));
+        match key {
+            Some(key) => {
+                batch.push(format!("set {}.key={}", iface, uci_quote(key)));
+                let before = saved
+                    .iter()
+                    .find(|(i, _)| *i == iface)
+                    .map(|(_, e)| e.as_str())
+                    .filter(|e| *e != "none");
+                match (before, encryption) {
+                    (Some(before), Some(now)) if before == now => {}
+                    (Some(before), _) => {
+                        batch.push(format!("set {}.encryption={}", iface, uci_quote(before)))
+                    }
+                    (None, None | Some("none")) => {
+                        batch.push(format!("set {}.encryption='psk2'", iface))
+                    }
+                    (None, _) => {}
+                }
+            }
+            None => {
+                if uci_get(wireless, &format!("{}.key", iface)).is_some() {
+                    batch.push(format!("delete {}.key", iface));
+                }
+                batch.push(format!("set {}.encryption='none'", iface));
+            }
+        }
+    }
+    batch.push("commit wireless".to_string());
+    Ok(batch.join("\n") + "\n")
+}
+
+/* returns `saved`, taken from the radio now when the caller has none yet */
+pub(crate) async fn uci_wifi_write(
+    ssid: Option<&str>,
+    key: Option<&str>,
+    saved: Option<Vec<(String, String)>>,
+) -> Result<Vec<(String, String)>> {
+    let wireless = uci_show("wireless").await?;
+    let saved = saved.unwrap_or_else(|| uci_wifi_encryption(&wireless));
+    let batch = uci_wifi_batch(&wireless, ssid, key, &saved)?;
+    uci_batch_run("wireless", &batch).await?;
+    let status = Command::new("wifi")
+        .arg("reload")
+        .status()
+        .await
+        .map_err(|e| anyhow!("wifi reload run fail - {e}"))?;
+    if !status.success() {
+        return Err(anyhow!("wifi reload fail - {}", status));
+    }
+    Ok(saved)
+}
+
+/*
+ * `ubus call network.wireless status`: a radio up with an ap on `ssid`
+ * running `key`, or open without one
+ */
+fn uci_wifi_status_up(status: &serde_json::Value, ssid: &str, key: Option<&str>) -> bool {
+    let key_up = |config: &serde_json::Value| match key {
+        Some(key) => config["key"] == key,
+        None => config["key"].is_null() || config["encryption"] == "none",
+    };
+    status.as_object().into_iter().flatten().any(|(_, radio)| {
+        radio["up"] == true
+            && radio["interfaces"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|i| {
+                    i["config"]["mode"] == "ap"
+                        && i["config"]["ssid"] == ssid
+                        && key_up(&i["config"])
+                })
+    })
+}
+
+pub(crate) async fn uci_wifi_up(ssid: &str, key: Option<&str>) -> Result<bool> {
+    let output = Command::new("ubus")
+        .args(["call", "network.wireless", "status"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("ubus network.wireless status run fail - {e}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ubus network.wireless status fail - {}",
+            output.status
+        ));
+    }
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow!("ubus network.wireless status not json - {e}"))?;
+    Ok(uci_wifi_status_up(&status, ssid, key))
+}
+
+pub(crate) fn uci_backup(name: &str, from: u32) -> String {
+    format!("/etc/{}.uci.v{}.bak", name, from)
+}
+
+#[test]
+fn test_uci_backend() {
+    let state = UciState {
+        package: uci_parse(
+            "fika.core=core\n\
+             fika.core.mac_address='AA:BB:CC:DD:EE:01'\n\
+             fika.core.serial_number='FK0001'\n\
+             fika.core.sku='K1'\n\
+             fika.network=network\n\
+             fika.network.password_overwrite='enc:v1:xx'\n\
+             fika.por=por\n\
+             fika.por.state='1'\n\
+             fika.por.nickname='Bob'\\''s'\n\
+             fika.por.bogus='x'\n\
+             fika.boss=boss\n\
+             fika.kdaemon=kdaemon\n\
+             fika.kdaemon.version='1'\n",
+        ),
+        network: uci_parse(
+            "network.wan=interface\nnetwork.wan.proto='pppoe'\nnetwork.wan.username='isp'\n",
+        ),
+        wireless: uci_parse(
+            "wireless.radio0=wifi-device\n\
+             wireless.@wifi-iface[0]=wifi-iface\n\
+             wireless.@wifi-iface[0].mode='sta'\n\
+             wireless.@wifi-iface[0].ssid='upstream'\n\
+             wireless.@wifi-iface[1]=wifi-iface\n\
+             wireless.@wifi-iface[1].mode='ap'\n\
+             wireless.@wifi-iface[1].ssid='fika'\n\
+             wireless.@wifi-iface[1].key='hunter22'\n",
+        ),
+    };
+    let root = uci_root(&state);
+    let cfg: crate::kap_daemon::KdaemonConfig =
+        toml::Value::Table(root.clone()).try_into().unwrap();
+    assert_eq!(cfg.version, 1);
+    assert_eq!(cfg.core.serial_number.as_str(), "FK0001");
+    assert!(cfg.por.state);
+    assert_eq!(cfg.por.nickname.as_deref(), Some("Bob's"));
+    assert_eq!(cfg.network.wan_type, 1);
+    assert_eq!(cfg.network.wan_username.as_deref(), Some("isp"));
+    assert_eq!(cfg.network.wifi_ssid.as_deref(), Some("fika"));
+    assert_eq!(cfg.network.wifi_password.as_deref(), Some("hunter22"));
+    assert!(cfg.aws.is_none());
+
+    let mut plain = cfg.network.clone();
+    plain.wan_password = None;
+    let mut sealed = toml::Value::Table(root);
+    sealed["network"].as_table_mut().unwrap().insert(
+        "wifi_password".to_string(),
+        toml::Value::String("enc:v1:yy".to_string()),
+    );
+    let batch = uci_batch("fika", &state, &sealed, &plain).unwrap();
+    let lines: Vec<&str> = batch.lines().collect();
+    assert!(lines.contains(&"delete fika.core"));
+    assert!(lines.contains(&"set fika.por.state='1'"));
+    assert!(lines.contains(&"set fika.por.nickname='Bob'\\''s'"));
+    assert!(lines.contains(&"set fika.network.password_overwrite='enc:v1:xx'"));
+    assert!(!lines
+        .iter()
+        .any(|l| l.starts_with("delete fika.aws") || l.contains("wifi_")));
+    assert!(lines.contains(&"set network.wan.proto='pppoe'"));
+    assert!(!lines
+        .iter()
+        .any(|l| l.starts_with("delete network.wan.password")));
+    assert!(lines.contains(&"set wireless.@wifi-iface[1].key='hunter22'"));
+    assert!(!lines.iter().any(|l| l.contains("@wifi-iface[0]")));
+    assert_eq!(lines.last(), Some(&"commit wireless"));
+
+    /* static and modem wans load as 2 and 3, a save leaves their proto */
+    let mut state = state;
+    for (proto, wan_type) in [("static", 2), ("qmi", 3), ("l2tp", 0)] {
+        state.network = uci_parse(&format!(
+            "network.wan=interface\nnetwork.wan.proto='{}'\n",
+            proto
+        ));
+        let root = uci_root(&state);
+        assert_eq!(root["network"]["wan_type"].as_integer(), Some(wan_type));
+        let mut root = toml::Value::Table(root);
+        let batch = uci_batch("fika", &state, &root, &plain).unwrap();
+        assert!(!batch.contains("network.wan.proto"), "{proto}");
+
+        /* switched to 0, only the proto without a wan_type stays */
+        root["network"]["wan_type"] = toml::Value::Integer(0);
+        let batch = uci_batch("fika", &state, &root, &plain).unwrap();
+        assert_eq!(
+            batch.contains("set network.wan.proto='dhcp'"),
+            wan_type != 0,
+            "{proto}"
+        );
+    }
+}
+
+#[test]
+fn test_uci_wifi() {
+    let wireless = uci_parse(
+        "wireless.radio0=wifi-device\n\
+         wireless.@wifi-iface[0]=wifi-iface\n\
+         wireless.@wifi-iface[0].mode='ap'\n\
+         wireless.@wifi-iface[0].ssid='fika'\n\
+         wireless.@wifi-iface[0].encryption='sae'\n\
+         wireless.@wifi-iface[0].key='hunter22'\n\
+         wireless.@wifi-iface[1]=wifi-iface\n\
+         wireless.@wifi-iface[1].mode='ap'\n\
+         wireless.@wifi-iface[1].ssid='fika'\n\
+         wireless.@wifi-iface[1].encryption='none'\n",
+    );
+    let saved = uci_wifi_encryption(&wireless);
+    let batch = uci_wifi_batch(&wireless, Some("fika2"), Some("new pass"), &saved).unwrap();
+    assert_eq!(
+        batch.lines().collect::<Vec<_>>(),
+        [
+            "set wireless.@wifi-iface[0].ssid='fika2'",
+            "set wireless.@wifi-iface[0].key='new pass'",
+            "set wireless.@wifi-iface[1].ssid='fika2'",
+            "set wireless.@wifi-iface[1].key='new pass'",
+            "set wireless.@wifi-iface[1].encryption='psk2'",
+            "commit wireless",
+        ]
+    );
+    let batch = uci_wifi_batch(&wireless, None, None, &saved).unwrap();
+    assert!(batch.contains("delete wireless.@wifi-iface[0].key\n"));
+    assert!(!batch.contains("delete wireless.@wifi-iface[1].key"));
+    assert!(uci_wifi_batch(&[], Some("x"), None, &[]).is_err());
+
+    /* rolled back from open, sae is what iface 0 had */
+    let open = uci_parse(
+        "wireless.@wifi-iface[0]=wifi-iface\n\
+         wireless.@wifi-iface[0].mode='ap'\n\
+         wireless.@wifi-iface[0].ssid='fika'\n\
+         wireless.@wifi-iface[0].encryption='none'\n\
+         wireless.@wifi-iface[1]=wifi-iface\n\
+         wireless.@wifi-iface[1].mode='ap'\n\
+         wireless.@wifi-iface[1].ssid='fika'\n\
+         wireless.@wifi-iface[1].encryption='none'\n",
+    );
+    let batch = uci_wifi_batch(&open, None, Some("hunter22"), &saved).unwrap();
+    assert!(batch.contains("set wireless.@wifi-iface[0].encryption='sae'\n"));
+    assert!(batch.contains("set wireless.@wifi-iface[1].encryption='psk2'\n"));
+
+    let status = serde_json::json!({
+        "radio0": { "up": true, "interfaces": [{ "config": { "mode": "ap", "ssid": "fika2", "key": "new pass" } }] },
+        "radio1": { "up": false, "interfaces": [{ "config": { "mode": "ap", "ssid": "fika5" } }] },
+    });
+    assert!(uci_wifi_status_up(&status, "fika2", Some("new pass")));
+    /* a password-only change is not up before the radio has it */
+    assert!(!uci_wifi_status_up(&status, "fika2", Some("hunter22")));
+    assert!(!uci_wifi_status_up(&status, "fika2", None));
+    assert!(!uci_wifi_status_up(&status, "fika5", None));
+    assert!(!uci_wifi_status_up(&status, "fika", Some("new pass")));
+}
@@ -0,0 +1,346 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use clap::{Args, Subcommand};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::kap_daemon::{KNetworkConfig, KWanProfile, KdaemonConfig, WanKind};
+use crate::kap_rule::RuleConfig;
+use crate::{event_message, event_publish, publish_message, set_message, setup_logging, DbCommand};
+
+/*
+ * the active one of the network.wan profiles: a switch is asked on
+ * WAN_SWITCH_CHANNEL, checked against the profiles here and applied by
+ * whoever listens (the daemon); what is up then gets reported with
+ * wan_report to WAN_STATUS_KEY and as `wan` event. wan_failover names the
+ * profile to try when the active one goes down. Tasks do the same through
+ * `wan switch` and `wan report`
+ */
+
+pub const WAN_STATUS_KEY: &str = "kap/status/wan";
+pub const WAN_SWITCH_CHANNEL: &str = "kap/network/wan/switch";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WanSwitch {
+    pub profile: String,
+    pub reason: Option<String>,
+}
+
+/* a profile without its credentials */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WanProfileState {
+    pub name: String,
+    pub kind: WanKind,
+    pub priority: u32,
+    pub interface: Option<String>,
+    pub enabled: bool,
+}
+
+impl From<&KWanProfile> for WanProfileState {
+    fn from(p: &KWanProfile) -> Self {
+        Self {
+            name: p.name.clone(),
+            kind: p.kind,
+            priority: p.priority,
+            interface: p.interface.clone(),
+            enabled: p.enabled(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WanStatus {
+    pub active: Option<String>,
+    pub kind: Option<WanKind>,
+    pub up: bool,
+    pub reason: Option<String>,
+    pub profiles: Vec<WanProfileState>,
+    pub at: DateTime<Utc>,
+}
+
+pub fn wan_request(
+    network: &KNetworkConfig,
+    profile: &str,
+    reason: Option<String>,
+) -> Result<WanSwitch> {
+    match network.wan_profile(profile) {
+        Some(p) if p.enabled() => Ok(WanSwitch {
+            profile: profile.to_string(),
+            reason,
+        }),
+        Some(_) => Err(anyhow!("wan profile {} disabled", profile)),
+        None => Err(anyhow!("wan profile {} nonexist", profile)),
+    }
+}
+
+/* the next enabled one after `failed` by priority, around to the first */
+pub fn wan_failover(network: &KNetworkConfig, failed: &str) -> Option<KWanProfile> {
+    let profiles = network.wan_profiles();
+    let from = profiles.iter().position(|p| p.name == failed)?;
+    profiles
+        .iter()
+        .cycle()
+        .skip(from + 1)
+        .take(profiles.len() - 1)
+        .find(|p| p.enabled())
+        .cloned()
+}
+
+pub fn wan_status(
+    network: &KNetworkConfig,
+    active: Option<&str>,
+    up: bool,
+    reason: Option<String>,
+) -> Result<WanStatus> {
+    let profiles = network.wan_profiles();
+    let kind = match active {
+        Some(name) => Some(
+            profiles
+                .iter()
+                .find(|p| p.name == name)
+                .ok_or_else(|| anyhow!("wan profile {} nonexist", name))?
+                .kind,
+        ),
+        None => None,
+    };
+    Ok(WanStatus {
+        active: active.map(String::from),
+        kind,
+        up: up && active.is_some(),
+        reason,
+        profiles: profiles.iter().map(WanProfileState::from).collect(),
+        at: Utc::now(),
+    })
+}
+
+pub async fn wan_switch(
+    db_chan: &mpsc::Sender<DbCommand>,
+    network: &KNetworkConfig,
+    profile: &str,
+    reason: Option<String>,
+) -> Result<()> {
+    let request = wan_request(network, profile, reason)?;
+    publish_message(
+        db_chan,
+        WAN_SWITCH_CHANNEL.to_string(),
+        serde_json::to_string(&request)?,
+    )
+    .await?;
+    info!("wan switch to {} asked", profile);
+    Ok(())
+}
+
+pub async fn wan_report(
+    db_chan: &mpsc::Sender<DbCommand>,
+    network: &KNetworkConfig,
+    active: Option<&str>,
+    up: bool,
+    reason: Option<String>,
+) -> Result<WanStatus> {
+    let status = wan_status(network, active, up, reason)?;
+    set_message(
+        db_chan.clone(),
+        WAN_STATUS_KEY.to_string(),
+        serde_json::to_string(&status)?,
+    )
+    .await?;
+    if let Err(e) = event_publish(db_chan, "wan", serde_json::to_value(&status)?).await {
+        warn!("wan event publish fail - {e}");
+    }
+    Ok(status)
+}
+
+#[derive(Subcommand, Debug)]
+enum WanCommand {
+    #[clap(about = "list the profiles by priority")]
+    List,
+    #[clap(about = "the last report of the active profile")]
+    Status,
+    #[clap(about = "ask the daemon to switch to a profile")]
+    Switch {
+        profile: String,
+        #[clap(long = "reason")]
+        reason: Option<String>,
+    },
+    #[clap(about = "report the profile now in use, by whoever applied it")]
+    Report {
+        profile: Option<String>,
+        #[clap(
+            long = "down",
+            action,
+            help = "the profile is applied but its link is down"
+        )]
+        down: bool,
+        #[clap(long = "reason")]
+        reason: Option<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager WAN profiles")]
+pub struct WanOpt {
+    #[clap(subcommand)]
+    command: WanCommand,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(short = 'l', long = "log-level", default_value = "warn")]
+    log_level: String,
+}
+
+async fn wan_conn(rule: &RuleConfig) -> Result<redis::aio::Connection> {
+    let database = rule
+        .core
+        .database
+        .as_deref()
+        .ok_or_else(|| anyhow!("rule/core/database none invalid"))?;
+    redis::Client::open(database)?
+        .get_async_connection()
+        .await
+        .map_err(|e| anyhow!("db/redis {} connect fail - {e}", database))
+}
+
+pub async fn wan_tools(opt: WanOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let rule = RuleConfig::build_from(&opt.rule).await?;
+    let network = KdaemonConfig::build_from(&rule.core.config).await?.network;
+    match opt.command {
+        WanCommand::List => {
+            let profiles: Vec<WanProfileState> = network
+                .wan_profiles()
+                .iter()
+                .map(WanProfileState::from)
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&profiles)?);
+        }
+        WanCommand::Status => {
+            let status: Option<String> = wan_conn(&rule).await?.get(WAN_STATUS_KEY).await?;
+            let status =
+                status.ok_or_else(|| anyhow!("{} none, nothing reported yet", WAN_STATUS_KEY))?;
+            let status: WanStatus = serde_json::from_str(&status)?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        WanCommand::Switch { profile, reason } => {
+            let request = wan_request(&network, &profile, reason)?;
+            let receivers: usize = wan_conn(&rule)
+                .await?
+                .publish(WAN_SWITCH_CHANNEL, serde_json::to_string(&request)?)
+                .await?;
+            if receivers == 0 {
+                return Err(anyhow!(
+                    "wan switch to {} unheard, daemon not listening",
+                    profile
+                ));
+            }
+            info!("wan switch to {} asked", profile);
+        }
+        WanCommand::Report {
+            profile,
+            down,
+            reason,
+        } => {
+            let status = wan_status(&network, profile.as_deref(), !down, reason)?;
+            let mut conn = wan_conn(&rule).await?;
+            conn.set::<_, _, ()>(WAN_STATUS_KEY, serde_json::to_string(&status)?)
+                .await?;
+            let (channel, event) = event_message("wan", serde_json::to_value(&status)?);
+            let _: usize = conn.publish(channel, event).await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_wan_profiles() {
+    let cfg: KdaemonConfig = toml::from_str(
+        r#"
+        [core]
+        mac_address = "AA:BB:CC:DD:EE:01"
+        serial_number = "FK0001"
+        sku = "K1"
+        [network]
+        wan_type = 0
+        [[network.wan]]
+        name = "lte"
+        kind = "lte"
+        priority = 20
+        apn = "internet"
+        [[network.wan]]
+        name = "fiber"
+        kind = "pppoe"
+        priority = 10
+        username = "isp"
+        password = "secret"
+        [[network.wan]]
+        name = "backup"
+        kind = "static"
+        priority = 30
+        ipaddr = "192.0.2.10"
+        gateway = "192.0.2.1"
+        disable = true
+        [por]
+        state = true
+        [boss]
+        "#,
+    )
+    .unwrap();
+    assert!(cfg.validate().is_empty());
+    let network = &cfg.network;
+    let names: Vec<String> = network.wan_profiles().into_iter().map(|p| p.name).collect();
+    assert_eq!(names, ["fiber", "lte", "backup"]);
+
+    assert_eq!(wan_failover(network, "fiber").unwrap().name, "lte");
+    /* backup is disabled, around to the first */
+    assert_eq!(wan_failover(network, "lte").unwrap().name, "fiber");
+    assert!(wan_failover(network, "nope").is_none());
+
+    assert!(wan_request(network, "lte", None).is_ok());
+    assert!(wan_request(network, "backup", None).is_err());
+    assert!(wan_request(network, "nope", None).is_err());
+
+    let status = wan_status(network, Some("lte"), true, Some("fiber down".to_string())).unwrap();
+    assert_eq!((status.kind, status.up), (Some(WanKind::Lte), true));
+    assert!(!serde_json::to_string(&status).unwrap().contains("secret"));
+    assert!(!wan_status(network, None, true, None).unwrap().up);
+    assert!(wan_status(network, Some("nope"), true, None).is_err());
+
+    /* the legacy fields are the one profile without [[network.wan]] */
+    let mut legacy = network.clone();
+    legacy.wan.clear();
+    legacy.wan_type = 1;
+    legacy.wan_username = Some("isp".to_string());
+    let profiles = legacy.wan_profiles();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(
+        (profiles[0].name.as_str(), profiles[0].kind),
+        ("wan", WanKind::Pppoe)
+    );
+    assert!(wan_failover(&legacy, "wan").is_none());
+    legacy.wan_type = 2;
+    assert_eq!(legacy.wan_profiles()[0].kind, WanKind::Static);
+
+    let mut bad = cfg.clone();
+    bad.network.wan[0].name = "fiber".to_string();
+    bad.network.wan[0].pin = Some("12".to_string());
+    bad.network.wan[1].username = None;
+    bad.network.wan[2].ipaddr = Some("192.0.2.300".to_string());
+    bad.network.wan[2].dns = Some(vec!["dns.example".to_string()]);
+    let paths: Vec<String> = bad.validate().into_iter().map(|v| v.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "network.wan[0].pin",
+            "network.wan[1].name",
+            "network.wan[1].username",
+            "network.wan[2].ipaddr",
+            "network.wan[2].dns",
+        ]
+    );
+}
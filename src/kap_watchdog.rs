@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::{event_publish, DbCommand, DAEMON_START_KEY};
+
+/*
+ * stuck-loop watchdog: a subsystem beats its Liveness from inside its own
+ * loop (the DB actor is probed through its channel instead, it has no
+ * loop of ours), `supervise` aborts and respawns only the one whose beat
+ * went stale for `timeout` and publishes a `watchdog` event per recovery.
+ * The daemon wires its DB actor and scheduler to it; in here the api db
+ * actor runs supervised and the mqtt receive loop gives up on a handler
+ * stuck longer than WATCHDOG_TIMEOUT, reconnecting through its retry loop
+ */
+
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+const WATCHDOG_EVENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+fn since_epoch() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+#[derive(Clone, Debug)]
+pub struct Liveness(Arc<AtomicU64>);
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU64::new(since_epoch())))
+    }
+}
+
+impl Liveness {
+    pub fn beat(&self) {
+        self.0.store(since_epoch(), Ordering::Relaxed);
+    }
+
+    pub fn stalled(&self) -> Duration {
+        Duration::from_millis(since_epoch().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/* the recovery record, bounded since the stuck one may be the DB actor itself */
+pub async fn watchdog_event(
+    db_chan: &mpsc::Sender<DbCommand>,
+    subsystem: &str,
+    stalled: Duration,
+    restarts: u32,
+) {
+    let event = json!({
+        "subsystem": subsystem,
+        "stalled_ms": stalled.as_millis() as u64,
+        "restarts": restarts,
+    });
+    match time::timeout(
+        WATCHDOG_EVENT_TIMEOUT,
+        event_publish(db_chan, "watchdog", event),
+    )
+    .await
+    {
+        Ok(Err(e)) => warn!("watchdog event publish fail - {e}"),
+        Err(_) => warn!("watchdog event publish timeout"),
+        Ok(Ok(_)) => {}
+    }
+}
+
+/*
+ * run what `spawn` builds, restart it whenever `live` goes stale; a
+ * subsystem ending by itself ends the supervision with its result, so
+ * state it must keep over a restart (its receiver) goes behind a lock
+ */
+pub async fn supervise<F, Fut>(
+    name: &str,
+    live: Liveness,
+    timeout: Duration,
+    db_chan: Option<mpsc::Sender<DbCommand>>,
+    mut spawn: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut restarts: u32 = 0;
+    let mut check = time::interval(timeout / 4);
+    check.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        live.beat();
+        let mut handle = tokio::spawn(spawn());
+        let stalled = loop {
+            tokio::select! {
+                r = &mut handle => {
+                    debug!("{} ended - {:?}", name, r);
+                    return r.map_err(|e| anyhow!("{} join fail - {e}", name))?;
+                }
+                _ = check.tick() => {
+                    let stalled = live.stalled();
+                    if stalled >= timeout {
+                        break stalled;
+                    }
+                }
+            }
+        };
+
+        handle.abort();
+        _ = handle.await;
+        restarts += 1;
+        warn!(
+            "{} unresponsive for {}ms, restarted ({})",
+            name,
+            stalled.as_millis(),
+            restarts
+        );
+        if let Some(ref db_chan) = db_chan {
+            watchdog_event(db_chan, name, stalled, restarts).await;
+        }
+        info!("{} recovered by watchdog", name);
+    }
+}
+
+/* beats `live` for as long as the DB actor answers a GET every `every` */
+pub async fn db_probe(db_chan: mpsc::Sender<DbCommand>, live: Liveness, every: Duration) {
+    loop {
+        time::sleep(every).await;
+        let (resp, resp_rx) = oneshot::channel();
+        let cmd = DbCommand::Get {
+            key: DAEMON_START_KEY.to_string(),
+            resp,
+        };
+        if db_chan.send(cmd).await.is_err() {
+            debug!("db probe leave, actor gone");
+            return;
+        }
+        /* a restart drops the pending reply, the next probe goes to the new actor */
+        if resp_rx.await.is_ok() {
+            live.beat();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_watchdog_restart() {
+    use std::sync::atomic::AtomicUsize;
+
+    let live = Liveness::default();
+    assert!(live.stalled() < Duration::from_millis(50));
+
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let counter = spawned.clone();
+    let beat = live.clone();
+    let r = supervise(
+        "test",
+        live.clone(),
+        Duration::from_millis(200),
+        None,
+        move || {
+            let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            let beat = beat.clone();
+            async move {
+                if first {
+                    /* stuck, never beats */
+                    time::sleep(Duration::from_secs(3600)).await;
+                }
+                for _ in 0..5 {
+                    beat.beat();
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(())
+            }
+        },
+    )
+    .await;
+    assert!(r.is_ok());
+    assert_eq!(spawned.load(Ordering::SeqCst), 2);
+
+    let (db_chan, mut rx) = mpsc::channel(4);
+    let probe = Liveness::default();
+    tokio::spawn(db_probe(db_chan, probe.clone(), Duration::from_millis(20)));
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(probe.stalled() >= Duration::from_millis(80));
+    if let Some(DbCommand::Get { resp, .. }) = rx.recv().await {
+        _ = resp.send(None);
+    }
+    time::sleep(Duration::from_millis(10)).await;
+    assert!(probe.stalled() < Duration::from_millis(50));
+}
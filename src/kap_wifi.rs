@@ -0,0 +1,450 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use clap::{Args, Subcommand};
+use serde_json::json;
+use tokio::process::Command;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::kap_daemon::{KNetworkConfig, KdaemonConfig, KDAEMON_SECRET};
+use crate::kap_feature::features_init;
+use crate::kap_rule::{RuleConfig, RuleWifiBackend, RuleWifiConfig};
+use crate::misc::secret_file_read;
+use crate::setup_logging;
+
+/*
+ * network.wifi_ssid/wifi_password onto the radio through a backend (uci
+ * wireless and `wifi reload`, or a script pair for hostapd and the like),
+ * then polled until the AP is up with them; one that does not come up
+ * within the timeout gets the previous pair applied back. `wifi set` saves
+ * kdaemon.toml only once the new pair is up, instead of a post hook
+ */
+
+const WIFI_TIMEOUT: Duration = Duration::from_secs(30);
+const WIFI_POLL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct WifiSettings {
+    pub ssid: Option<String>,
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for WifiSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WifiSettings")
+            .field("ssid", &self.ssid)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl std::fmt::Display for WifiSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.ssid.as_deref().unwrap_or_default())
+    }
+}
+
+impl From<&KNetworkConfig> for WifiSettings {
+    fn from(network: &KNetworkConfig) -> Self {
+        Self {
+            ssid: network.wifi_ssid.clone(),
+            password: network.wifi_password.clone(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait WifiBackend: Send + Sync {
+    /* what the radio runs now, None when the backend cannot tell */
+    async fn current(&self) -> Result<Option<WifiSettings>> {
+        Ok(None)
+    }
+    async fn apply(&self, wifi: &WifiSettings) -> Result<()>;
+    /* one look, wifi_apply does the polling */
+    async fn up(&self, wifi: &WifiSettings) -> Result<bool>;
+}
+
+/* FIKA_WIFI_SSID/FIKA_WIFI_PASSWORD in env, kept off the command line */
+pub struct WifiScript {
+    pub apply: String,
+    pub verify: Option<String>,
+    pub timeout: Duration,
+}
+
+impl WifiScript {
+    async fn run(&self, path: &str, wifi: &WifiSettings) -> Result<bool> {
+        let mut cmd = Command::new(path);
+        for (var, value) in [
+            ("FIKA_WIFI_SSID", &wifi.ssid),
+            ("FIKA_WIFI_PASSWORD", &wifi.password),
+        ] {
+            match value {
+                Some(v) => cmd.env(var, v),
+                None => cmd.env_remove(var),
+            };
+        }
+        let mut child = cmd
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("wifi {} run fail - {e}", path))?;
+        match time::timeout(self.timeout, child.wait()).await {
+            Ok(status) => Ok(status?.success()),
+            Err(_) => Err(anyhow!("wifi {} timeout", path)),
+        }
+    }
+}
+
+#[async_trait]
+impl WifiBackend for WifiScript {
+    async fn apply(&self, wifi: &WifiSettings) -> Result<()> {
+        if !self.run(&self.apply, wifi).await? {
+            return Err(anyhow!("wifi {} fail", self.apply));
+        }
+        Ok(())
+    }
+
+    /* nothing to ask without a verify script, applied is up */
+    async fn up(&self, wifi: &WifiSettings) -> Result<bool> {
+        match self.verify {
+            Some(ref verify) => self.run(verify, wifi).await,
+            None => Ok(true),
+        }
+    }
+}
+
+/* keeps the encryption the radio had before the first apply, for the roll back */
+#[cfg(feature = "uci")]
+#[derive(Default)]
+pub struct WifiUci {
+    encryption: std::sync::Mutex<Option<Vec<(String, String)>>>,
+}
+
+#[cfg(feature = "uci")]
+#[async_trait]
+impl WifiBackend for WifiUci {
+    async fn current(&self) -> Result<Option<WifiSettings>> {
+        let (ssid, password) = crate::kap_uci::uci_wifi_read().await?;
+        Ok(Some(WifiSettings { ssid, password }))
+    }
+
+    async fn apply(&self, wifi: &WifiSettings) -> Result<()> {
+        let saved = self
+            .encryption
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let saved =
+            crate::kap_uci::uci_wifi_write(wifi.ssid.as_deref(), wifi.password.as_deref(), saved)
+                .await?;
+        self.encryption
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(saved);
+        Ok(())
+    }
+
+    /* an ssid left as it is is the one the radio has, the key must match too */
+    async fn up(&self, wifi: &WifiSettings) -> Result<bool> {
+        let ssid = match wifi.ssid {
+            Some(ref ssid) => ssid.clone(),
+            None => crate::kap_uci::uci_wifi_read()
+                .await?
+                .0
+                .ok_or_else(|| anyhow!("wireless ap without ssid"))?,
+        };
+        crate::kap_uci::uci_wifi_up(&ssid, wifi.password.as_deref()).await
+    }
+}
+
+#[cfg(feature = "uci")]
+fn wifi_uci() -> Result<Box<dyn WifiBackend>> {
+    Ok(Box::<WifiUci>::default())
+}
+
+#[cfg(not(feature = "uci"))]
+fn wifi_uci() -> Result<Box<dyn WifiBackend>> {
+    Err(anyhow!("rule/wifi/backend uci due uci feature disable"))
+}
+
+pub fn wifi_backend(cfg: &RuleWifiConfig) -> Result<Box<dyn WifiBackend>> {
+    let timeout = cfg.timeout.unwrap_or(WIFI_TIMEOUT);
+    match cfg.backend {
+        Some(RuleWifiBackend::Uci) => wifi_uci(),
+        Some(RuleWifiBackend::Script) | None => {
+            let apply = cfg
+                .apply
+                .clone()
+                .ok_or_else(|| anyhow!("rule/wifi/apply none invalid"))?;
+            Ok(Box::new(WifiScript {
+                apply,
+                verify: cfg.verify.clone(),
+                timeout,
+            }))
+        }
+    }
+}
+
+async fn wifi_wait_up(
+    backend: &dyn WifiBackend,
+    wifi: &WifiSettings,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let poll = WIFI_POLL.min(timeout / 4);
+    loop {
+        match backend.up(wifi).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => debug!("wifi {} up check fail - {e}", wifi),
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!("AP {} not up within {:?}", wifi, timeout));
+        }
+        time::sleep(poll).await;
+    }
+}
+
+async fn wifi_try(backend: &dyn WifiBackend, wifi: &WifiSettings, timeout: Duration) -> Result<()> {
+    backend.apply(wifi).await?;
+    wifi_wait_up(backend, wifi, timeout).await
+}
+
+/* `next` up on the radio, else `prev` put back and the failure returned */
+pub async fn wifi_apply(
+    backend: &dyn WifiBackend,
+    next: &WifiSettings,
+    prev: Option<&WifiSettings>,
+    timeout: Duration,
+) -> Result<()> {
+    let e = match wifi_try(backend, next, timeout).await {
+        Ok(_) => {
+            info!("wifi {} up", next);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    let prev = match prev {
+        Some(prev) if prev != next => prev,
+        _ => return Err(anyhow!("wifi {} fail, nothing to roll back to - {e}", next)),
+    };
+
+    warn!("wifi {} fail, back to {} - {e}", next, prev);
+    match wifi_try(backend, prev, timeout).await {
+        Ok(_) => Err(anyhow!("wifi {} fail, rolled back to {} - {e}", next, prev)),
+        Err(back) => Err(anyhow!(
+            "wifi {} fail - {e}, roll back to {} fail too - {back}",
+            next,
+            prev
+        )),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum WifiCommand {
+    #[clap(about = "put network.wifi_ssid/wifi_password of the config on the radio")]
+    Apply,
+    #[clap(about = "change ssid/password, saved once the AP is up with them")]
+    Set {
+        #[clap(long = "ssid")]
+        ssid: Option<String>,
+        #[clap(
+            long = "password-file",
+            conflicts_with = "open",
+            help = "file holding the new password, - reads it from stdin"
+        )]
+        password_file: Option<String>,
+        #[clap(long = "open", action, help = "without password")]
+        open: bool,
+    },
+    #[clap(about = "what the radio runs and whether it is up")]
+    Status,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "FIKA manager Wi-Fi apply with roll back")]
+pub struct WifiOpt {
+    #[clap(subcommand)]
+    command: WifiCommand,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+    #[clap(short = 's', long = "secret", default_value = KDAEMON_SECRET)]
+    secret: String,
+    #[clap(short = 'l', long = "log-level", default_value = "info")]
+    log_level: String,
+}
+
+pub async fn wifi_tools(opt: WifiOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let rule = RuleConfig::build_from(&opt.rule).await?;
+    let features = features_init(&rule).await;
+    if !features.enabled("wifi") {
+        return Err(anyhow!("wifi off for sku {}", features.sku));
+    }
+    let wifi = rule
+        .wifi
+        .ok_or_else(|| anyhow!("rule/wifi none, no backend"))?;
+    let backend = wifi_backend(&wifi)?;
+    let timeout = wifi.timeout.unwrap_or(WIFI_TIMEOUT);
+    let config = rule.core.config;
+
+    match opt.command {
+        WifiCommand::Apply => {
+            let cfg = KdaemonConfig::build_from_secret(&config, &opt.secret).await?;
+            let prev = match backend.current().await {
+                Ok(prev) => prev,
+                Err(e) => {
+                    warn!("wifi current unknown, no roll back - {e}");
+                    None
+                }
+            };
+            wifi_apply(
+                backend.as_ref(),
+                &WifiSettings::from(&cfg.network),
+                prev.as_ref(),
+                timeout,
+            )
+            .await?;
+        }
+        WifiCommand::Set {
+            ssid,
+            password_file,
+            open,
+        } => {
+            let password = match password_file {
+                Some(ref file) => Some(secret_file_read(file).await?),
+                None => None,
+            };
+            let mut cfg = KdaemonConfig::build_from_secret(&config, &opt.secret).await?;
+            let prev = WifiSettings::from(&cfg.network);
+            if ssid.is_some() {
+                cfg.network.wifi_ssid = ssid;
+            }
+            if open {
+                cfg.network.wifi_password = None;
+            } else if password.is_some() {
+                cfg.network.wifi_password = password;
+            }
+            let next = WifiSettings::from(&cfg.network);
+            if next == prev {
+                info!("wifi {} unchanged", next);
+                return Ok(());
+            }
+            /* checked before the radio goes down for it */
+            let violations: Vec<String> = cfg
+                .validate()
+                .into_iter()
+                .filter(|v| v.path.starts_with("network.wifi_"))
+                .map(|v| v.to_string())
+                .collect();
+            if !violations.is_empty() {
+                return Err(anyhow!("wifi invalid - {}", violations.join("; ")));
+            }
+
+            wifi_apply(backend.as_ref(), &next, Some(&prev), timeout).await?;
+            cfg.save_secret(&config, &opt.secret).await?;
+            info!("{} wifi {} saved", config, next);
+        }
+        WifiCommand::Status => {
+            let current = match backend.current().await? {
+                Some(current) => current,
+                None => {
+                    let cfg = KdaemonConfig::build_from_secret(&config, &opt.secret).await?;
+                    WifiSettings::from(&cfg.network)
+                }
+            };
+            let up = backend.up(&current).await?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json!({
+                    "ssid": current.ssid,
+                    "password": current.password.is_some(),
+                    "up": up,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wifi_rollback() {
+    use std::sync::Mutex;
+
+    /* records what got applied, up only for the ssids in `good` */
+    struct Radio {
+        applied: Mutex<Vec<WifiSettings>>,
+        good: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl WifiBackend for Radio {
+        async fn apply(&self, wifi: &WifiSettings) -> Result<()> {
+            self.applied.lock().unwrap().push(wifi.clone());
+            Ok(())
+        }
+
+        async fn up(&self, wifi: &WifiSettings) -> Result<bool> {
+            Ok(self
+                .good
+                .contains(&wifi.ssid.as_deref().unwrap_or_default()))
+        }
+    }
+
+    let settings = |ssid: &str| WifiSettings {
+        ssid: Some(ssid.to_string()),
+        password: Some("hunter22".to_string()),
+    };
+    let (old, new) = (settings("fika"), settings("fika-new"));
+    let timeout = Duration::from_millis(100);
+
+    let radio = Radio {
+        applied: Mutex::new(vec![]),
+        good: vec!["fika", "fika-new"],
+    };
+    wifi_apply(&radio, &new, Some(&old), timeout).await.unwrap();
+    assert_eq!(*radio.applied.lock().unwrap(), std::slice::from_ref(&new));
+
+    let radio = Radio {
+        applied: Mutex::new(vec![]),
+        good: vec!["fika"],
+    };
+    let e = wifi_apply(&radio, &new, Some(&old), timeout)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("rolled back to \"fika\""), "{e}");
+    assert_eq!(*radio.applied.lock().unwrap(), [new.clone(), old.clone()]);
+
+    let radio = Radio {
+        applied: Mutex::new(vec![]),
+        good: vec![],
+    };
+    let e = wifi_apply(&radio, &new, Some(&old), timeout)
+        .await
+        .unwrap_err();
+    assert!(
+        e.to_string().contains("roll back to \"fika\" fail too"),
+        "{e}"
+    );
+    let e = wifi_apply(&radio, &new, None, timeout).await.unwrap_err();
+    assert!(e.to_string().contains("nothing to roll back to"), "{e}");
+    assert!(!format!("{:?}", new).contains("hunter22"));
+
+    let script = |apply: &str, verify: Option<&str>| {
+        wifi_backend(&RuleWifiConfig {
+            backend: Some(RuleWifiBackend::Script),
+            apply: Some(apply.to_string()),
+            verify: verify.map(String::from),
+            timeout: Some(Duration::from_secs(5)),
+        })
+        .unwrap()
+    };
+    assert!(script("true", Some("true")).apply(&new).await.is_ok());
+    assert!(script("false", None).apply(&new).await.is_err());
+    assert!(!script("true", Some("false")).up(&new).await.unwrap());
+    assert!(wifi_backend(&RuleWifiConfig::default()).is_err());
+}
@@ -1,18 +1,45 @@
 use crate::kap_daemon::KdaemonConfig;
-use crate::kap_rule::RuleConfig;
+use crate::kap_rule::{
+    RuleConfig, RuleConfigCore, RuleLogFile, RuleTaskBuiltin, RuleTaskClock, RuleTaskTruncate,
+    RuleTaskTxwatch,
+};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use tokio::sync::{/*broadcast, Notify,*/ mpsc, oneshot};
+use tokio::process::Command;
+use tokio::sync::{/*broadcast, Notify,*/ mpsc, oneshot, watch};
 use tokio::time::Duration;
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 pub mod activate;
 #[cfg(feature = "aws-iot")]
 pub mod aws_iot;
+#[cfg(feature = "api")]
+pub mod kap_api;
+pub mod kap_audit;
+pub mod kap_boot;
+pub mod kap_cfgsync;
+pub mod kap_collect;
+pub mod kap_crash;
 pub mod kap_daemon;
-pub use self::activate::{activate, ActivateOpt};
+pub mod kap_diagnose;
+pub mod kap_feature;
+pub mod kap_health;
+pub mod kap_honest;
+pub mod kap_monitor;
+pub mod kap_notify;
+pub mod kap_passwd;
+#[cfg(feature = "portal")]
+pub mod kap_portal;
+mod kap_syslog;
+#[cfg(feature = "uci")]
+mod kap_uci;
+pub mod kap_wan;
+pub mod kap_watchdog;
+pub mod kap_wifi;
+pub use self::activate::{activate, factory_reset, ActivateOpt, FactoryResetOpt};
+pub use self::misc::address_checksum;
 pub mod misc;
 pub mod web_api;
 #[cfg(feature = "boss-api")]
@@ -22,8 +49,27 @@ pub use self::misc::{time_tools, TimeToolOpt};
 pub use self::misc::{wallet_tools, WalletCommand};
 #[cfg(feature = "aws-cli")]
 pub use self::web_api::aws_web_cli;
-pub use self::web_api::{boss_web_cli, curl_web_cli, CurlMethod, WebAwsOpt, WebBossOpt};
+#[cfg(feature = "boss-api")]
+pub use self::web_api::BossClient;
+pub use self::web_api::{
+    boss_web_cli, curl_web_cli, oauth_web_cli, sse_web_cli, CurlMethod, WebAwsOpt, WebBossOpt,
+    WebOAuthOpt, WebSseOpt,
+};
 pub mod kap_rule;
+pub use self::kap_rule::{rule_tools, RuleOpt};
+pub mod kap_task;
+#[cfg(feature = "api")]
+pub use self::kap_api::{api_tools, ApiOpt};
+pub use self::kap_daemon::{config_tools, secret_tools, ConfigOpt, SecretOpt};
+pub use self::kap_diagnose::{diagnose_tools, DiagnoseOpt};
+pub use self::kap_feature::{feature_tools, FeatureOpt};
+pub use self::kap_health::{health_tools, HealthOpt};
+pub use self::kap_passwd::{passwd_tools, PasswdOpt};
+#[cfg(feature = "portal")]
+pub use self::kap_portal::{portal_tools, PortalOpt};
+pub use self::kap_task::{task_tools, TaskOpt};
+pub use self::kap_wan::{wan_tools, WanOpt};
+pub use self::kap_wifi::{wifi_tools, WifiOpt};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -53,6 +99,12 @@ pub enum DbCommand {
         val: String,
         limit: usize,
     },
+    /* `MAXLEN ~ maxlen` when set, an unbounded append otherwise */
+    Xadd {
+        key: String,
+        fields: Vec<(String, String)>,
+        maxlen: Option<usize>,
+    },
     /*AwsShadowPublish {
         key: String,
         val: String,
@@ -71,7 +123,11 @@ pub enum DbCommand {
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum SubscribeCmd {
-    Notify { topic: String, msg: String },
+    Notify {
+        topic: String,
+        msg: String,
+        version: Option<u16>,
+    },
     Exit,
 }
 
@@ -79,14 +135,127 @@ pub enum SubscribeCmd {
 #[allow(dead_code)]
 pub struct RuleConfigTask {
     pub topic: String,
-    pub path: PathBuf,
+    pub path: Option<PathBuf>,
+    pub command: Option<Vec<String>>,
+    pub inline_sh: Option<String>,
+    pub builtin: Option<RuleTaskBuiltin>,
+    pub txwatch: Option<RuleTaskTxwatch>,
+    pub clock: Option<RuleTaskClock>,
+    pub watch: Option<Vec<PathBuf>>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub debounce: Option<Duration>,
+    pub output_limit: Option<usize>,
+    pub truncate: Option<RuleTaskTruncate>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
     pub start_at: Option<Duration>,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
     pub period: Option<Duration>,
     pub db_publish: Option<bool>,
     pub db_set: Option<bool>,
     pub aws_publish: Option<bool>,
 }
 
+impl RuleConfigTask {
+    /* exactly one of path/command/inline_sh/builtin must be given */
+    pub fn build_command(&self) -> Result<Command> {
+        if let Some(builtin) = self.builtin {
+            return Err(anyhow!(
+                "task/{} builtin/{:?} is not a command",
+                self.topic,
+                builtin
+            ));
+        }
+
+        match (&self.path, &self.command, &self.inline_sh) {
+            (Some(path), None, None) => Ok(Command::new(path)),
+            (None, Some(argv), None) => {
+                let (prog, args) = argv
+                    .split_first()
+                    .ok_or_else(|| anyhow!("task/{} command empty", self.topic))?;
+                let mut cmd = Command::new(prog);
+                cmd.args(args);
+                Ok(cmd)
+            }
+            (None, None, Some(sh)) => {
+                let mut cmd = Command::new("/bin/sh");
+                cmd.arg("-c").arg(sh);
+                Ok(cmd)
+            }
+            (None, None, None) => Err(anyhow!(
+                "task/{} without path/command/inline_sh",
+                self.topic
+            )),
+            _ => Err(anyhow!(
+                "task/{} path/command/inline_sh are exclusive",
+                self.topic
+            )),
+        }
+    }
+}
+
+/*
+ * DbCommand served by redis directly, for who has no daemon db task (the
+ * api, notify); the receiver is locked so a supervised restart picks up
+ * the queue
+ */
+pub(crate) async fn db_serve(
+    db: redis::Client,
+    rx: std::sync::Arc<tokio::sync::Mutex<mpsc::Receiver<DbCommand>>>,
+    guard: Option<ShutdownGuard>,
+) -> Result<()> {
+    use redis::AsyncCommands;
+
+    /* not on `stopped()`, what the stopping loops queue still goes out */
+    let _guard = guard;
+    let mut rx = rx.lock().await;
+    let mut conn = db.get_async_connection().await?;
+
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            DbCommand::Get { key, resp } => {
+                _ = resp.send(conn.get(&key).await.ok());
+            }
+            DbCommand::Set { key, val, resp } => {
+                _ = resp.send(conn.set(&key, val).await.ok());
+            }
+            DbCommand::Publish { key, val, resp } => {
+                _ = resp.send(conn.publish(&key, val).await.ok());
+            }
+            DbCommand::Lindex { key, idx, resp } => {
+                _ = resp.send(conn.lindex(&key, idx).await.ok());
+            }
+            DbCommand::Rpush { key, val, limit } => {
+                let r: redis::RedisResult<()> = redis::pipe()
+                    .rpush(&key, val)
+                    .ltrim(&key, -(limit as isize), -1)
+                    .query_async(&mut conn)
+                    .await;
+                if let Err(e) = r {
+                    warn!("db/redis rpush {} fail - {e}", key);
+                }
+            }
+            DbCommand::Xadd {
+                key,
+                fields,
+                maxlen,
+            } => {
+                let mut cmd = redis::cmd("XADD");
+                cmd.arg(&key);
+                if let Some(maxlen) = maxlen {
+                    cmd.arg("MAXLEN").arg("~").arg(maxlen);
+                }
+                cmd.arg("*").arg(fields);
+                let r: redis::RedisResult<String> = cmd.query_async(&mut conn).await;
+                if let Err(e) = r {
+                    warn!("db/redis xadd {} fail - {e}", key);
+                }
+            }
+            DbCommand::Exit => break,
+        }
+    }
+    Ok(())
+}
+
 #[instrument(skip(chan_tx))]
 pub async fn publish_message(
     chan_tx: &mpsc::Sender<DbCommand>,
@@ -137,20 +306,618 @@ pub async fn set_message(
     Ok(())
 }
 
+/* unix seconds the daemon came up, `time-tool uptime` reads it */
+pub const DAEMON_START_KEY: &str = "kap/daemon/start_at";
+
+pub async fn daemon_start_mark(chan_tx: mpsc::Sender<DbCommand>) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    let r = set_message(
+        chan_tx.clone(),
+        DAEMON_START_KEY.to_string(),
+        now.to_string(),
+    )
+    .await;
+    if kap_boot::boot_stage(kap_boot::BootStage::Database, &r) {
+        kap_boot::boot_publish(&chan_tx).await;
+    }
+    r
+}
+
+/*
+ * internal events (task/subscribe results, shadow desired, connection state)
+ * for live debugging, published on `kap/event/{kind}`; nobody listening is
+ * fine, the local api relays them on /events
+ */
+pub const EVENT_CHANNEL_PREFIX: &str = "kap/event/";
+
+/* latest mqtt connection event, `health` reads it */
+pub const MQTT_STATE_KEY: &str = "kap/aws/connection";
+
+/* channel and payload, for who publishes without the DB actor */
+pub fn event_message(kind: &str, data: serde_json::Value) -> (String, String) {
+    let event = serde_json::json!({
+        "kind": kind,
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+        "data": data,
+    });
+    (
+        format!("{}{}", EVENT_CHANNEL_PREFIX, kind),
+        event.to_string(),
+    )
+}
+
+pub async fn event_publish(
+    chan_tx: &mpsc::Sender<DbCommand>,
+    kind: &str,
+    data: serde_json::Value,
+) -> Result<()> {
+    let (channel, event) = event_message(kind, data);
+    publish_message(chan_tx, channel, event).await
+}
+
+/*
+ * coordinated stop on SIGTERM: honest_start, monitor_start,
+ * notify_offline_watch and reload_listen hold a guard and leave once
+ * `stopped()` fires, the mqtt dedicated loop holds one until its
+ * AwsIotCmd::Exit and db_serve a `guard_last` until its DbCommand::Exit.
+ * shutdown_run sends those in order and waits up to core.shutdown_grace
+ */
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+pub struct Shutdown {
+    stop: watch::Sender<bool>,
+    done_tx: mpsc::Sender<()>,
+    done_rx: mpsc::Receiver<()>,
+    last_tx: mpsc::Sender<()>,
+    last_rx: mpsc::Receiver<()>,
+}
+
+#[derive(Clone)]
+pub struct ShutdownGuard {
+    stop: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (stop, _) = watch::channel(false);
+        let (done_tx, done_rx) = mpsc::channel(1);
+        let (last_tx, last_rx) = mpsc::channel(1);
+        Self {
+            stop,
+            done_tx,
+            done_rx,
+            last_tx,
+            last_rx,
+        }
+    }
+
+    pub fn guard(&self) -> ShutdownGuard {
+        ShutdownGuard {
+            stop: self.stop.subscribe(),
+            _done: self.done_tx.clone(),
+        }
+    }
+
+    /* for the db task, waited for only once every other guard is gone */
+    pub fn guard_last(&self) -> ShutdownGuard {
+        ShutdownGuard {
+            stop: self.stop.subscribe(),
+            _done: self.last_tx.clone(),
+        }
+    }
+
+    /* guards see `stopped()` from here on, drain still waits for them */
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+
+    /* false when some guard is still held after `grace` */
+    pub async fn drain(self, grace: Duration) -> bool {
+        self.drain_with(grace, async {}).await
+    }
+
+    /* the guards, then `between`, then the last guards, all within `grace` */
+    async fn drain_with(
+        self,
+        grace: Duration,
+        between: impl std::future::Future<Output = ()>,
+    ) -> bool {
+        let Self {
+            stop,
+            done_tx,
+            mut done_rx,
+            last_tx,
+            mut last_rx,
+        } = self;
+        let deadline = tokio::time::Instant::now() + grace;
+        stop.send_replace(true);
+        drop(done_tx);
+
+        let done = tokio::time::timeout_at(deadline, done_rx.recv())
+            .await
+            .is_ok();
+        between.await;
+        drop(last_tx);
+        let last = tokio::time::timeout_at(deadline, last_rx.recv())
+            .await
+            .is_ok();
+        if !(done && last) {
+            warn!("shutdown subtasks still running after {:?}", grace);
+        }
+        done && last
+    }
+}
+
+impl ShutdownGuard {
+    pub fn is_stopped(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    pub async fn stopped(&mut self) {
+        while !*self.stop.borrow() {
+            if self.stop.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/*
+ * guarded loops leave and mqtt disconnects clean while the db task can
+ * still record it, the db task gets its Exit once they are gone so all
+ * they queued is served; false when a guard outlived `grace`
+ */
+pub async fn shutdown_run(
+    shutdown: Shutdown,
+    #[cfg(feature = "aws-iot")] aws_chan: Option<&mpsc::Sender<aws_iot::AwsIotCmd>>,
+    db_chan: Option<&mpsc::Sender<DbCommand>>,
+    grace: Duration,
+) -> bool {
+    shutdown.stop();
+    #[cfg(feature = "aws-iot")]
+    if let Some(aws_chan) = aws_chan {
+        if aws_chan.send(aws_iot::AwsIotCmd::Exit).await.is_err() {
+            debug!("shutdown mqtt already gone");
+        }
+    }
+    let db_exit = async {
+        if let Some(db_chan) = db_chan {
+            if db_chan.send(DbCommand::Exit).await.is_err() {
+                debug!("shutdown db task already gone");
+            }
+        }
+    };
+    shutdown.drain_with(grace, db_exit).await
+}
+
+pub async fn shutdown_signal() -> Result<()> {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = term.recv() => info!("SIGTERM received, shutting down"),
+        r = tokio::signal::ctrl_c() => {
+            r?;
+            info!("SIGINT received, shutting down");
+        }
+    }
+    Ok(())
+}
+
+/* a bare level also quiets redis/mio to it, anything else is taken as is */
+fn log_directive(log_level: &str) -> String {
+    if log_level.contains(['=', ',']) {
+        log_level.to_string()
+    } else {
+        format!("{},redis={},mio={}", log_level, log_level, log_level)
+    }
+}
+
+static LOG_RELOAD: std::sync::OnceLock<
+    tracing_subscriber::reload::Handle<tracing_subscriber::EnvFilter, tracing_subscriber::Registry>,
+> = std::sync::OnceLock::new();
+
+/* json for shipping to Loki/CloudWatch, span fields (topic/thing) included */
+#[derive(Deserialize, Serialize, clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
 pub fn setup_logging(log_level: &str) -> Result<()> {
+    setup_logging_format(log_level, LogFormat::Text)
+}
+
+pub fn setup_logging_format(log_level: &str, format: LogFormat) -> Result<()> {
+    logging_init(log_level, format, None)
+}
+
+/* stdout plus the rule/core log_file and syslog targets */
+pub fn setup_logging_rule(log_level: &str, format: LogFormat, core: &RuleConfigCore) -> Result<()> {
+    logging_init(log_level, format, Some(core))
+}
+
+type LogRegistry = tracing_subscriber::layer::Layered<
+    tracing_subscriber::reload::Layer<tracing_subscriber::EnvFilter, tracing_subscriber::Registry>,
+    tracing_subscriber::Registry,
+>;
+
+fn log_layer<W>(
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+    time: bool,
+) -> Box<dyn tracing_subscriber::Layer<LogRegistry> + Send + Sync>
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match (format, time) {
+        (LogFormat::Text, true) => tracing_subscriber::Layer::boxed(layer),
+        (LogFormat::Text, false) => tracing_subscriber::Layer::boxed(layer.without_time()),
+        (LogFormat::Json, _) => tracing_subscriber::Layer::boxed(
+            layer
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false),
+        ),
+    }
+}
+
+fn logging_init(log_level: &str, format: LogFormat, core: Option<&RuleConfigCore>) -> Result<()> {
     // See https://docs.rs/tracing for more info
     //tracing_subscriber::fmt::try_init()
+    let directive = std::env::var("RUST_LOG").unwrap_or_else(move |_| log_directive(log_level));
+    let (filter, handle) =
+        tracing_subscriber::reload::Layer::new(tracing_subscriber::EnvFilter::new(&directive));
+    LOG_LEVEL.lock().unwrap().base = directive;
+    let mut layers = vec![log_layer(format, std::io::stdout, true, true)];
+    if let Some(file) = core.and_then(|c| c.log_file.as_ref()) {
+        let writer = LogFile::open(file)?;
+        layers.push(log_layer(
+            format,
+            std::sync::Mutex::new(writer),
+            false,
+            true,
+        ));
+    }
+    if let Some(syslog) = core.and_then(|c| c.syslog.as_ref()) {
+        let writer = kap_syslog::SyslogWriter::open(syslog)?;
+        /* syslog header carries the timestamp */
+        layers.push(log_layer(format, writer, false, false));
+    }
+    if let Some(core) = core {
+        layers.push(log_layer(LogFormat::Text, kap_crash::LogRing, false, true));
+        kap_crash::crash_hook_install(core);
+    }
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(move |_| {
-                format!("{},redis={},mio={}", log_level, log_level, log_level).into()
-            }),
-        ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(filter)
+        .with(layers)
         .init();
+    _ = LOG_RELOAD.set(handle);
     Ok(())
 }
 
+/* size/age rotated log file, {path}.1 is the newest of `keep` rotated ones */
+pub(crate) struct LogFile {
+    cfg: RuleLogFile,
+    file: std::fs::File,
+    size: u64,
+    since: std::time::SystemTime,
+}
+
+impl LogFile {
+    pub(crate) fn open(cfg: &RuleLogFile) -> Result<Self> {
+        if let Some(dir) = cfg.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&cfg.path)
+            .map_err(|e| anyhow!("log file {} open fail - {e}", cfg.path.display()))?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            cfg: cfg.clone(),
+            file,
+            size: metadata.len(),
+            since: metadata
+                .created()
+                .unwrap_or_else(|_| std::time::SystemTime::now()),
+        })
+    }
+
+    fn expired(&self) -> bool {
+        let oversize = self.cfg.size.is_some_and(|s| self.size >= s);
+        let overage = self
+            .cfg
+            .age
+            .is_some_and(|a| self.since.elapsed().is_ok_and(|e| e >= a));
+        self.size > 0 && (oversize || overage)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = &self.cfg.path;
+        let keep = self.cfg.keep.unwrap_or(0);
+        for i in (1..keep).rev() {
+            let from = PathBuf::from(format!("{}.{}", path.display(), i));
+            if from.exists() {
+                std::fs::rename(&from, format!("{}.{}", path.display(), i + 1))?;
+            }
+        }
+        if keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            std::fs::rename(path, format!("{}.1", path.display()))?;
+        }
+
+        self.file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        self.size = 0;
+        self.since = std::time::SystemTime::now();
+        Ok(())
+    }
+}
+
+impl std::io::Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.expired() {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/* configured directive plus a runtime override put on top until it expires */
+struct LogLevelState {
+    base: String,
+    overlay: Option<String>,
+    generation: u64,
+}
+
+static LOG_LEVEL: std::sync::Mutex<LogLevelState> = std::sync::Mutex::new(LogLevelState {
+    base: String::new(),
+    overlay: None,
+    generation: 0,
+});
+
+impl LogLevelState {
+    fn directive(&self) -> String {
+        match self.overlay {
+            Some(ref overlay) if self.base.is_empty() => overlay.clone(),
+            Some(ref overlay) => format!("{},{}", self.base, overlay),
+            None => self.base.clone(),
+        }
+    }
+
+    fn apply(&self) -> Result<()> {
+        let filter = tracing_subscriber::EnvFilter::try_new(self.directive())
+            .map_err(|e| anyhow!("log level {} invalid - {e}", self.directive()))?;
+        LOG_RELOAD
+            .get()
+            .ok_or_else(|| anyhow!("logging not setup"))?
+            .reload(filter)
+            .map_err(|e| anyhow!("log level reload fail - {e}"))
+    }
+}
+
+pub fn log_level_reload(log_level: &str) -> Result<()> {
+    let mut state = LOG_LEVEL.lock().unwrap();
+    let base = std::mem::replace(&mut state.base, log_directive(log_level));
+    if let Err(e) = state.apply() {
+        state.base = base;
+        return Err(e);
+    }
+    info!("log level reloaded as {}", log_level);
+    Ok(())
+}
+
+/*
+ * runtime override on LOG_LEVEL_CHANNEL or the api, e.g.
+ * `{"directive":"fika_utils::aws_iot=trace","duration":"10m"}` (a bare
+ * directive works too); targets are module paths, reverts to the
+ * configured level after duration, an empty directive reverts now
+ */
+pub const LOG_LEVEL_CHANNEL: &str = "kap/ctrl/log_level";
+pub const LOG_OVERRIDE_DEFAULT: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogOverride {
+    #[serde(default)]
+    pub directive: String,
+    #[serde(default, deserialize_with = "crate::misc::de_duration_opt")]
+    pub duration: Option<Duration>,
+}
+
+impl LogOverride {
+    pub fn parse(payload: &str) -> Result<Self> {
+        let payload = payload.trim();
+        if payload.starts_with('{') {
+            serde_json::from_str(payload).map_err(|e| anyhow!("log override invalid - {e}"))
+        } else {
+            Ok(Self {
+                directive: payload.to_string(),
+                duration: None,
+            })
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        tracing_subscriber::EnvFilter::try_new(&self.directive)
+            .map(|_| ())
+            .map_err(|e| anyhow!("log directive {} invalid - {e}", self.directive))
+    }
+}
+
+pub fn log_level_override(ov: &LogOverride) -> Result<()> {
+    let mut state = LOG_LEVEL.lock().unwrap();
+    if ov.directive.is_empty() {
+        state.overlay = None;
+        state.generation += 1;
+        state.apply()?;
+        info!("log level override reverted");
+        return Ok(());
+    }
+
+    ov.validate()?;
+    let overlay = state.overlay.replace(ov.directive.clone());
+    if let Err(e) = state.apply() {
+        state.overlay = overlay;
+        return Err(e);
+    }
+    state.generation += 1;
+    let generation = state.generation;
+    let duration = ov.duration.unwrap_or(LOG_OVERRIDE_DEFAULT);
+    info!(
+        "log level override {} for {}",
+        ov.directive,
+        crate::misc::duration_human(duration)
+    );
+
+    /* a newer override owns the revert */
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let mut state = LOG_LEVEL.lock().unwrap();
+        if state.generation == generation {
+            state.overlay = None;
+            match state.apply() {
+                Ok(_) => info!("log level override expired, back to {}", state.base),
+                Err(e) => warn!("log level override revert fail - {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/*
+ * SIGHUP or a message on RELOAD_CHANNEL re-reads the rule, applies the log
+ * level (message payload first, then rule/core/log_level), resolves the
+ * feature flags again and hands the fresh rule to the daemon, which picks
+ * the sections of `RuleConfig::reload_sections`; a broken rule keeps the
+ * running one. LOG_LEVEL_CHANNEL messages only touch the log level
+ * override. A lost subscription (redis restart) is taken again, backing off
+ * to a minute
+ */
+pub const RELOAD_CHANNEL: &str = "kap/ctrl/reload";
+const RELOAD_RETRY_MIN: Duration = Duration::from_secs(1);
+const RELOAD_RETRY_MAX: Duration = Duration::from_secs(60);
+
+type ReloadMessages = std::pin::Pin<Box<dyn futures_util::Stream<Item = redis::Msg> + Send>>;
+
+/* ends at once when the subscribe fails, the caller waits and tries again */
+async fn reload_messages(database: Option<&str>) -> ReloadMessages {
+    let database = match database {
+        Some(database) => database,
+        None => return Box::pin(futures_util::stream::pending()),
+    };
+    match reload_subscribe(database).await {
+        Ok(sub) => Box::pin(sub.into_on_message()),
+        Err(e) => {
+            warn!("{} subscribe fail, SIGHUP meanwhile - {e}", RELOAD_CHANNEL);
+            Box::pin(futures_util::stream::empty())
+        }
+    }
+}
+
+pub async fn reload_listen(
+    rule_path: String,
+    tx: mpsc::Sender<RuleConfig>,
+    mut guard: ShutdownGuard,
+) -> Result<()> {
+    use futures_util::StreamExt;
+
+    let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let database = RuleConfig::build_from(&rule_path).await?.core.database;
+    let mut messages = reload_messages(database.as_deref()).await;
+    let mut retry = RELOAD_RETRY_MIN;
+
+    loop {
+        let log_level = tokio::select! {
+            _ = guard.stopped() => break,
+            _ = hup.recv() => {
+                info!("SIGHUP received, reload");
+                None
+            }
+            msg = messages.next() => {
+                /* redis restarted or never came up, subscribe again */
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        warn!("{} subscription lost, again in {:?}", RELOAD_CHANNEL, retry);
+                        tokio::select! {
+                            _ = guard.stopped() => break,
+                            _ = tokio::time::sleep(retry) => {}
+                        }
+                        retry = (retry * 2).min(RELOAD_RETRY_MAX);
+                        messages = reload_messages(database.as_deref()).await;
+                        continue;
+                    }
+                };
+                retry = RELOAD_RETRY_MIN;
+                let payload: String = msg.get_payload().unwrap_or_default();
+                if msg.get_channel_name() == LOG_LEVEL_CHANNEL {
+                    if let Err(e) =
+                        LogOverride::parse(&payload).and_then(|ov| log_level_override(&ov))
+                    {
+                        warn!("{} - {e}", LOG_LEVEL_CHANNEL);
+                    }
+                    continue;
+                }
+                info!("{} received, reload", RELOAD_CHANNEL);
+                Some(payload.trim().to_string()).filter(|p| !p.is_empty())
+            }
+        };
+
+        let rule = RuleConfig::build_from(&rule_path)
+            .await
+            .map_err(|e| warn!("rule reload from {} fail - {e}", rule_path))
+            .ok();
+        if let Some(log_level) = log_level
+            .as_deref()
+            .or_else(|| rule.as_ref()?.core.log_level.as_deref())
+        {
+            if let Err(e) = log_level_reload(log_level) {
+                warn!("{e}");
+            }
+        }
+        if let Some(rule) = rule {
+            kap_feature::features_init(&rule).await;
+            if tx.send(rule).await.is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn reload_subscribe(database: &str) -> Result<redis::aio::PubSub> {
+    let mut sub = redis::Client::open(database)?
+        .get_async_connection()
+        .await?
+        .into_pubsub();
+    sub.subscribe(RELOAD_CHANNEL).await?;
+    sub.subscribe(LOG_LEVEL_CHANNEL).await?;
+    Ok(sub)
+}
+
 pub async fn rule_config_load(
     rule_path: &str,
     cfg_path: Option<&str>,
@@ -177,3 +944,175 @@ pub fn get_shadow_password(username: &str) -> Result<String> {
         None => Err(anyhow::anyhow!("User {} password not found", username)),
     }
 }
+
+#[test]
+fn test_task_command_exclusive() {
+    let task: RuleConfigTask = toml::from_str(
+        r#"
+        topic = "kap/test"
+        command = ["/usr/bin/curl", "-s", "http://127.0.0.1"]
+        "#,
+    )
+    .unwrap();
+    let cmd = task.build_command().unwrap();
+    assert_eq!(cmd.as_std().get_program(), "/usr/bin/curl");
+    assert_eq!(cmd.as_std().get_args().count(), 2);
+
+    let task: RuleConfigTask = toml::from_str(
+        r#"
+        topic = "kap/test"
+        path = "/tmp/test.sh"
+        inline_sh = "echo hello"
+        "#,
+    )
+    .unwrap();
+    assert!(task.build_command().is_err());
+}
+
+#[test]
+fn test_task_duration() {
+    let task: RuleConfigTask = toml::from_str(
+        r#"
+        topic = "kap/test"
+        builtin = "sysinfo"
+        period = "6h"
+        start_at = 10
+        debounce = { secs = 1, nanos = 0 }
+        "#,
+    )
+    .unwrap();
+    assert_eq!(task.period, Some(Duration::from_secs(6 * 3600)));
+    assert_eq!(task.start_at, Some(Duration::from_secs(10)));
+    assert_eq!(task.debounce, Some(Duration::from_secs(1)));
+
+    let task: RuleConfigTask = toml::from_str(
+        r#"
+        topic = "kap/test"
+        builtin = "sysinfo"
+        "#,
+    )
+    .unwrap();
+    assert!(task.period.is_none());
+    assert!(toml::from_str::<RuleConfigTask>("topic = \"t\"\nperiod = \"6x\"").is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_drain() {
+    let shutdown = Shutdown::new();
+    let mut guard = shutdown.guard();
+    assert!(!guard.is_stopped());
+    tokio::spawn(async move {
+        guard.stopped().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    });
+    assert!(shutdown.drain(Duration::from_secs(2)).await);
+
+    let shutdown = Shutdown::new();
+    let stuck = shutdown.guard();
+    assert!(!shutdown.drain(Duration::from_millis(50)).await);
+    assert!(stuck.is_stopped());
+
+    /* the db task holds its guard until the Exit queued behind a Set */
+    let shutdown = Shutdown::new();
+    let (db_chan, mut db_rx) = mpsc::channel(4);
+    let db_guard = shutdown.guard_last();
+    let db = tokio::spawn(async move {
+        let _guard = db_guard;
+        let mut served = vec![];
+        while let Some(cmd) = db_rx.recv().await {
+            match cmd {
+                DbCommand::Exit => break,
+                DbCommand::Set { key, .. } => served.push(key),
+                _ => {}
+            }
+        }
+        served
+    });
+    let mut guard = shutdown.guard();
+    let loop_chan = db_chan.clone();
+    tokio::spawn(async move {
+        guard.stopped().await;
+        let (resp, _) = oneshot::channel();
+        _ = loop_chan
+            .send(DbCommand::Set {
+                key: "kap/last".to_string(),
+                val: String::new(),
+                resp,
+            })
+            .await;
+    });
+    assert!(
+        shutdown_run(
+            shutdown,
+            #[cfg(feature = "aws-iot")]
+            None,
+            Some(&db_chan),
+            Duration::from_secs(2)
+        )
+        .await
+    );
+    assert_eq!(db.await.unwrap(), vec!["kap/last".to_string()]);
+}
+
+#[test]
+fn test_log_file_rotate() {
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("fika-log-{}", std::process::id()));
+    let cfg = RuleLogFile {
+        path: dir.join("fika.log"),
+        size: Some(8),
+        age: None,
+        keep: Some(2),
+    };
+    _ = std::fs::remove_dir_all(&dir);
+    let mut log = LogFile::open(&cfg).unwrap();
+    /* rotated before the write once the size is reached */
+    for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccc\n", "dddd\n", "eeee\n"] {
+        log.write_all(line.as_bytes()).unwrap();
+    }
+
+    let read = |p: &str| std::fs::read_to_string(dir.join(p)).unwrap_or_default();
+    assert_eq!(read("fika.log"), "eeee\n");
+    assert_eq!(read("fika.log.1"), "cccc\ndddd\n");
+    assert_eq!(read("fika.log.2"), "bbbbbbbb\n");
+    assert!(!dir.join("fika.log.3").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_log_override() {
+    let ov =
+        LogOverride::parse(r#"{"directive":"fika_utils::aws_iot=trace","duration":"2m"}"#).unwrap();
+    assert_eq!(ov.directive, "fika_utils::aws_iot=trace");
+    assert_eq!(ov.duration, Some(Duration::from_secs(120)));
+    assert_eq!(
+        LogOverride::parse(&serde_json::to_string(&ov).unwrap()).unwrap(),
+        ov
+    );
+    assert_eq!(
+        LogOverride::parse(" rumqttc=debug\n").unwrap(),
+        LogOverride {
+            directive: "rumqttc=debug".to_string(),
+            duration: None,
+        }
+    );
+    assert!(LogOverride::parse("rumqttc=loud")
+        .unwrap()
+        .validate()
+        .is_err());
+    assert!(LogOverride::parse("{\"duration\":\"2m\"").is_err());
+
+    let mut state = LogLevelState {
+        base: log_directive("info"),
+        overlay: None,
+        generation: 0,
+    };
+    assert_eq!(state.directive(), "info,redis=info,mio=info");
+    state.overlay = Some(ov.directive);
+    assert_eq!(
+        state.directive(),
+        "info,redis=info,mio=info,fika_utils::aws_iot=trace"
+    );
+}
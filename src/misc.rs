@@ -1,6 +1,11 @@
+use anyhow::anyhow;
 use anyhow::Result;
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
+#[cfg(feature = "wallet")]
+use serde_json::json;
 use serde_json::Value;
+#[cfg(feature = "wallet")]
+use tracing::warn;
 use tracing::{debug, instrument};
 //use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -8,7 +13,12 @@ use tracing::{debug, instrument};
 use ethers::prelude::*;
 
 use chrono::prelude::*;
+use std::time::Duration;
 
+#[cfg(feature = "wallet")]
+use crate::kap_daemon::KdaemonConfig;
+#[cfg(feature = "wallet")]
+use crate::kap_rule::{RuleChainConfig, RuleConfig};
 use crate::setup_logging;
 
 #[derive(Args, Debug)]
@@ -26,21 +36,449 @@ struct ApHcsOpt {
 #[derive(Args, Debug, Clone)]
 #[clap(about = "Generate Wallet")]
 pub struct GenerateOpt {
-    #[clap(short = 'o', long = "output")]
-    output: Option<String>,
+    #[clap(
+        short = 'o',
+        long = "output",
+        default_value = "/userdata/wallet",
+        help = "keystore directory"
+    )]
+    output: String,
+    #[clap(
+        short = 'p',
+        long = "password-file",
+        help = "keystore passphrase file, secret:[FILE] or keyring:FILE[#ENTRY], prompt when not given"
+    )]
+    password_file: Option<String>,
+    #[clap(
+        short = 'n',
+        long = "name",
+        help = "keystore saved as {output}/{name} instead of a uuid"
+    )]
+    name: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Import Wallet from private key or keystore")]
+pub struct ImportOpt {
+    #[clap(
+        long = "key-file",
+        help = "file holding the raw hex private key, - reads stdin (give --password-file then)",
+        conflicts_with = "keystore"
+    )]
+    key_file: Option<String>,
+    #[clap(long = "keystore", help = "existing encrypted json keystore")]
+    keystore: Option<String>,
+    #[clap(
+        long = "keystore-password-file",
+        help = "passphrase of --keystore, prompt when not given"
+    )]
+    keystore_password_file: Option<String>,
+    #[clap(
+        short = 'o',
+        long = "output",
+        default_value = "/userdata/wallet",
+        help = "keystore directory"
+    )]
+    output: String,
+    #[clap(
+        short = 'p',
+        long = "password-file",
+        help = "keystore passphrase file, secret:[FILE] or keyring:FILE[#ENTRY], prompt when not given"
+    )]
+    password_file: Option<String>,
+    #[clap(
+        short = 'n',
+        long = "name",
+        help = "keystore saved as {output}/{name}, core.wallet_address kept unless \"device\""
+    )]
+    name: Option<String>,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct WalletKeyOpt {
+    #[clap(short = 'k', long = "keystore", help = "keystore of the wallet to use")]
+    keystore: Option<String>,
+    #[clap(
+        short = 'w',
+        long = "wallet",
+        help = "named keystore under dir, `wallet use` one when not given"
+    )]
+    wallet: Option<String>,
+    #[clap(
+        short = 'd',
+        long = "dir",
+        default_value = "/userdata/wallet",
+        help = "keystore directory, core.wallet_address searched without a name"
+    )]
+    dir: String,
+    #[clap(
+        short = 'p',
+        long = "password-file",
+        help = "keystore passphrase file, secret:[FILE] or keyring:FILE[#ENTRY], prompt when not given"
+    )]
+    password_file: Option<String>,
+    #[clap(short = 'c', long = "config", default_value = "/userdata/kdaemon.toml")]
+    config: String,
+    #[cfg(feature = "ledger")]
+    #[clap(long = "ledger", action, help = "sign on a connected Ledger instead")]
+    ledger: bool,
+    #[cfg(feature = "ledger")]
+    #[clap(
+        long = "ledger-index",
+        default_value = "0",
+        help = "Ledger Live account index"
+    )]
+    ledger_index: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Sign message by EIP-191 personal_sign")]
+pub struct SignOpt {
+    #[clap(flatten)]
+    wallet: WalletKeyOpt,
+    #[clap(short = 'm', long = "message", help = "message text or @file")]
+    message: String,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Verify EIP-191 message signature")]
+pub struct VerifyOpt {
+    #[clap(short = 'a', long = "address")]
+    address: String,
+    #[clap(short = 'm', long = "message", help = "message text or @file")]
+    message: String,
+    #[clap(short = 's', long = "signature")]
+    signature: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct WalletRpcOpt {
+    #[clap(long = "rpc-url", help = "chain.rpc_url of the rule when not given")]
+    rpc_url: Option<String>,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Native token balance by RPC")]
+pub struct BalanceOpt {
+    #[clap(
+        short = 'a',
+        long = "address",
+        help = "core.wallet_address when not given"
+    )]
+    address: Option<String>,
+    #[clap(
+        short = 'b',
+        long = "block",
+        help = "block number, latest, pending, safe, finalized or earliest"
+    )]
+    block: Option<String>,
+    #[clap(flatten)]
+    rpc: WalletRpcOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "ERC-20 token balance")]
+pub struct Erc20BalanceOpt {
+    #[clap(long = "contract")]
+    contract: String,
+    #[clap(
+        short = 'a',
+        long = "address",
+        help = "core.wallet_address when not given"
+    )]
+    address: Option<String>,
+    #[clap(flatten)]
+    rpc: WalletRpcOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "ERC-20 token transfer from the device wallet")]
+pub struct Erc20TransferOpt {
+    #[clap(long = "contract")]
+    contract: String,
+    #[clap(long = "to")]
+    to: String,
+    #[clap(long = "amount", help = "in token units, scaled by decimals()")]
+    amount: String,
+    #[clap(long = "wait", action, help = "wait for the receipt")]
+    wait: bool,
+    #[clap(flatten)]
+    fee: WalletTxOpt,
+    #[clap(flatten)]
+    wallet: WalletKeyOpt,
+    #[clap(flatten)]
+    rpc: WalletRpcOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Export the public key of the device wallet")]
+pub struct ExportPubOpt {
+    #[clap(flatten)]
+    wallet: WalletKeyOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Sign the AP/user wallet binding for the BOSS get_eth_wallet/pairing APIs")]
+pub struct BindUserOpt {
+    #[clap(
+        short = 'u',
+        long = "user-wallet",
+        help = "core.user_wallet of the config when not given"
+    )]
+    user_wallet: Option<String>,
+    #[clap(long = "comment", default_value = "")]
+    comment: String,
+    #[clap(flatten)]
+    wallet: WalletKeyOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Mint a short-lived did:ethr JWT (ES256K-R) signed by the device wallet")]
+pub struct TokenOpt {
+    #[clap(long = "aud", help = "audience, the BOSS root url usually")]
+    aud: String,
+    #[clap(long = "ttl", default_value = "300", help = "seconds until exp")]
+    ttl: u64,
+    #[clap(
+        long = "network",
+        help = "did:ethr network, e.g. 0x5 or goerli, mainnet without"
+    )]
+    network: Option<String>,
+    #[clap(flatten)]
+    wallet: WalletKeyOpt,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Read-only contract call (eth_call) with decoded outputs")]
+pub struct CallOpt {
+    #[clap(long = "contract")]
+    contract: String,
+    #[clap(
+        long = "abi",
+        help = "ABI json file, inline json or `function f(uint256) view returns (bool)` signatures separated by ';'"
+    )]
+    abi: String,
+    #[clap(long = "method")]
+    method: String,
+    #[clap(
+        long = "args",
+        multiple_values = true,
+        help = "method arguments in order"
+    )]
+    args: Vec<String>,
+    #[clap(
+        short = 'b',
+        long = "block",
+        help = "block number, latest, pending, safe, finalized or earliest"
+    )]
+    block: Option<String>,
+    #[clap(flatten)]
+    rpc: WalletRpcOpt,
+}
+
+/* nonce/EIP-1559 fee overrides of the sending commands */
+#[derive(Args, Debug, Clone)]
+pub struct WalletTxOpt {
+    #[clap(
+        long = "nonce",
+        help = "pending count/local nonce cache when not given"
+    )]
+    nonce: Option<u64>,
+    #[clap(long = "gas-limit", help = "estimated when not given")]
+    gas_limit: Option<u64>,
+    #[clap(
+        long = "max-fee",
+        help = "max fee per gas in gwei, estimated when not given"
+    )]
+    max_fee: Option<String>,
+    #[clap(
+        long = "priority-fee",
+        help = "max priority fee per gas in gwei, estimated when not given"
+    )]
+    priority_fee: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Validate and print the EIP-55 checksummed address")]
+pub struct ChecksumOpt {
+    address: String,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "List keystores of the wallet directory")]
+pub struct ListOpt {
+    #[clap(
+        short = 'd',
+        long = "dir",
+        default_value = "/userdata/wallet",
+        help = "keystore directory"
+    )]
+    dir: String,
+    #[clap(
+        short = 'p',
+        long = "password-file",
+        help = "decrypt with it to show addresses"
+    )]
+    password_file: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+#[clap(about = "Select the named keystore used without --wallet")]
+pub struct UseOpt {
+    name: String,
+    #[clap(
+        short = 'd',
+        long = "dir",
+        default_value = "/userdata/wallet",
+        help = "keystore directory"
+    )]
+    dir: String,
+}
+
+#[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Erc20Command {
+    Balance(Erc20BalanceOpt),
+    Transfer(Erc20TransferOpt),
 }
 
 #[derive(Subcommand, Debug)]
 pub enum WalletCommand {
     Generate(GenerateOpt),
+    Import(ImportOpt),
+    Sign(SignOpt),
+    Verify(VerifyOpt),
+    ExportPub(ExportPubOpt),
+    BindUser(BindUserOpt),
+    Token(TokenOpt),
+    Balance(BalanceOpt),
+    #[clap(subcommand)]
+    Erc20(Erc20Command),
+    Call(CallOpt),
+    Checksum(ChecksumOpt),
+    List(ListOpt),
+    Use(UseOpt),
     //Transact(TransactOpt),
-    //Balance(BalanceOpt),
 }
 
 #[derive(Args, Debug)]
-#[clap(about = "Timestamp now")]
+pub struct TimeZoneOpt {
+    #[clap(long = "tz", help = "IANA time zone, e.g. Asia/Taipei")]
+    tz: Option<String>,
+    #[clap(
+        long = "local",
+        action,
+        conflicts_with = "tz",
+        help = "system local time zone"
+    )]
+    local: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeOutput {
+    Secs,
+    Millis,
+    Rfc3339,
+    IsoWeek,
+    Ordinal,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Unix timestamp of a date/time")]
 pub struct TimestampOpt {
-    timestamp: DateTime<Utc>,
+    #[clap(help = "RFC3339, RFC2822, unix secs/millis or YYYY-MM-DD[ HH:MM:SS] (UTC or --tz)")]
+    timestamp: String,
+    #[clap(
+        short = 'f',
+        long = "format",
+        help = "strftime format of the input instead, UTC/--tz unless it has %z"
+    )]
+    format: Option<String>,
+    #[clap(short = 'o', long = "output", value_enum, default_value = "secs")]
+    output: TimeOutput,
+    #[clap(flatten)]
+    zone: TimeZoneOpt,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "RFC3339 of a unix timestamp")]
+pub struct FromTimestampOpt {
+    #[clap(help = "unix secs or millis", allow_hyphen_values = true)]
+    timestamp: i64,
+    /* printed in the zone as well */
+    #[clap(flatten)]
+    zone: TimeZoneOpt,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "RFC3339 of now")]
+pub struct Rfc3339Opt {
+    #[clap(short = 'o', long = "output", value_enum, default_value = "rfc3339")]
+    output: TimeOutput,
+    #[clap(flatten)]
+    zone: TimeZoneOpt,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Convert between human durations, seconds and ISO-8601")]
+pub struct DurationOpt {
+    #[clap(help = "90m, 1d2h, 1h30m15s, 500ms, 3600 (secs) or PT1H30M")]
+    duration: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Local clock offset against an NTP server (SNTP)")]
+pub struct NtpCheckOpt {
+    #[clap(short = 's', long = "server", default_value = "pool.ntp.org:123")]
+    server: String,
+    #[clap(
+        short = 't',
+        long = "threshold",
+        default_value = "1s",
+        help = "fail when the offset is beyond it"
+    )]
+    threshold: String,
+    #[clap(long = "timeout", default_value = "3s")]
+    timeout: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Uptime, boot time and daemon start time")]
+pub struct UptimeOpt {
+    #[clap(long = "json", action)]
+    json: bool,
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml",
+        help = "core.database of it has the daemon start time"
+    )]
+    rule: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Wall time of a command over runs, min/avg/p95 as JSON")]
+pub struct BenchOpt {
+    #[clap(short = 'n', long = "runs", default_value = "10")]
+    runs: usize,
+    #[clap(long = "warmup", default_value = "0", help = "runs not measured")]
+    warmup: usize,
+    #[clap(
+        long = "show-output",
+        action,
+        help = "keep stdout/stderr of the command"
+    )]
+    show_output: bool,
+    #[clap(last = true, required = true)]
+    command: Vec<String>,
 }
 
 #[derive(Args, Debug)]
@@ -56,59 +494,2197 @@ pub struct TimeToolOpt {
 #[derive(Subcommand, Debug)]
 enum TimeToolCommand {
     Timestamp(TimestampOpt),
-    Rfc3339,
+    FromTimestamp(FromTimestampOpt),
+    Duration(DurationOpt),
+    NtpCheck(NtpCheckOpt),
+    Uptime(UptimeOpt),
+    Bench(BenchOpt),
+    Rfc3339(Rfc3339Opt),
+}
+
+const TIME_NAIVE_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y/%m/%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+];
+
+/* 10^11 secs is year 5138, anything above is taken as millis */
+const TIME_MILLIS_FROM: i64 = 100_000_000_000;
+
+fn timestamp_from_unix(n: i64) -> Result<DateTime<Utc>> {
+    let t = if n.abs() >= TIME_MILLIS_FROM {
+        Utc.timestamp_millis_opt(n)
+    } else {
+        Utc.timestamp_opt(n, 0)
+    };
+    t.single()
+        .ok_or_else(|| anyhow!("timestamp {} out of range", n))
+}
+
+enum TimeZoneArg {
+    Utc,
+    Local,
+    Tz(chrono_tz::Tz),
+}
+
+impl TimeZoneOpt {
+    fn zone(&self) -> Result<TimeZoneArg> {
+        if self.local {
+            return Ok(TimeZoneArg::Local);
+        }
+        match self.tz {
+            Some(ref tz) => tz
+                .parse::<chrono_tz::Tz>()
+                .map(TimeZoneArg::Tz)
+                .map_err(|e| anyhow!("time zone {} invalid - {e}", tz)),
+            None => Ok(TimeZoneArg::Utc),
+        }
+    }
+}
+
+impl TimeZoneArg {
+    fn rfc3339(&self, t: DateTime<Utc>, secs: SecondsFormat) -> String {
+        match self {
+            TimeZoneArg::Utc => t.to_rfc3339_opts(secs, true),
+            TimeZoneArg::Local => t.with_timezone(&Local).to_rfc3339_opts(secs, false),
+            TimeZoneArg::Tz(tz) => t.with_timezone(tz).to_rfc3339_opts(secs, false),
+        }
+    }
+
+    /* ISO week/ordinal date of the zone's calendar day */
+    fn format(&self, t: DateTime<Utc>, fmt: &str) -> String {
+        match self {
+            TimeZoneArg::Utc => t.format(fmt).to_string(),
+            TimeZoneArg::Local => t.with_timezone(&Local).format(fmt).to_string(),
+            TimeZoneArg::Tz(tz) => t.with_timezone(tz).format(fmt).to_string(),
+        }
+    }
+
+    fn output(&self, t: DateTime<Utc>, output: TimeOutput) -> String {
+        match output {
+            TimeOutput::Secs => t.timestamp().to_string(),
+            TimeOutput::Millis => t.timestamp_millis().to_string(),
+            TimeOutput::Rfc3339 => self.rfc3339(t, SecondsFormat::Secs),
+            TimeOutput::IsoWeek => self.format(t, "%G-W%V-%u"),
+            TimeOutput::Ordinal => self.format(t, "%Y-%j"),
+        }
+    }
+
+    /* the earlier one of a DST overlap, none in a DST gap */
+    fn naive_utc(&self, naive: &NaiveDateTime) -> Result<DateTime<Utc>> {
+        let t = match self {
+            TimeZoneArg::Utc => return Ok(Utc.from_utc_datetime(naive)),
+            TimeZoneArg::Local => Local
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc)),
+            TimeZoneArg::Tz(tz) => tz
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc)),
+        };
+        t.ok_or_else(|| anyhow!("{} nonexistent in the time zone", naive))
+    }
+}
+
+fn parse_timestamp(s: &str, format: Option<&str>, zone: &TimeZoneArg) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Some(fmt) = format {
+        if let Ok(t) = DateTime::parse_from_str(s, fmt) {
+            return Ok(t.with_timezone(&Utc));
+        }
+        let t = NaiveDateTime::parse_from_str(s, fmt)
+            .map_err(|e| anyhow!("{} not in format {} - {e}", s, fmt))?;
+        return zone.naive_utc(&t);
+    }
+
+    if let Ok(n) = s.parse::<i64>() {
+        return timestamp_from_unix(n);
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc2822(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for fmt in TIME_NAIVE_FORMATS {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, fmt) {
+            return zone.naive_utc(&t);
+        }
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return zone.naive_utc(&d.and_hms_opt(0, 0, 0).unwrap_or_default());
+    }
+    Err(anyhow!("{} not a known date/time", s))
 }
 
 #[instrument(name = "timestamp")]
-async fn do_timestamp(t: DateTime<Utc>) -> Result<()> {
+async fn do_timestamp(opt: TimestampOpt) -> Result<()> {
+    let zone = opt.zone.zone()?;
+    let t = parse_timestamp(&opt.timestamp, opt.format.as_deref(), &zone)?;
     debug!("DateTime - {:?} to Timestamp - {}", t, t.timestamp());
-    println!("{}", t.timestamp());
+    println!("{}", zone.output(t, opt.output));
     Ok(())
 }
 
-#[instrument(name = "rfc3339")]
-async fn do_rfc3339() -> Result<()> {
-    let now = Utc::now();
-    println!("{}", now.to_rfc3339_opts(SecondsFormat::Secs, false));
+#[instrument(name = "from-timestamp")]
+async fn do_from_timestamp(opt: FromTimestampOpt) -> Result<()> {
+    let zone = opt.zone.zone()?;
+    let t = timestamp_from_unix(opt.timestamp)?;
+    println!("{}", t.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+    if !matches!(zone, TimeZoneArg::Utc) {
+        println!("{}", zone.rfc3339(t, SecondsFormat::AutoSi));
+    }
     Ok(())
 }
 
-#[cfg(feature = "wallet")]
-#[instrument(name = "wallet")]
-pub async fn wallet_tools(w: WalletCommand) -> Result<()> {
-    match w {
-        WalletCommand::Generate(_cfg) => {
-            let wallet = LocalWallet::new(&mut rand::thread_rng());
-            println!("{:?}", wallet.address());
+const DURATION_UNITS: &[(&str, u64)] = &[("w", 604800), ("d", 86400), ("h", 3600), ("m", 60)];
+
+/* P[nW][nD][T[nH][nM][n[.n]S]], no years/months, their length is not fixed */
+fn duration_iso8601_parse(s: &str) -> Result<Duration> {
+    let err = || anyhow!("duration {} invalid ISO-8601", s);
+    let body = s.strip_prefix('P').ok_or_else(err)?;
+    let (date, time) = body.split_once('T').unwrap_or((body, ""));
+    if body.is_empty() || body.ends_with('T') {
+        return Err(err());
+    }
+
+    let mut total = Duration::ZERO;
+    let parts = [
+        (date, &[('W', 604800), ('D', 86400)][..]),
+        (time, &[('H', 3600), ('M', 60)][..]),
+    ];
+    for (i, (part, units)) in parts.into_iter().enumerate() {
+        let mut rest = part;
+        for (unit, secs) in units {
+            if let Some((n, r)) = rest.split_once(*unit) {
+                total += Duration::from_secs(n.parse::<u64>().map_err(|_| err())? * secs);
+                rest = r;
+            }
+        }
+        if i == 1 {
+            if let Some(n) = rest.strip_suffix('S') {
+                total += Duration::try_from_secs_f64(n.parse::<f64>().map_err(|_| err())?)
+                    .map_err(|_| err())?;
+                rest = "";
+            }
+        }
+        if !rest.is_empty() {
+            return Err(err());
         }
     }
-    Ok(())
+    Ok(total)
 }
 
-//#[tokio::main]
-pub async fn time_tools(opt: TimeToolOpt) -> Result<()> {
-    setup_logging(&opt.log_level)?;
+/* plain seconds, ISO-8601, or number+unit runs like 1d2h30m, 1.5h, 500ms */
+pub fn duration_parse(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    if s.starts_with('P') {
+        return duration_iso8601_parse(s);
+    }
 
-    match opt.commands {
-        TimeToolCommand::Timestamp(t) => {
-            do_timestamp(t.timestamp).await?;
+    let err = || anyhow!("duration {} invalid, e.g. 90s, 1d2h, 500ms", s);
+    if s.is_empty() {
+        return Err(err());
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let n = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(err)?;
+        let (value, r) = rest.split_at(n);
+        let value = value.parse::<f64>().map_err(|_| err())?;
+        let unit = r.find(|c: char| c.is_ascii_digit()).unwrap_or(r.len());
+        let (unit, r) = r.split_at(unit);
+        let secs = match unit.trim() {
+            "ms" => 0.001,
+            "s" | "sec" | "secs" => 1.0,
+            u => DURATION_UNITS
+                .iter()
+                .find(|(name, _)| *name == u)
+                .map(|(_, secs)| *secs as f64)
+                .ok_or_else(err)?,
+        };
+        total += Duration::try_from_secs_f64(value * secs).map_err(|_| err())?;
+        rest = r.trim_start();
+    }
+    Ok(total)
+}
+
+pub fn duration_human(d: Duration) -> String {
+    let mut secs = d.as_secs();
+    let mut human = String::new();
+    for (unit, n) in &DURATION_UNITS[1..] {
+        if secs >= *n {
+            human.push_str(&format!("{}{}", secs / n, unit));
+            secs %= n;
+        }
+    }
+    if secs > 0 || human.is_empty() && d.subsec_millis() == 0 {
+        human.push_str(&format!("{}s", secs));
+    }
+    if d.subsec_millis() > 0 {
+        human.push_str(&format!("{}ms", d.subsec_millis()));
+    }
+    human
+}
+
+pub fn duration_iso8601(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, h, m, s) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    let mut iso = String::from("P");
+    if days > 0 {
+        iso.push_str(&format!("{}D", days));
+    }
+    if h > 0 || m > 0 || s > 0 || d.subsec_nanos() > 0 || days == 0 {
+        iso.push('T');
+        if h > 0 {
+            iso.push_str(&format!("{}H", h));
+        }
+        if m > 0 {
+            iso.push_str(&format!("{}M", m));
+        }
+        if d.subsec_millis() > 0 {
+            iso.push_str(&format!("{}.{:03}S", s, d.subsec_millis()));
+        } else if s > 0 || iso == "PT" {
+            iso.push_str(&format!("{}S", s));
+        }
+    }
+    iso
+}
+
+/* rule.toml durations, "6h"/600 or the serde { secs, nanos } table */
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum DurationToml {
+    Secs(u64),
+    Text(String),
+    Table(Duration),
+}
+
+impl DurationToml {
+    fn duration<E: serde::de::Error>(self) -> std::result::Result<Duration, E> {
+        match self {
+            DurationToml::Secs(s) => Ok(Duration::from_secs(s)),
+            DurationToml::Text(s) => duration_parse(&s).map_err(E::custom),
+            DurationToml::Table(d) => Ok(d),
         }
-        TimeToolCommand::Rfc3339 => {
-            do_rfc3339().await?;
+    }
+}
+
+pub(crate) fn de_duration<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <DurationToml as serde::Deserialize>::deserialize(deserializer)?.duration()
+}
+
+pub(crate) fn de_duration_opt<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Option<DurationToml> as serde::Deserialize>::deserialize(deserializer)?
+        .map(DurationToml::duration)
+        .transpose()
+}
+
+/*
+ * temp file, fsync, rename over `path`, fsync of the directory: after a
+ * power cut the file is the old or the new content, never truncated. The
+ * temp takes the mode and owner of the file it replaces, private keys stay
+ * private and root:shadow stays root:shadow
+ */
+pub async fn write_atomic(path: impl AsRef<std::path::Path>, body: impl AsRef<[u8]>) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use tokio::io::AsyncWriteExt;
+
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp.{}", std::process::id()));
+    let tmp = std::path::PathBuf::from(tmp);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => std::path::Path::new("."),
+    };
+
+    let write = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        if let Ok(meta) = tokio::fs::metadata(path).await {
+            file.set_permissions(meta.permissions()).await?;
+            let own = file.metadata().await?;
+            if (own.uid(), own.gid()) != (meta.uid(), meta.gid()) {
+                std::os::unix::fs::chown(&tmp, Some(meta.uid()), Some(meta.gid()))?;
+            }
         }
+        file.write_all(body.as_ref()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        tokio::fs::File::open(dir).await?.sync_all().await
+    };
+    if let Err(e) = write.await {
+        _ = tokio::fs::remove_file(&tmp).await;
+        return Err(anyhow!("{} write fail - {e}", path.display()));
+    }
+    Ok(())
+}
+
+/*
+ * a secret given as a file instead of on the command line (ps, shell
+ * history), `-` reads it from stdin; only the line end is trimmed
+ */
+pub(crate) async fn secret_file_read(path: &str) -> Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut content = String::new();
+    if path == "-" {
+        tokio::io::stdin()
+            .read_to_string(&mut content)
+            .await
+            .map_err(|e| anyhow!("stdin read fail - {e}"))?;
+    } else {
+        content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("{} read fail - {e}", path))?;
     }
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
 
+#[instrument(name = "duration")]
+async fn do_duration(opt: DurationOpt) -> Result<()> {
+    let d = duration_parse(&opt.duration)?;
+    println!(
+        "{}",
+        serde_json::json!({
+            "seconds": d.as_secs_f64(),
+            "human": duration_human(d),
+            "iso8601": duration_iso8601(d),
+        })
+    );
     Ok(())
 }
-/*#[tokio::test]
-async fn test_toml_duration() {
-    let cp = ConfigTask {
-        topic: String::from("test"),
-        path: PathBuf::from("/tmp/test.sh"),
-        start_at: Some(Duration::from_secs(1)),
-        period: Some(Duration::from_secs(10)),
+
+/* seconds between the NTP era 0 (1900) and the unix epoch */
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+
+fn ntp_time(b: &[u8]) -> f64 {
+    let secs = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64;
+    let frac = u32::from_be_bytes([b[4], b[5], b[6], b[7]]) as f64;
+    secs - NTP_UNIX_OFFSET + frac / 4_294_967_296.0
+}
+
+fn ntp_time_put(b: &mut [u8], t: f64) {
+    let t = t + NTP_UNIX_OFFSET;
+    b[..4].copy_from_slice(&(t.trunc() as u32).to_be_bytes());
+    b[4..8].copy_from_slice(&((t.fract() * 4_294_967_296.0) as u32).to_be_bytes());
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SntpSample {
+    pub offset: f64,
+    pub delay: f64,
+    pub stratum: u8,
+}
+
+/* RFC 4330, t1 sent and t4 received locally, t2/t3 of the server */
+fn sntp_parse(resp: &[u8], t1: f64, t4: f64) -> Result<SntpSample> {
+    if resp.len() < 48 {
+        return Err(anyhow!("sntp response {} bytes short", resp.len()));
+    }
+    let (mode, stratum) = (resp[0] & 0x7, resp[1]);
+    if mode != 4 && mode != 5 {
+        return Err(anyhow!("sntp response mode {} not server", mode));
+    }
+    if stratum == 0 {
+        return Err(anyhow!("sntp kiss-o'-death from server"));
+    }
+    let (t2, t3) = (ntp_time(&resp[32..40]), ntp_time(&resp[40..48]));
+    Ok(SntpSample {
+        offset: ((t2 - t1) + (t3 - t4)) / 2.0,
+        delay: (t4 - t1) - (t3 - t2),
+        stratum,
+    })
+}
+
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/* host, host:port, v4/v6 address or [v6]:port; without a port it is :123 */
+fn ntp_target(server: &str) -> String {
+    use std::net::{IpAddr, SocketAddr};
+
+    if server.parse::<SocketAddr>().is_ok() {
+        return server.to_string();
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return SocketAddr::new(ip, 123).to_string();
+    }
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => {
+            server.to_string()
+        }
+        _ => format!("{}:123", server),
+    }
+}
+
+pub(crate) async fn sntp_query(server: &str, timeout: Duration) -> Result<SntpSample> {
+    let target = ntp_target(server);
+    let addr = tokio::net::lookup_host(&target)
+        .await
+        .map_err(|e| anyhow!("ntp server {} fail - {e}", server))?
+        .next()
+        .ok_or_else(|| anyhow!("ntp server {} without address", server))?;
+    /* the unspecified address of the family the server resolved to */
+    let bind = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
     };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+    socket
+        .connect(addr)
+        .await
+        .map_err(|e| anyhow!("ntp server {} fail - {e}", server))?;
 
-    let toml = toml::to_string(&cp);
-    assert_eq!(toml, Ok(String::from("hello")));
-}*/
+    let mut req = [0u8; 48];
+    /* LI 0, version 4, mode 3 (client) */
+    req[0] = 0x23;
+    let t1 = unix_now();
+    ntp_time_put(&mut req[40..48], t1);
+    socket.send(&req).await?;
+
+    let mut resp = [0u8; 48];
+    let n = tokio::time::timeout(timeout, socket.recv(&mut resp))
+        .await
+        .map_err(|_| anyhow!("ntp server {} no answer in {:?}", server, timeout))??;
+    let t4 = unix_now();
+    if resp[24..32] != req[40..48] {
+        return Err(anyhow!("ntp server {} answered another request", server));
+    }
+    sntp_parse(&resp[..n], t1, t4)
+}
+
+#[instrument(name = "ntp-check")]
+async fn do_ntp_check(opt: NtpCheckOpt) -> Result<()> {
+    let threshold = duration_parse(&opt.threshold)?.as_secs_f64();
+    let sample = sntp_query(&opt.server, duration_parse(&opt.timeout)?).await?;
+    println!(
+        "{}",
+        serde_json::json!({
+            "server": opt.server,
+            "offset": sample.offset,
+            "delay": sample.delay,
+            "stratum": sample.stratum,
+        })
+    );
+    if sample.offset.abs() > threshold {
+        return Err(anyhow!(
+            "clock offset {:.3}s beyond {}s",
+            sample.offset,
+            threshold
+        ));
+    }
+    Ok(())
+}
+
+/* daemon may be down or no redis at all, report none then */
+async fn daemon_start_at(rule: &str) -> Option<i64> {
+    use redis::AsyncCommands;
+
+    let database = crate::kap_rule::RuleConfig::build_from(rule)
+        .await
+        .map_err(|e| debug!("rule {} load fail - {e}", rule))
+        .ok()?
+        .core
+        .database?;
+    let mut conn = redis::Client::open(database)
+        .ok()?
+        .get_async_connection()
+        .await
+        .map_err(|e| debug!("db/redis connect fail - {e}"))
+        .ok()?;
+    let start: Option<String> = conn.get(crate::DAEMON_START_KEY).await.ok()?;
+    start?.trim().parse::<i64>().ok()
+}
+
+#[instrument(name = "uptime")]
+async fn do_uptime(opt: UptimeOpt) -> Result<()> {
+    let uptime = tokio::fs::read_to_string("/proc/uptime")
+        .await?
+        .split_whitespace()
+        .next()
+        .and_then(|u| u.parse::<f64>().ok())
+        .ok_or_else(|| anyhow!("/proc/uptime invalid"))?;
+    let now = Utc::now();
+    let boot = now - chrono::Duration::milliseconds((uptime * 1000.0) as i64);
+    let daemon = daemon_start_at(&opt.rule)
+        .await
+        .and_then(|t| Utc.timestamp_opt(t, 0).single());
+    let daemon_uptime = daemon.map(|t| (now - t).num_seconds().max(0) as u64);
+
+    if opt.json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "uptime": uptime,
+                "boot_time": boot.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "daemon_start": daemon.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                "daemon_uptime": daemon_uptime,
+            })
+        );
+        return Ok(());
+    }
+
+    println!("up {}", duration_human(Duration::from_secs(uptime as u64)));
+    println!("boot {}", boot.to_rfc3339_opts(SecondsFormat::Secs, true));
+    match (daemon, daemon_uptime) {
+        (Some(t), Some(up)) => println!(
+            "daemon {} (up {})",
+            t.to_rfc3339_opts(SecondsFormat::Secs, true),
+            duration_human(Duration::from_secs(up))
+        ),
+        _ => println!("daemon unknown"),
+    }
+    Ok(())
+}
+
+/* min, avg, p95 (nearest rank), max of the ms samples */
+fn bench_stats(samples: &mut [f64]) -> Option<(f64, f64, f64, f64)> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let n = samples.len();
+    let p95 = samples[((n as f64 * 0.95).ceil() as usize).clamp(1, n) - 1];
+    let avg = samples.iter().sum::<f64>() / n as f64;
+    Some((samples[0], avg, p95, samples[n - 1]))
+}
+
+#[instrument(name = "bench")]
+async fn do_bench(opt: BenchOpt) -> Result<()> {
+    use std::process::Stdio;
+
+    let (program, args) = opt
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow!("command missing"))?;
+    let (mut samples, mut failed) = (vec![], 0);
+    for i in 0..opt.warmup + opt.runs {
+        let mut cmd = tokio::process::Command::new(program);
+        cmd.args(args).stdin(Stdio::null());
+        if !opt.show_output {
+            cmd.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        let start = std::time::Instant::now();
+        let status = cmd
+            .status()
+            .await
+            .map_err(|e| anyhow!("{} run fail - {e}", program))?;
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+        if i < opt.warmup {
+            continue;
+        }
+        if !status.success() {
+            debug!("run {} {}", i - opt.warmup, status);
+            failed += 1;
+        }
+        samples.push(elapsed);
+    }
+
+    let stats = bench_stats(&mut samples);
+    println!(
+        "{}",
+        serde_json::json!({
+            "command": opt.command,
+            "runs": samples.len(),
+            "failed": failed,
+            "min_ms": stats.map(|s| s.0),
+            "avg_ms": stats.map(|s| s.1),
+            "p95_ms": stats.map(|s| s.2),
+            "max_ms": stats.map(|s| s.3),
+        })
+    );
+    Ok(())
+}
+
+#[instrument(name = "rfc3339")]
+async fn do_rfc3339(opt: Rfc3339Opt) -> Result<()> {
+    let now = Utc::now();
+    match (opt.zone.zone()?, opt.output) {
+        (TimeZoneArg::Utc, TimeOutput::Rfc3339) => {
+            println!("{}", now.to_rfc3339_opts(SecondsFormat::Secs, false))
+        }
+        (zone, output) => println!("{}", zone.output(now, output)),
+    }
+    Ok(())
+}
+
+/*
+ * EIP-55, 0x + 40 hex digits; all-lower/all-upper is taken as is, mixed
+ * case must match the checksum. Returns the checksummed form.
+ */
+pub fn address_checksum(address: &str) -> Result<String> {
+    use tiny_keccak::{Hasher, Keccak};
+
+    let hex = address
+        .strip_prefix("0x")
+        .ok_or_else(|| anyhow::anyhow!("address {} without 0x prefix", address))?;
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("address {} not 40 hex digits", address));
+    }
+
+    let lower = hex.to_ascii_lowercase();
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(lower.as_bytes());
+    keccak.finalize(&mut hash);
+
+    let checksum = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+            if c.is_ascii_alphabetic() && nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+
+    let mixed = hex != lower && hex != hex.to_ascii_uppercase();
+    if mixed && hex != checksum {
+        return Err(anyhow::anyhow!(
+            "address {} checksum mismatch, expect 0x{}",
+            address,
+            checksum
+        ));
+    }
+
+    Ok(format!("0x{}", checksum))
+}
+
+/* read one line from stdin, with echo off when it is a tty */
+#[cfg(feature = "wallet")]
+fn wallet_password_read(prompt: &str) -> Result<String> {
+    use std::io::{BufRead, Write};
+
+    let tty = atty::is(atty::Stream::Stdin);
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if tty {
+        eprint!("{}", prompt);
+        std::io::stderr().flush()?;
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == 0 {
+            let mut noecho = saved;
+            noecho.c_lflag &= !libc::ECHO;
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &noecho) };
+        }
+    }
+
+    let mut line = String::new();
+    let read = std::io::stdin().lock().read_line(&mut line);
+    if tty {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        eprintln!();
+    }
+    read?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(feature = "wallet")]
+const WALLET_KEYRING_ENTRY: &str = "wallet";
+#[cfg(feature = "wallet")]
+const WALLET_SECRET_PURPOSE: &str = "fika-wallet";
+
+/* keyring is a toml table of entry = "passphrase" */
+#[cfg(feature = "wallet")]
+fn keyring_entry(content: &str, entry: &str) -> Result<String> {
+    let keyring: toml::value::Table =
+        toml::from_str(content).map_err(|e| anyhow!("keyring invalid - {e}"))?;
+    keyring
+        .get(entry)
+        .and_then(|p| p.as_str())
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("keyring entry {} missing", entry))
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_password_keyring(keyring: &str) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (file, entry) = keyring
+        .split_once('#')
+        .unwrap_or((keyring, WALLET_KEYRING_ENTRY));
+    let meta = tokio::fs::metadata(file)
+        .await
+        .map_err(|e| anyhow!("keyring {} stat fail - {e}", file))?;
+    if meta.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "keyring {} mode {:o} too open, chmod 600",
+            file,
+            meta.mode() & 0o777
+        ));
+    }
+    if meta.uid() != unsafe { libc::geteuid() } {
+        return Err(anyhow!("keyring {} not owned by us", file));
+    }
+    let content = tokio::fs::read_to_string(file)
+        .await
+        .map_err(|e| anyhow!("keyring {} read fail - {e}", file))?;
+    keyring_entry(&content, entry).map_err(|e| anyhow!("{} - {e}", file))
+}
+
+/*
+ * passphrase source if given, prompt otherwise; secret:[{file}] derives it
+ * from the device secret (KDAEMON_SECRET by default) for unattended boot,
+ * keyring:{file}[#{entry}] reads it from an owner-only keyring, anything
+ * else is a passphrase file
+ */
+#[cfg(feature = "wallet")]
+pub(crate) async fn wallet_password(
+    file: Option<&str>,
+    prompt: &str,
+    confirm: bool,
+) -> Result<String> {
+    if let Some(secret) = file.and_then(|f| f.strip_prefix("secret:")) {
+        let secret = match secret {
+            "" => crate::kap_daemon::KDAEMON_SECRET,
+            secret => secret,
+        };
+        return crate::kap_daemon::secret_derive(secret, WALLET_SECRET_PURPOSE)
+            .await
+            .map_err(|e| anyhow!("wallet password of {} fail - {e}", secret));
+    }
+    if let Some(keyring) = file.and_then(|f| f.strip_prefix("keyring:")) {
+        return wallet_password_keyring(keyring).await;
+    }
+    if let Some(file) = file {
+        let password = tokio::fs::read_to_string(file)
+            .await
+            .map_err(|e| anyhow!("wallet password {} read fail - {e}", file))?;
+        /* only the line end, as a prompted one */
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let password = wallet_password_read(prompt)?;
+    if password.is_empty() {
+        return Err(anyhow!("wallet passphrase empty"));
+    }
+    if confirm
+        && atty::is(atty::Stream::Stdin)
+        && wallet_password_read("confirm passphrase: ")? != password
+    {
+        return Err(anyhow!("wallet passphrase mismatch"));
+    }
+    Ok(password)
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_keystore_write(
+    dir: &str,
+    password: &str,
+    name: Option<&str>,
+) -> Result<(String, String)> {
+    wallet_name_check(dir, name).await?;
+    tokio::fs::create_dir_all(dir).await?;
+
+    let (wallet, uuid) = LocalWallet::new_keystore(dir, &mut rand::thread_rng(), password, name)
+        .map_err(|e| anyhow!("wallet keystore create fail - {e}"))?;
+    /* the file is named after name, uuid otherwise */
+    let keystore = std::path::Path::new(dir).join(name.map(str::to_string).unwrap_or(uuid));
+
+    Ok((
+        format!("{:?}", wallet.address()),
+        keystore.to_string_lossy().to_string(),
+    ))
+}
+
+/* encrypted json keystore under dir, returns (address, keystore path) */
+#[cfg(feature = "wallet")]
+pub(crate) async fn wallet_keystore_new(
+    dir: &str,
+    password_file: &str,
+) -> Result<(String, String)> {
+    let password = wallet_password(Some(password_file), "", false).await?;
+    wallet_keystore_write(dir, &password, None).await
+}
+
+/* re-encrypt an existing key under dir and adopt it as core.wallet_address */
+#[cfg(feature = "wallet")]
+async fn wallet_import(opt: &ImportOpt) -> Result<(String, String)> {
+    let wallet = match (&opt.key_file, &opt.keystore) {
+        (Some(key_file), None) => secret_file_read(key_file)
+            .await?
+            .trim()
+            .trim_start_matches("0x")
+            .parse::<LocalWallet>()
+            .map_err(|e| anyhow!("wallet private key invalid - {e}"))?,
+        (None, Some(keystore)) => {
+            let password = wallet_password(
+                opt.keystore_password_file.as_deref(),
+                "source keystore passphrase: ",
+                false,
+            )
+            .await?;
+            LocalWallet::decrypt_keystore(keystore, password)
+                .map_err(|e| anyhow!("wallet keystore {} decrypt fail - {e}", keystore))?
+        }
+        _ => {
+            return Err(anyhow!(
+                "wallet import needs one of --key-file or --keystore"
+            ))
+        }
+    };
+    let address = format!("{:?}", wallet.address());
+
+    wallet_name_check(&opt.output, opt.name.as_deref()).await?;
+    let password =
+        wallet_password(opt.password_file.as_deref(), "keystore passphrase: ", true).await?;
+    tokio::fs::create_dir_all(&opt.output).await?;
+    let uuid = eth_keystore::encrypt_key(
+        &opt.output,
+        &mut rand::thread_rng(),
+        wallet.signer().to_bytes(),
+        password,
+        opt.name.as_deref(),
+    )
+    .map_err(|e| anyhow!("wallet keystore create fail - {e}"))?;
+    let keystore = std::path::Path::new(&opt.output).join(opt.name.clone().unwrap_or(uuid));
+    if opt.name.as_deref().unwrap_or(WALLET_DEVICE) != WALLET_DEVICE {
+        return Ok((address, keystore.to_string_lossy().to_string()));
+    }
+
+    let mut cfg = KdaemonConfig::build_from(&opt.config)
+        .await
+        .map_err(|e| anyhow!("{} load fail - {e}", opt.config))?;
+    if let Some(ref old) = cfg.core.wallet_address {
+        if !old.as_str().eq_ignore_ascii_case(&address) {
+            warn!("core.wallet_address {} replaced by {}", old, address);
+        }
+    }
+    cfg.core.wallet_address = Some(address.parse()?);
+    cfg.save(&opt.config).await?;
+
+    Ok((address, keystore.to_string_lossy().to_string()))
+}
+
+#[cfg(feature = "wallet")]
+const WALLET_DEVICE: &str = "device";
+#[cfg(feature = "wallet")]
+const WALLET_CURRENT: &str = ".current";
+
+/* a new named keystore neither escapes dir nor replaces another one */
+#[cfg(feature = "wallet")]
+async fn wallet_name_check(dir: &str, name: Option<&str>) -> Result<()> {
+    let name = if let Some(name) = name {
+        name
+    } else {
+        return Ok(());
+    };
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(anyhow!("wallet name {} invalid", name));
+    }
+    if tokio::fs::metadata(std::path::Path::new(dir).join(name))
+        .await
+        .is_ok()
+    {
+        return Err(anyhow!("wallet {} already exists in {}", name, dir));
+    }
+    Ok(())
+}
+
+/* name selected by `wallet use` */
+#[cfg(feature = "wallet")]
+async fn wallet_current(dir: &str) -> Option<String> {
+    tokio::fs::read_to_string(std::path::Path::new(dir).join(WALLET_CURRENT))
+        .await
+        .ok()
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_list(opt: &ListOpt) -> Result<Value> {
+    let password = if opt.password_file.is_some() {
+        Some(wallet_password(opt.password_file.as_deref(), "", false).await?)
+    } else {
+        None
+    };
+    let current = wallet_current(&opt.dir).await;
+
+    let mut entries = tokio::fs::read_dir(&opt.dir)
+        .await
+        .map_err(|e| anyhow!("wallet dir {} read fail - {e}", opt.dir))?;
+    let mut wallets = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let keystore = match tokio::fs::read_to_string(entry.path()).await {
+            Ok(k) => serde_json::from_str::<Value>(&k).unwrap_or_default(),
+            Err(_) => continue,
+        };
+        if keystore
+            .get("crypto")
+            .or_else(|| keystore.get("Crypto"))
+            .is_none()
+        {
+            continue;
+        }
+
+        /* geth style keystores carry the address, others need the passphrase */
+        let address = match password {
+            Some(ref password) => LocalWallet::decrypt_keystore(entry.path(), password)
+                .map(|w| json!(format!("{:?}", w.address())))
+                .unwrap_or(Value::Null),
+            None => keystore["address"]
+                .as_str()
+                .map(|a| json!(format!("0x{}", a.trim_start_matches("0x"))))
+                .unwrap_or(Value::Null),
+        };
+        wallets.push(json!({
+            "name": name,
+            "id": keystore["id"],
+            "address": address,
+            "current": current.as_deref() == Some(name.as_str()),
+        }));
+    }
+    wallets.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+    Ok(Value::Array(wallets))
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_use(opt: &UseOpt) -> Result<()> {
+    let path = std::path::Path::new(&opt.dir).join(&opt.name);
+    if opt.name.starts_with('.') || opt.name.contains('/') || !path.is_file() {
+        return Err(anyhow!("wallet {} not found in {}", opt.name, opt.dir));
+    }
+    write_atomic(
+        std::path::Path::new(&opt.dir).join(WALLET_CURRENT),
+        format!("{}\n", opt.name),
+    )
+    .await
+    .map_err(|e| anyhow!("wallet {} select fail - {e}", opt.name))
+}
+
+/* keystore under dir that decrypts to address */
+#[cfg(feature = "wallet")]
+async fn wallet_keystore_find(dir: &str, password: &str, address: &str) -> Result<LocalWallet> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow!("wallet dir {} read fail - {e}", dir))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match LocalWallet::decrypt_keystore(entry.path(), password) {
+            Ok(wallet) if format!("{:?}", wallet.address()).eq_ignore_ascii_case(address) => {
+                return Ok(wallet)
+            }
+            Ok(_) => {}
+            Err(e) => debug!("wallet keystore {:?} skip - {e}", entry.path()),
+        }
+    }
+
+    Err(anyhow!("wallet {} keystore not found in {}", address, dir))
+}
+
+/* the keystore if given, else the one under dir of core.wallet_address in config */
+#[cfg(feature = "wallet")]
+pub(crate) async fn wallet_keystore_load(
+    keystore: Option<&str>,
+    dir: &str,
+    password: &str,
+    config: &str,
+) -> Result<LocalWallet> {
+    if let Some(keystore) = keystore {
+        return LocalWallet::decrypt_keystore(keystore, password)
+            .map_err(|e| anyhow!("wallet keystore {} decrypt fail - {e}", keystore));
+    }
+
+    let address = KdaemonConfig::build_from(config)
+        .await
+        .ok()
+        .and_then(|cfg| cfg.core.wallet_address)
+        .ok_or_else(|| anyhow!("core.wallet_address missing in {}", config))?;
+    wallet_keystore_find(dir, password, address.as_str()).await
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_load(opt: &WalletKeyOpt) -> Result<LocalWallet> {
+    let password =
+        wallet_password(opt.password_file.as_deref(), "keystore passphrase: ", false).await?;
+    let name = match opt.wallet {
+        Some(ref name) => Some(name.clone()),
+        None => wallet_current(&opt.dir).await,
+    };
+    let keystore = opt.keystore.clone().or_else(|| {
+        name.map(|n| {
+            std::path::Path::new(&opt.dir)
+                .join(n)
+                .to_string_lossy()
+                .to_string()
+        })
+    });
+    wallet_keystore_load(keystore.as_deref(), &opt.dir, &password, &opt.config).await
+}
+
+#[cfg(feature = "wallet")]
+enum WalletSigner {
+    Local(LocalWallet),
+    #[cfg(feature = "ledger")]
+    Ledger(ethers::signers::Ledger),
+}
+
+/* keystore wallet, or the Ledger over HID with --ledger; chain_id is for tx signing */
+#[cfg(feature = "wallet")]
+async fn wallet_signer(opt: &WalletKeyOpt, chain_id: u64) -> Result<WalletSigner> {
+    #[cfg(feature = "ledger")]
+    if opt.ledger {
+        let path = ethers::signers::HDPath::LedgerLive(opt.ledger_index);
+        let ledger = ethers::signers::Ledger::new(path, chain_id)
+            .await
+            .map_err(|e| anyhow!("ledger open fail - {e}"))?;
+        return Ok(WalletSigner::Ledger(ledger));
+    }
+
+    Ok(WalletSigner::Local(
+        wallet_load(opt).await?.with_chain_id(chain_id),
+    ))
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_sign<S: Signer>(signer: &S, message: &[u8]) -> Result<Value> {
+    let signature = signer
+        .sign_message(message)
+        .await
+        .map_err(|e| anyhow!("wallet sign fail - {e}"))?;
+    Ok(json!({
+        "address": format!("{:?}", signer.address()),
+        "signature": format!("0x{}", signature),
+    }))
+}
+
+/* message text, or file content for @file */
+#[cfg(feature = "wallet")]
+async fn wallet_message(message: &str) -> Result<Vec<u8>> {
+    if let Some(file) = message.strip_prefix('@') {
+        tokio::fs::read(file)
+            .await
+            .map_err(|e| anyhow!("message {} read fail - {e}", file))
+    } else {
+        Ok(message.as_bytes().to_vec())
+    }
+}
+
+#[cfg(feature = "wallet")]
+fn wallet_verify(address: &str, message: &[u8], signature: &str) -> Result<Address> {
+    let address = address
+        .parse::<Address>()
+        .map_err(|e| anyhow!("address {} invalid - {e}", address))?;
+    let signature = signature
+        .trim()
+        .parse::<Signature>()
+        .map_err(|e| anyhow!("signature invalid - {e}"))?;
+    let signer = signature
+        .recover(message)
+        .map_err(|e| anyhow!("signature recover fail - {e}"))?;
+    if signer != address {
+        return Err(anyhow!(
+            "signature mismatch, signed by {:?} not {:?}",
+            signer,
+            address
+        ));
+    }
+    Ok(signer)
+}
+
+#[cfg(feature = "wallet")]
+fn wallet_public_key(wallet: &LocalWallet) -> Value {
+    use ethers::core::k256::elliptic_curve::sec1::ToEncodedPoint;
+
+    let key = wallet.signer().verifying_key();
+    json!({
+        "address": format!("{:?}", wallet.address()),
+        "public_key": format!("0x{}", ethers::utils::hex::encode(key.to_encoded_point(false))),
+        "compressed": format!("0x{}", ethers::utils::hex::encode(key.to_encoded_point(true))),
+    })
+}
+
+/* what the AP wallet signs to bind user_wallet, BOSS rebuilds it to verify */
+#[cfg(feature = "wallet")]
+fn bind_message(ap_wallet: &str, user_wallet: &str, timestamp: i64) -> String {
+    format!("{}\n{}\n{}", ap_wallet, user_wallet, timestamp)
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_bind_user<S: Signer>(
+    signer: &S,
+    user_wallet: &str,
+    comment: &str,
+    timestamp: i64,
+) -> Result<Value> {
+    let ap_wallet = address_checksum(&format!("{:?}", signer.address()))?;
+    let user_wallet = address_checksum(user_wallet)
+        .map_err(|e| anyhow!("user wallet {} invalid - {e}", user_wallet))?;
+    let message = bind_message(&ap_wallet, &user_wallet, timestamp);
+    let signed = wallet_sign(signer, message.as_bytes()).await?;
+
+    Ok(json!({
+        "who": user_wallet,
+        "where": ap_wallet,
+        "comment": comment,
+        "ap_wallet": ap_wallet,
+        "user_wallet": user_wallet,
+        "timestamp": timestamp,
+        "signature": signed["signature"],
+    }))
+}
+
+/* ES256K-R, r || s || recovery id over sha256 of the signing input */
+#[cfg(feature = "wallet")]
+fn wallet_jwt(
+    wallet: &LocalWallet,
+    aud: &str,
+    ttl: u64,
+    network: Option<&str>,
+    now: i64,
+) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let b64 = |v: &[u8]| base64::encode_config(v, base64::URL_SAFE_NO_PAD);
+    let address = address_checksum(&format!("{:?}", wallet.address()))?;
+    let did = match network {
+        Some(n) => format!("did:ethr:{}:{}", n, address),
+        None => format!("did:ethr:{}", address),
+    };
+    let header = json!({ "alg": "ES256K-R", "typ": "JWT" });
+    let claims = json!({
+        "iss": did,
+        "sub": did,
+        "aud": aud,
+        "iat": now,
+        "exp": now + ttl as i64,
+    });
+    let input = format!(
+        "{}.{}",
+        b64(header.to_string().as_bytes()),
+        b64(claims.to_string().as_bytes())
+    );
+
+    let hash = H256::from_slice(&Sha256::digest(input.as_bytes()));
+    let signature = wallet.sign_hash(hash);
+    let mut raw = [0u8; 65];
+    signature.r.to_big_endian(&mut raw[..32]);
+    signature.s.to_big_endian(&mut raw[32..64]);
+    raw[64] = (signature.v - 27) as u8;
+    Ok(format!("{}.{}", input, b64(&raw)))
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_user(opt: &BindUserOpt) -> Result<String> {
+    if let Some(ref user) = opt.user_wallet {
+        return Ok(user.clone());
+    }
+    let config = &opt.wallet.config;
+    KdaemonConfig::build_from(config)
+        .await
+        .map_err(|e| anyhow!("{} load fail - {e}", config))?
+        .core
+        .user_wallet
+        .map(String::from)
+        .ok_or_else(|| anyhow!("core.user_wallet missing in {}, use --user-wallet", config))
+}
+
+/* decimal number first, U64 from_str would take it as hex */
+#[cfg(feature = "wallet")]
+fn wallet_block(block: &str) -> Result<BlockNumber> {
+    if let Ok(n) = block.parse::<u64>() {
+        return Ok(BlockNumber::Number(n.into()));
+    }
+    block
+        .parse::<BlockNumber>()
+        .map_err(|e| anyhow!("block {} invalid - {e}", block))
+}
+
+#[cfg(feature = "wallet")]
+fn wallet_address_parse(address: &str) -> Result<Address> {
+    address
+        .parse::<Address>()
+        .map_err(|e| anyhow!("address {} invalid - {e}", address))
+}
+
+#[cfg(feature = "wallet")]
+impl WalletRpcOpt {
+    async fn rule(&self) -> Result<RuleConfig> {
+        RuleConfig::build_from(&self.rule)
+            .await
+            .map_err(|e| anyhow!("rule build from {} fail - {:?}", self.rule, e))
+    }
+
+    /* [chain] of the rule, the rule may be absent when --rpc-url is given */
+    async fn chain(&self) -> Result<RuleChainConfig> {
+        let mut chain = match self.rule().await {
+            Ok(rule) => rule.chain.unwrap_or_default(),
+            Err(e) if self.rpc_url.is_some() => {
+                debug!("chain from --rpc-url only - {e}");
+                RuleChainConfig::default()
+            }
+            Err(e) => return Err(e),
+        };
+        if self.rpc_url.is_some() {
+            chain.rpc_url = self.rpc_url.clone();
+        }
+        Ok(chain)
+    }
+
+    async fn provider(&self) -> Result<(Provider<Http>, RuleChainConfig)> {
+        let chain = self.chain().await?;
+        let rpc_url = chain
+            .rpc_url
+            .clone()
+            .ok_or_else(|| anyhow!("chain.rpc_url missing in {}", self.rule))?;
+        let provider = Provider::<Http>::try_from(rpc_url.as_str())
+            .map_err(|e| anyhow!("rpc url {} invalid - {e}", rpc_url))?;
+        Ok((provider, chain))
+    }
+
+    /* configured chain_id, or what the rpc reports */
+    async fn chain_id(provider: &Provider<Http>, chain: &RuleChainConfig) -> Result<u64> {
+        if let Some(id) = chain.chain_id {
+            return Ok(id);
+        }
+        Ok(provider
+            .get_chainid()
+            .await
+            .map_err(|e| anyhow!("chain id query fail - {e}"))?
+            .as_u64())
+    }
+
+    /* the given address, or core.wallet_address of the rule's kdaemon config */
+    async fn address(&self, address: Option<&str>) -> Result<Address> {
+        if let Some(address) = address {
+            return wallet_address_parse(address);
+        }
+        let config = self.rule().await?.core.config;
+        let address = KdaemonConfig::build_from(&config)
+            .await
+            .map_err(|e| anyhow!("{} load fail - {e}", config))?
+            .core
+            .wallet_address
+            .ok_or_else(|| anyhow!("core.wallet_address missing in {}", config))?;
+        wallet_address_parse(address.as_str())
+    }
+}
+
+#[cfg(feature = "wallet")]
+async fn wallet_balance(opt: &BalanceOpt) -> Result<Value> {
+    let address = opt.rpc.address(opt.address.as_deref()).await?;
+    let block = opt.block.as_deref().map(wallet_block).transpose()?;
+    let (provider, _) = opt.rpc.provider().await?;
+    let wei = provider
+        .get_balance(address, block.map(BlockId::Number))
+        .await
+        .map_err(|e| anyhow!("balance query fail - {e}"))?;
+
+    Ok(json!({
+        "address": format!("{:?}", address),
+        "block": opt.block.as_deref().unwrap_or("latest"),
+        "wei": wei.to_string(),
+        "eth": ethers::utils::format_units(wei, "ether")?,
+    }))
+}
+
+#[cfg(feature = "wallet")]
+const ERC20_ABI: &[&str] = &[
+    "function balanceOf(address) view returns (uint256)",
+    "function decimals() view returns (uint8)",
+    "function symbol() view returns (string)",
+    "function transfer(address,uint256) returns (bool)",
+];
+
+#[cfg(feature = "wallet")]
+fn erc20_contract<M: Middleware>(
+    contract: &str,
+    client: M,
+) -> Result<ethers::contract::Contract<M>> {
+    let abi = ethers::abi::parse_abi(ERC20_ABI)?;
+    Ok(ethers::contract::Contract::new(
+        wallet_address_parse(contract)?,
+        abi,
+        std::sync::Arc::new(client),
+    ))
+}
+
+#[cfg(feature = "wallet")]
+async fn erc20_meta<M: Middleware>(
+    contract: &ethers::contract::Contract<M>,
+) -> Result<(String, u8)> {
+    let symbol = contract
+        .method::<_, String>("symbol", ())?
+        .call()
+        .await
+        .map_err(|e| anyhow!("erc20 symbol fail - {e}"))?;
+    let decimals = contract
+        .method::<_, u8>("decimals", ())?
+        .call()
+        .await
+        .map_err(|e| anyhow!("erc20 decimals fail - {e}"))?;
+    Ok((symbol, decimals))
+}
+
+#[cfg(feature = "wallet")]
+async fn erc20_tools(cmd: Erc20Command) -> Result<Value> {
+    match cmd {
+        Erc20Command::Balance(opt) => {
+            let address = opt.rpc.address(opt.address.as_deref()).await?;
+            let (provider, _) = opt.rpc.provider().await?;
+            let contract = erc20_contract(&opt.contract, provider)?;
+            let (symbol, decimals) = erc20_meta(&contract).await?;
+            let raw = contract
+                .method::<_, U256>("balanceOf", address)?
+                .call()
+                .await
+                .map_err(|e| anyhow!("erc20 balanceOf fail - {e}"))?;
+
+            Ok(json!({
+                "address": format!("{:?}", address),
+                "contract": format!("{:?}", contract.address()),
+                "symbol": symbol,
+                "decimals": decimals,
+                "raw": raw.to_string(),
+                "balance": ethers::utils::format_units(raw, decimals as u32)?,
+            }))
+        }
+        Erc20Command::Transfer(opt) => {
+            let (provider, chain) = opt.rpc.provider().await?;
+            let chain_id = WalletRpcOpt::chain_id(&provider, &chain).await?;
+            match wallet_signer(&opt.wallet, chain_id).await? {
+                WalletSigner::Local(w) => erc20_transfer(&opt, provider, &chain, w).await,
+                #[cfg(feature = "ledger")]
+                WalletSigner::Ledger(l) => erc20_transfer(&opt, provider, &chain, l).await,
+            }
+        }
+    }
+}
+
+/* inline json, signatures, or a json file (a bare array or an artifact with "abi") */
+#[cfg(feature = "wallet")]
+async fn contract_abi(abi: &str) -> Result<ethers::abi::Abi> {
+    let abi = abi.trim();
+    let content = if abi.starts_with('[') || abi.starts_with('{') {
+        abi.to_string()
+    } else if abi.starts_with("function ") {
+        let signatures = abi
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+        return ethers::abi::parse_abi(&signatures)
+            .map_err(|e| anyhow!("abi signatures invalid - {e}"));
+    } else {
+        tokio::fs::read_to_string(abi)
+            .await
+            .map_err(|e| anyhow!("abi {} read fail - {e}", abi))?
+    };
+
+    let value: Value =
+        serde_json::from_str(&content).map_err(|e| anyhow!("abi json invalid - {e}"))?;
+    let value = match value {
+        Value::Object(mut artifact) if artifact.contains_key("abi") => {
+            artifact.remove("abi").unwrap()
+        }
+        v => v,
+    };
+    serde_json::from_value(value).map_err(|e| anyhow!("abi invalid - {e}"))
+}
+
+/* uint/int as decimal strings, they do not fit a json number */
+#[cfg(feature = "wallet")]
+fn abi_token_json(token: ethers::abi::Token) -> Value {
+    use ethers::abi::Token;
+
+    match token {
+        Token::Address(a) => json!(format!("{:?}", a)),
+        Token::Uint(u) => json!(u.to_string()),
+        Token::Int(i) => json!(I256::from_raw(i).to_string()),
+        Token::Bool(b) => json!(b),
+        Token::String(s) => json!(s),
+        Token::Bytes(b) | Token::FixedBytes(b) => {
+            json!(format!("0x{}", ethers::utils::hex::encode(b)))
+        }
+        Token::Array(t) | Token::FixedArray(t) | Token::Tuple(t) => {
+            Value::Array(t.into_iter().map(abi_token_json).collect())
+        }
+    }
+}
+
+#[cfg(feature = "wallet")]
+async fn contract_call(opt: &CallOpt) -> Result<Value> {
+    use ethers::abi::token::{LenientTokenizer, Tokenizer};
+
+    let abi = contract_abi(&opt.abi).await?;
+    let function = abi
+        .functions_by_name(&opt.method)
+        .map_err(|e| anyhow!("method {} not in abi - {e}", opt.method))?
+        .iter()
+        .find(|f| f.inputs.len() == opt.args.len())
+        .ok_or_else(|| {
+            anyhow!(
+                "method {} with {} arguments not in abi",
+                opt.method,
+                opt.args.len()
+            )
+        })?;
+    let tokens = function
+        .inputs
+        .iter()
+        .zip(opt.args.iter())
+        .map(|(p, a)| {
+            LenientTokenizer::tokenize(&p.kind, a)
+                .map_err(|e| anyhow!("argument {} for {} {} invalid - {e}", a, p.kind, p.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let data = function
+        .encode_input(&tokens)
+        .map_err(|e| anyhow!("{} encode fail - {e}", opt.method))?;
+
+    let contract = wallet_address_parse(&opt.contract)?;
+    let block = opt.block.as_deref().map(wallet_block).transpose()?;
+    let (provider, _) = opt.rpc.provider().await?;
+    let tx: ethers::types::transaction::eip2718::TypedTransaction =
+        TransactionRequest::new().to(contract).data(data).into();
+    let raw = provider
+        .call(&tx, block.map(BlockId::Number))
+        .await
+        .map_err(|e| anyhow!("{} call fail - {e}", opt.method))?;
+    let outputs = function
+        .decode_output(&raw)
+        .map_err(|e| anyhow!("{} output decode fail - {e}", opt.method))?;
+
+    /* named outputs keyed by name, the others by position */
+    let mut decoded = serde_json::Map::new();
+    for (i, (p, t)) in function.outputs.iter().zip(outputs).enumerate() {
+        let key = if p.name.is_empty() {
+            i.to_string()
+        } else {
+            p.name.clone()
+        };
+        decoded.insert(key, abi_token_json(t));
+    }
+
+    Ok(json!({
+        "contract": format!("{:?}", contract),
+        "method": function.signature(),
+        "block": opt.block.as_deref().unwrap_or("latest"),
+        "outputs": decoded,
+    }))
+}
+
+#[cfg(feature = "wallet")]
+const WALLET_NONCE: &str = ".nonce.json";
+/* past this a nonce the node still does not count was dropped, not lagging */
+#[cfg(feature = "wallet")]
+const WALLET_NONCE_TTL: i64 = 600;
+
+/* the node's pending count may lag behind what we sent a moment ago */
+#[cfg(feature = "wallet")]
+fn nonce_pick(nonce: Option<u64>, cached: Option<u64>, pending: u64) -> u64 {
+    nonce.unwrap_or_else(|| cached.map_or(pending, |c| c.max(pending)))
+}
+
+/* {dir}/.nonce.json, next nonce and when per {chain_id}:{address} */
+#[cfg(feature = "wallet")]
+struct WalletNonceCache {
+    path: std::path::PathBuf,
+    key: String,
+    nonces: serde_json::Map<String, Value>,
+}
+
+#[cfg(feature = "wallet")]
+impl WalletNonceCache {
+    async fn open(dir: &str, chain_id: u64, address: Address) -> Self {
+        let path = std::path::Path::new(dir).join(WALLET_NONCE);
+        let nonces = tokio::fs::read_to_string(&path)
+            .await
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            key: format!("{}:{:?}", chain_id, address),
+            nonces,
+        }
+    }
+
+    fn get(&self) -> Option<u64> {
+        let entry = self.nonces.get(&self.key)?;
+        let at = entry["at"].as_i64()?;
+        if Utc::now().timestamp() - at > WALLET_NONCE_TTL {
+            return None;
+        }
+        entry["next"].as_u64()
+    }
+
+    /* None drops the entry, the next send goes by the pending count again */
+    async fn set(&mut self, next: Option<u64>) {
+        match next {
+            Some(next) => self.nonces.insert(
+                self.key.clone(),
+                json!({ "next": next, "at": Utc::now().timestamp() }),
+            ),
+            None => self.nonces.remove(&self.key),
+        };
+        if let Err(e) =
+            write_atomic(&self.path, Value::Object(self.nonces.clone()).to_string()).await
+        {
+            warn!("wallet nonce cache {:?} write fail - {e}", self.path);
+        }
+    }
+}
+
+#[cfg(feature = "wallet")]
+fn wallet_gwei(gwei: &str) -> Result<U256> {
+    ethers::utils::parse_units(gwei, "gwei").map_err(|e| anyhow!("gwei {} invalid - {e}", gwei))
+}
+
+/* fill nonce/gas/fees of tx, returns the nonce cache to bump once sent */
+#[cfg(feature = "wallet")]
+async fn wallet_tx_fill(
+    tx: &mut ethers::types::transaction::eip2718::TypedTransaction,
+    opt: &WalletTxOpt,
+    provider: &Provider<Http>,
+    dir: &str,
+    chain_id: u64,
+    from: Address,
+) -> Result<(u64, WalletNonceCache)> {
+    let cache = WalletNonceCache::open(dir, chain_id, from).await;
+    let pending = provider
+        .get_transaction_count(from, Some(BlockNumber::Pending.into()))
+        .await
+        .map_err(|e| anyhow!("nonce query fail - {e}"))?
+        .as_u64();
+    let nonce = nonce_pick(opt.nonce, cache.get(), pending);
+    tx.set_nonce(nonce);
+    if let Some(gas) = opt.gas_limit {
+        tx.set_gas(gas);
+    }
+
+    if let ethers::types::transaction::eip2718::TypedTransaction::Eip1559(ref mut tx) = tx {
+        let (max_fee, priority_fee) = match (&opt.max_fee, &opt.priority_fee) {
+            (Some(max), Some(priority)) => (wallet_gwei(max)?, wallet_gwei(priority)?),
+            (max, priority) => {
+                let (est_max, est_priority) = provider
+                    .estimate_eip1559_fees(None)
+                    .await
+                    .map_err(|e| anyhow!("fee estimate fail - {e}"))?;
+                (
+                    max.as_deref()
+                        .map(wallet_gwei)
+                        .transpose()?
+                        .unwrap_or(est_max),
+                    priority
+                        .as_deref()
+                        .map(wallet_gwei)
+                        .transpose()?
+                        .unwrap_or(est_priority),
+                )
+            }
+        };
+        if priority_fee > max_fee {
+            return Err(anyhow!(
+                "priority fee {} above max fee {}",
+                priority_fee,
+                max_fee
+            ));
+        }
+        tx.max_fee_per_gas = Some(max_fee);
+        tx.max_priority_fee_per_gas = Some(priority_fee);
+    }
+
+    Ok((nonce, cache))
+}
+
+#[cfg(feature = "wallet")]
+async fn erc20_transfer<S: Signer + 'static>(
+    opt: &Erc20TransferOpt,
+    provider: Provider<Http>,
+    chain: &RuleChainConfig,
+    signer: S,
+) -> Result<Value> {
+    let to = wallet_address_parse(&opt.to)?;
+    let from = signer.address();
+    let chain_id = signer.chain_id();
+    let contract = erc20_contract(
+        &opt.contract,
+        SignerMiddleware::new(provider.clone(), signer),
+    )?;
+    let (symbol, decimals) = erc20_meta(&contract).await?;
+    let raw = ethers::utils::parse_units(&opt.amount, decimals as u32)
+        .map_err(|e| anyhow!("amount {} invalid - {e}", opt.amount))?;
+
+    let mut call = contract.method::<_, bool>("transfer", (to, raw))?;
+    let (nonce, mut cache) = wallet_tx_fill(
+        &mut call.tx,
+        &opt.fee,
+        &provider,
+        &opt.wallet.dir,
+        chain_id,
+        from,
+    )
+    .await?;
+    let pending = match call.send().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            cache.set(None).await;
+            return Err(anyhow!("erc20 transfer fail - {e}"));
+        }
+    };
+    cache.set(Some(nonce + 1)).await;
+    let tx = format!("{:?}", *pending);
+    let receipt = if opt.wait {
+        /* dropped or unknown, the nonce may be free again */
+        match pending
+            .confirmations(chain.confirmations.unwrap_or(1))
+            .await
+        {
+            Ok(Some(r)) => Some(json!({ "block": r.block_number, "status": r.status })),
+            Ok(None) => {
+                cache.set(None).await;
+                None
+            }
+            Err(e) => {
+                cache.set(None).await;
+                return Err(anyhow!("erc20 transfer {} receipt fail - {e}", tx));
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(json!({
+        "from": format!("{:?}", from),
+        "to": format!("{:?}", to),
+        "contract": format!("{:?}", contract.address()),
+        "symbol": symbol,
+        "raw": raw.to_string(),
+        "nonce": nonce,
+        "gas_limit": call.tx.gas(),
+        "max_fee_per_gas": call.tx.as_eip1559_ref().and_then(|tx| tx.max_fee_per_gas),
+        "max_priority_fee_per_gas": call
+            .tx
+            .as_eip1559_ref()
+            .and_then(|tx| tx.max_priority_fee_per_gas),
+        "explorer": chain
+            .explorer_url
+            .as_ref()
+            .map(|url| format!("{}/tx/{}", url.trim_end_matches('/'), tx)),
+        "tx": tx,
+        "receipt": receipt,
+    }))
+}
+
+#[cfg(feature = "wallet")]
+#[instrument(name = "wallet")]
+pub async fn wallet_tools(w: WalletCommand) -> Result<()> {
+    match w {
+        WalletCommand::Generate(cfg) => {
+            let password =
+                wallet_password(cfg.password_file.as_deref(), "keystore passphrase: ", true)
+                    .await?;
+            let (address, keystore) =
+                wallet_keystore_write(&cfg.output, &password, cfg.name.as_deref()).await?;
+            debug!("wallet keystore {}", keystore);
+            println!("{}", json!({ "address": address, "keystore": keystore }));
+        }
+        WalletCommand::Import(opt) => {
+            let (address, keystore) = wallet_import(&opt).await?;
+            debug!("wallet keystore {}", keystore);
+            println!("{}", json!({ "address": address, "keystore": keystore }));
+        }
+        WalletCommand::Sign(opt) => {
+            let message = wallet_message(&opt.message).await?;
+            /* personal_sign is chain independent */
+            let signed = match wallet_signer(&opt.wallet, 1).await? {
+                WalletSigner::Local(w) => wallet_sign(&w, &message).await?,
+                #[cfg(feature = "ledger")]
+                WalletSigner::Ledger(l) => wallet_sign(&l, &message).await?,
+            };
+            println!("{}", signed);
+        }
+        WalletCommand::Verify(opt) => {
+            let message = wallet_message(&opt.message).await?;
+            let signer = wallet_verify(&opt.address, &message, &opt.signature)?;
+            println!(
+                "{}",
+                json!({ "address": format!("{:?}", signer), "valid": true })
+            );
+        }
+        WalletCommand::ExportPub(opt) => match wallet_signer(&opt.wallet, 1).await? {
+            WalletSigner::Local(w) => println!("{}", wallet_public_key(&w)),
+            #[cfg(feature = "ledger")]
+            WalletSigner::Ledger(_) => {
+                return Err(anyhow!("export-pub needs a keystore, not --ledger"));
+            }
+        },
+        WalletCommand::BindUser(opt) => {
+            let user = wallet_user(&opt).await?;
+            let now = Utc::now().timestamp();
+            let blob = match wallet_signer(&opt.wallet, 1).await? {
+                WalletSigner::Local(w) => wallet_bind_user(&w, &user, &opt.comment, now).await?,
+                #[cfg(feature = "ledger")]
+                WalletSigner::Ledger(l) => wallet_bind_user(&l, &user, &opt.comment, now).await?,
+            };
+            println!("{}", blob);
+        }
+        WalletCommand::Token(opt) => match wallet_signer(&opt.wallet, 1).await? {
+            WalletSigner::Local(w) => {
+                let now = Utc::now().timestamp();
+                let network = opt.network.as_deref();
+                println!("{}", wallet_jwt(&w, &opt.aud, opt.ttl, network, now)?);
+            }
+            #[cfg(feature = "ledger")]
+            WalletSigner::Ledger(_) => {
+                return Err(anyhow!("token needs a keystore, Ledger signs no raw hash"));
+            }
+        },
+        WalletCommand::Balance(opt) => {
+            println!("{}", wallet_balance(&opt).await?);
+        }
+        WalletCommand::Erc20(cmd) => {
+            println!("{}", erc20_tools(cmd).await?);
+        }
+        WalletCommand::Call(opt) => {
+            println!("{}", contract_call(&opt).await?);
+        }
+        WalletCommand::Checksum(opt) => {
+            println!("{}", address_checksum(&opt.address)?);
+        }
+        WalletCommand::List(opt) => {
+            println!("{}", wallet_list(&opt).await?);
+        }
+        WalletCommand::Use(opt) => {
+            wallet_use(&opt).await?;
+        }
+    }
+    Ok(())
+}
+
+//#[tokio::main]
+pub async fn time_tools(opt: TimeToolOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    match opt.commands {
+        TimeToolCommand::Timestamp(t) => {
+            do_timestamp(t).await?;
+        }
+        TimeToolCommand::FromTimestamp(opt) => {
+            do_from_timestamp(opt).await?;
+        }
+        TimeToolCommand::Duration(opt) => {
+            do_duration(opt).await?;
+        }
+        TimeToolCommand::NtpCheck(opt) => {
+            do_ntp_check(opt).await?;
+        }
+        TimeToolCommand::Uptime(opt) => {
+            do_uptime(opt).await?;
+        }
+        TimeToolCommand::Bench(opt) => {
+            do_bench(opt).await?;
+        }
+        TimeToolCommand::Rfc3339(opt) => {
+            do_rfc3339(opt).await?;
+        }
+    }
+
+    Ok(())
+}
+/*#[tokio::test]
+async fn test_toml_duration() {
+    let cp = ConfigTask {
+        topic: String::from("test"),
+        path: PathBuf::from("/tmp/test.sh"),
+        start_at: Some(Duration::from_secs(1)),
+        period: Some(Duration::from_secs(10)),
+    };
+
+    let toml = toml::to_string(&cp);
+    assert_eq!(toml, Ok(String::from("hello")));
+}*/
+
+#[test]
+fn test_parse_timestamp() {
+    let t = Utc.timestamp_opt(1664627400, 0).unwrap();
+    for s in [
+        "2022-10-01T12:30:00Z",
+        "2022-10-01T20:30:00+08:00",
+        "Sat, 01 Oct 2022 12:30:00 +0000",
+        "1664627400",
+        "1664627400000",
+        "2022-10-01 12:30:00",
+        "2022/10/01 12:30:00",
+        "2022-10-01 12:30",
+    ] {
+        assert_eq!(
+            parse_timestamp(s, None, &TimeZoneArg::Utc).unwrap(),
+            t,
+            "{}",
+            s
+        );
+    }
+    assert_eq!(
+        parse_timestamp("2022-10-01", None, &TimeZoneArg::Utc)
+            .unwrap()
+            .timestamp(),
+        1664582400
+    );
+    assert_eq!(
+        parse_timestamp("01/10/22 12:30", Some("%d/%m/%y %H:%M"), &TimeZoneArg::Utc).unwrap(),
+        t
+    );
+    assert!(parse_timestamp("yesterday", None, &TimeZoneArg::Utc).is_err());
+
+    let taipei = TimeZoneArg::Tz(chrono_tz::Asia::Taipei);
+    assert_eq!(
+        parse_timestamp("2022-10-01 20:30:00", None, &taipei).unwrap(),
+        t
+    );
+    /* an explicit offset wins over --tz */
+    assert_eq!(
+        parse_timestamp("2022-10-01T12:30:00Z", None, &taipei).unwrap(),
+        t
+    );
+    assert_eq!(
+        taipei.rfc3339(t, SecondsFormat::Secs),
+        "2022-10-01T20:30:00+08:00"
+    );
+    assert_eq!(taipei.output(t, TimeOutput::Millis), "1664627400000");
+    assert_eq!(taipei.output(t, TimeOutput::IsoWeek), "2022-W39-6");
+    assert_eq!(taipei.output(t, TimeOutput::Ordinal), "2022-274");
+    /* 2021-01-01 is in week 53 of 2020 */
+    let newyear = Utc.timestamp_opt(1609459200, 0).unwrap();
+    assert_eq!(
+        TimeZoneArg::Utc.output(newyear, TimeOutput::IsoWeek),
+        "2020-W53-5"
+    );
+    let berlin = TimeZoneArg::Tz(chrono_tz::Europe::Berlin);
+    assert!(parse_timestamp("2022-03-27 02:30:00", None, &berlin).is_err());
+}
+
+#[test]
+fn test_duration_parse() {
+    for (s, secs) in [
+        ("90m", 5400),
+        ("1d2h", 93600),
+        ("1h 30m 15s", 5415),
+        ("3600", 3600),
+        ("1w", 604800),
+        ("PT1H30M", 5400),
+        ("P1DT2H", 93600),
+        ("P2W", 1209600),
+        ("PT0S", 0),
+    ] {
+        assert_eq!(
+            duration_parse(s).unwrap(),
+            Duration::from_secs(secs),
+            "{}",
+            s
+        );
+    }
+    assert_eq!(duration_parse("1.5h").unwrap(), Duration::from_secs(5400));
+    assert_eq!(duration_parse("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(
+        duration_parse("PT1.5S").unwrap(),
+        Duration::from_millis(1500)
+    );
+    for s in ["", "6x", "h", "P", "PT", "P1H", "-1s"] {
+        assert!(duration_parse(s).is_err(), "{}", s);
+    }
+
+    let d = Duration::from_secs(93675);
+    assert_eq!(duration_human(d), "1d2h1m15s");
+    assert_eq!(duration_iso8601(d), "P1DT2H1M15S");
+    assert_eq!(duration_human(Duration::ZERO), "0s");
+    assert_eq!(duration_iso8601(Duration::ZERO), "PT0S");
+    assert_eq!(duration_iso8601(Duration::from_secs(86400)), "P1D");
+    assert_eq!(duration_human(Duration::from_millis(1500)), "1s500ms");
+    assert_eq!(duration_iso8601(Duration::from_millis(1500)), "PT1.500S");
+}
+
+#[test]
+fn test_sntp_parse() {
+    let (t1, t4) = (1664627400.0, 1664627400.2);
+    let mut resp = [0u8; 48];
+    resp[0] = 0x24;
+    resp[1] = 2;
+    /* server clock 5s ahead, 0.1s each way */
+    ntp_time_put(&mut resp[32..40], t1 + 5.1);
+    ntp_time_put(&mut resp[40..48], t1 + 5.1);
+    let sample = sntp_parse(&resp, t1, t4).unwrap();
+    assert!((sample.offset - 5.0).abs() < 1e-3, "{:?}", sample);
+    assert!((sample.delay - 0.2).abs() < 1e-3, "{:?}", sample);
+    assert_eq!(sample.stratum, 2);
+
+    resp[1] = 0;
+    assert!(sntp_parse(&resp, t1, t4).is_err());
+    assert!(sntp_parse(&resp[..40], t1, t4).is_err());
+
+    assert_eq!(ntp_target("pool.ntp.org"), "pool.ntp.org:123");
+    assert_eq!(ntp_target("pool.ntp.org:1123"), "pool.ntp.org:1123");
+    assert_eq!(ntp_target("10.0.0.1"), "10.0.0.1:123");
+    assert_eq!(ntp_target("2001:db8::1"), "[2001:db8::1]:123");
+    assert_eq!(ntp_target("[2001:db8::1]:1123"), "[2001:db8::1]:1123");
+}
+
+#[test]
+fn test_bench_stats() {
+    let mut samples = (1..=20).rev().map(|n| n as f64).collect::<Vec<f64>>();
+    assert_eq!(bench_stats(&mut samples), Some((1.0, 10.5, 19.0, 20.0)));
+    assert_eq!(bench_stats(&mut [5.0]), Some((5.0, 5.0, 5.0, 5.0)));
+    assert_eq!(bench_stats(&mut []), None);
+}
+
+#[test]
+fn test_timestamp_from_unix() {
+    let t = timestamp_from_unix(1664627400).unwrap();
+    assert_eq!(timestamp_from_unix(1664627400000).unwrap(), t);
+    assert_eq!(
+        t.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        "2022-10-01T12:30:00Z"
+    );
+    assert_eq!(
+        timestamp_from_unix(1664627400123)
+            .unwrap()
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        "2022-10-01T12:30:00.123Z"
+    );
+}
+
+#[test]
+fn test_address_checksum() {
+    for a in [
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ] {
+        assert_eq!(address_checksum(a).unwrap(), a);
+        assert_eq!(address_checksum(&a.to_ascii_lowercase()).unwrap(), a);
+    }
+    assert!(address_checksum("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").is_err());
+    assert!(address_checksum("5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").is_err());
+    assert!(address_checksum("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeA").is_err());
+    assert!(address_checksum("0xw").is_err());
+}
+
+#[cfg(feature = "wallet")]
+#[test]
+fn test_wallet_verify() {
+    let address = "0x2c7536E3605D9C16a7a3D7b1898e529396a65c23";
+    let signature = "0xa5d58782075bdf09490159d634d1aae66a8f6777c7247d2f233e9511cfd7c64c34f288cdbcea5370e4863fdbe9f4d86654c2ba1d86589e9ebb64494c649008591b";
+    assert!(wallet_verify(address, b"hello", signature).is_ok());
+    assert!(wallet_verify(address, b"hello!", signature).is_err());
+    assert!(wallet_verify(
+        "0x4dea8068f424ff52c5c300d95458fbce31da89a8",
+        b"hello",
+        signature
+    )
+    .is_err());
+    assert!(wallet_verify(address, b"hello", "0x1234").is_err());
+}
+
+#[cfg(feature = "wallet")]
+#[test]
+fn test_wallet_block() {
+    assert_eq!(
+        wallet_block("1000").unwrap(),
+        BlockNumber::Number(1000u64.into())
+    );
+    assert_eq!(wallet_block("latest").unwrap(), BlockNumber::Latest);
+    assert!(wallet_block("yesterday").is_err());
+}
+
+#[cfg(feature = "wallet")]
+#[test]
+fn test_nonce_pick() {
+    assert_eq!(nonce_pick(None, None, 7), 7);
+    assert_eq!(nonce_pick(None, Some(9), 7), 9);
+    assert_eq!(nonce_pick(None, Some(5), 7), 7);
+    assert_eq!(nonce_pick(Some(3), Some(9), 7), 3);
+
+    let now = Utc::now().timestamp();
+    let mut cache = WalletNonceCache {
+        path: std::path::PathBuf::from("/nonexistent/.nonce.json"),
+        key: "137:0x01".to_string(),
+        nonces: serde_json::Map::new(),
+    };
+    assert_eq!(cache.get(), None);
+    cache
+        .nonces
+        .insert(cache.key.clone(), json!({ "next": 9, "at": now }));
+    assert_eq!(cache.get(), Some(9));
+    /* a send never counted by the node is not waited for forever */
+    cache.nonces.insert(
+        cache.key.clone(),
+        json!({ "next": 9, "at": now - WALLET_NONCE_TTL - 1 }),
+    );
+    assert_eq!(cache.get(), None);
+}
+
+#[cfg(feature = "wallet")]
+#[tokio::test]
+async fn test_contract_abi() {
+    use ethers::abi::Token;
+
+    let abi = contract_abi(
+        "function owner() view returns (address); function registered(address) view returns (bool)",
+    )
+    .await
+    .unwrap();
+    assert_eq!(abi.function("registered").unwrap().inputs.len(), 1);
+    let abi = contract_abi(r#"{"abi":[{"type":"function","name":"owner","inputs":[],"outputs":[{"name":"","type":"address"}],"stateMutability":"view"}]}"#)
+        .await
+        .unwrap();
+    assert!(abi.function("owner").is_ok());
+
+    let token = Token::Tuple(vec![
+        Token::Uint(U256::exp10(20)),
+        Token::Int(I256::from(-1).into_raw()),
+        Token::FixedBytes(vec![0xab, 0xcd]),
+    ]);
+    assert_eq!(
+        abi_token_json(token),
+        json!(["100000000000000000000", "-1", "0xabcd"])
+    );
+}
+
+#[cfg(feature = "wallet")]
+#[tokio::test]
+async fn test_wallet_bind_user() {
+    let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse::<LocalWallet>()
+        .unwrap();
+    let user = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed";
+    let blob = wallet_bind_user(&wallet, user, "", 1700000000)
+        .await
+        .unwrap();
+    assert_eq!(
+        blob["user_wallet"],
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+    );
+    assert_eq!(blob["who"], blob["user_wallet"]);
+
+    let ap = blob["ap_wallet"].as_str().unwrap();
+    let message = bind_message(ap, blob["user_wallet"].as_str().unwrap(), 1700000000);
+    let signature = blob["signature"].as_str().unwrap();
+    assert!(wallet_verify(ap, message.as_bytes(), signature).is_ok());
+    assert_eq!(
+        wallet_public_key(&wallet)["address"],
+        format!("{:?}", wallet.address())
+    );
+}
+
+#[cfg(feature = "wallet")]
+#[tokio::test]
+async fn test_wallet_password_source() {
+    let dir = std::env::temp_dir().join(format!("fika_wallet_pass_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let secret = dir.join("secret");
+    std::fs::write(&secret, [7u8; 32]).unwrap();
+    let source = format!("secret:{}", secret.display());
+    let derived = wallet_password(Some(&source), "", false).await.unwrap();
+    assert_eq!(derived.len(), 64);
+    assert_eq!(
+        derived,
+        wallet_password(Some(&source), "", false).await.unwrap()
+    );
+    std::fs::write(&secret, [7u8; 8]).unwrap();
+    assert!(wallet_password(Some(&source), "", false).await.is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let keyring = "wallet = \"boot-secret\"\nowner = \"other\"\n";
+    assert_eq!(keyring_entry(keyring, "wallet").unwrap(), "boot-secret");
+    assert_eq!(keyring_entry(keyring, "owner").unwrap(), "other");
+    assert!(keyring_entry(keyring, "device").is_err());
+}
+
+#[cfg(feature = "wallet")]
+#[test]
+fn test_wallet_jwt() {
+    use sha2::{Digest, Sha256};
+
+    let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse::<LocalWallet>()
+        .unwrap();
+    let jwt = wallet_jwt(&wallet, "https://boss", 60, Some("0x5"), 1700000000).unwrap();
+    let parts = jwt.split('.').collect::<Vec<&str>>();
+    assert_eq!(parts.len(), 3);
+
+    let claims: Value =
+        serde_json::from_slice(&base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap())
+            .unwrap();
+    assert_eq!(
+        claims["iss"],
+        "did:ethr:0x5:0x2c7536E3605D9C16a7a3D7b1898e529396a65c23"
+    );
+    assert_eq!(claims["exp"], 1700000060);
+
+    let raw = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+    assert_eq!(raw.len(), 65);
+    let signature = Signature {
+        r: U256::from_big_endian(&raw[..32]),
+        s: U256::from_big_endian(&raw[32..64]),
+        v: raw[64] as u64 + 27,
+    };
+    let input = format!("{}.{}", parts[0], parts[1]);
+    let hash = H256::from_slice(&Sha256::digest(input.as_bytes()));
+    assert_eq!(signature.recover(hash).unwrap(), wallet.address());
+}
+
+#[tokio::test]
+async fn test_write_atomic() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("fika_atomic_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("private.pem");
+    write_atomic(&path, "one").await.unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    write_atomic(&path, "two").await.unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "two");
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    /* only the target left, no temp */
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(write_atomic(dir.join("missing/x"), "x").await.is_err());
+
+    write_atomic(&path, "hunter22\r\n").await.unwrap();
+    let path = path.display().to_string();
+    assert_eq!(secret_file_read(&path).await.unwrap(), "hunter22");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(secret_file_read(&path).await.is_err());
+}
@@ -2,24 +2,35 @@ use anyhow::{anyhow, Result};
 use chrono::prelude::*;
 use clap::{Args, Subcommand};
 use colored_json::to_colored_json_auto;
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tracing::error;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, error, info, warn};
 
+use crate::kap_daemon::KdaemonConfig;
+use crate::kap_rule::{RuleConfig, RuleConfigBoss};
+use crate::misc::write_atomic;
 use crate::rule_config_load;
-#[cfg(feature = "aws-cli")]
 use crate::setup_logging;
 
 #[derive(Error, Debug)]
 pub enum CurlError {
     #[error("key-value {0} invalid")]
     KvFormat(String),
+    #[error("boss auth fail - {0}")]
+    BossAuth(String),
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 pub struct CurlKV {
     key: String,
     value: String,
@@ -52,6 +63,24 @@ pub struct CurlPostJsonArgs {
     #[clap(short = 'J', long = "json-data", help = "{...}")]
     json: Option<Value>,
 
+    #[clap(flatten)]
+    timeout: CurlTimeout,
+
+    #[clap(flatten)]
+    pool: CurlPool,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    show: CurlShow,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(short = 'K', long = "config", help = "toml file with default options")]
+    config: Option<PathBuf>,
+
     url: String,
 }
 
@@ -64,10 +93,34 @@ pub struct CurlGetArgs {
     #[clap(short = 'Q', long = "query", help = "KEY:ValUE(s)")]
     query: Option<Vec<CurlKV>>,
 
+    #[clap(
+        long = "cache-dir",
+        help = "reuse unchanged bodies via ETag/Last-Modified"
+    )]
+    cache: Option<PathBuf>,
+
+    #[clap(flatten)]
+    timeout: CurlTimeout,
+
+    #[clap(flatten)]
+    pool: CurlPool,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    show: CurlShow,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(short = 'K', long = "config", help = "toml file with default options")]
+    config: Option<PathBuf>,
+
     url: String,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 #[clap(about = "Curl-Get-Json")]
 pub struct CurlGetJsonArgs {
     #[clap(short = 'H', long = "header", help = "KEY:ValUE(s)")]
@@ -79,6 +132,30 @@ pub struct CurlGetJsonArgs {
     #[clap(short = 'J', long = "json-data", help = "{...}")]
     json: Option<Value>,
 
+    #[clap(
+        long = "cache-dir",
+        help = "reuse unchanged bodies via ETag/Last-Modified"
+    )]
+    cache: Option<PathBuf>,
+
+    #[clap(flatten)]
+    timeout: CurlTimeout,
+
+    #[clap(flatten)]
+    pool: CurlPool,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    show: CurlShow,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(short = 'K', long = "config", help = "toml file with default options")]
+    config: Option<PathBuf>,
+
     url: String,
 }
 
@@ -94,15 +171,281 @@ pub struct CurlPostArgs {
     #[clap(short = 'F', long = "form-data", help = "KEY:ValUE(s)")]
     form: Option<Vec<CurlKV>>,
 
+    #[clap(flatten)]
+    timeout: CurlTimeout,
+
+    #[clap(flatten)]
+    pool: CurlPool,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    show: CurlShow,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(short = 'K', long = "config", help = "toml file with default options")]
+    config: Option<PathBuf>,
+
+    url: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Curl-Download")]
+pub struct CurlDownloadArgs {
+    #[clap(short = 'H', long = "header", help = "KEY:ValUE(s)")]
+    header: Option<Vec<CurlKV>>,
+
+    #[clap(short = 'Q', long = "query", help = "KEY:ValUE(s)")]
+    query: Option<Vec<CurlKV>>,
+
+    #[clap(short = 'C', long = "resume", help = "continue a partial download")]
+    resume: bool,
+
+    #[clap(long = "sha256", help = "expected hex digest")]
+    sha256: Option<String>,
+
+    #[clap(flatten)]
+    timeout: CurlTimeout,
+
+    #[clap(flatten)]
+    pool: CurlPool,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    show: CurlShow,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(short = 'K', long = "config", help = "toml file with default options")]
+    config: Option<PathBuf>,
+
     url: String,
 }
 
+#[derive(Args, Debug, Clone, Default)]
+pub struct CurlAuth {
+    #[clap(
+        short = 'u',
+        long = "user",
+        help = "USER[:PASSWORD], basic auth",
+        conflicts_with = "bearer"
+    )]
+    user: Option<String>,
+
+    #[clap(long = "bearer", help = "TOKEN, bearer auth", conflicts_with = "oauth")]
+    bearer: Option<String>,
+
+    #[clap(
+        long = "oauth",
+        help = "NAME of a rule.toml [[oauth]], bearer auth with its cached token",
+        conflicts_with = "user"
+    )]
+    oauth: Option<String>,
+
+    #[clap(long = "rule", default_value = "/etc/fika_manager/rule.toml")]
+    rule: String,
+}
+
+impl CurlAuth {
+    async fn apply(&self, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder> {
+        if let Some(ref user) = self.user {
+            Ok(match user.split_once(':') {
+                Some((u, p)) => req.basic_auth(u, Some(p)),
+                None => req.basic_auth(user, None::<&str>),
+            })
+        } else if let Some(ref token) = self.bearer {
+            Ok(req.bearer_auth(token))
+        } else if let Some(ref name) = self.oauth {
+            let rule = RuleConfig::build_from(&self.rule).await?;
+            let cfg = oauth_config(&rule, name)?;
+            let token = oauth_token(cfg, &rule, false).await?;
+            Ok(req.bearer_auth(token))
+        } else {
+            Ok(req)
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct CurlShow {
+    #[clap(short = 'i', long = "include", help = "print status line and headers")]
+    include: bool,
+
+    #[clap(short = 'o', long = "output", help = "write the raw body to a file")]
+    output: Option<PathBuf>,
+
+    #[clap(
+        short = 'w',
+        long = "write-out",
+        help = "after the body, e.g. '%{http_code} %{time_total}\\n'"
+    )]
+    write_out: Option<String>,
+
+    #[clap(
+        long = "filter",
+        help = "JSONPath subset applied to json replies, e.g. '$.data[0].wallet'"
+    )]
+    filter: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct CurlTimeout {
+    #[clap(long = "connect-timeout", help = "seconds")]
+    connect: Option<u64>,
+
+    #[clap(long = "max-time", help = "seconds, whole request")]
+    max: Option<u64>,
+}
+
+impl CurlTimeout {
+    /* boss/aws calls made on behalf of scripts must not hang them */
+    fn api_default() -> Self {
+        Self {
+            connect: Some(10),
+            max: Some(30),
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CurlPool {
+    #[clap(
+        long = "pool-idle-timeout",
+        help = "seconds an idle connection is kept"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[clap(long = "pool-max-idle", help = "idle connections kept per host")]
+    max_idle: Option<usize>,
+}
+
+/* client-level options, also read from rule.toml [boss.client]/[aws.client] */
+#[derive(Args, Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CurlClientConfig {
+    #[clap(long = "cacert", help = "extra root CA (PEM) to trust")]
+    pub cacert: Option<PathBuf>,
+
+    #[clap(long = "cert", help = "client certificate (PEM) for mutual TLS")]
+    pub cert: Option<PathBuf>,
+
+    #[clap(long = "key", help = "client private key (PEM), requires --cert")]
+    pub key: Option<PathBuf>,
+
+    #[clap(
+        long = "proxy",
+        help = "http://, https:// or socks5://[user:pass@]host:port"
+    )]
+    pub proxy: Option<String>,
+
+    #[clap(long = "no-proxy", help = "ignore proxies from the environment")]
+    #[serde(default)]
+    pub no_proxy: bool,
+}
+
+impl CurlClientConfig {
+    /* command line wins over rule.toml, field by field */
+    pub fn or(self, rule: Option<&CurlClientConfig>) -> Self {
+        let rule = if let Some(r) = rule {
+            r.clone()
+        } else {
+            return self;
+        };
+        Self {
+            cacert: self.cacert.or(rule.cacert),
+            cert: self.cert.or(rule.cert),
+            key: self.key.or(rule.key),
+            proxy: self.proxy.or(rule.proxy),
+            no_proxy: self.no_proxy || rule.no_proxy,
+        }
+    }
+
+    pub(crate) fn apply(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder> {
+        if let Some(ref ca) = self.cacert {
+            let pem = std::fs::read(ca).map_err(|e| anyhow!("cacert {:?} fail - {e}", ca))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        match (&self.cert, &self.key) {
+            (Some(cert), key) => {
+                let mut pem =
+                    std::fs::read(cert).map_err(|e| anyhow!("cert {:?} fail - {e}", cert))?;
+                /* without --key the certificate file must carry the key too */
+                if let Some(key) = key {
+                    pem.push(b'\n');
+                    pem.extend(
+                        std::fs::read(key).map_err(|e| anyhow!("key {:?} fail - {e}", key))?,
+                    );
+                }
+                builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+            }
+            (None, Some(_)) => return Err(anyhow!("client key without certificate invalid")),
+            (None, None) => {}
+        }
+
+        /* an explicit proxy replaces whatever HTTP(S)_PROXY says */
+        if let Some(ref proxy) = self.proxy {
+            builder = builder
+                .no_proxy()
+                .proxy(reqwest::Proxy::all(proxy).map_err(|e| anyhow!("proxy {proxy} - {e}"))?);
+        } else if self.no_proxy {
+            builder = builder.no_proxy();
+        }
+
+        Ok(builder)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct CurlClientKey {
+    connect: Option<u64>,
+    pool: CurlPool,
+    client: CurlClientConfig,
+}
+
+/* one client per distinct client-level option set, so repeated boss/aws
+ * calls from the same process reuse their keep-alive connections */
+static CURL_CLIENTS: OnceLock<Mutex<HashMap<CurlClientKey, reqwest::Client>>> = OnceLock::new();
+
+fn curl_client(key: CurlClientKey) -> Result<reqwest::Client> {
+    let mut clients = CURL_CLIENTS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| anyhow!("curl client cache - {e}"))?;
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+
+    let mut builder = reqwest::Client::builder();
+    if let Some(connect) = key.connect {
+        builder = builder.connect_timeout(Duration::from_secs(connect));
+    }
+    if let Some(idle) = key.pool.idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(idle));
+    }
+    if let Some(max) = key.pool.max_idle {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    let client = key.client.apply(builder)?.build()?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
 #[derive(Debug, Subcommand)]
 pub enum CurlMethod {
     Get(CurlGetArgs),
     GetJson(CurlGetJsonArgs),
     Post(CurlPostArgs),
     PostJson(CurlPostJsonArgs),
+    Download(CurlDownloadArgs),
 }
 
 #[derive(Debug)]
@@ -111,12 +454,173 @@ pub enum CurlResponse {
     JsonFmt(Value),
 }
 
+#[derive(Debug)]
+pub struct CurlMeta {
+    status: reqwest::StatusCode,
+    version: reqwest::Version,
+    headers: reqwest::header::HeaderMap,
+    url: String,
+    size: u64,
+    elapsed: Duration,
+}
+
+impl CurlMeta {
+    fn from_response(resp: &reqwest::Response) -> Self {
+        Self {
+            status: resp.status(),
+            version: resp.version(),
+            headers: resp.headers().clone(),
+            url: resp.url().to_string(),
+            size: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    fn header(&self, name: &str) -> String {
+        self.headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+
+    fn head(&self) -> String {
+        let mut out = format!("{:?} {}\n", self.version, self.status);
+        for (k, v) in self.headers.iter() {
+            out.push_str(&format!("{}: {}\n", k, v.to_str().unwrap_or_default()));
+        }
+        out
+    }
+
+    /* curl --write-out subset: %{http_code} %{time_total} %{size_download}
+     * %{url_effective} %{content_type} %header{name}, plus \n \t escapes */
+    fn write_out(&self, fmt: &str) -> String {
+        let mut out = String::new();
+        let mut chars = fmt.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some(o) => out.push(o),
+                    None => out.push(c),
+                },
+                '%' => {
+                    let rest = chars.clone().collect::<String>();
+                    let (header, skip) = if rest.starts_with("header{") {
+                        (true, "header{".len())
+                    } else if rest.starts_with('{') {
+                        (false, 1)
+                    } else {
+                        if chars.peek() == Some(&'%') {
+                            chars.next();
+                        }
+                        out.push('%');
+                        continue;
+                    };
+                    let end = if let Some(end) = rest[skip..].find('}') {
+                        end
+                    } else {
+                        out.push('%');
+                        continue;
+                    };
+                    let var = &rest[skip..skip + end];
+                    if header {
+                        out.push_str(&self.header(var));
+                    } else {
+                        match var {
+                            "http_code" | "response_code" => out.push_str(self.status.as_str()),
+                            "time_total" => {
+                                out.push_str(&format!("{:.6}", self.elapsed.as_secs_f64()))
+                            }
+                            "size_download" => out.push_str(&self.size.to_string()),
+                            "url_effective" => out.push_str(&self.url),
+                            "content_type" => out.push_str(&self.header("content-type")),
+                            _ => {}
+                        }
+                    }
+                    for _ in 0..skip + end + 1 {
+                        chars.next();
+                    }
+                }
+                _ => out.push(c),
+            }
+        }
+
+        out
+    }
+}
+
+impl CurlMethod {
+    fn timeout(&self) -> &CurlTimeout {
+        match self {
+            CurlMethod::Get(args) => &args.timeout,
+            CurlMethod::GetJson(args) => &args.timeout,
+            CurlMethod::Post(args) => &args.timeout,
+            CurlMethod::PostJson(args) => &args.timeout,
+            CurlMethod::Download(args) => &args.timeout,
+        }
+    }
+
+    fn auth(&self) -> &CurlAuth {
+        match self {
+            CurlMethod::Get(args) => &args.auth,
+            CurlMethod::GetJson(args) => &args.auth,
+            CurlMethod::Post(args) => &args.auth,
+            CurlMethod::PostJson(args) => &args.auth,
+            CurlMethod::Download(args) => &args.auth,
+        }
+    }
+
+    fn show(&self) -> &CurlShow {
+        match self {
+            CurlMethod::Get(args) => &args.show,
+            CurlMethod::GetJson(args) => &args.show,
+            CurlMethod::Post(args) => &args.show,
+            CurlMethod::PostJson(args) => &args.show,
+            CurlMethod::Download(args) => &args.show,
+        }
+    }
+
+    fn client_key(&self) -> CurlClientKey {
+        let (pool, client) = match self {
+            CurlMethod::Get(args) => (&args.pool, &args.client),
+            CurlMethod::GetJson(args) => (&args.pool, &args.client),
+            CurlMethod::Post(args) => (&args.pool, &args.client),
+            CurlMethod::PostJson(args) => (&args.pool, &args.client),
+            CurlMethod::Download(args) => (&args.pool, &args.client),
+        };
+        CurlClientKey {
+            connect: self.timeout().connect,
+            pool: pool.clone(),
+            client: client.clone(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 async fn curl_web_api(method: CurlMethod) -> Result<CurlResponse> {
-    let client = reqwest::Client::new();
-    match method {
+    curl_web_send(method).await.map(|(resp, _)| resp)
+}
+
+async fn curl_web_send(method: CurlMethod) -> Result<(CurlResponse, CurlMeta)> {
+    let timeout = method.timeout().clone();
+    let output = method.show().output.clone();
+    let auth = method.auth().clone();
+    let client = curl_client(method.client_key())?;
+
+    let cache = match method {
+        CurlMethod::Get(ref args) => args.cache.clone(),
+        CurlMethod::GetJson(ref args) => args.cache.clone(),
+        _ => None,
+    };
+
+    let (mut req, json) = match method {
         CurlMethod::Get(args) => {
-            let mut req = client.get(&format!("{}", &args.url));
+            let mut req = client.get(&args.url);
 
             req = if let Some(hs) = args.header {
                 for h in hs {
@@ -136,15 +640,10 @@ async fn curl_web_api(method: CurlMethod) -> Result<CurlResponse> {
                 req
             };
 
-            req.send()
-                .await?
-                .text()
-                .await
-                .map(|r| CurlResponse::TextFmt(r))
-                .map_err(|e| anyhow!("{:?}", e))
+            (req, false)
         }
         CurlMethod::GetJson(args) => {
-            let mut req = client.get(&format!("{}", &args.url));
+            let mut req = client.get(&args.url);
 
             req = if let Some(hs) = args.header {
                 for h in hs {
@@ -164,20 +663,15 @@ async fn curl_web_api(method: CurlMethod) -> Result<CurlResponse> {
                 req
             };
 
-            if let Some(js) = args.json {
+            let req = if let Some(js) = args.json {
                 req.json(&js)
             } else {
                 req
-            }
-            .send()
-            .await?
-            .json::<Value>()
-            .await
-            .map(|r| CurlResponse::JsonFmt(r))
-            .map_err(|e| anyhow!("{:?}", e))
+            };
+            (req, true)
         }
         CurlMethod::Post(args) => {
-            let mut req = client.post(&format!("{}", &args.url));
+            let mut req = client.post(&args.url);
 
             req = if let Some(hs) = args.header {
                 for h in hs {
@@ -207,15 +701,10 @@ async fn curl_web_api(method: CurlMethod) -> Result<CurlResponse> {
                 req
             };
 
-            req.send()
-                .await?
-                .text()
-                .await
-                .map(|r| CurlResponse::TextFmt(r))
-                .map_err(|e| anyhow!("{:?}", e))
+            (req, false)
         }
         CurlMethod::PostJson(args) => {
-            let mut req = client.post(&format!("{}", &args.url));
+            let mut req = client.post(&args.url);
 
             req = if let Some(hs) = args.header {
                 for h in hs {
@@ -235,81 +724,619 @@ async fn curl_web_api(method: CurlMethod) -> Result<CurlResponse> {
                 req
             };
 
-            if let Some(js) = args.json {
+            let req = if let Some(js) = args.json {
                 req.json(&js)
             } else {
                 req
-            }
-            .send()
-            .await?
-            .json::<Value>()
-            .await
-            .map(|r| CurlResponse::JsonFmt(r))
-            .map_err(|e| anyhow!("{:?}", e))
+            };
+            (req, true)
         }
+        CurlMethod::Download(args) => return curl_download(client, &timeout, args).await,
+    };
+    if let Some(max) = timeout.max {
+        req = req.timeout(Duration::from_secs(max));
     }
-}
+    req = auth.apply(req).await?;
 
-pub async fn curl_web_cli(method: CurlMethod) -> Result<()> {
-    let resp = curl_web_api(method).await?;
-    match resp {
-        CurlResponse::TextFmt(s) => println!("{s}"),
-        CurlResponse::JsonFmt(j) => println!("{}", to_colored_json_auto(&j)?),
+    let start = Instant::now();
+    let mut request = req.build()?;
+    let cache = cache.map(|dir| CurlCache::new(dir, request.url().as_str()));
+    let cached = if let Some(ref c) = cache {
+        c.condition(request.headers_mut()).await
+    } else {
+        None
+    };
+    let resp = client.execute(request).await?;
+    let mut meta = CurlMeta::from_response(&resp);
+    let body = resp.bytes().await?;
+    meta.size = body.len() as u64;
+    meta.elapsed = start.elapsed();
+
+    /* the 304 status is kept, %{http_code} tells scripts nothing changed */
+    let body = match (cache, cached) {
+        (Some(_), Some(cached)) if meta.status == reqwest::StatusCode::NOT_MODIFIED => cached,
+        (Some(c), _) if meta.status.is_success() => {
+            if let Err(e) = c.store(&meta.headers, &body).await {
+                warn!("curl cache {} fail - {e}", meta.url);
+            }
+            body.to_vec()
+        }
+        _ => body.to_vec(),
+    };
+
+    if let Some(path) = output {
+        write_atomic(&path, &body).await?;
+        return Ok((CurlResponse::TextFmt(path.display().to_string()), meta));
     }
-    Ok(())
+    let resp = if json {
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => CurlResponse::JsonFmt(value),
+            /* an html/empty 401 still has to reach the token refresh */
+            Err(_) if meta.status == reqwest::StatusCode::UNAUTHORIZED => {
+                return Err(CurlError::BossAuth(format!(
+                    "{} {}",
+                    meta.status,
+                    String::from_utf8_lossy(&body).trim()
+                ))
+                .into());
+            }
+            Err(e) => return Err(anyhow!("{:?}", e)),
+        }
+    } else {
+        CurlResponse::TextFmt(String::from_utf8_lossy(&body).to_string())
+    };
+    Ok((resp, meta))
 }
 
-#[derive(Args, Debug)]
-pub struct ApWalletArg {
-    #[clap(help = r#"{"who": $who, "where": $where, "comment": $comment }"#)]
-    json: Value,
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct CurlCacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
 
-    #[clap(long = "path", default_value = "v0/device/get_eth_wallet")]
-    path: String,
+/* GET cache, {sha256(url)}.json holds the validators, .body the payload */
+struct CurlCache {
+    url: String,
+    meta: PathBuf,
+    body: PathBuf,
 }
 
-#[derive(Args, Debug)]
-pub struct ApHcsArg {
-    #[clap(help = r#"{"ap_wallet":$wallet,"hcs_token":$hcs_token,"hash":$hash}"#)]
-    json: Value,
+impl CurlCache {
+    fn new(dir: PathBuf, url: &str) -> Self {
+        let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+        Self {
+            url: url.to_string(),
+            meta: dir.join(format!("{key}.json")),
+            body: dir.join(format!("{key}.body")),
+        }
+    }
 
-    #[clap(long = "path", default_value = "v0/ap/hcs")]
-    path: String,
+    /* add If-None-Match/If-Modified-Since and return the cached body */
+    async fn condition(&self, headers: &mut reqwest::header::HeaderMap) -> Option<Vec<u8>> {
+        let meta = fs::read_to_string(&self.meta).await.ok()?;
+        let meta = serde_json::from_str::<CurlCacheMeta>(&meta).ok()?;
+        if meta.url != self.url {
+            return None;
+        }
+        let body = fs::read(&self.body).await.ok()?;
+
+        if let Some(etag) = meta.etag.and_then(|e| e.parse().ok()) {
+            headers.insert(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(lm) = meta.last_modified.and_then(|l| l.parse().ok()) {
+            headers.insert(reqwest::header::IF_MODIFIED_SINCE, lm);
+        }
+        Some(body)
+    }
+
+    async fn store(&self, headers: &reqwest::header::HeaderMap, body: &[u8]) -> Result<()> {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let meta = CurlCacheMeta {
+            url: self.url.clone(),
+            etag: value(reqwest::header::ETAG),
+            last_modified: value(reqwest::header::LAST_MODIFIED),
+        };
+        if meta.etag.is_none() && meta.last_modified.is_none() {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.meta.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        /* body first, old validators left by a crash only cost a full fetch */
+        write_atomic(&self.body, body).await?;
+        write_atomic(&self.meta, serde_json::to_string(&meta)?.as_bytes()).await
+    }
 }
 
-#[derive(Args, Debug)]
-pub struct ApTokenArg {
-    #[clap(long = "path", default_value = "v0/ap/ap_token")]
-    path: String,
+#[derive(Debug, PartialEq)]
+enum JsonStep {
+    Key(String),
+    Index(i64),
+    Wildcard,
 }
 
-#[derive(Args, Debug)]
-pub struct OtpArg {
-    #[clap(long = "path", default_value = "v0/ap/otp")]
-    pub path: String,
+/* $ root, .key, ['key'], [n] (negative from the end), [*] and .* */
+fn json_filter_parse(path: &str) -> Result<Vec<JsonStep>> {
+    let invalid = || anyhow!("filter {path} invalid");
+    let rest = path.trim();
+    let mut rest = rest.strip_prefix('$').unwrap_or(rest);
+    let mut steps = vec![];
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            let key = &r[..end];
+            if key.is_empty() {
+                return Err(invalid());
+            }
+            steps.push(if key == "*" {
+                JsonStep::Wildcard
+            } else {
+                JsonStep::Key(key.to_string())
+            });
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(invalid)?;
+            let inner = r[..end].trim();
+            steps.push(if inner == "*" {
+                JsonStep::Wildcard
+            } else if let Some(key) = inner
+                .strip_prefix('\'')
+                .and_then(|k| k.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|k| k.strip_suffix('"')))
+            {
+                JsonStep::Key(key.to_string())
+            } else {
+                JsonStep::Index(inner.parse().map_err(|_| invalid())?)
+            });
+            rest = &r[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    Ok(steps)
 }
 
-#[derive(Args, Debug)]
-pub struct HcsArg {
-    #[clap(long = "path", default_value = "v0/hcs/pair")]
-    path: String,
+fn json_filter(value: &Value, path: &str) -> Result<Vec<Value>> {
+    let mut current = vec![value];
+
+    for step in json_filter_parse(path)? {
+        let mut next = vec![];
+        for v in current {
+            match (&step, v) {
+                (JsonStep::Key(k), Value::Object(m)) => next.extend(m.get(k)),
+                (JsonStep::Index(i), Value::Array(a)) => {
+                    let i = if *i < 0 { a.len() as i64 + i } else { *i };
+                    if i >= 0 {
+                        next.extend(a.get(i as usize));
+                    }
+                }
+                (JsonStep::Wildcard, Value::Array(a)) => next.extend(a.iter()),
+                (JsonStep::Wildcard, Value::Object(m)) => next.extend(m.values()),
+                _ => {}
+            }
+        }
+        current = next;
+    }
+
+    Ok(current.into_iter().cloned().collect())
 }
 
-#[derive(Args, Debug)]
-pub struct ApInfoArg {
-    #[clap(long = "path", default_value = "v0/ap/info")]
-    pub path: String,
+fn curl_temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{suffix}"));
+    PathBuf::from(tmp)
 }
 
-#[derive(Subcommand, Debug)]
-#[clap(about = "Web/Boss")]
-pub enum WebBossPath {
-    GetApToken(ApTokenArg),
-    GetOtp(OtpArg),
-    GetHcs(HcsArg),
-    GetApInfo(ApInfoArg),
-    GetApWallet(ApWalletArg),
+fn download_progress(done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => {
+            eprint!("\r{done}/{total} bytes ({}%)", done * 100 / total)
+        }
+        _ => eprint!("\r{done} bytes"),
+    }
+}
+
+async fn curl_download(
+    client: reqwest::Client,
+    timeout: &CurlTimeout,
+    args: CurlDownloadArgs,
+) -> Result<(CurlResponse, CurlMeta)> {
+    let start = Instant::now();
+    let output = args
+        .show
+        .output
+        .clone()
+        .ok_or_else(|| anyhow!("download -o/--output required"))?;
+    /* the target only appears once complete (and verified) */
+    let part = curl_temp_path(&output, "part");
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    if args.resume {
+        if let Ok(mut f) = fs::File::open(&part).await {
+            /* the digest has to cover what is already on disk */
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = f.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                offset += n as u64;
+            }
+        }
+    }
+
+    let mut req = client.get(&args.url);
+    if let Some(max) = timeout.max {
+        req = req.timeout(Duration::from_secs(max));
+    }
+    if let Some(hs) = args.header {
+        for h in hs {
+            req = req.header(h.key, h.value);
+        }
+    }
+    if let Some(qs) = args.query {
+        for q in qs {
+            req = req.query(&[(q.key, q.value)])
+        }
+    }
+    if offset > 0 {
+        req = req.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+    }
+    req = args.auth.apply(req).await?;
+
+    let mut resp = req.send().await?;
+    let mut meta = CurlMeta::from_response(&resp);
+    let status = resp.status();
+    let append = if status == reqwest::StatusCode::PARTIAL_CONTENT {
+        true
+    } else if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+        /* nothing left to fetch, local file already complete */
+        false
+    } else if status.is_success() {
+        if offset > 0 {
+            /* server ignored Range, start over */
+            hasher = Sha256::new();
+            offset = 0;
+        }
+        false
+    } else {
+        return Err(anyhow!("download {} fail - {}", args.url, status));
+    };
+
+    let mut done = offset;
+    if status != reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        let total = resp.content_length().map(|l| l + offset);
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&part)
+            .await
+            .map_err(|e| anyhow!("open {:?} fail - {e}", &part))?;
+        let progress = atty::is(atty::Stream::Stderr);
+
+        while let Some(chunk) = resp.chunk().await? {
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            done += chunk.len() as u64;
+            if progress {
+                download_progress(done, total);
+            }
+        }
+        file.sync_all().await?;
+        if progress {
+            eprintln!();
+        }
+    }
+
+    let digest = format!("{:x}", hasher.finalize());
+    if let Some(expected) = args.sha256 {
+        if !expected.eq_ignore_ascii_case(&digest) {
+            /* a corrupt file must not be resumed next time */
+            let _ = fs::remove_file(&part).await;
+            return Err(anyhow!(
+                "download {:?} sha256 mismatch - expected {expected}, got {digest}",
+                &output
+            ));
+        }
+    }
+    fs::rename(&part, &output)
+        .await
+        .map_err(|e| anyhow!("rename {:?} fail - {e}", &output))?;
+
+    meta.size = done - offset;
+    meta.elapsed = start.elapsed();
+    Ok((
+        CurlResponse::TextFmt(format!("{} {done} bytes sha256 {digest}", output.display())),
+        meta,
+    ))
+}
+
+/*
+ * -K file, toml with the long option names:
+ *   header = ["ACCESSTOKEN:xxx"]  query = ["k:v"]  user = "u:p"
+ *   bearer/oauth, connect_timeout, max_time, cacert/cert/key, proxy/no_proxy
+ * headers/queries go before the command line ones, other options only
+ * apply when not given on the command line
+ */
+#[derive(Deserialize, Debug, Default)]
+struct CurlFileConfig {
+    header: Option<Vec<String>>,
+    query: Option<Vec<String>>,
+    user: Option<String>,
+    bearer: Option<String>,
+    oauth: Option<String>,
+    connect_timeout: Option<u64>,
+    max_time: Option<u64>,
+    #[serde(flatten)]
+    client: CurlClientConfig,
+}
+
+fn curl_kv_merge(file: Option<Vec<String>>, cli: &mut Option<Vec<CurlKV>>) -> Result<()> {
+    if let Some(file) = file {
+        let mut kvs = file
+            .iter()
+            .map(|kv| CurlKV::from_str(kv))
+            .collect::<Result<Vec<CurlKV>, CurlError>>()?;
+        kvs.extend(cli.take().unwrap_or_default());
+        *cli = Some(kvs);
+    }
+    Ok(())
+}
+
+impl CurlFileConfig {
+    async fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("curl config {:?} fail - {e}", path))?;
+        toml::from_str(&raw).map_err(|e| anyhow!("curl config {:?} invalid - {e}", path))
+    }
+
+    fn merge(
+        self,
+        header: &mut Option<Vec<CurlKV>>,
+        query: &mut Option<Vec<CurlKV>>,
+        timeout: &mut CurlTimeout,
+        auth: &mut CurlAuth,
+        client: &mut CurlClientConfig,
+    ) -> Result<()> {
+        curl_kv_merge(self.header, header)?;
+        curl_kv_merge(self.query, query)?;
+        timeout.connect = timeout.connect.or(self.connect_timeout);
+        timeout.max = timeout.max.or(self.max_time);
+        if auth.user.is_none() && auth.bearer.is_none() && auth.oauth.is_none() {
+            auth.user = self.user;
+            auth.bearer = self.bearer;
+            auth.oauth = self.oauth;
+        }
+        *client = client.clone().or(Some(&self.client));
+        Ok(())
+    }
+}
+
+impl CurlMethod {
+    async fn config_merge(&mut self) -> Result<()> {
+        let (config, header, query, timeout, auth, client) = match self {
+            CurlMethod::Get(a) => (
+                &a.config,
+                &mut a.header,
+                &mut a.query,
+                &mut a.timeout,
+                &mut a.auth,
+                &mut a.client,
+            ),
+            CurlMethod::GetJson(a) => (
+                &a.config,
+                &mut a.header,
+                &mut a.query,
+                &mut a.timeout,
+                &mut a.auth,
+                &mut a.client,
+            ),
+            CurlMethod::Post(a) => (
+                &a.config,
+                &mut a.header,
+                &mut a.query,
+                &mut a.timeout,
+                &mut a.auth,
+                &mut a.client,
+            ),
+            CurlMethod::PostJson(a) => (
+                &a.config,
+                &mut a.header,
+                &mut a.query,
+                &mut a.timeout,
+                &mut a.auth,
+                &mut a.client,
+            ),
+            CurlMethod::Download(a) => (
+                &a.config,
+                &mut a.header,
+                &mut a.query,
+                &mut a.timeout,
+                &mut a.auth,
+                &mut a.client,
+            ),
+        };
+        if let Some(path) = config {
+            CurlFileConfig::load(path)
+                .await?
+                .merge(header, query, timeout, auth, client)?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn curl_web_cli(mut method: CurlMethod) -> Result<()> {
+    method.config_merge().await?;
+    let show = method.show().clone();
+    let is_download = matches!(method, CurlMethod::Download(_));
+    let (resp, meta) = curl_web_send(method).await?;
+    if show.include {
+        println!("{}", meta.head());
+    }
+    /* with -o the body went to the file and only download reports a summary */
+    match resp {
+        CurlResponse::TextFmt(s) if show.output.is_none() || is_download => println!("{s}"),
+        CurlResponse::TextFmt(_) => {}
+        CurlResponse::JsonFmt(j) => {
+            let j = if let Some(ref filter) = show.filter {
+                let mut got = json_filter(&j, filter)?;
+                if got.len() == 1 {
+                    got.remove(0)
+                } else {
+                    Value::Array(got)
+                }
+            } else {
+                j
+            };
+            /* plain strings raw, as `jq -r` would */
+            match j {
+                Value::String(s) if show.filter.is_some() => println!("{s}"),
+                j => println!("{}", to_colored_json_auto(&j)?),
+            }
+        }
+    }
+    if let Some(fmt) = show.write_out {
+        print!("{}", meta.write_out(&fmt));
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug)]
+pub struct ApWalletArg {
+    #[clap(help = r#"{"who": $who, "where": $where, "comment": $comment }"#)]
+    json: Value,
+
+    #[clap(long = "path", default_value = "v0/device/get_eth_wallet")]
+    path: String,
+}
+
+impl ApWalletArg {
+    /* not in rule.toml [boss], keep the CLI default */
+    fn default_path() -> String {
+        "v0/device/get_eth_wallet".to_string()
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ApHcsArg {
+    #[clap(help = r#"{"ap_wallet":$wallet,"hcs_token":$hcs_token,"hash":$hash}"#)]
+    json: Value,
+
+    #[clap(long = "path", default_value = "v0/ap/hcs")]
+    path: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "replay PostApHcs submissions queued while offline")]
+pub struct ApHcsFlushArg {
+    #[clap(long = "path", default_value = "v0/ap/hcs")]
+    path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ApTokenArg {
+    #[clap(long = "path", default_value = "v0/ap/ap_token")]
+    pub path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct OtpArg {
+    #[clap(long = "path", default_value = "v0/ap/otp")]
+    pub path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct HcsArg {
+    #[clap(long = "path", default_value = "v0/hcs/pair")]
+    path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ApInfoArg {
+    #[clap(long = "path", default_value = "v0/ap/info")]
+    pub path: String,
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "endpoint from rule.toml [[boss.endpoint]] or a builtin name")]
+pub struct BossCallArg {
+    #[clap(help = "ap_token, otp, hcs, ap_hcs, ap_info or a configured name")]
+    name: String,
+
+    #[clap(short = 'J', long = "json-data", help = "{...}")]
+    json: Option<Value>,
+}
+
+#[derive(Subcommand, Debug)]
+#[clap(about = "Web/Boss")]
+pub enum WebBossPath {
+    GetApToken(ApTokenArg),
+    GetOtp(OtpArg),
+    GetHcs(HcsArg),
+    GetApInfo(ApInfoArg),
+    GetApWallet(ApWalletArg),
     PostApHcs(ApHcsArg),
+    FlushApHcs(ApHcsFlushArg),
+    Call(BossCallArg),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleBossMethod {
+    Get,
+    Post,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleBossWallet {
+    Query,
+    Json,
+    None,
+}
+
+/* new BOSS endpoints by config only, see `boss call` */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RuleBossEndpoint {
+    pub name: String,
+    pub path: String,
+    pub method: Option<RuleBossMethod>,
+    pub ap_token: Option<bool>,
+    pub wallet: Option<RuleBossWallet>,
+    pub field: Option<String>,
+}
+
+impl RuleBossEndpoint {
+    fn builtin(
+        name: &str,
+        path: &Option<String>,
+        method: RuleBossMethod,
+        ap_token: bool,
+        wallet: RuleBossWallet,
+        field: Option<&str>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            path: path.clone().unwrap_or_default(),
+            method: Some(method),
+            ap_token: Some(ap_token),
+            wallet: Some(wallet),
+            field: field.map(|f| f.to_string()),
+        }
+    }
 }
 
 #[derive(Args, Debug)]
@@ -336,265 +1363,721 @@ pub struct WebBossOpt {
 
     #[clap(short = 'w', long = "ap-wallet")]
     wallet: Option<String>,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
 }
 
 #[cfg(feature = "boss-api")]
-#[allow(dead_code)]
-pub async fn boss_web_api(
-    wallet: Option<String>,
-    root_url: String,
-    region: String,
-    token: Option<String>,
-    class: WebBossPath,
-) -> Result<serde_json::Value> {
-    match class {
-        WebBossPath::GetApToken(arg) => {
-            let wallet = if let Some(w) = wallet {
-                w
-            } else {
-                return Err(anyhow::anyhow!("wallet-address invalid"));
-            };
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BossApToken {
+    #[serde(alias = "ap_token", alias = "ap_access_token")]
+    pub access_token: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
 
-            match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
-                header: Some(vec![CurlKV {
-                    key: "ACCESSTOKEN".to_string(),
-                    value: region,
-                }]),
-                query: Some(vec![CurlKV {
-                    key: "ap_wallet".to_string(),
-                    value: wallet,
-                }]),
-                json: None,
-                url: format!("{}/{}", root_url, &arg.path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response)
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ))
-                    }
-                }
-                CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
-            }
+#[cfg(feature = "boss-api")]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BossOtp {
+    pub otp: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[cfg(feature = "boss-api")]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BossHcs {
+    pub hcs_token: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[cfg(feature = "boss-api")]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BossApInfo {
+    pub ap_wallet: Option<String>,
+    pub nickname: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[cfg(feature = "boss-api")]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BossApWallet {
+    pub wallet: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+pub const BOSS_AP_TOKEN_KEY: &str = "kap/boss/ap_access_token";
+
+pub const BOSS_HCS_QUEUE_KEY: &str = "kap/boss/hcs_queue";
+/* the entry being posted, back to the queue head if we died meanwhile */
+pub const BOSS_HCS_PROCESSING_KEY: &str = "kap/boss/hcs_processing";
+pub(crate) const BOSS_HCS_QUEUE_MAX_AGE: u64 = 7 * 24 * 3600;
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BossHcsQueued {
+    timestamp: DateTime<Utc>,
+    json: Value,
+}
+
+/* the one to post, None for an invalid or too old one that is dropped */
+fn hcs_queued_take(
+    entry: &str,
+    now: DateTime<Utc>,
+    max_age: chrono::Duration,
+) -> Option<BossHcsQueued> {
+    match serde_json::from_str::<BossHcsQueued>(entry) {
+        Ok(q) if now - q.timestamp > max_age => {
+            warn!(
+                "[kap][boss] queued hcs from {} too old, dropped",
+                q.timestamp
+            );
+            None
         }
-        WebBossPath::PostApHcs(map) => {
-            if token.is_none() {
-                error!("[kap][boss] ap-acess-token not exist");
-                return Err(anyhow!("[kap][boss] ap-acess-token not exist"));
-            }
+        Ok(q) => Some(q),
+        Err(e) => {
+            warn!("[kap][boss] queued hcs invalid, dropped - {e}");
+            None
+        }
+    }
+}
+
+async fn hcs_queue_move(
+    conn: &mut redis::aio::Connection,
+    src: &str,
+    dst: &str,
+    from: &str,
+    to: &str,
+) -> Result<Option<String>> {
+    Ok(redis::cmd("LMOVE")
+        .arg(src)
+        .arg(dst)
+        .arg(from)
+        .arg(to)
+        .query_async(conn)
+        .await?)
+}
+
+#[cfg(feature = "boss-api")]
+pub(crate) fn boss_offline(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .map(|e| e.is_connect() || e.is_timeout())
+        .unwrap_or(false)
+}
+
+#[cfg(feature = "boss-api")]
+pub(crate) fn boss_auth_fail(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<CurlError>(), Some(CurlError::BossAuth(_)))
+}
+
+/*
+ * BOSS endpoints, region token as ACCESSTOKEN and (for the ap endpoints)
+ * ap token as ACCESSTOKEN-AP; paths come from rule.toml [boss]
+ */
+#[cfg(feature = "boss-api")]
+#[derive(Debug, Clone)]
+pub struct BossClient {
+    pub root_url: String,
+    pub region: String,
+    pub ap_token: Option<String>,
+    pub wallet: Option<String>,
+    pub paths: RuleConfigBoss,
+    pub client: CurlClientConfig,
+    /* where a refreshed ap token is saved, see request() */
+    pub auto_refresh: bool,
+    pub config: Option<String>,
+    pub database: Option<String>,
+    /* X-AP-TIMESTAMP/X-AP-SIGNATURE on every request, see sign_headers() */
+    #[cfg(feature = "wallet")]
+    pub signer: Option<ethers::signers::LocalWallet>,
+    refreshed: Arc<Mutex<Option<String>>>,
+}
+
+#[cfg(feature = "boss-api")]
+impl BossClient {
+    pub fn new(root_url: String, region: String) -> Self {
+        Self {
+            root_url,
+            region,
+            ap_token: None,
+            wallet: None,
+            paths: RuleConfigBoss::default(),
+            client: CurlClientConfig::default(),
+            auto_refresh: true,
+            config: None,
+            database: None,
+            #[cfg(feature = "wallet")]
+            signer: None,
+            refreshed: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn from_config(rule: &RuleConfig, cfg: &KdaemonConfig) -> Result<Self> {
+        Ok(Self {
+            root_url: rule
+                .boss
+                .root_url
+                .clone()
+                .ok_or_else(|| anyhow!("boss/root-url none invalid"))?,
+            region: cfg
+                .boss
+                .access_token
+                .clone()
+                .ok_or_else(|| anyhow!("boss/access-token none invalid"))?,
+            ap_token: cfg.boss.ap_access_token.clone(),
+            wallet: cfg.core.wallet_address.clone().map(String::from),
+            paths: rule.boss.clone(),
+            client: rule.boss.client.clone().unwrap_or_default(),
+            auto_refresh: true,
+            config: Some(rule.core.config.clone()),
+            database: rule.core.database.clone(),
+            #[cfg(feature = "wallet")]
+            signer: None,
+            refreshed: Arc::new(Mutex::new(None)),
+        })
+    }
 
-            let wallet = if let Some(w) = wallet {
-                w
+    /* device wallet of [boss.sign], the passphrase file is required as it runs unattended */
+    #[cfg(feature = "wallet")]
+    pub async fn sign_from(&mut self, boss: &RuleConfigBoss, config: &str) -> Result<()> {
+        let sign = if let Some(ref sign) = boss.sign {
+            sign
+        } else {
+            return Ok(());
+        };
+        let password_file = sign
+            .password_file
+            .as_deref()
+            .unwrap_or("/etc/fika_manager/wallet.pass");
+        let password = crate::misc::wallet_password(Some(password_file), "", false).await?;
+        let dir = sign.dir.as_deref().unwrap_or("/userdata/wallet");
+        let signer =
+            crate::misc::wallet_keystore_load(sign.keystore.as_deref(), dir, &password, config)
+                .await
+                .map_err(|e| anyhow!("[kap][boss] sign wallet load fail - {e}"))?;
+        self.signer = Some(signer);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "wallet"))]
+    pub async fn sign_from(&mut self, boss: &RuleConfigBoss, _config: &str) -> Result<()> {
+        if boss.sign.is_some() {
+            warn!("[kap][boss] boss.sign ignored, built without the wallet feature");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "wallet")]
+    async fn sign_headers(
+        signer: &ethers::signers::LocalWallet,
+        args: &CurlGetJsonArgs,
+    ) -> Result<Vec<CurlKV>> {
+        use ethers::signers::Signer;
+
+        let timestamp = Utc::now().timestamp().to_string();
+        let path = reqwest::Url::parse(&args.url)?.path().to_string();
+        /* GetApInfo sends its json on GET too */
+        let body = match args.json {
+            Some(ref json) => serde_json::to_vec(json)?,
+            None => vec![],
+        };
+        let payload = format!("{}\n{}\n{:x}", timestamp, path, Sha256::digest(&body));
+        let signature = signer
+            .sign_message(payload.as_bytes())
+            .await
+            .map_err(|e| anyhow!("[kap][boss] request sign fail - {e}"))?;
+
+        Ok(vec![
+            CurlKV {
+                key: "X-AP-TIMESTAMP".to_string(),
+                value: timestamp,
+            },
+            CurlKV {
+                key: "X-AP-SIGNATURE".to_string(),
+                value: format!("0x{}", signature),
+            },
+        ])
+    }
+
+    fn wallet(&self) -> Result<String> {
+        self.wallet
+            .clone()
+            .ok_or_else(|| anyhow!("wallet-address invalid"))
+    }
+
+    fn headers(&self, ap: bool) -> Result<Vec<CurlKV>> {
+        let mut headers = vec![CurlKV {
+            key: "ACCESSTOKEN".to_string(),
+            value: self.region.clone(),
+        }];
+        if ap {
+            let refreshed = self.refreshed.lock().ok().and_then(|r| r.clone());
+            let token = if let Some(token) = refreshed.or_else(|| self.ap_token.clone()) {
+                token
             } else {
-                return Err(anyhow::anyhow!("wallet-address invalid"));
+                error!("[kap][boss] ap-acess-token not exist");
+                return Err(anyhow!("[kap][boss] ap-acess-token not exist"));
             };
+            headers.push(CurlKV {
+                key: "ACCESSTOKEN-AP".to_string(),
+                value: token,
+            });
+        }
+        Ok(headers)
+    }
 
-            match curl_web_api(CurlMethod::PostJson(CurlPostJsonArgs {
-                header: Some(vec![
-                    CurlKV {
-                        key: "ACCESSTOKEN".to_string(),
-                        value: region,
-                    },
-                    CurlKV {
-                        key: "ACCESSTOKEN-AP".to_string(),
-                        value: token.expect("ACCESSTOKEN-AP none invalid"),
-                    },
-                ]),
-                query: Some(vec![CurlKV {
-                    key: "ap_wallet".to_string(),
-                    value: wallet,
-                }]),
-                json: Some(map.json),
-                url: format!("{}/{}", root_url, &map.path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response["data"].clone())
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ))
-                    }
+    fn url(&self, path: &Option<String>) -> String {
+        format!("{}/{}", self.root_url, path.as_deref().unwrap_or_default())
+    }
+
+    /* BOSS answers 200 with its own code/message envelope */
+    async fn request_once(&self, post: bool, args: CurlGetJsonArgs) -> Result<Value> {
+        #[cfg(feature = "wallet")]
+        let args = if let Some(ref signer) = self.signer {
+            let mut args = args;
+            let signed = Self::sign_headers(signer, &args).await?;
+            args.header.get_or_insert_with(Vec::new).extend(signed);
+            args
+        } else {
+            args
+        };
+
+        let method = if post {
+            CurlMethod::PostJson(CurlPostJsonArgs {
+                header: args.header,
+                query: args.query,
+                json: args.json,
+                timeout: args.timeout,
+                pool: args.pool,
+                client: args.client,
+                show: args.show,
+                auth: args.auth,
+                config: args.config,
+                url: args.url,
+            })
+        } else {
+            CurlMethod::GetJson(args)
+        };
+
+        let (response, meta) = curl_web_send(method).await?;
+        match response {
+            CurlResponse::JsonFmt(response) => {
+                if response["code"] == 200 {
+                    Ok(response)
+                } else if response["code"] == 401
+                    || meta.status == reqwest::StatusCode::UNAUTHORIZED
+                {
+                    Err(CurlError::BossAuth(response["message"].to_string()).into())
+                } else {
+                    Err(anyhow::anyhow!(
+                        "{} [{}]",
+                        response["message"],
+                        response["code"]
+                    ))
                 }
-                CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
             }
+            CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
         }
-        WebBossPath::GetOtp(arg) => {
-            if token.is_none() {
-                error!("[kap][boss] ap-acess-token not exist");
-                return Err(anyhow!("[kap][boss] ap-acess-token not exist"));
-            }
-
-            let wallet = if let Some(w) = wallet {
-                w
-            } else {
-                return Err(anyhow::anyhow!("wallet-address invalid"));
-            };
+    }
 
-            match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
-                header: Some(vec![
-                    CurlKV {
-                        key: "ACCESSTOKEN".to_string(),
-                        value: region,
-                    },
-                    CurlKV {
-                        key: "ACCESSTOKEN-AP".to_string(),
-                        value: token.expect("ACCESSTOKEN-AP none invalid"),
-                    },
-                ]),
-                query: Some(vec![CurlKV {
-                    key: "ap_wallet".to_string(),
-                    value: wallet,
-                }]),
-                json: None,
-                url: format!("{}/{}", root_url, &arg.path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response)
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ))
+    /* an expired ap token is renewed, persisted and the request retried once */
+    async fn request(&self, post: bool, mut args: CurlGetJsonArgs) -> Result<Value> {
+        let ap = args
+            .header
+            .iter()
+            .flatten()
+            .any(|h| h.key == "ACCESSTOKEN-AP");
+
+        match self.request_once(post, args.clone()).await {
+            Err(e) if ap && self.auto_refresh && boss_auth_fail(&e) => {
+                warn!("[kap][boss] {e}, refresh ap-access-token");
+                let token = self.ap_token_refresh().await?;
+                for h in args.header.iter_mut().flatten() {
+                    if h.key == "ACCESSTOKEN-AP" {
+                        h.value = token.clone();
                     }
                 }
-                CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
+                self.request_once(post, args).await
             }
+            r => r,
         }
-        WebBossPath::GetHcs(arg) => {
-            if token.is_none() {
-                error!("[kap][boss] ap-acess-token not exist");
-                return Err(anyhow!("[kap][boss] ap-acess-token not exist"));
+    }
+
+    async fn ap_token_refresh(&self) -> Result<String> {
+        let args = self.args(
+            self.headers(false)?,
+            self.wallet_query()?,
+            None,
+            &self.paths.ap_token_path,
+        );
+        let response = self.request_once(false, args).await?;
+        let token = serde_json::from_value::<BossApToken>(response["data"].clone())
+            .ok()
+            .and_then(|t| t.access_token)
+            .ok_or_else(|| anyhow!("[kap][boss] ap-token reply without token"))?;
+
+        if let Ok(mut refreshed) = self.refreshed.lock() {
+            *refreshed = Some(token.clone());
+        }
+        if let Err(e) = self.ap_token_persist(&token).await {
+            warn!("[kap][boss] ap-access-token persist fail - {e}");
+        }
+        Ok(token)
+    }
+
+    async fn ap_token_persist(&self, token: &str) -> Result<()> {
+        if let Some(ref config) = self.config {
+            let mut cfg = KdaemonConfig::build_from(config).await?;
+            cfg.boss.ap_access_token = Some(token.to_string());
+            cfg.save(config).await?;
+        }
+        if let Some(ref database) = self.database {
+            let mut conn = redis::Client::open(database.as_str())?
+                .get_async_connection()
+                .await?;
+            conn.set::<_, _, ()>(BOSS_AP_TOKEN_KEY, token).await?;
+        }
+        Ok(())
+    }
+
+    fn args(
+        &self,
+        header: Vec<CurlKV>,
+        query: Option<Vec<CurlKV>>,
+        json: Option<Value>,
+        path: &Option<String>,
+    ) -> CurlGetJsonArgs {
+        CurlGetJsonArgs {
+            cache: None,
+            header: Some(header),
+            query,
+            json,
+            timeout: CurlTimeout::api_default(),
+            pool: CurlPool::default(),
+            client: self.client.clone(),
+            show: CurlShow::default(),
+            auth: CurlAuth::default(),
+            config: None,
+            url: self.url(path),
+        }
+    }
+
+    fn wallet_query(&self) -> Result<Option<Vec<CurlKV>>> {
+        Ok(Some(vec![CurlKV {
+            key: "ap_wallet".to_string(),
+            value: self.wallet()?,
+        }]))
+    }
+
+    pub async fn get_ap_token_raw(&self) -> Result<Value> {
+        let args = self.args(
+            self.headers(false)?,
+            self.wallet_query()?,
+            None,
+            &self.paths.ap_token_path,
+        );
+        self.request(false, args).await
+    }
+
+    pub async fn get_otp_raw(&self) -> Result<Value> {
+        let args = self.args(
+            self.headers(true)?,
+            self.wallet_query()?,
+            None,
+            &self.paths.otp_path,
+        );
+        self.request(false, args).await
+    }
+
+    pub async fn get_hcs_raw(&self) -> Result<Value> {
+        let args = self.args(
+            self.headers(true)?,
+            self.wallet_query()?,
+            None,
+            &self.paths.hcs_path,
+        );
+        Ok(self.request(false, args).await?["hcs"].clone())
+    }
+
+    pub async fn get_ap_info_raw(&self) -> Result<Value> {
+        let json = json!({ "ap_wallet": self.wallet()? });
+        let args = self.args(
+            self.headers(true)?,
+            None,
+            Some(json),
+            &self.paths.ap_info_path,
+        );
+        Ok(self.request(false, args).await?["data"].clone())
+    }
+
+    pub async fn get_ap_wallet_raw(&self, path: &str, json: Value) -> Result<Value> {
+        let args = self.args(
+            self.headers(false)?,
+            None,
+            Some(json),
+            &Some(path.to_string()),
+        );
+        Ok(self.request(false, args).await?["data"].clone())
+    }
+
+    async fn post_ap_hcs_once(&self, json: Value) -> Result<Value> {
+        let headers = self.headers(true)?;
+        let args = self.args(
+            headers,
+            self.wallet_query()?,
+            Some(json),
+            &self.paths.ap_hcs_path,
+        );
+        Ok(self.request(true, args).await?["data"].clone())
+    }
+
+    /*
+     * submissions made while BOSS is unreachable go to BOSS_HCS_QUEUE_KEY
+     * and are replayed in order by the next post or hcs_queue_flush()
+     */
+    pub async fn post_ap_hcs(&self, json: Value) -> Result<Value> {
+        let database = if let Some(ref database) = self.database {
+            database
+        } else {
+            return self.post_ap_hcs_once(json).await;
+        };
+
+        let pending = match self.hcs_queue_flush().await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("[kap][boss] hcs queue flush fail - {e}");
+                0
+            }
+        };
+        if pending == 0 {
+            match self.post_ap_hcs_once(json.clone()).await {
+                Err(e) if boss_offline(&e) => warn!("[kap][boss] post hcs fail, queued - {e}"),
+                r => return r,
             }
+        }
 
-            let wallet = if let Some(w) = wallet {
-                w
-            } else {
-                return Err(anyhow::anyhow!("wallet-address invalid"));
-            };
+        let queued = BossHcsQueued {
+            timestamp: Utc::now(),
+            json,
+        };
+        let mut conn = redis::Client::open(database.as_str())?
+            .get_async_connection()
+            .await?;
+        let len: usize = conn
+            .rpush(BOSS_HCS_QUEUE_KEY, serde_json::to_string(&queued)?)
+            .await?;
+        Ok(json!({ "queued": len }))
+    }
 
-            match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
-                header: Some(vec![
-                    CurlKV {
-                        key: "ACCESSTOKEN".to_string(),
-                        value: region,
-                    },
-                    CurlKV {
-                        key: "ACCESSTOKEN-AP".to_string(),
-                        value: token.expect("ACCESSTOKEN-AP none invalid"),
-                    },
-                ]),
-                query: Some(vec![CurlKV {
-                    key: "ap_wallet".to_string(),
-                    value: wallet,
-                }]),
-                json: None,
-                url: format!("{}/{}", root_url, &arg.path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response["hcs"].clone())
-                    } else {
-                        return Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ));
+    /* returns the entries still queued, only an unreachable BOSS stops it */
+    pub async fn hcs_queue_flush(&self) -> Result<usize> {
+        let database = self
+            .database
+            .as_ref()
+            .ok_or_else(|| anyhow!("core/database none invalid"))?;
+        let mut conn = redis::Client::open(database.as_str())?
+            .get_async_connection()
+            .await?;
+        let max_age = chrono::Duration::seconds(
+            self.paths
+                .hcs_queue_max_age
+                .unwrap_or(BOSS_HCS_QUEUE_MAX_AGE) as i64,
+        );
+
+        /* left by a run that died while posting, it goes first again */
+        while hcs_queue_move(
+            &mut conn,
+            BOSS_HCS_PROCESSING_KEY,
+            BOSS_HCS_QUEUE_KEY,
+            "RIGHT",
+            "LEFT",
+        )
+        .await?
+        .is_some()
+        {}
+
+        /* moved out atomically, removed once posted, put back if BOSS is away */
+        while let Some(entry) = hcs_queue_move(
+            &mut conn,
+            BOSS_HCS_QUEUE_KEY,
+            BOSS_HCS_PROCESSING_KEY,
+            "LEFT",
+            "RIGHT",
+        )
+        .await?
+        {
+            if let Some(q) = hcs_queued_take(&entry, Utc::now(), max_age) {
+                match self.post_ap_hcs_once(q.json).await {
+                    Ok(_) => debug!("[kap][boss] queued hcs from {} posted", q.timestamp),
+                    Err(e) if boss_offline(&e) => {
+                        hcs_queue_move(
+                            &mut conn,
+                            BOSS_HCS_PROCESSING_KEY,
+                            BOSS_HCS_QUEUE_KEY,
+                            "RIGHT",
+                            "LEFT",
+                        )
+                        .await?;
+                        break;
                     }
+                    Err(e) => warn!("[kap][boss] queued hcs from {} rejected - {e}", q.timestamp),
                 }
-                CurlResponse::TextFmt(s) => return Err(anyhow::anyhow!("text format - {s}")),
             }
+            conn.lrem::<_, _, ()>(BOSS_HCS_PROCESSING_KEY, 1, &entry)
+                .await?;
         }
-        WebBossPath::GetApInfo(arg) => {
-            if token.is_none() {
-                error!("[kap][boss] ap-acess-token not exist");
-                return Err(anyhow!("[kap][boss] ap-acess-token not exist"));
-            }
 
-            let path = arg.path;
+        Ok(conn.llen(BOSS_HCS_QUEUE_KEY).await?)
+    }
 
-            let wallet = if let Some(w) = wallet {
-                w
-            } else {
-                return Err(anyhow::anyhow!("wallet-address invalid"));
-            };
+    pub async fn get_ap_token(&self) -> Result<BossApToken> {
+        let response = self.get_ap_token_raw().await?;
+        serde_json::from_value(response["data"].clone())
+            .map_err(|e| anyhow!("[kap][boss] ap-access-token parse fail - {e}"))
+    }
 
-            match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
-                header: Some(vec![
-                    CurlKV {
-                        key: "ACCESSTOKEN".to_string(),
-                        value: region,
-                    },
-                    CurlKV {
-                        key: "ACCESSTOKEN-AP".to_string(),
-                        value: token.expect("ACCESSTOKEN-AP none invalid"),
-                    },
-                ]),
-                query: None,
-                json: Some(json!({ "ap_wallet": wallet })),
-                url: format!("{}/{}", root_url, &path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response["data"].clone())
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ))
-                    }
-                }
-                CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
-            }
+    pub async fn get_otp(&self) -> Result<BossOtp> {
+        let response = self.get_otp_raw().await?;
+        serde_json::from_value(response["data"].clone())
+            .map_err(|e| anyhow!("[kap][boss] otp parse fail - {e}"))
+    }
+
+    pub async fn get_hcs(&self) -> Result<Vec<BossHcs>> {
+        let hcs = self.get_hcs_raw().await?;
+        /* no hcs field is no task, anything else must parse */
+        if hcs.is_null() {
+            return Ok(vec![]);
         }
-        WebBossPath::GetApWallet(map) => {
-            match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
-                header: Some(vec![CurlKV {
-                    key: "ACCESSTOKEN".to_string(),
-                    value: region,
-                }]),
-                query: None,
-                json: Some(map.json),
-                url: format!("{}/{}", root_url, &map.path),
-            }))
-            .await?
-            {
-                CurlResponse::JsonFmt(response) => {
-                    if response["code"] == 200 {
-                        Ok(response["data"].clone())
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "{} [{}]",
-                            response["message"],
-                            response["code"]
-                        ))
-                    }
-                }
-                CurlResponse::TextFmt(s) => Err(anyhow::anyhow!("text format - {s}")),
+        serde_json::from_value(hcs).map_err(|e| anyhow!("[kap][boss] hcs parse fail - {e}"))
+    }
+
+    pub async fn get_ap_info(&self) -> Result<BossApInfo> {
+        Ok(serde_json::from_value(self.get_ap_info_raw().await?)?)
+    }
+
+    pub async fn get_ap_wallet(&self, json: Value) -> Result<BossApWallet> {
+        let path = ApWalletArg::default_path();
+        Ok(serde_json::from_value(
+            self.get_ap_wallet_raw(&path, json).await?,
+        )?)
+    }
+
+    /* configured entries shadow the builtin ones of the same name */
+    pub fn endpoint(&self, name: &str) -> Result<RuleBossEndpoint> {
+        if let Some(ep) = self
+            .paths
+            .endpoint
+            .iter()
+            .flatten()
+            .find(|ep| ep.name == name)
+        {
+            return Ok(ep.clone());
+        }
+
+        use RuleBossMethod::*;
+        use RuleBossWallet::Json as WJson;
+        use RuleBossWallet::Query;
+        let p = &self.paths;
+        let ep = match name {
+            "ap_token" => {
+                RuleBossEndpoint::builtin(name, &p.ap_token_path, Get, false, Query, None)
+            }
+            "otp" => RuleBossEndpoint::builtin(name, &p.otp_path, Get, true, Query, None),
+            "hcs" => RuleBossEndpoint::builtin(name, &p.hcs_path, Get, true, Query, Some("hcs")),
+            "ap_hcs" => {
+                RuleBossEndpoint::builtin(name, &p.ap_hcs_path, Post, true, Query, Some("data"))
+            }
+            "ap_info" => {
+                RuleBossEndpoint::builtin(name, &p.ap_info_path, Get, true, WJson, Some("data"))
+            }
+            _ => return Err(anyhow!("boss/endpoint {name} nonexist")),
+        };
+        Ok(ep)
+    }
+
+    pub async fn call_endpoint(&self, ep: &RuleBossEndpoint, json: Option<Value>) -> Result<Value> {
+        let headers = self.headers(ep.ap_token.unwrap_or(true))?;
+        let (query, json) = match ep.wallet.unwrap_or(RuleBossWallet::Query) {
+            RuleBossWallet::Query => (self.wallet_query()?, json),
+            RuleBossWallet::Json => {
+                let mut json = json.unwrap_or_else(|| json!({}));
+                json.as_object_mut()
+                    .ok_or_else(|| anyhow!("boss/endpoint {} json-data not object", ep.name))?
+                    .insert("ap_wallet".to_string(), json!(self.wallet()?));
+                (None, Some(json))
+            }
+            RuleBossWallet::None => (None, json),
+        };
+
+        let args = self.args(headers, query, json, &Some(ep.path.clone()));
+        let response = self
+            .request(ep.method == Some(RuleBossMethod::Post), args)
+            .await?;
+        Ok(if let Some(ref field) = ep.field {
+            response[field.as_str()].clone()
+        } else {
+            response
+        })
+    }
+
+    /* CLI-shaped entry, --path of the subcommand wins over rule.toml */
+    pub async fn call(&self, class: WebBossPath) -> Result<Value> {
+        let mut boss = self.clone();
+        match class {
+            WebBossPath::GetApToken(arg) => {
+                boss.paths.ap_token_path = Some(arg.path);
+                boss.get_ap_token_raw().await
+            }
+            WebBossPath::GetOtp(arg) => {
+                boss.paths.otp_path = Some(arg.path);
+                boss.get_otp_raw().await
+            }
+            WebBossPath::GetHcs(arg) => {
+                boss.paths.hcs_path = Some(arg.path);
+                boss.get_hcs_raw().await
+            }
+            WebBossPath::GetApInfo(arg) => {
+                boss.paths.ap_info_path = Some(arg.path);
+                boss.get_ap_info_raw().await
+            }
+            WebBossPath::GetApWallet(arg) => boss.get_ap_wallet_raw(&arg.path, arg.json).await,
+            WebBossPath::PostApHcs(arg) => {
+                boss.paths.ap_hcs_path = Some(arg.path);
+                boss.post_ap_hcs(arg.json).await
+            }
+            WebBossPath::FlushApHcs(arg) => {
+                boss.paths.ap_hcs_path = Some(arg.path);
+                let remaining = boss.hcs_queue_flush().await?;
+                Ok(json!({ "remaining": remaining }))
+            }
+            WebBossPath::Call(arg) => {
+                let ep = boss.endpoint(&arg.name)?;
+                boss.call_endpoint(&ep, arg.json).await
             }
         }
     }
 }
 
+#[cfg(feature = "boss-api")]
+#[allow(dead_code)]
+pub async fn boss_web_api(
+    wallet: Option<String>,
+    root_url: String,
+    region: String,
+    token: Option<String>,
+    class: WebBossPath,
+    client: &CurlClientConfig,
+) -> Result<serde_json::Value> {
+    let mut boss = BossClient::new(root_url, region);
+    boss.wallet = wallet;
+    boss.ap_token = token;
+    boss.client = client.clone();
+    boss.call(class).await
+}
+
 #[cfg(feature = "boss-api")]
 #[allow(dead_code)]
 pub async fn boss_web_cli(opt: WebBossOpt) -> Result<()> {
@@ -606,7 +2089,10 @@ pub async fn boss_web_cli(opt: WebBossOpt) -> Result<()> {
     let root_url = if let Some(root) = opt.root {
         root
     } else {
-        rule.boss.root_url.expect("boss/root-url none invalid")
+        rule.boss
+            .root_url
+            .clone()
+            .expect("boss/root-url none invalid")
     };
 
     let region = if let Some(region) = opt.access_region {
@@ -624,10 +2110,18 @@ pub async fn boss_web_cli(opt: WebBossOpt) -> Result<()> {
     let wallet = if let Some(wallet) = opt.wallet {
         Some(wallet)
     } else {
-        core.wallet_address
+        core.wallet_address.map(String::from)
     };
 
-    let resp = boss_web_api(wallet, root_url, region, token, opt.class).await?;
+    let mut boss = BossClient::new(root_url, region);
+    boss.wallet = wallet;
+    boss.ap_token = token;
+    boss.client = opt.client.or(rule.boss.client.as_ref());
+    boss.sign_from(&rule.boss, &rule.core.config).await?;
+    boss.paths = rule.boss;
+    boss.config = Some(rule.core.config);
+    boss.database = rule.core.database;
+    let resp = boss.call(opt.class).await?;
     println!("{}", to_colored_json_auto(&resp)?);
     Ok(())
 }
@@ -676,9 +2170,13 @@ pub struct WebAwsOpt {
         default_value = "/etc/fika_manager/rule.toml"
     )]
     rule: String,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
 struct AwsDeviceEntry {
     device: Option<String>,
     owner: Option<String>,
@@ -686,15 +2184,22 @@ struct AwsDeviceEntry {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
 struct AwsDeviceList {
     data: Vec<AwsDeviceEntry>,
 }
 
 #[cfg(feature = "aws-cli")]
-pub async fn aws_web_api(root_url: &str, auth_token: &str, class: WebAwsPath) -> Result<()> {
+pub async fn aws_web_api(
+    root_url: &str,
+    auth_token: &str,
+    class: WebAwsPath,
+    client: &CurlClientConfig,
+) -> Result<()> {
     match class {
         WebAwsPath::GetDevice(state) => {
             match curl_web_api(CurlMethod::GetJson(CurlGetJsonArgs {
+                cache: None,
                 header: Some(vec![CurlKV {
                     key: "authorizationToken".to_string(),
                     value: auth_token.to_string(),
@@ -708,6 +2213,12 @@ pub async fn aws_web_api(root_url: &str, auth_token: &str, class: WebAwsPath) ->
                     None
                 },
                 json: None,
+                timeout: CurlTimeout::api_default(),
+                pool: CurlPool::default(),
+                client: client.clone(),
+                show: CurlShow::default(),
+                auth: CurlAuth::default(),
+                config: None,
                 url: format!("{}/{}", root_url, &state.device_path),
             }))
             .await?
@@ -762,7 +2273,8 @@ pub async fn aws_web_cli(opt: WebAwsOpt) -> Result<()> {
             .expect("auth-token nonexist")
     };
 
-    aws_web_api(&root_url, &auth_token, opt.class).await
+    let client = opt.client.or(rule.aws.client.as_ref());
+    aws_web_api(&root_url, &auth_token, opt.class, &client).await
 }
 
 pub fn web_full_url(url: &str, path: &str, query: &Vec<(&str, &str)>) -> Result<String> {
@@ -770,3 +2282,534 @@ pub fn web_full_url(url: &str, path: &str, query: &Vec<(&str, &str)>) -> Result<
 
     Ok(url.into())
 }
+
+/*
+ * OAuth2 client-credentials, tokens are cached until shortly before expiry
+ * under kap/oauth/{name} in redis (core.database), or in `cache` if set
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RuleOAuthConfig {
+    pub name: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+    pub cache: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct OAuthToken {
+    access_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OAuthTokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+}
+
+const OAUTH_EXPIRY_MARGIN: i64 = 30;
+const OAUTH_DEFAULT_EXPIRY: i64 = 300;
+
+fn oauth_config<'a>(rule: &'a RuleConfig, name: &str) -> Result<&'a RuleOAuthConfig> {
+    rule.oauth
+        .iter()
+        .flatten()
+        .find(|o| o.name == name)
+        .ok_or_else(|| anyhow!("rule/oauth {name} nonexist"))
+}
+
+fn oauth_expires_at(now: DateTime<Utc>, expires_in: Option<i64>) -> DateTime<Utc> {
+    let secs = expires_in.unwrap_or(OAUTH_DEFAULT_EXPIRY);
+    now + chrono::Duration::seconds((secs - OAUTH_EXPIRY_MARGIN).max(0))
+}
+
+async fn oauth_cache_get(cfg: &RuleOAuthConfig, database: Option<&str>) -> Result<OAuthToken> {
+    let raw = if let Some(ref path) = cfg.cache {
+        fs::read_to_string(path).await?
+    } else {
+        let database = database.ok_or_else(|| anyhow!("core/database none invalid"))?;
+        let mut conn = redis::Client::open(database)?
+            .get_async_connection()
+            .await?;
+        conn.get::<_, String>(format!("kap/oauth/{}", cfg.name))
+            .await?
+    };
+    Ok(serde_json::from_str(&raw)?)
+}
+
+async fn oauth_cache_set(
+    cfg: &RuleOAuthConfig,
+    database: Option<&str>,
+    token: &OAuthToken,
+) -> Result<()> {
+    let raw = serde_json::to_string(token)?;
+    if let Some(ref path) = cfg.cache {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        /* write_atomic keeps the mode of what it replaces, a bearer token is 0600 */
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .and_then(|f| f.set_permissions(std::fs::Permissions::from_mode(0o600)))
+            .map_err(|e| anyhow!("oauth/{} cache {} fail - {e}", cfg.name, path.display()))?;
+        write_atomic(path, raw.as_bytes()).await
+    } else {
+        let database = database.ok_or_else(|| anyhow!("core/database none invalid"))?;
+        let mut conn = redis::Client::open(database)?
+            .get_async_connection()
+            .await?;
+        let ttl = (token.expires_at - Utc::now()).num_seconds().max(1) as usize;
+        conn.set_ex::<_, _, ()>(format!("kap/oauth/{}", cfg.name), raw, ttl)
+            .await?;
+        Ok(())
+    }
+}
+
+/* through the [boss.client] proxy and CA as every other boss-side call */
+async fn oauth_fetch(
+    cfg: &RuleOAuthConfig,
+    client: Option<&CurlClientConfig>,
+) -> Result<OAuthToken> {
+    let timeout = CurlTimeout::api_default();
+    let client = curl_client(CurlClientKey {
+        connect: timeout.connect,
+        client: client.cloned().unwrap_or_default(),
+        ..Default::default()
+    })?;
+
+    let mut form = vec![("grant_type", "client_credentials")];
+    if let Some(ref scope) = cfg.scope {
+        form.push(("scope", scope));
+    }
+    let resp = client
+        .post(&cfg.token_url)
+        .basic_auth(&cfg.client_id, Some(&cfg.client_secret))
+        .form(&form)
+        .timeout(Duration::from_secs(timeout.max.unwrap_or_default()))
+        .send()
+        .await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "oauth/{} token fail - {} {}",
+            cfg.name,
+            status,
+            resp.text().await.unwrap_or_default()
+        ));
+    }
+
+    let resp = resp.json::<OAuthTokenResponse>().await?;
+    Ok(OAuthToken {
+        access_token: resp.access_token,
+        expires_at: oauth_expires_at(Utc::now(), resp.expires_in),
+    })
+}
+
+pub async fn oauth_token(
+    cfg: &RuleOAuthConfig,
+    rule: &RuleConfig,
+    refresh: bool,
+) -> Result<String> {
+    let database = rule.core.database.as_deref();
+    if !refresh {
+        match oauth_cache_get(cfg, database).await {
+            Ok(token) if token.expires_at > Utc::now() => return Ok(token.access_token),
+            Ok(_) => debug!("oauth/{} cached token expired", cfg.name),
+            Err(e) => debug!("oauth/{} no cached token - {e}", cfg.name),
+        }
+    }
+
+    let token = oauth_fetch(cfg, rule.boss.client.as_ref()).await?;
+    /* a cache failure only costs another token request next time */
+    if let Err(e) = oauth_cache_set(cfg, database, &token).await {
+        warn!("oauth/{} cache token fail - {e}", cfg.name);
+    }
+    Ok(token.access_token)
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "OAuth2 client-credentials token")]
+pub struct WebOAuthOpt {
+    #[clap(
+        short = 'r',
+        long = "rule",
+        default_value = "/etc/fika_manager/rule.toml"
+    )]
+    rule: String,
+
+    #[clap(long = "refresh", help = "ignore the cached token")]
+    refresh: bool,
+
+    name: String,
+}
+
+pub async fn oauth_web_cli(opt: WebOAuthOpt) -> Result<()> {
+    let rule = RuleConfig::build_from(&opt.rule).await?;
+    let cfg = oauth_config(&rule, &opt.name)?;
+    println!("{}", oauth_token(cfg, &rule, opt.refresh).await?);
+    Ok(())
+}
+
+/*
+ * Server-Sent Events: a long-lived GET whose frames are republished as
+ * {"event","data","id","timestamp"} on a redis channel; reconnects after
+ * `retry` (or --reconnect) with Last-Event-ID
+ */
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct SseEvent {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct SseParser {
+    buf: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+    last_id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl SseParser {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = vec![];
+
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(SseEvent {
+                        event: self.event.take(),
+                        data: self.data.join("\n"),
+                        id: self.last_id.clone(),
+                    });
+                }
+                self.data.clear();
+                self.event = None;
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => self.data.push(value.to_string()),
+                "id" => self.last_id = Some(value.to_string()),
+                "retry" => self.retry = value.parse().ok().or(self.retry),
+                _ => {}
+            }
+        }
+
+        events
+    }
+}
+
+#[derive(Args, Debug)]
+#[clap(about = "Server-Sent Events to redis")]
+pub struct WebSseOpt {
+    #[clap(short = 'H', long = "header", help = "KEY:ValUE(s)")]
+    header: Option<Vec<CurlKV>>,
+
+    #[clap(short = 'Q', long = "query", help = "KEY:ValUE(s)")]
+    query: Option<Vec<CurlKV>>,
+
+    #[clap(short = 't', long = "topic", default_value = "kap/sse")]
+    topic: String,
+
+    #[clap(long = "database", default_value = "redis://127.0.0.1:6379")]
+    database: String,
+
+    #[clap(long = "reconnect", default_value = "5", help = "seconds")]
+    reconnect: u64,
+
+    #[clap(long = "connect-timeout", default_value = "10", help = "seconds")]
+    connect: u64,
+
+    #[clap(
+        long = "idle-timeout",
+        default_value = "120",
+        help = "seconds without a byte from the server before reconnect, 0 never"
+    )]
+    idle: u64,
+
+    #[clap(flatten)]
+    client: CurlClientConfig,
+
+    #[clap(flatten)]
+    auth: CurlAuth,
+
+    #[clap(long = "log-level", default_value = "info")]
+    log_level: String,
+
+    url: String,
+}
+
+async fn sse_stream(
+    opt: &WebSseOpt,
+    parser: &mut SseParser,
+    conn: &mut redis::aio::Connection,
+) -> Result<()> {
+    let client = curl_client(CurlClientKey {
+        connect: Some(opt.connect),
+        pool: CurlPool::default(),
+        client: opt.client.clone(),
+    })?;
+
+    let mut req = client
+        .get(&opt.url)
+        .header(reqwest::header::ACCEPT, "text/event-stream");
+    for h in opt.header.iter().flatten() {
+        req = req.header(&h.key, &h.value);
+    }
+    for q in opt.query.iter().flatten() {
+        req = req.query(&[(&q.key, &q.value)]);
+    }
+    if let Some(ref id) = parser.last_id {
+        req = req.header("Last-Event-ID", id);
+    }
+    req = opt.auth.apply(req).await?;
+
+    let mut resp = req.send().await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("sse {} fail - {}", opt.url, resp.status()));
+    }
+    info!("sse {} connected", opt.url);
+
+    /* a half-open connection sends nothing and never errors */
+    let idle = Duration::from_secs(opt.idle);
+    loop {
+        let chunk = if opt.idle == 0 {
+            resp.chunk().await?
+        } else {
+            tokio::time::timeout(idle, resp.chunk())
+                .await
+                .map_err(|_| anyhow!("sse {} idle for {}s", opt.url, opt.idle))??
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        for ev in parser.feed(&chunk) {
+            let payload = json!({
+                "event": ev.event,
+                "data": ev.data,
+                "id": ev.id,
+                "timestamp": Utc::now(),
+            });
+            debug!("sse event {payload}");
+            conn.publish::<_, _, ()>(&opt.topic, payload.to_string())
+                .await?;
+        }
+    }
+
+    Err(anyhow!("sse {} closed by server", opt.url))
+}
+
+const SSE_REDIS_RETRY_MAX: Duration = Duration::from_secs(60);
+
+pub async fn sse_web_cli(opt: WebSseOpt) -> Result<()> {
+    setup_logging(&opt.log_level)?;
+
+    let client = redis::Client::open(opt.database.as_str())?;
+    let mut conn: Option<redis::aio::Connection> = None;
+    let mut backoff = Duration::from_secs(opt.reconnect.max(1));
+    let mut parser = SseParser::default();
+
+    loop {
+        /* redis going away is retried with backoff, not the end of the tool */
+        let redis = match conn {
+            Some(ref mut c) => c,
+            None => match client.get_async_connection().await {
+                Ok(c) => {
+                    backoff = Duration::from_secs(opt.reconnect.max(1));
+                    conn.insert(c)
+                }
+                Err(e) => {
+                    warn!("db/redis async connect fail, retry in {:?} - {e}", backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(SSE_REDIS_RETRY_MAX);
+                    continue;
+                }
+            },
+        };
+        if let Err(e) = sse_stream(&opt, &mut parser, redis).await {
+            warn!("{e}");
+            if e.downcast_ref::<redis::RedisError>().is_some() {
+                conn = None;
+            }
+        }
+        /* a partial frame is never completed by the next connection */
+        parser.buf.clear();
+        parser.data.clear();
+        parser.event = None;
+
+        let wait = parser
+            .retry
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| Duration::from_secs(opt.reconnect));
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[test]
+fn test_curl_write_out() {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.append("x-trace", "a".parse().unwrap());
+    headers.append("x-trace", "b".parse().unwrap());
+    let meta = CurlMeta {
+        status: reqwest::StatusCode::NOT_FOUND,
+        version: reqwest::Version::HTTP_11,
+        headers,
+        url: "http://127.0.0.1/x".to_string(),
+        size: 42,
+        elapsed: Duration::from_millis(1500),
+    };
+
+    assert_eq!(
+        meta.write_out(r"%{http_code} %{size_download} %{time_total}\n"),
+        "404 42 1.500000\n"
+    );
+    assert_eq!(
+        meta.write_out("%{content_type};%header{x-trace};%{unknown};100%%"),
+        "application/json;a, b;;100%"
+    );
+    assert!(meta.head().starts_with("HTTP/1.1 404 Not Found\n"));
+}
+
+#[test]
+fn test_oauth_expires_at() {
+    let now = Utc::now();
+    assert_eq!(
+        (oauth_expires_at(now, Some(3600)) - now).num_seconds(),
+        3570
+    );
+    assert_eq!((oauth_expires_at(now, None) - now).num_seconds(), 270);
+    assert_eq!(oauth_expires_at(now, Some(10)), now);
+}
+
+#[test]
+fn test_curl_file_config_merge() {
+    let file: CurlFileConfig = toml::from_str(
+        r#"
+        header = ["ACCESSTOKEN:region", "X-Ap:1"]
+        bearer = "file-token"
+        connect_timeout = 5
+        max_time = 60
+        proxy = "http://10.0.0.1:3128"
+        "#,
+    )
+    .unwrap();
+    let mut header = Some(vec![CurlKV::from_str("X-Cli:2").unwrap()]);
+    let mut query = None;
+    let mut timeout = CurlTimeout {
+        connect: None,
+        max: Some(10),
+    };
+    let mut auth = CurlAuth::default();
+    let mut client = CurlClientConfig::default();
+
+    file.merge(
+        &mut header,
+        &mut query,
+        &mut timeout,
+        &mut auth,
+        &mut client,
+    )
+    .unwrap();
+    let header = header.unwrap();
+    assert_eq!(header.len(), 3);
+    assert_eq!(header[0].key, "ACCESSTOKEN");
+    assert_eq!(header[2].key, "X-Cli");
+    assert!(query.is_none());
+    assert_eq!((timeout.connect, timeout.max), (Some(5), Some(10)));
+    assert_eq!(auth.bearer.as_deref(), Some("file-token"));
+    assert_eq!(client.proxy.as_deref(), Some("http://10.0.0.1:3128"));
+}
+
+#[test]
+fn test_json_filter() {
+    let v = json!({
+        "code": 200,
+        "data": [
+            { "wallet": "0xaa", "tags": ["a", "b"] },
+            { "wallet": "0xbb", "odd key": 1 }
+        ]
+    });
+
+    assert_eq!(
+        json_filter(&v, "$.data[0].wallet").unwrap(),
+        vec![json!("0xaa")]
+    );
+    assert_eq!(
+        json_filter(&v, "$.data[-1]['odd key']").unwrap(),
+        vec![json!(1)]
+    );
+    assert_eq!(
+        json_filter(&v, "$.data[*].wallet").unwrap(),
+        vec![json!("0xaa"), json!("0xbb")]
+    );
+    assert_eq!(json_filter(&v, "$.code").unwrap(), vec![json!(200)]);
+    assert!(json_filter(&v, "$.nope[3]").unwrap().is_empty());
+    assert_eq!(json_filter(&v, "$").unwrap(), vec![v.clone()]);
+    assert!(json_filter(&v, "$.data[x").is_err());
+    assert!(json_filter(&v, "$..data").is_err());
+}
+
+#[test]
+fn test_hcs_queued_take() {
+    let now = Utc::now();
+    let max_age = chrono::Duration::seconds(BOSS_HCS_QUEUE_MAX_AGE as i64);
+    let entry = |at: DateTime<Utc>| {
+        serde_json::to_string(&BossHcsQueued {
+            timestamp: at,
+            json: json!({ "hcs_token": "t" }),
+        })
+        .unwrap()
+    };
+
+    let q = hcs_queued_take(&entry(now - chrono::Duration::hours(1)), now, max_age).unwrap();
+    assert_eq!(q.json["hcs_token"], "t");
+    assert!(hcs_queued_take(&entry(now - chrono::Duration::days(8)), now, max_age).is_none());
+    assert!(hcs_queued_take("{\"json\":{}}", now, max_age).is_none());
+    assert!(hcs_queued_take("not json", now, max_age).is_none());
+}
+
+#[test]
+fn test_sse_parser() {
+    let mut p = SseParser::default();
+
+    assert!(p
+        .feed(b": keepalive\n\nevent: config\nid: 7\nda")
+        .is_empty());
+    let evs = p.feed(b"ta: {\"a\":\ndata:1}\r\n\r\ndata: plain\n\nretry: 3000\n");
+    assert_eq!(
+        evs,
+        vec![
+            SseEvent {
+                event: Some("config".to_string()),
+                data: "{\"a\":\n1}".to_string(),
+                id: Some("7".to_string()),
+            },
+            SseEvent {
+                event: None,
+                data: "plain".to_string(),
+                id: Some("7".to_string()),
+            },
+        ]
+    );
+    assert_eq!(p.retry, Some(3000));
+}